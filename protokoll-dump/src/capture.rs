@@ -0,0 +1,240 @@
+//! Extracts UDP datagrams from pcap files and hex dumps.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use byteorder::{ByteOrder, BE, LE};
+use hex;
+
+pub struct Datagram {
+    /// capture time, unknown for hex dumps
+    pub time: Option<Duration>,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IPPROTO_UDP: u8 = 17;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parses a classic pcap file, skipping everything but unfragmented UDP datagrams.
+pub fn parse_pcap(data: &[u8]) -> io::Result<Vec<Datagram>> {
+    if data.len() < 24 {
+        return Err(invalid("not a pcap file"));
+    }
+    let (little_endian, nanos) = match (LE::read_u32(data), BE::read_u32(data)) {
+        (0xa1b2c3d4, _) => (true, false),
+        (0xa1b23c4d, _) => (true, true),
+        (_, 0xa1b2c3d4) => (false, false),
+        (_, 0xa1b23c4d) => (false, true),
+        _ => return Err(invalid("not a pcap file, pcapng isn't supported")),
+    };
+    let read_u32 = |buf: &[u8]| if little_endian { LE::read_u32(buf) } else { BE::read_u32(buf) };
+    let linktype = read_u32(&data[20..]);
+
+    let mut datagrams = Vec::new();
+    let mut rest = &data[24..];
+    while rest.len() >= 16 {
+        let secs = read_u32(rest);
+        let frac = read_u32(&rest[4..]);
+        let len = read_u32(&rest[8..]) as usize;
+        if rest.len() < 16 + len {
+            return Err(invalid("truncated pcap record"));
+        }
+        let frame = &rest[16..16 + len];
+        rest = &rest[16 + len..];
+
+        let time = Duration::new(secs as u64, if nanos { frac } else { frac.saturating_mul(1000) });
+        if let Some((src, dst, payload)) = link(linktype, frame) {
+            datagrams.push(Datagram { time: Some(time), src, dst, payload: payload.to_vec() });
+        }
+    }
+    Ok(datagrams)
+}
+
+/// Strips the link layer header and decodes the IP packet.
+fn link(linktype: u32, frame: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (ethertype, packet) = match linktype {
+        LINKTYPE_NULL if frame.len() >= 4 => {
+            // address family in host byte order of the capturing machine
+            let family = LE::read_u32(frame);
+            let family = if family > 0xffff { family.swap_bytes() } else { family };
+            match family {
+                2 => (ETHERTYPE_IPV4, &frame[4..]),
+                24 | 28 | 30 => (ETHERTYPE_IPV6, &frame[4..]),
+                _ => return None,
+            }
+        }
+        LINKTYPE_ETHERNET if frame.len() >= 14 => {
+            let mut offset = 12;
+            while frame.len() >= offset + 2 && BE::read_u16(&frame[offset..]) == ETHERTYPE_VLAN {
+                offset += 4;
+            }
+            if frame.len() < offset + 2 {
+                return None;
+            }
+            (BE::read_u16(&frame[offset..]), &frame[offset + 2..])
+        }
+        LINKTYPE_RAW if !frame.is_empty() => match frame[0] >> 4 {
+            4 => (ETHERTYPE_IPV4, frame),
+            6 => (ETHERTYPE_IPV6, frame),
+            _ => return None,
+        },
+        LINKTYPE_LINUX_SLL if frame.len() >= 16 => (BE::read_u16(&frame[14..]), &frame[16..]),
+        LINKTYPE_LINUX_SLL2 if frame.len() >= 20 => (BE::read_u16(frame), &frame[20..]),
+        _ => return None,
+    };
+    match ethertype {
+        ETHERTYPE_IPV4 => ipv4(packet),
+        ETHERTYPE_IPV6 => ipv6(packet),
+        _ => None,
+    }
+}
+
+fn ipv4(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header = (packet[0] & 0xf) as usize * 4;
+    let total = BE::read_u16(&packet[2..]) as usize;
+    // fragments are skipped, they don't carry complete datagrams
+    let fragment = BE::read_u16(&packet[6..]) & 0x3fff;
+    if packet[9] != IPPROTO_UDP || fragment != 0 || header < 20 || total < header || packet.len() < total {
+        return None;
+    }
+    let src = IpAddr::V4(Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]));
+    let dst = IpAddr::V4(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]));
+    udp(src, dst, &packet[header..total])
+}
+
+fn ipv6(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    if packet.len() < 40 || packet[0] >> 4 != 6 {
+        return None;
+    }
+    // extension headers aren't followed
+    let total = 40 + BE::read_u16(&packet[4..]) as usize;
+    if packet[6] != IPPROTO_UDP || packet.len() < total {
+        return None;
+    }
+    let mut src = [0; 16];
+    let mut dst = [0; 16];
+    src.copy_from_slice(&packet[8..24]);
+    dst.copy_from_slice(&packet[24..40]);
+    udp(IpAddr::V6(Ipv6Addr::from(src)), IpAddr::V6(Ipv6Addr::from(dst)), &packet[40..total])
+}
+
+fn udp(src: IpAddr, dst: IpAddr, segment: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    if segment.len() < 8 {
+        return None;
+    }
+    let len = BE::read_u16(&segment[4..]) as usize;
+    if len < 8 || segment.len() < len {
+        return None;
+    }
+    let src = SocketAddr::new(src, BE::read_u16(segment));
+    let dst = SocketAddr::new(dst, BE::read_u16(&segment[2..]));
+    Some((src, dst, &segment[8..len]))
+}
+
+/// Parses a hex dump with one datagram per line.
+///
+/// Lines are either `SRC -> DST HEX`, or `> HEX` and `< HEX` for datagrams to and from
+/// the server of a single client. Whitespace within the hex is ignored, `#` starts a comment.
+pub fn parse_hex(dump: &str, port: u16) -> io::Result<Vec<Datagram>> {
+    let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+    let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    let mut datagrams = Vec::new();
+    for (number, line) in dump.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: &str| invalid(&format!("line {}: {}", number + 1, msg));
+        let (src, dst, hex): (_, _, String) = if line.starts_with('>') {
+            (client, server, line[1..].split_whitespace().collect())
+        } else if line.starts_with('<') {
+            (server, client, line[1..].split_whitespace().collect())
+        } else {
+            let mut words = line.split_whitespace();
+            let src = words.next().and_then(|s| s.parse().ok()).ok_or_else(|| error("invalid source address"))?;
+            if words.next() != Some("->") {
+                return Err(error("expected `SRC -> DST HEX`, `> HEX` or `< HEX`"));
+            }
+            let dst = words.next().and_then(|s| s.parse().ok()).ok_or_else(|| error("invalid destination address"))?;
+            (src, dst, words.collect())
+        };
+        let payload = hex::decode(&hex).map_err(|e| error(&e.to_string()))?;
+        datagrams.push(Datagram { time: None, src, dst, payload });
+    }
+    Ok(datagrams)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        let total = 20 + 8 + payload.len();
+        frame.extend_from_slice(&[0x45, 0, (total >> 8) as u8, total as u8, 0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]);
+        frame.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 2]);
+        let len = 8 + payload.len();
+        frame.extend_from_slice(&[0x13, 0x88, 0x52, 0x60, (len >> 8) as u8, len as u8, 0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_pcap() {
+        let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 1, 0, 0, 0];
+        for (i, payload) in [&b"foo"[..], b""].iter().enumerate() {
+            let frame = frame(payload);
+            for &field in &[i as u32, 500_000, frame.len() as u32, frame.len() as u32] {
+                let mut buf = [0; 4];
+                LE::write_u32(&mut buf, field);
+                pcap.extend_from_slice(&buf);
+            }
+            pcap.extend_from_slice(&frame);
+        }
+        let datagrams = parse_pcap(&pcap).unwrap();
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].src, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(datagrams[0].dst, "127.0.0.2:21088".parse().unwrap());
+        assert_eq!(datagrams[0].payload, b"foo");
+        assert_eq!(datagrams[1].time, Some(Duration::from_millis(1500)));
+        assert!(datagrams[1].payload.is_empty());
+
+        pcap.truncate(pcap.len() - 1);
+        assert!(parse_pcap(&pcap).is_err());
+        assert!(parse_pcap(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let dump = "# login\n> 0a 0b\n< 00ff\n127.0.0.1:5000 -> 127.0.0.2:21088 c0 ffee\n";
+        let datagrams = parse_hex(dump, 21088).unwrap();
+        assert_eq!(datagrams.len(), 3);
+        assert_eq!(datagrams[0].payload, [0x0a, 0x0b]);
+        assert_eq!(datagrams[0].dst.port(), 21088);
+        assert_eq!(datagrams[1].src.port(), 21088);
+        assert_eq!(datagrams[2].src, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(datagrams[2].payload, [0xc0, 0xff, 0xee]);
+
+        assert!(parse_hex("> 0", 21088).is_err());
+        assert!(parse_hex("127.0.0.1:5000 0a", 21088).is_err());
+    }
+}
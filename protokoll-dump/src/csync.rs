@@ -0,0 +1,234 @@
+//! Describes the datagrams of a csync connection.
+//!
+//! The client sends a `Login` followed by chunks, the server answers with `ServerMessage`s.
+//! Chunks can only be decoded after an accepted login, which tells the file length.
+
+use bitte_ein_bit::rle;
+use hex;
+use codec::{extension, features, index_field_size, stream_chunk_info, Chunk, ChunkInfo, Command, CopyRange,
+            DecodeError, EndOfStream, Login, Parity, ServerMessage, StatusUpdate, UploadRequest, ZeroRun};
+use Describe;
+
+/// Missing ranges printed per status update.
+const MAX_RANGES: usize = 8;
+
+#[derive(Default)]
+pub struct Flow {
+    /// login carrying a cookie, later logins with the same bytes are retransmissions
+    login: Option<Vec<u8>>,
+    chunk_info: Option<ChunkInfo>,
+    /// the upload is a streaming one, its chunk info changes with the end of the stream
+    stream: bool,
+    /// the login requested no features, status updates are bare runlengths
+    bare: bool,
+}
+
+impl Describe for Flow {
+    fn describe(&mut self, to_server: bool, payload: &[u8]) -> String {
+        if !to_server && self.bare && self.chunk_info.is_some() {
+            let update = StatusUpdate { features: 0, rebuilt: 0, prefix: &[], runlengths: payload };
+            return describe_status_update(&update, self.chunk_info.as_ref());
+        }
+        if !to_server {
+            return describe_server_message(payload, self.chunk_info.as_ref());
+        }
+        if self.login.as_ref().map_or(false, |login| login.as_slice() == payload) {
+            return "login (retransmitted)".to_string();
+        }
+        match self.chunk_info {
+            Some(_) if self.stream => self.describe_stream_message(payload),
+            Some(ref chunk_info) => describe_chunk(payload, chunk_info),
+            None => self.describe_login(payload),
+        }
+    }
+}
+
+impl Flow {
+    fn describe_login(&mut self, payload: &[u8]) -> String {
+        let login = match Login::decode(payload) {
+            Ok(login) => login,
+            Err(e) => return format!("invalid login: {}", e),
+        };
+        let command = match login.command {
+            Command::UploadRequest(UploadRequest { path, features, metadata, .. }) if features & features::STREAM != 0 => {
+                if !login.cookie.is_empty() {
+                    self.chunk_info = Some(stream_chunk_info(0));
+                    self.stream = true;
+                }
+                format!("streaming upload {:?}, features {:#x}{}", path, features,
+                    metadata.map_or(String::new(), |m| format!(", {:?}", m)))
+            }
+            Command::UploadRequest(UploadRequest { path, length, features, metadata }) => {
+                let chunk_info = index_field_size(length, features);
+                let description = format!("upload {:?}, {} bytes in {} chunks of {}, features {:#x}{}",
+                    path, length, chunk_info.num_chunks, chunk_info.chunk_size, features,
+                    metadata.map_or(String::new(), |m| format!(", {:?}", m)));
+                if !login.cookie.is_empty() {
+                    self.chunk_info = Some(chunk_info);
+                    self.bare = features == 0;
+                }
+                description
+            }
+            command => format!("{:?}", command),
+        };
+        if login.cookie.is_empty() {
+            format!("login without cookie: {}", command)
+        } else {
+            self.login = Some(payload.to_vec());
+            format!("login: {}", command)
+        }
+    }
+}
+
+impl Flow {
+    fn describe_stream_message(&mut self, payload: &[u8]) -> String {
+        let chunk = match Chunk::decode_stream(payload.to_vec()) {
+            Ok(chunk) => chunk,
+            Err(e) => return format!("invalid chunk: {}", e),
+        };
+        match chunk.index {
+            extension::FIN => "fin".to_string(),
+            extension::END_OF_STREAM => match EndOfStream::decode(&chunk) {
+                Ok(end) => {
+                    let chunk_info = stream_chunk_info(end.length);
+                    let description = format!("end of stream at {} bytes in {} chunks", end.length, chunk_info.num_chunks);
+                    self.chunk_info = Some(chunk_info);
+                    description
+                }
+                Err(e) => format!("invalid extension {}: {}", chunk.index, e),
+            },
+            id if id < extension::RESERVED => format!("unknown extension {}", id),
+            id => format!("chunk {}", id - extension::RESERVED),
+        }
+    }
+}
+
+fn describe_chunk(payload: &[u8], chunk_info: &ChunkInfo) -> String {
+    let chunk = match Chunk::decode(payload.to_vec(), chunk_info.index_field_size) {
+        Ok(chunk) => chunk,
+        Err(e) => return format!("invalid chunk: {}", e),
+    };
+    let num_chunks = chunk_info.num_chunks;
+    let result: Result<String, DecodeError> = match chunk.index {
+        index if index < num_chunks => Ok(format!("chunk {}{}", index,
+            if chunk.is_compressed(chunk_info) { " (compressed)" } else { "" })),
+        index if index == num_chunks + extension::FIN => Ok("fin".to_string()),
+        index if index == num_chunks + extension::PARITY => Parity::decode(&chunk, chunk_info)
+            .map(|p| format!("parity of chunks {}..{}", p.first, p.first + p.count)),
+        index if index == num_chunks + extension::ZERO_RUN => ZeroRun::decode(&chunk, chunk_info)
+            .map(|z| format!("zero run of chunks {}..{}", z.first, z.first + z.count)),
+        index if index == num_chunks + extension::COPY_RANGE => CopyRange::decode(&chunk, chunk_info)
+            .map(|c| format!("copy of chunks {}..{} from old offset {}", c.first, c.first + c.count, c.old_offset)),
+        index => Ok(format!("unknown extension {}", index - num_chunks)),
+    };
+    result.unwrap_or_else(|e| format!("invalid extension {}: {}", chunk.index - num_chunks, e))
+}
+
+fn describe_server_message(payload: &[u8], chunk_info: Option<&ChunkInfo>) -> String {
+    match ServerMessage::decode(payload) {
+        Ok(ServerMessage::StatusUpdate(update)) => describe_status_update(&update, chunk_info),
+        Ok(ServerMessage::Signatures(s)) => format!("signatures of blocks {}..{} of {}, block size {}",
+            s.first, s.first + s.blocks.len() as u64, s.num_blocks, s.block_size),
+        Ok(ServerMessage::Ack(ack)) => format!("ack {:?}", ack),
+        Ok(ServerMessage::Cookie(cookie)) => format!("cookie of {} bytes", cookie.len()),
+        Ok(ServerMessage::RateHint(rate)) => format!("rate hint of {} bytes/s", rate),
+        Err(e) => format!("invalid server message: {}", e),
+    }
+}
+
+fn describe_status_update(update: &StatusUpdate, chunk_info: Option<&ChunkInfo>) -> String {
+    let mut received = 0u64;
+    let mut position = 0u64;
+    let mut missing = Vec::new();
+    for run in rle::decode_runs(update.runlengths) {
+        let (received_run, range) = match run {
+            Ok(run) => run,
+            Err(e) => return format!("invalid status update: {}", e),
+        };
+        if received_run {
+            received += range.end - range.start;
+        } else {
+            missing.push(format!("{}..{}", range.start, range.end));
+        }
+        position = range.end;
+    }
+    let mut description = format!("status update: features {:#x}, rebuilt {}, received {}",
+        update.features, update.rebuilt, received);
+    if !update.prefix.is_empty() {
+        description += &format!(", digest of the kept chunks {}", hex::encode(update.prefix));
+    }
+    if let Some(chunk_info) = chunk_info {
+        description += &format!(" of {}", chunk_info.num_chunks);
+        if position < chunk_info.num_chunks {
+            description += &format!(", truncated after chunk {}", position);
+        }
+    }
+    if !missing.is_empty() {
+        let more = missing.len().saturating_sub(MAX_RANGES);
+        missing.truncate(MAX_RANGES);
+        description += &format!(", missing {}", missing.join(", "));
+        if more > 0 {
+            description += &format!(" and {} more ranges", more);
+        }
+    }
+    description
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::{stream_id, write_cookie, Ack, STREAM_CHUNK_SIZE};
+
+    #[test]
+    fn test_flow() {
+        let mut flow = Flow::default();
+        let request = || Command::UploadRequest(UploadRequest { path: "foo", length: 100_000, features: 0, metadata: None });
+        let mut login = Vec::new();
+        Login { client_token: b"token", cookie: &[], command: request() }.encode(&mut login);
+        assert!(flow.describe(true, &login).starts_with("login without cookie: upload \"foo\", 100000 bytes"));
+        let mut cookie = Vec::new();
+        write_cookie(&[0; 24], &mut cookie);
+        assert_eq!(flow.describe(false, &cookie), "cookie of 24 bytes");
+
+        let mut login = Vec::new();
+        Login { client_token: b"token", cookie: &[0; 24], command: request() }.encode(&mut login);
+        assert!(flow.describe(true, &login).starts_with("login: upload"));
+        assert_eq!(flow.describe(true, &login), "login (retransmitted)");
+
+        let chunk_info = index_field_size(100_000, 0);
+        let chunk = Chunk::new(Vec::new(), 3, chunk_info.index_field_size, chunk_info.chunk_size as usize);
+        assert_eq!(flow.describe(true, &chunk.buf), "chunk 3");
+        let fin = Chunk::new(Vec::new(), chunk_info.num_chunks + extension::FIN, chunk_info.index_field_size, 0);
+        assert_eq!(flow.describe(true, &fin.buf), "fin");
+        let zero_run = ZeroRun { first: 5, count: 2 }.encode(&chunk_info, Vec::new());
+        assert_eq!(flow.describe(true, &zero_run.buf), "zero run of chunks 5..7");
+
+        // 2 received, 3 missing, 1 received, 2 missing, bare as no features were requested
+        let update = [2, 3, 1, 2];
+        assert_eq!(flow.describe(false, &update),
+            format!("status update: features 0x0, rebuilt 0, received 3 of {}, truncated after chunk 8, missing 2..5, 6..8",
+                chunk_info.num_chunks));
+
+        // no upload, e.g. a rename
+        let mut flow = Flow::default();
+        let mut ack = Vec::new();
+        Ack::NotFound.encode(&mut ack);
+        assert_eq!(flow.describe(false, &ack), "ack NotFound");
+        assert_eq!(flow.describe(false, &[42]), "invalid server message: unknown server message 42");
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut flow = Flow::default();
+        let request = Command::UploadRequest(UploadRequest { path: "pipe", length: 0, features: features::STREAM, metadata: None });
+        let mut login = Vec::new();
+        Login { client_token: b"token", cookie: &[0; 24], command: request }.encode(&mut login);
+        assert_eq!(flow.describe(true, &login), "login: streaming upload \"pipe\", features 0x20");
+        let chunk = Chunk::new_stream(Vec::new(), stream_id(1000), 10);
+        assert_eq!(flow.describe(true, &chunk.buf), "chunk 1000");
+        let end = EndOfStream { length: STREAM_CHUNK_SIZE + 1 }.encode(Vec::new());
+        assert_eq!(flow.describe(true, &end.buf), format!("end of stream at {} bytes in 2 chunks", STREAM_CHUNK_SIZE + 1));
+        assert_eq!(flow.describe(false, &[0, 0x20, 1, 1]), "status update: features 0x20, rebuilt 0, received 1 of 2, missing 1..2");
+        assert_eq!(flow.describe(true, &Chunk::new_stream(Vec::new(), extension::FIN, 0).buf), "fin");
+    }
+}
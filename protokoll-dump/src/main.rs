@@ -0,0 +1,148 @@
+extern crate byteorder;
+extern crate hex;
+#[macro_use]
+extern crate structopt;
+extern crate bitte_ein_bit;
+extern crate bytes;
+extern crate tokio_io;
+extern crate csync as csync_proto;
+extern crate scsync as scsync_proto;
+
+mod capture;
+mod csync;
+mod scsync;
+
+// only decoding of the codecs is used here
+use csync_proto::codec;
+use scsync_proto::codec as scodec;
+use scsync_proto::{blockdb, seal};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+
+use structopt::StructOpt;
+
+use capture::Datagram;
+
+#[derive(StructOpt)]
+#[structopt(name = "protokoll-dump", about = "Decodes captured csync and scsync datagrams")]
+struct Opt {
+    /// Protocol of the capture, csync or scsync
+    #[structopt(long = "protocol", default_value = "csync")]
+    protocol: Protocol,
+    /// Port of the server, used to tell the direction of datagrams
+    #[structopt(short = "p", long = "port", default_value = "21088")]
+    port: u16,
+    /// Master keyfile of scsync, root updates and their responses can't be decoded without it
+    #[structopt(long = "key", parse(from_os_str))]
+    key: Option<PathBuf>,
+    /// Read a hex dump instead of a pcap file, see `capture::parse_hex`
+    #[structopt(long = "hex")]
+    hex: bool,
+    /// pcap file or hex dump
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Csync,
+    Scsync,
+}
+
+impl ::std::str::FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Protocol, String> {
+        match s {
+            "csync" => Ok(Protocol::Csync),
+            "scsync" => Ok(Protocol::Scsync),
+            _ => Err(format!("unknown protocol {:?}, expected csync or scsync", s)),
+        }
+    }
+}
+
+/// Decoder keeping the state of a single flow.
+pub trait Describe {
+    /// Describes a datagram of the flow in a single line.
+    fn describe(&mut self, to_server: bool, payload: &[u8]) -> String;
+}
+
+struct Flow {
+    client: SocketAddr,
+    server: SocketAddr,
+    decoder: Box<Describe>,
+    lines: Vec<String>,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(&opt) {
+        eprintln!("Can't read {}: {}", opt.input.display(), e);
+        process::exit(1);
+    }
+}
+
+fn run(opt: &Opt) -> io::Result<()> {
+    let key = match opt.key {
+        Some(ref path) => seal::read_keyfile(path)?,
+        None => [0; 32],
+    };
+    let data = fs::read(&opt.input)?;
+    let datagrams = if opt.hex {
+        capture::parse_hex(&String::from_utf8_lossy(&data), opt.port)?
+    } else {
+        capture::parse_pcap(&data)?
+    };
+
+    let mut flows: Vec<Flow> = Vec::new();
+    let mut index = HashMap::new();
+    let mut unrelated = 0;
+    let start = datagrams.first().and_then(|d| d.time);
+    for Datagram { time, src, dst, payload } in datagrams {
+        let (client, server, to_server) = if dst.port() == opt.port {
+            (src, dst, true)
+        } else if src.port() == opt.port {
+            (dst, src, false)
+        } else {
+            unrelated += 1;
+            continue;
+        };
+        let flow = *index.entry((client, server)).or_insert_with(|| {
+            let decoder: Box<Describe> = match opt.protocol {
+                Protocol::Csync => Box::new(csync::Flow::default()),
+                Protocol::Scsync => Box::new(scsync::Flow::new(key)),
+            };
+            flows.push(Flow { client, server, decoder, lines: Vec::new() });
+            flows.len() - 1
+        });
+        let flow = &mut flows[flow];
+        let time = match (time, start) {
+            (Some(time), Some(start)) => {
+                // pcap records aren't necessarily ordered
+                let elapsed = time.checked_sub(start).unwrap_or_default();
+                format!("{:>4}.{:06}", elapsed.as_secs(), elapsed.subsec_micros())
+            }
+            _ => String::new(),
+        };
+        let description = flow.decoder.describe(to_server, &payload);
+        let arrow = if to_server { "->" } else { "<-" };
+        flow.lines.push(format!("{:>11} {} {:>4} {}", time, arrow, payload.len(), description));
+    }
+
+    for flow in &flows {
+        println!("{} <-> {}", flow.client, flow.server);
+        for line in &flow.lines {
+            println!("{}", line);
+        }
+        println!();
+    }
+    if unrelated > 0 {
+        println!("{} datagrams neither from nor to port {}", unrelated, opt.port);
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Describes the datagrams of a scsync connection.
+//!
+//! Both sides send the same messages. Chunk ids of payloads are allocated to blocks by
+//! `BlockRequestResponse`s, which are remembered to tell the block of a payload.
+
+use bytes::BytesMut;
+use hex;
+use tokio_io::codec::Decoder;
+
+use blockdb::BlockId;
+use scodec::{self, Msg, MyCodec};
+use seal::MasterKey;
+use Describe;
+
+/// Missing ranges printed per status.
+const MAX_RANGES: usize = 8;
+
+pub struct Flow {
+    /// accepts replays, captures are old and may contain retransmissions
+    codec: MyCodec,
+    /// chunk ids `start..end` allocated to byte ranges of a block
+    transfers: Vec<(u64, u64, BlockId, Vec<(u64, u64)>)>,
+}
+
+impl Flow {
+    pub fn new(key: MasterKey) -> Flow {
+        Flow {
+            codec: MyCodec::accepting_replays(key),
+            transfers: Vec::new(),
+        }
+    }
+}
+
+/// Abbreviates a block id like git does with commits.
+fn short(id: &BlockId) -> String {
+    hex::encode(&id[..4])
+}
+
+impl Describe for Flow {
+    fn describe(&mut self, _to_server: bool, payload: &[u8]) -> String {
+        let msg = match self.codec.decode(&mut BytesMut::from(payload)) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return "empty datagram".to_string(),
+            Err(e) => return format!("invalid message: {}", e),
+        };
+        match msg {
+            Msg::TransferPayload(payload) => {
+                let block = self.transfers.iter()
+                    .find(|&&(start, end, _, _)| start <= payload.chunkid && payload.chunkid < end)
+                    .and_then(|&(start, _, ref id, ref ranges)| {
+                        let (offset, _) = scodec::range_chunk(start, ranges, payload.chunkid)?;
+                        Some(format!(", byte {} of block {}", offset, short(id)))
+                    })
+                    .unwrap_or_default();
+                format!("payload {}{}, {} bytes", payload.chunkid, block, payload.data.len())
+            }
+            Msg::TransferStatus(status) => {
+                if status.missing_ranges.is_empty() {
+                    return "transfer status: nothing missing".to_string();
+                }
+                let ranges: Vec<_> = status.missing_ranges.iter().take(MAX_RANGES)
+                    .map(|&(from, to)| format!("{}..{}", from, to))
+                    .collect();
+                let mut description = format!("transfer status: missing {}", ranges.join(", "));
+                if status.missing_ranges.len() > MAX_RANGES {
+                    description += &format!(" and {} more ranges", status.missing_ranges.len() - MAX_RANGES);
+                }
+                description
+            }
+            Msg::RootUpdate(update) => format!("root update {} -> {} with {} hints",
+                short(&update.from_blockid), short(&update.to_blockref.blockid), update.to_blockref.hints.len()),
+            Msg::RootUpdateResponse(res) => format!("root update response {} -> {}",
+                short(&res.from_blockid), short(&res.to_blockid)),
+            Msg::BlockRequest(req) => {
+                let have: u64 = req.have.iter().map(|&(from, to)| to.saturating_sub(from)).sum();
+                if have == 0 {
+                    format!("block request {}", short(&req.blockid))
+                } else {
+                    format!("block request {}, having {} bytes", short(&req.blockid), have)
+                }
+            }
+            Msg::BlockRequestResponse(res) => {
+                let sent: u64 = res.ranges.iter().map(|&(from, to)| to.saturating_sub(from)).sum();
+                let description = format!("block request response {}, {} of {} bytes as payloads {}..{}",
+                    short(&res.blockid), sent, res.len, res.start_id, res.end_id);
+                self.transfers.push((res.start_id, res.end_id, res.blockid, res.ranges));
+                description
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio_io::codec::Encoder;
+    use bytes::Bytes;
+    use blockdb::BlockRef;
+    use scodec::{BlockRequest, BlockRequestResponse, RootUpdate, TransferPayload, TransferStatus};
+
+    fn describe(flow: &mut Flow, msg: Msg) -> String {
+        let mut buf = BytesMut::new();
+        MyCodec::new([0; 32]).encode(msg, &mut buf).unwrap();
+        flow.describe(true, &buf)
+    }
+
+    #[test]
+    fn test_flow() {
+        let mut flow = Flow::new([0; 32]);
+        let payload = || Msg::TransferPayload(TransferPayload { chunkid: 12, data: Bytes::from(&b"data"[..]) });
+        assert_eq!(describe(&mut flow, payload()), "payload 12, 4 bytes");
+        let mut blockid = [0; 32];
+        blockid[0] = 0xab;
+        let request = BlockRequest { blockid, have: vec![(0, 100), (5000, 6000)] };
+        assert_eq!(describe(&mut flow, Msg::BlockRequest(request)), "block request ab000000, having 1100 bytes");
+        let ranges = vec![(100, 5000), (6000, 10_000)];
+        let response = BlockRequestResponse { blockid, start_id: 10, end_id: 20, len: 10_000, ranges };
+        assert_eq!(describe(&mut flow, Msg::BlockRequestResponse(response)),
+            "block request response ab000000, 8900 of 10000 bytes as payloads 10..20");
+        assert_eq!(describe(&mut flow, payload()), "payload 12, byte 3016 of block ab000000, 4 bytes");
+        let status = TransferStatus { missing_ranges: vec![(1, 3), (5, 8)] };
+        assert_eq!(describe(&mut flow, Msg::TransferStatus(status)), "transfer status: missing 1..3, 5..8");
+        assert_eq!(flow.describe(false, &[42]), "invalid message: unknown message 42");
+        // root updates are sealed under the master key
+        let update = RootUpdate { nonce: [1; 12], from_blockid: [0; 32], to_blockref: BlockRef::new(blockid, [2; 16], Vec::new()) };
+        let mut buf = BytesMut::new();
+        MyCodec::new([1; 32]).encode(Msg::RootUpdate(update), &mut buf).unwrap();
+        assert_eq!(flow.describe(true, &buf), "invalid message: message not authentic");
+        // replays are shown
+        let update = RootUpdate { nonce: [1; 12], from_blockid: [0; 32], to_blockref: BlockRef::new(blockid, [2; 16], Vec::new()) };
+        let mut buf = BytesMut::new();
+        MyCodec::new([0; 32]).encode(Msg::RootUpdate(update), &mut buf).unwrap();
+        for _ in 0..2 {
+            assert_eq!(flow.describe(true, &buf), "root update 00000000 -> ab000000 with 0 hints");
+        }
+    }
+}
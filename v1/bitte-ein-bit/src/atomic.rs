@@ -0,0 +1,379 @@
+use alloc::boxed::Box;
+use core::fmt;
+use core::mem;
+use core::slice;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use {BitMap, BitSet, RangeArgument};
+
+/// A BitMap whose bits can be changed through shared references, e.g. by a receiving thread
+/// while another one encodes status updates from snapshots.
+///
+/// The bits are accessed as atomic bytes, thus the buffer may have any length and alignment,
+/// such as a memory mapped file. Ranges are filled a word at a time where they're aligned.
+///
+/// Bits may be set concurrently, and cleared concurrently, but clears must not run concurrently
+/// with sets: the zero count is updated after the bits, so a set of bits whose clear didn't count
+/// them yet would take it below zero.
+pub struct AtomicBitMap<T> {
+    /// the buffer, boxed so its bytes stay in place when the `AtomicBitMap` is moved
+    buf: *mut T,
+    bytes: *const AtomicU8,
+    len: usize,
+    num_bits: u64,
+    zeroes: AtomicU64,
+}
+
+// the buffer is only accessed through atomics while shared
+unsafe impl<T: Send> Send for AtomicBitMap<T> {}
+unsafe impl<T: Send> Sync for AtomicBitMap<T> {}
+
+impl<T: AsMut<[u8]>> AtomicBitMap<T> {
+    /// Create a new AtomicBitMap from an underlying buffer.
+    pub fn new(mut buf: T) -> AtomicBitMap<T> {
+        let num_bits = buf.as_mut().len() as u64 * 8;
+        AtomicBitMap::with_length(buf, num_bits)
+    }
+
+    /// Create a new AtomicBitMap with given number of bits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the passed number of bits do not fit into the buffer.
+    pub fn with_length(mut buf: T, num_bits: u64) -> AtomicBitMap<T> {
+        let zeroes = BitMap::with_length(buf.as_mut(), num_bits).zeroes();
+        let buf = Box::into_raw(Box::new(buf));
+        let bytes = unsafe { (*buf).as_mut() };
+        AtomicBitMap {
+            bytes: bytes.as_mut_ptr() as *const AtomicU8,
+            len: bytes.len(),
+            buf,
+            num_bits,
+            zeroes: AtomicU64::new(zeroes),
+        }
+    }
+}
+
+impl<T> AtomicBitMap<T> {
+    fn bytes(&self) -> &[AtomicU8] {
+        unsafe { slice::from_raw_parts(self.bytes, self.len) }
+    }
+
+    #[inline]
+    fn check(&self, bit: u64) {
+        if bit >= self.num_bits {
+            panic!("bit index out of bounds: the number of bits is {} but the the index is {}",
+                   self.num_bits, bit);
+        }
+    }
+
+    /// Get the bit at given position.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provided position is out of bounds.
+    pub fn get(&self, bit: u64) -> bool {
+        self.check(bit);
+        let byte = self.bytes()[(bit / 8) as usize].load(Ordering::Acquire);
+        byte & (1 << (bit % 8)) != 0
+    }
+
+    /// Set the bit at given position to given value and returns its old value.
+    ///
+    /// Clearing it must not run concurrently with sets, see `AtomicBitMap`.
+    ///
+    /// # Panics
+    ///
+    /// This functions panics if the bit-index is out of bounds.
+    pub fn set(&self, bit: u64, new: bool) -> bool {
+        self.check(bit);
+        let changed = self.count(fill_byte(&self.bytes()[(bit / 8) as usize], 1 << (bit % 8), new), new) != 0;
+        changed != new
+    }
+
+    /// Set all bits in the range to `1`, returning the number of bits which changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn set_range<R: RangeArgument<u64>>(&self, range: R) -> u64 {
+        self.fill_range(range, true)
+    }
+
+    /// Set all bits in the range to `0`, returning the number of bits which changed.
+    ///
+    /// Must not run concurrently with sets, see `AtomicBitMap`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn clear_range<R: RangeArgument<u64>>(&self, range: R) -> u64 {
+        self.fill_range(range, false)
+    }
+
+    fn fill_range<R: RangeArgument<u64>>(&self, range: R, value: bool) -> u64 {
+        let start = range.start().unwrap_or(0);
+        let end = range.end().unwrap_or(self.num_bits);
+        if start > end || end > self.num_bits {
+            panic!("range out of bounds: the number of bits is {} but the range is {}..{}",
+                   self.num_bits, start, end);
+        }
+        if start == end {
+            return 0;
+        }
+        let bytes = self.bytes();
+        let first = (start / 8) as usize;
+        let last = ((end - 1) / 8) as usize;
+        let head = 0xff << (start % 8);
+        let tail = 0xff >> (7 - (end - 1) % 8);
+        if first == last {
+            return self.count(fill_byte(&bytes[first], head & tail, value), value);
+        }
+        let mut changed = fill_byte(&bytes[first], head, value);
+        // the whole bytes in between, as words where they're aligned; any bytes are a valid word
+        // and they're only accessed through atomics
+        let (before, words, after) = unsafe { bytes[first + 1..last].align_to::<AtomicU64>() };
+        for byte in before.iter().chain(after) {
+            changed += fill_byte(byte, 0xff, value);
+        }
+        for word in words {
+            changed += fill_word(word, value);
+        }
+        changed += fill_byte(&bytes[last], tail, value);
+        self.count(changed, value)
+    }
+
+    /// Updates the zero count after `changed` bits were set to `value`, returning `changed`.
+    fn count(&self, changed: u64, value: bool) -> u64 {
+        if value {
+            self.zeroes.fetch_sub(changed, Ordering::AcqRel);
+        } else {
+            self.zeroes.fetch_add(changed, Ordering::AcqRel);
+        }
+        changed
+    }
+
+    /// Returns the number of `0` bits.
+    ///
+    /// It lags behind concurrent changes, but is exact once they returned.
+    pub fn zeroes(&self) -> u64 {
+        self.zeroes.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of `1` bits, see `zeroes`.
+    pub fn ones(&self) -> u64 {
+        self.num_bits - self.zeroes()
+    }
+
+    /// Returns the number of bits
+    pub fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    /// Returns `true` if all bits are `1`.
+    pub fn all(&self) -> bool {
+        self.zeroes() == 0
+    }
+
+    /// Returns `true` if any bit is `1`.
+    pub fn any(&self) -> bool {
+        self.zeroes() != self.num_bits
+    }
+
+    /// Copies the bits into `buf`, returning them as a BitMap.
+    ///
+    /// The bits are read one byte after the other, so the snapshot holds every change which
+    /// returned before it was taken. Its zero count always matches its bits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bits do not fit into `buf`.
+    pub fn snapshot_into<B: AsRef<[u8]> + AsMut<[u8]>>(&self, mut buf: B) -> BitMap<B> {
+        let len = ::div_ceil(self.num_bits, 8) as usize;
+        for (copy, byte) in buf.as_mut()[..len].iter_mut().zip(self.bytes()) {
+            *copy = byte.load(Ordering::Acquire);
+        }
+        BitMap::with_length(buf, self.num_bits)
+    }
+
+    /// Returns the position of the first bit at or after `from` which is `value`.
+    fn find_next(&self, from: u64, value: bool) -> Option<u64> {
+        if from >= self.num_bits {
+            return None;
+        }
+        // looking for `1`s in the flipped bytes when looking for `0`s
+        let flip = if value { 0 } else { 0xff };
+        let mut index = (from / 8) as usize;
+        let mut byte = (self.bytes()[index].load(Ordering::Acquire) ^ flip) & (0xff << (from % 8));
+        while byte == 0 {
+            index += 1;
+            if index == self.len {
+                return None;
+            }
+            byte = self.bytes()[index].load(Ordering::Acquire) ^ flip;
+        }
+        let bit = index as u64 * 8 + byte.trailing_zeros() as u64;
+        if bit < self.num_bits { Some(bit) } else { None }
+    }
+
+    /// Consumes self, returning the inner type
+    pub fn into_inner(self) -> T {
+        let buf = self.buf;
+        mem::forget(self);
+        unsafe { *Box::from_raw(buf) }
+    }
+}
+
+/// Sets the bits of `mask` in `byte` to `value`, returning how many changed.
+fn fill_byte(byte: &AtomicU8, mask: u8, value: bool) -> u64 {
+    if value {
+        (!byte.fetch_or(mask, Ordering::AcqRel) & mask).count_ones() as u64
+    } else {
+        (byte.fetch_and(!mask, Ordering::AcqRel) & mask).count_ones() as u64
+    }
+}
+
+/// Sets all bits of `word` to `value`, returning how many changed.
+fn fill_word(word: &AtomicU64, value: bool) -> u64 {
+    if value {
+        (!word.fetch_or(!0, Ordering::AcqRel)).count_ones() as u64
+    } else {
+        word.fetch_and(0, Ordering::AcqRel).count_ones() as u64
+    }
+}
+
+/// Reads the bits one byte after the other like `snapshot_into`, e.g. to encode the runs without
+/// copying the whole bitmap.
+impl<T> BitSet for AtomicBitMap<T> {
+    fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    fn zeroes(&self) -> u64 {
+        AtomicBitMap::zeroes(self)
+    }
+
+    fn get(&self, bit: u64) -> bool {
+        AtomicBitMap::get(self, bit)
+    }
+
+    fn find_next_zero(&self, from: u64) -> Option<u64> {
+        self.find_next(from, false)
+    }
+
+    fn find_next_one(&self, from: u64) -> Option<u64> {
+        self.find_next(from, true)
+    }
+}
+
+impl<T> Drop for AtomicBitMap<T> {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.buf)) }
+    }
+}
+
+impl<T> fmt::Debug for AtomicBitMap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AtomicBitMap")
+            .field("num_bits", &self.num_bits)
+            .field("zeroes", &self.zeroes())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec;
+    use std::vec::Vec;
+
+    #[test]
+    fn concurrent_set() {
+        let bitmap = Arc::new(AtomicBitMap::with_length(vec![0u8; 1000], 7999));
+        let threads: Vec<_> = (0..4).map(|thread| {
+            let bitmap = Arc::clone(&bitmap);
+            thread::spawn(move || {
+                // every thread sets the multiples of its number, overlapping with the others
+                for bit in (0..7999).filter(|bit| bit % (thread + 2) == 0) {
+                    bitmap.set(bit, true);
+                    assert!(bitmap.get(bit));
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let expected = (0..7999).filter(|bit| (2..6).any(|n| bit % n == 0)).count() as u64;
+        assert_eq!(bitmap.ones(), expected);
+
+        let snapshot = bitmap.snapshot_into(vec![0xff; 1000]);
+        assert_eq!(snapshot.ones(), expected);
+        for bit in 0..7999 {
+            assert_eq!(snapshot.get(bit), bitmap.get(bit));
+        }
+        let bitmap = Arc::try_unwrap(bitmap).unwrap();
+        assert_eq!(bitmap.into_inner(), snapshot.into_inner());
+    }
+
+    #[test]
+    fn ranges() {
+        let atomic = AtomicBitMap::with_length([0b1010_0101u8; 40], 315);
+        let mut bitmap = BitMap::with_length([0b1010_0101u8; 40], 315);
+        for &(start, end, value) in &[(3, 5, true), (5, 19, false), (7, 250, true), (64, 315, false), (0, 0, true)] {
+            let changed = if value { atomic.set_range(start..end) } else { atomic.clear_range(start..end) };
+            let expected = if value { bitmap.set_range(start..end) } else { bitmap.clear_range(start..end) };
+            assert_eq!(changed, expected);
+            assert_eq!(atomic.zeroes(), bitmap.zeroes());
+        }
+        assert!(atomic.set(3, false));
+        assert!(!atomic.set(3, false));
+        assert!(!atomic.set(3, true));
+        assert!(atomic.set(3, true));
+        assert!(!atomic.set(5, true));
+        bitmap.set(5, true);
+        assert_eq!(atomic.into_inner(), bitmap.into_inner());
+    }
+
+    #[test]
+    fn words() {
+        // the words in between are aligned differently depending on where the buffer starts
+        for offset in 0..8 {
+            let mut buf = [0u8; 72];
+            let mut expected = BitMap::with_length([0u8; 64], 509);
+            {
+                let atomic = AtomicBitMap::with_length(&mut buf[offset..offset + 64], 509);
+                for &(start, end, value) in &[(3, 500, true), (70, 200, false), (64, 509, false), (0, 137, true), (8, 136, false)] {
+                    let changed = if value { atomic.set_range(start..end) } else { atomic.clear_range(start..end) };
+                    let bits = if value { expected.set_range(start..end) } else { expected.clear_range(start..end) };
+                    assert_eq!(changed, bits, "offset {}, {}..{}", offset, start, end);
+                    assert_eq!(atomic.zeroes(), expected.zeroes());
+                }
+            }
+            assert_eq!(&buf[offset..offset + 64], &expected.get_ref()[..]);
+        }
+    }
+
+    #[test]
+    fn bitset() {
+        let atomic = AtomicBitMap::with_length([0b1010_0101u8; 40], 315);
+        let mut bitmap = BitMap::with_length([0b1010_0101u8; 40], 315);
+        for &(start, end) in &[(3, 5), (7, 250), (300, 315)] {
+            atomic.set_range(start..end);
+            bitmap.set_range(start..end);
+        }
+        // the padding bits after the last one are ignored
+        assert!(BitSet::iter_runs(&atomic).eq(bitmap.iter_runs()));
+        for from in 0..320 {
+            assert_eq!(atomic.find_next_zero(from), bitmap.find_next_zero(from), "next zero from {}", from);
+            assert_eq!(atomic.find_next_one(from), bitmap.find_next_one(from), "next one from {}", from);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_bounds() {
+        AtomicBitMap::with_length([0u8; 2], 12).get(12);
+    }
+}
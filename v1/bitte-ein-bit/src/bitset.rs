@@ -0,0 +1,183 @@
+use core::ops::Range;
+
+use RangeArgument;
+
+/// The read-only operations shared by all bitmap backends.
+///
+/// * `BitMap` stores the bits flat and is the cheapest for small and fragmented bitmaps.
+/// * `SummaryBitMap` adds summary levels to a `BitMap`, finding the next `0` in O(log n).
+/// * `RunBitMap` stores runs of `1`s, taking space by fragmentation instead of size.
+pub trait BitSet {
+    /// Returns the number of bits
+    fn num_bits(&self) -> u64;
+
+    /// Returns the number of `0` bits.
+    fn zeroes(&self) -> u64;
+
+    /// Get the bit at given position.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provided position is out of bounds.
+    fn get(&self, bit: u64) -> bool;
+
+    /// Returns the position of the first `0` bit at or after `from`.
+    fn find_next_zero(&self, from: u64) -> Option<u64>;
+
+    /// Returns the position of the first `1` bit at or after `from`.
+    fn find_next_one(&self, from: u64) -> Option<u64>;
+
+    /// Returns the number of `1` bits.
+    fn ones(&self) -> u64 {
+        self.num_bits() - self.zeroes()
+    }
+
+    /// Returns `true` if all bits are `1`.
+    fn all(&self) -> bool {
+        self.zeroes() == 0
+    }
+
+    /// Returns `true` if any bit is `1`.
+    fn any(&self) -> bool {
+        self.zeroes() != self.num_bits()
+    }
+
+    /// Returns the position of the first `0` bit.
+    fn find_first_zero(&self) -> Option<u64> {
+        self.find_next_zero(0)
+    }
+
+    /// Creates an iterator over the runs of equal bits, as their value and range.
+    fn iter_runs(&self) -> Runs<'_, Self> where Self: Sized {
+        Runs { bits: self, front: 0 }
+    }
+}
+
+/// The changing operations shared by all bitmap backends.
+pub trait BitSetMut: BitSet {
+    /// Set the bit at given position to given value and returns its old value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bit-index is out of bounds.
+    fn set(&mut self, bit: u64, new: bool) -> bool;
+
+    /// Set all bits in the range to `1`, returning the number of bits which changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    fn set_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64;
+
+    /// Set all bits in the range to `0`, returning the number of bits which changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    fn clear_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64;
+}
+
+/// Iterator over the runs of equal bits, see `BitSet::iter_runs`.
+pub struct Runs<'a, S: ?Sized + 'a> {
+    pub(crate) bits: &'a S,
+    pub(crate) front: u64,
+}
+
+impl<'a, S: BitSet + ?Sized> Iterator for Runs<'a, S> {
+    type Item = (bool, Range<u64>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front >= self.bits.num_bits() {
+            return None;
+        }
+        let start = self.front;
+        let value = self.bits.get(start);
+        let next = if value { self.bits.find_next_zero(start) } else { self.bits.find_next_one(start) };
+        self.front = next.unwrap_or(self.bits.num_bits());
+        Some((value, start..self.front))
+    }
+}
+
+/// Returns the bits of `range`, panicking if they are not within `num_bits`.
+#[cfg(any(test, feature = "alloc"))]
+pub(crate) fn bounds<R: RangeArgument<u64>>(range: &R, num_bits: u64) -> Range<u64> {
+    let start = range.start().unwrap_or(0);
+    let end = range.end().unwrap_or(num_bits);
+    if start > end || end > num_bits {
+        panic!("range out of bounds: the number of bits is {} but the range is {}..{}",
+               num_bits, start, end);
+    }
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+    use {BitMap, RunBitMap, SummaryBitMap};
+
+    /// xorshift, so the sequence is the same on every run
+    fn random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn assert_same<A: BitSet, B: BitSet>(a: &A, b: &B) {
+        assert_eq!(a.num_bits(), b.num_bits());
+        assert_eq!(a.zeroes(), b.zeroes());
+        assert!(a.iter_runs().eq(b.iter_runs()));
+        for from in (0..a.num_bits() + 1).step_by(7) {
+            assert_eq!(a.find_next_zero(from), b.find_next_zero(from), "next zero from {}", from);
+            assert_eq!(a.find_next_one(from), b.find_next_one(from), "next one from {}", from);
+        }
+    }
+
+    #[test]
+    fn backends_agree() {
+        for &num_bits in &[0, 1, 63, 64, 65, 4095, 4097, 9000] {
+            let len = ::div_ceil(num_bits, 8) as usize;
+            let mut flat = BitMap::with_length(vec![0u8; len], num_bits);
+            let mut summary = SummaryBitMap::new(BitMap::with_length(vec![0u8; len], num_bits));
+            let mut runs = RunBitMap::new(num_bits);
+            assert_same(&flat, &summary);
+            assert_same(&flat, &runs);
+            if num_bits == 0 {
+                continue;
+            }
+            let mut state = 0x2545_f491_4f6c_dd1d;
+            for round in 0..300u64 {
+                let start = random(&mut state) % num_bits;
+                // short ranges fragment the bits, long ones merge them
+                let max_len = if round % 4 == 0 { num_bits } else { 20 };
+                let len = random(&mut state) % max_len;
+                let end = (start + len).min(num_bits);
+                let value = random(&mut state) % 3 != 0;
+                let expected = flat.set(start, value);
+                assert_eq!(summary.set(start, value), expected);
+                assert_eq!(runs.set(start, value), expected);
+                let expected = if value { flat.clear_range(start..end) } else { flat.set_range(start..end) };
+                let changed = if value { summary.clear_range(start..end) } else { summary.set_range(start..end) };
+                assert_eq!(changed, expected);
+                let changed = if value { runs.clear_range(start..end) } else { runs.set_range(start..end) };
+                assert_eq!(changed, expected);
+                if round % 50 == 0 {
+                    assert_same(&flat, &summary);
+                    assert_same(&flat, &runs);
+                }
+            }
+            // fill the holes one after the other, as a receiver does
+            while let Some(bit) = summary.find_first_zero() {
+                assert_eq!(runs.find_first_zero(), Some(bit));
+                summary.set(bit, true);
+                runs.set(bit, true);
+                flat.set(bit, true);
+            }
+            assert!(flat.all() && summary.all() && runs.all());
+            let runs: Vec<_> = runs.iter_runs().collect();
+            assert_eq!(runs, vec![(true, 0..num_bits)]);
+        }
+    }
+}
@@ -1,10 +1,47 @@
 #![no_std]
+#[cfg(any(test, feature = "alloc"))]
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_test;
+#[cfg(test)]
+extern crate proptest;
+
 use core::ops::Index;
+use core::cmp;
 use core::fmt;
 
+#[cfg(all(feature = "serde", not(feature = "alloc")))]
+compile_error!("the `serde` feature needs the `alloc` feature");
+
 mod range;
+#[cfg(any(test, feature = "alloc"))]
+mod atomic;
+mod bitset;
+#[cfg(any(test, feature = "alloc"))]
+mod summary;
+#[cfg(any(test, feature = "alloc"))]
+mod runs;
+mod ops;
+#[cfg(feature = "serde")]
+mod serialize;
+pub mod rle;
+#[cfg(test)]
+mod model;
 
 pub use range::RangeArgument;
+#[cfg(any(test, feature = "alloc"))]
+pub use atomic::AtomicBitMap;
+pub use bitset::{BitSet, BitSetMut, Runs};
+#[cfg(any(test, feature = "alloc"))]
+pub use summary::SummaryBitMap;
+#[cfg(any(test, feature = "alloc"))]
+pub use runs::RunBitMap;
+#[cfg(feature = "serde")]
+pub use serialize::MaxBits;
 
 pub struct BitMap<T> {
     buf: T,
@@ -34,10 +71,64 @@ impl<T: AsRef<[u8]>> BitMap<T> {
             zeroes: 0,
         };
 
-        map.zeroes = map.iter().filter(|x| !x).count() as u64;
+        map.zeroes = map.num_bits - map.count_ones();
         map
     }
 
+    /// Counts the `1` bits a word at a time.
+    fn count_ones(&self) -> u64 {
+        (0..self.num_words()).map(|index| self.word(index).count_ones() as u64).sum()
+    }
+
+    pub(crate) fn num_words(&self) -> u64 {
+        div_ceil(self.num_bits, 64)
+    }
+
+    /// Returns the bits `index * 64..index * 64 + 64`, those from `num_bits` on are `0`.
+    pub(crate) fn word(&self, index: u64) -> u64 {
+        let buf = self.buf.as_ref();
+        let start = (index * 8) as usize;
+        let end = cmp::min(start + 8, buf.len());
+        let mut bytes = [0u8; 8];
+        bytes[..end - start].copy_from_slice(&buf[start..end]);
+        let word = u64::from_le_bytes(bytes);
+        let valid = self.num_bits - index * 64;
+        if valid < 64 {
+            word & ((1 << valid) - 1)
+        } else {
+            word
+        }
+    }
+
+    /// Returns the word `index` with the bits equal to `value` set.
+    ///
+    /// For `value == false` the bits from `num_bits` on are set as well.
+    fn word_of(&self, index: u64, value: bool) -> u64 {
+        let word = self.word(index);
+        if value { word } else { !word }
+    }
+
+    /// Returns the position of the first bit at or after `from` which equals `value`.
+    fn find_next(&self, from: u64, value: bool) -> Option<u64> {
+        if from >= self.num_bits {
+            return None;
+        }
+        let mut index = from / 64;
+        // ignore the bits before `from`
+        let mut word = self.word_of(index, value) & (!0 << (from % 64));
+        loop {
+            if word != 0 {
+                let bit = index * 64 + word.trailing_zeros() as u64;
+                return if bit < self.num_bits { Some(bit) } else { None };
+            }
+            index += 1;
+            if index >= self.num_words() {
+                return None;
+            }
+            word = self.word_of(index, value);
+        }
+    }
+
     /// Get the bit at given position.
     ///
     /// # Panics
@@ -52,6 +143,25 @@ impl<T: AsRef<[u8]>> BitMap<T> {
 }
 
 impl<T: AsMut<[u8]>> BitMap<T> {
+    /// Re-validates the zero count after a mutation.
+    ///
+    /// Only checked in tests and debug builds with the `debug-invariants` feature, as it counts
+    /// all bits.
+    #[inline]
+    pub(crate) fn check_zeroes(&mut self) {
+        if cfg!(any(test, feature = "debug-invariants")) {
+            let num_bits = self.num_bits;
+            let ones = {
+                // mutable buffers needn't be readable through `AsRef`
+                let buf = self.buf.as_mut();
+                let full = (num_bits / 8) as usize;
+                let partial = buf.get(full).map_or(0, |&byte| byte & !(0xff << (num_bits % 8)));
+                count_ones(&buf[..full]) + partial.count_ones() as u64
+            };
+            debug_assert_eq!(self.zeroes, num_bits - ones, "zero count out of sync");
+        }
+    }
+
     /// Set the bit at given position to given value and returns its old value.
     ///
     /// # Panics
@@ -73,6 +183,7 @@ impl<T: AsMut<[u8]>> BitMap<T> {
                 self.zeroes += 1;
             }
         }
+        self.check_zeroes();
         old
     }
 
@@ -87,21 +198,78 @@ impl<T: AsMut<[u8]>> BitMap<T> {
         let byte = &mut self.buf.as_mut()[(bit / 8) as usize];
         let bitmask = 1 << (bit % 8);
         *byte ^= bitmask;
-        let new = *byte == bitmask;
+        let new = *byte & bitmask != 0;
         if new {
             self.zeroes -= 1;
         } else {
             self.zeroes += 1;
         }
+        self.check_zeroes();
         new
     }
 
+    /// Set all bits in the range to `1`, returning the number of bits which changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn set_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64 {
+        self.fill_range(range, true)
+    }
+
+    /// Set all bits in the range to `0`, returning the number of bits which changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn clear_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64 {
+        self.fill_range(range, false)
+    }
+
+    fn fill_range<R: RangeArgument<u64>>(&mut self, range: R, value: bool) -> u64 {
+        let start = range.start().unwrap_or(0);
+        let end = range.end().unwrap_or(self.num_bits);
+        if start > end || end > self.num_bits {
+            panic!("range out of bounds: the number of bits is {} but the range is {}..{}",
+                   self.num_bits, start, end);
+        }
+        if start == end {
+            return 0;
+        }
+        let buf = self.buf.as_mut();
+        let first = (start / 8) as usize;
+        let last = ((end - 1) / 8) as usize;
+        let head = 0xff << (start % 8);
+        let tail = 0xff >> (7 - (end - 1) % 8);
+        let mut changed = 0;
+        if first == last {
+            changed += fill_byte(&mut buf[first], head & tail, value);
+        } else {
+            changed += fill_byte(&mut buf[first], head, value);
+            let middle = &mut buf[first + 1..last];
+            let ones = count_ones(middle);
+            changed += if value { middle.len() as u64 * 8 - ones } else { ones };
+            for byte in middle {
+                *byte = if value { 0xff } else { 0 };
+            }
+            changed += fill_byte(&mut buf[last], tail, value);
+        }
+        if value {
+            self.zeroes -= changed;
+        } else {
+            self.zeroes += changed;
+        }
+        self.check_zeroes();
+        changed
+    }
+
     /// Reset all bits to `0`
     pub fn reset(&mut self) {
         for byte in self.buf.as_mut() {
             *byte = 0;
         }
         self.zeroes = self.num_bits;
+        self.check_zeroes();
     }
 }
 
@@ -148,24 +316,82 @@ impl<T: AsRef<[u8]>> BitMap<T> {
     }
 
     /// Creates an iterator over all bits of this BitMap
-    pub fn iter(&self) -> Iter<T> {
+    pub fn iter(&self) -> Iter<'_, T> {
         self.iter_range(..)
     }
 
     /// Creates an iterator over a range of bits
-    pub fn iter_range<R: RangeArgument<u64>>(&self, range: R) -> Iter<T> {
+    pub fn iter_range<R: RangeArgument<u64>>(&self, range: R) -> Iter<'_, T> {
         Iter {
             bitmap: self,
             front: range.start().unwrap_or(0),
             back: range.end().unwrap_or(self.num_bits),
         }
     }
+
+    /// Returns the position of the first `0` bit.
+    pub fn find_first_zero(&self) -> Option<u64> {
+        self.find_next(0, false)
+    }
+
+    /// Returns the position of the first `0` bit at or after `from`.
+    pub fn find_next_zero(&self, from: u64) -> Option<u64> {
+        self.find_next(from, false)
+    }
+
+    /// Returns the position of the first `1` bit at or after `from`.
+    pub fn find_next_one(&self, from: u64) -> Option<u64> {
+        self.find_next(from, true)
+    }
+
+    /// Creates an iterator over the positions of all `1` bits
+    pub fn iter_ones(&self) -> Indices<'_, T> {
+        Indices::new(self, true)
+    }
+
+    /// Creates an iterator over the positions of all `0` bits
+    pub fn iter_zeros(&self) -> Indices<'_, T> {
+        Indices::new(self, false)
+    }
+
+    /// Creates an iterator over the runs of equal bits, as their value and range.
+    pub fn iter_runs(&self) -> Runs<'_, Self> {
+        Runs { bits: self, front: 0 }
+    }
+}
+
+/// Sets the bits of `mask` in `byte` to `value`, returning how many changed.
+fn fill_byte(byte: &mut u8, mask: u8, value: bool) -> u64 {
+    let old = *byte & mask;
+    if value {
+        *byte |= mask;
+        (mask ^ old).count_ones() as u64
+    } else {
+        *byte &= !mask;
+        old.count_ones() as u64
+    }
+}
+
+/// Divides rounding up, without overflowing for any `value`.
+pub(crate) fn div_ceil(value: u64, divisor: u64) -> u64 {
+    value / divisor + (value % divisor != 0) as u64
+}
+
+fn count_ones(bytes: &[u8]) -> u64 {
+    let mut words = bytes.chunks_exact(8);
+    let mut ones = 0;
+    for word in &mut words {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(word);
+        ones += u64::from_le_bytes(buf).count_ones() as u64;
+    }
+    ones + words.remainder().iter().map(|byte| byte.count_ones() as u64).sum::<u64>()
 }
 
 impl<T> BitMap<T> {
     #[inline]
     fn check(&self, bit: u64) {
-        if bit > self.num_bits {
+        if bit >= self.num_bits {
             panic!("bit index out of bounds: the number of bits is {} but the the index is {}",
                    self.num_bits, bit);
         }
@@ -228,6 +454,82 @@ impl<'a, T: AsRef<[u8]> + 'a> DoubleEndedIterator for Iter<'a, T> {
     }
 }
 
+/// Iterator over the positions of the bits equal to a value, see `BitMap::iter_ones`.
+pub struct Indices<'a, T: AsRef<[u8]> + 'a> {
+    bitmap: &'a BitMap<T>,
+    value: bool,
+    /// index of the current word
+    index: u64,
+    /// bits of the current word which are yet to be yielded
+    word: u64,
+}
+
+impl<'a, T: AsRef<[u8]>> Indices<'a, T> {
+    fn new(bitmap: &'a BitMap<T>, value: bool) -> Indices<'a, T> {
+        let word = if bitmap.num_bits == 0 { 0 } else { bitmap.word_of(0, value) };
+        Indices { bitmap, value, index: 0, word }
+    }
+}
+
+impl<'a, T: AsRef<[u8]>> Iterator for Indices<'a, T> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.word == 0 {
+            if self.index + 1 >= self.bitmap.num_words() {
+                return None;
+            }
+            self.index += 1;
+            self.word = self.bitmap.word_of(self.index, self.value);
+        }
+        let bit = self.index * 64 + self.word.trailing_zeros() as u64;
+        // clear the lowest set bit
+        self.word &= self.word - 1;
+        if bit < self.bitmap.num_bits {
+            Some(bit)
+        } else {
+            self.word = 0;
+            None
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> BitSet for BitMap<T> {
+    fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    fn zeroes(&self) -> u64 {
+        self.zeroes
+    }
+
+    fn get(&self, bit: u64) -> bool {
+        BitMap::get(self, bit)
+    }
+
+    fn find_next_zero(&self, from: u64) -> Option<u64> {
+        self.find_next(from, false)
+    }
+
+    fn find_next_one(&self, from: u64) -> Option<u64> {
+        self.find_next(from, true)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> BitSetMut for BitMap<T> {
+    fn set(&mut self, bit: u64, new: bool) -> bool {
+        BitMap::set(self, bit, new)
+    }
+
+    fn set_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64 {
+        self.fill_range(range, true)
+    }
+
+    fn clear_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64 {
+        self.fill_range(range, false)
+    }
+}
+
 impl<T: AsRef<[u8]> + AsMut<[u8]>> fmt::Debug for BitMap<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("BitMap")
@@ -249,4 +551,107 @@ mod tests {
             assert!(bitmap.get(i));
         }
     }
+
+    /// Fills `buf` with a deterministic pattern of long and short runs.
+    fn pattern(buf: &mut [u8]) {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for byte in buf.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = match state % 4 {
+                0 => 0,
+                1 => 0xff,
+                _ => state as u8,
+            };
+        }
+    }
+
+    #[test]
+    fn fast_paths() {
+        let mut buf = [0u8; 67];
+        pattern(&mut buf);
+        for &num_bits in &[0, 1, 63, 64, 65, 130, 535, 536] {
+            let bitmap = BitMap::with_length(&buf[..], num_bits);
+            let bits: [bool; 536] = {
+                let mut bits = [false; 536];
+                for (i, bit) in bitmap.iter().enumerate() {
+                    bits[i] = bit;
+                }
+                bits
+            };
+            let bits = &bits[..num_bits as usize];
+            assert_eq!(bitmap.zeroes(), bits.iter().filter(|&&bit| !bit).count() as u64);
+
+            let mut ones = bitmap.iter_ones();
+            let mut zeros = bitmap.iter_zeros();
+            for (i, &bit) in bits.iter().enumerate() {
+                let i = i as u64;
+                assert_eq!(if bit { ones.next() } else { zeros.next() }, Some(i));
+                let next_zero = bits[i as usize..].iter().position(|&bit| !bit).map(|p| i + p as u64);
+                assert_eq!(bitmap.find_next_zero(i), next_zero);
+                let next_one = bits[i as usize..].iter().position(|&bit| bit).map(|p| i + p as u64);
+                assert_eq!(bitmap.find_next_one(i), next_one);
+            }
+            assert_eq!((ones.next(), zeros.next()), (None, None));
+            assert_eq!(bitmap.find_first_zero(), bits.iter().position(|&bit| !bit).map(|p| p as u64));
+            assert_eq!(bitmap.find_next_zero(num_bits), None);
+
+            let mut expected = 0;
+            for (value, range) in bitmap.iter_runs() {
+                assert_eq!(range.start, expected);
+                assert!(range.end > range.start);
+                assert!(bits[range.start as usize..range.end as usize].iter().all(|&bit| bit == value));
+                if range.end < num_bits {
+                    assert_ne!(bits[range.end as usize], value);
+                }
+                expected = range.end;
+            }
+            assert_eq!(expected, num_bits);
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let mut buf = [0u8; 40];
+        pattern(&mut buf);
+        for &(start, end) in &[(0, 0), (3, 5), (0, 8), (5, 19), (7, 250), (64, 256), (0, 260)] {
+            for &value in &[true, false] {
+                let mut fast = BitMap::with_length(buf, 260);
+                let mut slow = BitMap::with_length(buf, 260);
+                let changed = if value {
+                    fast.set_range(start..end)
+                } else {
+                    fast.clear_range(start..end)
+                };
+                let slow_changed = (start..end).filter(|&bit| slow.set(bit, value) != value).count() as u64;
+                assert_eq!(changed, slow_changed);
+                assert_eq!(fast.get_ref()[..], slow.get_ref()[..]);
+                assert_eq!(fast.zeroes(), slow.zeroes());
+                assert_eq!(fast.zeroes(), fast.iter().filter(|bit| !bit).count() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn flip_bit() {
+        let mut bitmap = BitMap::new([0b1010_0000u8]);
+        assert!(bitmap.flip_bit(0));
+        assert!(!bitmap.flip_bit(5));
+        assert!(bitmap.flip_bit(5));
+        assert_eq!(bitmap.get_ref(), &[0b1010_0001]);
+        assert_eq!(bitmap.ones(), 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bit_out_of_bounds() {
+        BitMap::with_length([0u8; 2], 12).get(12);
+    }
+
+    #[test]
+    #[should_panic]
+    fn range_out_of_bounds() {
+        BitMap::with_length([0u8; 2], 12).set_range(4..13);
+    }
 }
@@ -0,0 +1,253 @@
+//! Property tests of the bitmaps against a `Vec<bool>` model.
+
+use core::cmp;
+use core::mem;
+use core::ops::Range;
+use std::vec;
+use std::vec::Vec;
+
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+
+use bitset::{BitSet, BitSetMut};
+use {rle, BitMap, RunBitMap, SummaryBitMap};
+
+/// A buffer with `num_bits` in `bits` and a byte of garbage after them, and `num_bits`.
+fn bitmap(bits: Range<u64>) -> impl Strategy<Value = (Vec<u8>, u64)> {
+    bits.prop_flat_map(|num_bits| {
+        let len = ::div_ceil(num_bits, 8) as usize + 1;
+        (proptest::collection::vec(any::<u8>(), len), Just(num_bits))
+    })
+}
+
+fn to_model(buf: &[u8], num_bits: u64) -> Vec<bool> {
+    (0..num_bits).map(|bit| buf[(bit / 8) as usize] & (1 << (bit % 8)) != 0).collect()
+}
+
+/// A range within `0..num_bits`, possibly empty.
+fn range(num_bits: u64) -> impl Strategy<Value = Range<u64>> {
+    (0..=num_bits, 0..=num_bits).prop_map(|(a, b)| cmp::min(a, b)..cmp::max(a, b))
+}
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(u64, bool),
+    /// returns the new value
+    Flip(u64),
+    SetRange(Range<u64>),
+    ClearRange(Range<u64>),
+    Reset,
+}
+
+fn op(num_bits: u64) -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..num_bits, any::<bool>()).prop_map(|(bit, value)| Op::Set(bit, value)),
+        2 => (0..num_bits).prop_map(Op::Flip),
+        2 => range(num_bits).prop_map(Op::SetRange),
+        2 => range(num_bits).prop_map(Op::ClearRange),
+        1 => Just(Op::Reset),
+    ]
+}
+
+fn scenario() -> impl Strategy<Value = (Vec<u8>, u64, Vec<Op>)> {
+    bitmap(1..300).prop_flat_map(|(buf, num_bits)| {
+        (Just(buf), Just(num_bits), proptest::collection::vec(op(num_bits), 0..40))
+    })
+}
+
+fn fill(model: &mut [bool], range: &Range<u64>, value: bool) -> u64 {
+    let bits = &mut model[range.start as usize..range.end as usize];
+    let changed = bits.iter().filter(|&&bit| bit != value).count() as u64;
+    for bit in bits {
+        *bit = value;
+    }
+    changed
+}
+
+/// Applies `op` to the model, returning the expected result as number.
+fn apply_model(model: &mut [bool], op: &Op) -> u64 {
+    match *op {
+        Op::Set(bit, value) => mem::replace(&mut model[bit as usize], value) as u64,
+        Op::Flip(bit) => {
+            model[bit as usize] = !model[bit as usize];
+            model[bit as usize] as u64
+        }
+        Op::SetRange(ref range) => fill(model, range, true),
+        Op::ClearRange(ref range) => fill(model, range, false),
+        Op::Reset => {
+            let len = model.len() as u64;
+            fill(model, &(0..len), false);
+            0
+        }
+    }
+}
+
+fn apply_bitmap(bitmap: &mut BitMap<Vec<u8>>, op: &Op) -> u64 {
+    match *op {
+        Op::Set(bit, value) => bitmap.set(bit, value) as u64,
+        Op::Flip(bit) => bitmap.flip_bit(bit) as u64,
+        Op::SetRange(ref range) => bitmap.set_range(range.clone()),
+        Op::ClearRange(ref range) => bitmap.clear_range(range.clone()),
+        Op::Reset => {
+            bitmap.reset();
+            0
+        }
+    }
+}
+
+/// Applies `op` through the `BitSetMut` trait.
+fn apply<B: BitSetMut>(bits: &mut B, op: &Op) -> u64 {
+    match *op {
+        Op::Set(bit, value) => bits.set(bit, value) as u64,
+        Op::Flip(bit) => {
+            let new = !bits.get(bit);
+            bits.set(bit, new);
+            new as u64
+        }
+        Op::SetRange(ref range) => bits.set_range(range.clone()),
+        Op::ClearRange(ref range) => bits.clear_range(range.clone()),
+        Op::Reset => {
+            bits.clear_range(..);
+            0
+        }
+    }
+}
+
+/// Returns the position of the next bit equal to `value` for every position of the model.
+fn next_positions(model: &[bool], value: bool) -> Vec<Option<u64>> {
+    let mut next = vec![None; model.len() + 1];
+    for (bit, &model_bit) in model.iter().enumerate().rev() {
+        next[bit] = if model_bit == value { Some(bit as u64) } else { next[bit + 1] };
+    }
+    next
+}
+
+fn model_runs(model: &[bool]) -> Vec<(bool, Range<u64>)> {
+    let mut runs: Vec<(bool, Range<u64>)> = Vec::new();
+    for (bit, &value) in model.iter().enumerate() {
+        let bit = bit as u64;
+        match runs.last_mut() {
+            Some(&mut (run_value, ref mut run)) if run_value == value => run.end = bit + 1,
+            _ => runs.push((value, bit..bit + 1)),
+        }
+    }
+    runs
+}
+
+/// Checks every query of the `BitSet` trait against the model.
+fn check<B: BitSet>(bits: &B, model: &[bool]) -> Result<(), TestCaseError> {
+    let num_bits = model.len() as u64;
+    let zeroes = model.iter().filter(|&&bit| !bit).count() as u64;
+    prop_assert_eq!(bits.num_bits(), num_bits);
+    prop_assert_eq!(bits.zeroes(), zeroes);
+    prop_assert_eq!(bits.ones(), num_bits - zeroes);
+    prop_assert_eq!(bits.all(), zeroes == 0);
+    prop_assert_eq!(bits.any(), zeroes != num_bits);
+    for (bit, &value) in model.iter().enumerate() {
+        prop_assert_eq!(bits.get(bit as u64), value, "bit {}", bit);
+    }
+    let (next_zero, next_one) = (next_positions(model, false), next_positions(model, true));
+    for from in 0..=num_bits {
+        prop_assert_eq!(bits.find_next_zero(from), next_zero[from as usize], "next zero from {}", from);
+        prop_assert_eq!(bits.find_next_one(from), next_one[from as usize], "next one from {}", from);
+    }
+    prop_assert_eq!(bits.find_next_zero(num_bits + 1), None);
+    prop_assert_eq!(bits.find_next_one(num_bits + 1), None);
+    prop_assert_eq!(bits.find_first_zero(), next_zero[0]);
+    prop_assert_eq!(bits.iter_runs().collect::<Vec<_>>(), model_runs(model));
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn ops_match_model((buf, num_bits, ops) in scenario()) {
+        let mut model = to_model(&buf, num_bits);
+        let mut bitmap = BitMap::with_length(buf.clone(), num_bits);
+        let mut summary = SummaryBitMap::new(BitMap::with_length(buf.clone(), num_bits));
+        let mut runs = RunBitMap::from_bits(&BitMap::with_length(&buf[..], num_bits));
+        check(&bitmap, &model)?;
+        check(&summary, &model)?;
+        check(&runs, &model)?;
+        for op in &ops {
+            let expected = apply_model(&mut model, op);
+            prop_assert_eq!(apply_bitmap(&mut bitmap, op), expected, "{:?}", op);
+            prop_assert_eq!(apply(&mut summary, op), expected, "{:?}", op);
+            prop_assert_eq!(apply(&mut runs, op), expected, "{:?}", op);
+            check(&bitmap, &model)?;
+            check(&summary, &model)?;
+            check(&runs, &model)?;
+        }
+        // the garbage after the bits is only touched by `reset`, which clears the whole buffer
+        if !ops.iter().any(|op| matches!(*op, Op::Reset)) {
+            let buf_bits = buf.len() as u64 * 8;
+            let (after, before) = (to_model(bitmap.get_ref(), buf_bits), to_model(&buf, buf_bits));
+            prop_assert_eq!(&after[num_bits as usize..], &before[num_bits as usize..]);
+        }
+    }
+
+    #[test]
+    fn queries_match_model((buf, num_bits) in bitmap(0..300), from in 0..310u64, to in 0..310u64) {
+        let model = to_model(&buf, num_bits);
+        let bitmap = BitMap::with_length(&buf[..], num_bits);
+        check(&bitmap, &model)?;
+        prop_assert_eq!(bitmap.iter().collect::<Vec<_>>(), model.clone());
+        prop_assert_eq!(bitmap.iter().rev().collect::<Vec<_>>(), model.iter().cloned().rev().collect::<Vec<_>>());
+        let range = cmp::min(from, num_bits) as usize..cmp::min(cmp::max(from, to), num_bits) as usize;
+        prop_assert_eq!(bitmap.iter_range(range.start as u64..range.end as u64).collect::<Vec<_>>(), &model[range]);
+        let ones: Vec<u64> = (0..num_bits).filter(|&bit| model[bit as usize]).collect();
+        let zeros: Vec<u64> = (0..num_bits).filter(|&bit| !model[bit as usize]).collect();
+        prop_assert_eq!(bitmap.iter_ones().collect::<Vec<_>>(), ones);
+        prop_assert_eq!(bitmap.iter_zeros().collect::<Vec<_>>(), zeros);
+        for (bit, &value) in model.iter().enumerate() {
+            prop_assert_eq!(bitmap[bit as u64], value);
+        }
+    }
+
+    #[test]
+    fn algebra_matches_model((a, num_bits) in bitmap(0..300), seed in any::<u64>()) {
+        // the second bitmap needs the same length
+        let b: Vec<u8> = a.iter().enumerate().map(|(i, byte)| byte ^ (seed >> (i % 8 * 8)) as u8).collect();
+        let (model_a, model_b) = (to_model(&a, num_bits), to_model(&b, num_bits));
+        let combined = |f: fn(bool, bool) -> bool| -> Vec<bool> {
+            model_a.iter().zip(&model_b).map(|(&a, &b)| f(a, b)).collect()
+        };
+        let other = BitMap::with_length(&b[..], num_bits);
+
+        let mut bitmap = BitMap::with_length(a.clone(), num_bits);
+        bitmap &= &other;
+        check(&bitmap, &combined(|a, b| a & b))?;
+        let mut bitmap = BitMap::with_length(a.clone(), num_bits);
+        bitmap |= &other;
+        check(&bitmap, &combined(|a, b| a | b))?;
+        let mut bitmap = BitMap::with_length(a.clone(), num_bits);
+        bitmap ^= &other;
+        check(&bitmap, &combined(|a, b| a ^ b))?;
+        let bitmap = !BitMap::with_length(a.clone(), num_bits);
+        check(&bitmap, &combined(|a, _| !a))?;
+        prop_assert_eq!(bitmap.get_ref().last(), a.last());
+
+        prop_assert_eq!(BitMap::with_length(&a[..], num_bits) == other, model_a == model_b);
+        prop_assert!(BitMap::with_length(&a[..], num_bits) == BitMap::with_length(a.clone(), num_bits));
+    }
+
+    #[test]
+    fn rle_matches_model((buf, num_bits) in bitmap(0..300), budget in 0..40usize) {
+        let model = to_model(&buf, num_bits);
+        let bitmap = BitMap::with_length(&buf[..], num_bits);
+        let encoded = rle::encode_to_vec(&bitmap);
+        let mut decoded = RunBitMap::new(num_bits);
+        prop_assert_eq!(rle::decode_into(&encoded, &mut decoded), Ok(num_bits));
+        check(&decoded, &model)?;
+        prop_assert_eq!(rle::encode_to_vec(&decoded), encoded.clone());
+
+        // truncated runs are a prefix of the full ones
+        let mut truncated = [0u8; 40];
+        let written = rle::encode(&bitmap, &mut truncated[..budget]);
+        prop_assert!(written <= budget);
+        prop_assert_eq!(&truncated[..written], &encoded[..written]);
+        prop_assert!(written == encoded.len() || encoded.len() > budget);
+        let mut decoded = BitMap::with_length(vec![0u8; buf.len()], num_bits);
+        let covered = rle::decode_into(&truncated[..written], &mut decoded).unwrap();
+        prop_assert_eq!(decoded.iter().take(covered as usize).collect::<Vec<_>>(), &model[..covered as usize]);
+    }
+}
@@ -0,0 +1,138 @@
+//! Bitwise operations and comparison between BitMaps.
+//!
+//! Only the bits below `num_bits` take part, the rest of the buffers is kept as it is.
+
+use core::ops::{BitAndAssign, BitOrAssign, BitXorAssign, Not};
+
+use BitMap;
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> BitMap<T> {
+    /// Combines the bytes of `other` into the own ones with `f`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the BitMaps differ in length.
+    fn combine<U: AsRef<[u8]>, F: Fn(u8, u8) -> u8>(&mut self, other: &BitMap<U>, f: F) {
+        if self.num_bits != other.num_bits {
+            panic!("bitmaps differ in length: {} and {} bits", self.num_bits, other.num_bits);
+        }
+        let len = ::div_ceil(self.num_bits, 8) as usize;
+        if len == 0 {
+            return;
+        }
+        let dst = &mut self.buf.as_mut()[..len];
+        let src = &other.buf.as_ref()[..len];
+        let last = dst[len - 1];
+        for (dst, &src) in dst.iter_mut().zip(src) {
+            *dst = f(*dst, src);
+        }
+        let valid = valid_bits(self.num_bits);
+        dst[len - 1] = (dst[len - 1] & valid) | (last & !valid);
+        self.zeroes = self.num_bits - self.count_ones();
+    }
+}
+
+impl<'a, T, U> BitAndAssign<&'a BitMap<U>> for BitMap<T>
+where
+    T: AsRef<[u8]> + AsMut<[u8]>,
+    U: AsRef<[u8]>,
+{
+    fn bitand_assign(&mut self, other: &'a BitMap<U>) {
+        self.combine(other, |a, b| a & b);
+    }
+}
+
+impl<'a, T, U> BitOrAssign<&'a BitMap<U>> for BitMap<T>
+where
+    T: AsRef<[u8]> + AsMut<[u8]>,
+    U: AsRef<[u8]>,
+{
+    fn bitor_assign(&mut self, other: &'a BitMap<U>) {
+        self.combine(other, |a, b| a | b);
+    }
+}
+
+impl<'a, T, U> BitXorAssign<&'a BitMap<U>> for BitMap<T>
+where
+    T: AsRef<[u8]> + AsMut<[u8]>,
+    U: AsRef<[u8]>,
+{
+    fn bitxor_assign(&mut self, other: &'a BitMap<U>) {
+        self.combine(other, |a, b| a ^ b);
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Not for BitMap<T> {
+    type Output = BitMap<T>;
+
+    /// Inverts the bits in place.
+    fn not(mut self) -> BitMap<T> {
+        let len = ::div_ceil(self.num_bits, 8) as usize;
+        let valid = valid_bits(self.num_bits);
+        let bytes = &mut self.buf.as_mut()[..len];
+        if let Some((last, bytes)) = bytes.split_last_mut() {
+            for byte in bytes {
+                *byte = !*byte;
+            }
+            *last ^= valid;
+        }
+        self.zeroes = self.num_bits - self.zeroes;
+        self.check_zeroes();
+        self
+    }
+}
+
+/// BitMaps are equal if they have the same bits, regardless of the rest of their buffers.
+impl<T: AsRef<[u8]>, U: AsRef<[u8]>> PartialEq<BitMap<U>> for BitMap<T> {
+    fn eq(&self, other: &BitMap<U>) -> bool {
+        self.num_bits == other.num_bits
+            && self.zeroes == other.zeroes
+            && (0..self.num_words()).all(|index| self.word(index) == other.word(index))
+    }
+}
+
+impl<T: AsRef<[u8]>> Eq for BitMap<T> {}
+
+/// Returns the mask of the bits of the last byte which are below `num_bits`.
+fn valid_bits(num_bits: u64) -> u8 {
+    match num_bits % 8 {
+        0 => 0xff,
+        bits => (1 << bits) - 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    #[test]
+    fn bitwise() {
+        // the bits after num_bits differ between the buffers and must be kept
+        let mut a = BitMap::with_length([0b1100_1010u8, 0b1111_0011], 12);
+        let b = BitMap::with_length([0b1010_0110u8, 0b0000_0101], 12);
+        a &= &b;
+        assert_eq!(a.get_ref(), &[0b1000_0010, 0b1111_0001]);
+        assert_eq!(a.ones(), 3);
+        a |= &b;
+        assert_eq!(a.get_ref(), &[0b1010_0110, 0b1111_0101]);
+        assert_eq!(a.ones(), 6);
+        a ^= &BitMap::with_length([0xffu8, 0xff], 12);
+        assert_eq!(a.get_ref(), &[0b0101_1001, 0b1111_1010]);
+        assert_eq!(a.ones(), 6);
+        let a = !a;
+        assert_eq!(a.get_ref(), &[0b1010_0110, 0b1111_0101]);
+        assert_eq!(a.ones(), 6);
+        assert_eq!(a, BitMap::with_length(vec![0b1010_0110u8, 0b0000_0101], 12));
+        assert!(a != BitMap::with_length(vec![0b1010_0110u8, 0b0000_0101], 11));
+        let a = !BitMap::with_length([0u8; 0], 0);
+        assert!(a.all());
+    }
+
+    #[test]
+    #[should_panic]
+    fn different_lengths() {
+        let mut a = BitMap::with_length([0u8; 2], 12);
+        a &= &BitMap::with_length([0u8; 2], 13);
+    }
+}
@@ -0,0 +1,329 @@
+//! Runlength encoding of bitmaps as varints.
+//!
+//! The runs alternate between `1` and `0` bits, starting with `1`s. If the bits start with a `0`
+//! or there are none, the first run is empty and written as `0`; no other run is empty, thus the
+//! encoding of a bitmap is canonical. A run is written as LEB128 varint: 7 bits per byte, least
+//! significant first, with the highest bit set on all bytes but the last.
+//!
+//! Encoders truncate to the buffer on a varint boundary, so a decoder sees a prefix of the runs.
+
+#[cfg(any(test, feature = "alloc"))]
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use bitset::{BitSet, BitSetMut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end within a varint.
+    Truncated,
+    /// A varint doesn't fit into 64 bits.
+    InvalidVarint,
+    /// The runs add up to more than 64 bits or the decoded bitmap.
+    Overflow,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Truncated => write!(f, "runlengths truncated"),
+            DecodeError::InvalidVarint => write!(f, "varint too long"),
+            DecodeError::Overflow => write!(f, "runlengths overflow"),
+        }
+    }
+}
+
+/// Encodes the runs of `bits` into `buf`, returning the number of bytes written.
+pub fn encode<S: BitSet>(bits: &S, buf: &mut [u8]) -> usize {
+    encode_runs(bits.iter_runs().map(|(value, range)| (value, range.end - range.start)), buf)
+}
+
+/// Encodes runs of bits given as value and length into `buf`, returning the number of bytes
+/// written.
+///
+/// The runs don't need to be canonical, empty ones are dropped and adjacent ones of the same
+/// value merged.
+pub fn encode_runs<I: IntoIterator<Item = (bool, u64)>>(runs: I, buf: &mut [u8]) -> usize {
+    let mut written = 0;
+    for_each_runlength(runs, |len| {
+        let size = varint_len(len);
+        if written + size > buf.len() {
+            return false;
+        }
+        write_varint(len, &mut buf[written..written + size]);
+        written += size;
+        true
+    });
+    written
+}
+
+/// Encodes the runs of `bits`, without truncation.
+#[cfg(any(test, feature = "alloc"))]
+pub fn encode_to_vec<S: BitSet>(bits: &S) -> Vec<u8> {
+    let mut vec = Vec::new();
+    let runs = bits.iter_runs().map(|(value, range)| (value, range.end - range.start));
+    for_each_runlength(runs, |len| {
+        let start = vec.len();
+        vec.resize(start + varint_len(len), 0);
+        write_varint(len, &mut vec[start..]);
+        true
+    });
+    vec
+}
+
+/// Calls `f` with the canonical runlengths of `runs` until it returns `false`.
+fn for_each_runlength<I, F>(runs: I, mut f: F)
+where
+    I: IntoIterator<Item = (bool, u64)>,
+    F: FnMut(u64) -> bool,
+{
+    let mut value = true;
+    let mut len = 0;
+    let mut emitted = false;
+    for (run_value, run_len) in runs {
+        if run_len == 0 {
+            continue;
+        }
+        if run_value != value {
+            if !f(len) {
+                return;
+            }
+            emitted = true;
+            value = run_value;
+            len = 0;
+        }
+        len += run_len;
+    }
+    if len > 0 || !emitted {
+        f(len);
+    }
+}
+
+fn varint_len(value: u64) -> usize {
+    ::div_ceil(64 - (value | 1).leading_zeros() as u64, 7) as usize
+}
+
+/// Writes `value` into `buf`, which must be exactly `varint_len(value)` long.
+fn write_varint(mut value: u64, buf: &mut [u8]) {
+    let last = buf.len() - 1;
+    for byte in &mut buf[..last] {
+        *byte = value as u8 | 0x80;
+        value >>= 7;
+    }
+    buf[last] = value as u8;
+}
+
+/// Iterator over the runlengths of encoded runs.
+///
+/// Yields an error for a malformed varint and stops afterwards.
+pub struct Runlengths<'a> {
+    bytes: &'a [u8],
+    failed: bool,
+}
+
+impl<'a> Runlengths<'a> {
+    pub fn new(bytes: &'a [u8]) -> Runlengths<'a> {
+        Runlengths { bytes, failed: false }
+    }
+
+    fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for (i, &byte) in self.bytes.iter().enumerate() {
+            let bits = (byte & 0x7f) as u64;
+            // the tenth byte may only hold the highest bit
+            if (i == 9 && bits > 1) || i > 9 {
+                return Err(DecodeError::InvalidVarint);
+            }
+            value |= bits << (7 * i);
+            if byte & 0x80 == 0 {
+                self.bytes = &self.bytes[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::Truncated)
+    }
+}
+
+impl<'a> Iterator for Runlengths<'a> {
+    type Item = Result<u64, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.bytes.is_empty() {
+            return None;
+        }
+        let res = self.read_varint();
+        self.failed = res.is_err();
+        Some(res)
+    }
+}
+
+/// Iterator over the non-empty runs of encoded runs, as their value and range.
+///
+/// Yields an error for malformed runs and stops afterwards.
+pub struct DecodeRuns<'a> {
+    lengths: Runlengths<'a>,
+    value: bool,
+    position: u64,
+}
+
+/// Decodes runs encoded with `encode` or `encode_runs`.
+pub fn decode_runs(bytes: &[u8]) -> DecodeRuns<'_> {
+    DecodeRuns { lengths: Runlengths::new(bytes), value: true, position: 0 }
+}
+
+impl<'a> Iterator for DecodeRuns<'a> {
+    type Item = Result<(bool, Range<u64>), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let len = match self.lengths.next()? {
+                Ok(len) => len,
+                Err(e) => return Some(Err(e)),
+            };
+            let value = self.value;
+            self.value = !value;
+            let start = self.position;
+            self.position = match start.checked_add(len) {
+                Some(end) => end,
+                None => {
+                    self.lengths.failed = true;
+                    return Some(Err(DecodeError::Overflow));
+                }
+            };
+            if len > 0 {
+                return Some(Ok((value, start..self.position)));
+            }
+        }
+    }
+}
+
+/// Decodes runs into `bits`, returning the number of bits they cover.
+///
+/// Bits after those covered, e.g. by truncated runs, are kept. If the runs are malformed or
+/// cover more bits than `bits` has, nothing is changed.
+pub fn decode_into<S: BitSetMut>(bytes: &[u8], bits: &mut S) -> Result<u64, DecodeError> {
+    let mut covered = 0;
+    for run in decode_runs(bytes) {
+        covered = run?.1.end;
+    }
+    if covered > bits.num_bits() {
+        return Err(DecodeError::Overflow);
+    }
+    for run in decode_runs(bytes) {
+        let (value, range) = run.unwrap();
+        if value {
+            bits.set_range(range);
+        } else {
+            bits.clear_range(range);
+        }
+    }
+    Ok(covered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+    use {BitMap, RunBitMap};
+
+    fn test_encode(bitmap: &[u8], result: &[u8]) {
+        let bitmap = BitMap::new(bitmap);
+        let mut buf = [0u8; 16];
+        let written = encode(&bitmap, &mut buf);
+        assert_eq!(&buf[..written], result);
+        assert_eq!(encode_to_vec(&bitmap), result);
+        assert_eq!(encode(&RunBitMap::from_bits(&bitmap), &mut buf), written);
+    }
+
+    #[test]
+    fn encode_bitmaps() {
+        test_encode(&[], &[0]);
+        test_encode(&[0b1111_1111], &[8]);
+        test_encode(&[0b0000_0000], &[0, 8]);
+        test_encode(&[0b0000_0001], &[1, 7]);
+        test_encode(&[0b1000_0000], &[0, 7, 1]);
+        test_encode(&[0b0000_1011], &[2, 1, 1, 4]);
+        test_encode(&[0b1000_1011], &[2, 1, 1, 3, 1]);
+        test_encode(&[0b1000_1011, 0b0000_1111], &[2, 1, 1, 3, 5, 4]);
+        test_encode(&[0xff; 17], &[0x88, 1]);
+    }
+
+    #[test]
+    fn encode_canonical() {
+        let mut buf = [0u8; 16];
+        let written = encode_runs(vec![(false, 0), (true, 2), (true, 0), (true, 1), (false, 3), (false, 0)], &mut buf);
+        assert_eq!(&buf[..written], &[3, 3]);
+        let written = encode_runs(vec![(false, 2), (true, 0), (false, 1)], &mut buf);
+        assert_eq!(&buf[..written], &[0, 3]);
+        let written = encode_runs(vec![], &mut buf);
+        assert_eq!(&buf[..written], &[0]);
+    }
+
+    #[test]
+    fn encode_truncated() {
+        let runs = [(true, 1), (false, 200), (true, 3)];
+        let mut buf = [0u8; 4];
+        for &(len, expected) in &[(0, &[][..]), (1, &[1]), (2, &[1]), (3, &[1, 0xc8, 1]), (4, &[1, 0xc8, 1, 3])] {
+            let written = encode_runs(runs.iter().cloned(), &mut buf[..len]);
+            assert_eq!(&buf[..written], expected);
+        }
+    }
+
+    #[test]
+    fn decode() {
+        let vector: &[(&[u8], &[u64])] = &[
+            (&[], &[]),
+            (&[0], &[0]),
+            (&[1, 2, 3], &[1, 2, 3]),
+            (&[1, 0x80, 1, 2], &[1, 0x80, 2]),
+            (&[1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 2], &[1, 0xffffffff_ffffffff, 2]),
+            (&[1, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x40, 2], &[1, 0x40000000_00000000, 2]),
+        ];
+        for &(bytes, numbers) in vector {
+            let decoded: Vec<_> = Runlengths::new(bytes).collect::<Result<_, _>>().unwrap();
+            assert_eq!(decoded, numbers);
+        }
+
+        let runs: Vec<_> = decode_runs(&[0, 2, 0, 1, 3]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(runs, vec![(false, 0..2), (false, 2..3), (true, 3..6)]);
+    }
+
+    #[test]
+    fn decode_malformed() {
+        let vector: &[(&[u8], DecodeError)] = &[
+            (&[1, 0x80], DecodeError::Truncated),
+            (&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02], DecodeError::InvalidVarint),
+            (&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01], DecodeError::InvalidVarint),
+        ];
+        for &(bytes, error) in vector {
+            let decoded: Result<Vec<_>, _> = Runlengths::new(bytes).collect();
+            assert_eq!(decoded, Err(error));
+            // the iterator stops after the first error
+            assert_eq!(Runlengths::new(bytes).filter(Result::is_err).count(), 1);
+        }
+        let overflow = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 1, 5];
+        let decoded: Vec<_> = decode_runs(&overflow).collect();
+        assert_eq!(decoded, vec![Ok((true, 0..u64::MAX)), Err(DecodeError::Overflow)]);
+    }
+
+    #[test]
+    fn decode_into_bitmap() {
+        let mut bitmap = BitMap::with_length(vec![0b1111_0000u8; 2], 12);
+        let encoded = [1, 2, 3];
+        assert_eq!(decode_into(&encoded, &mut bitmap), Ok(6));
+        assert_eq!(bitmap.get_ref(), &[0b1111_1001, 0b1111_0000]);
+        assert_eq!(bitmap.zeroes(), 6);
+
+        // malformed or too long runs don't change anything
+        assert_eq!(decode_into(&[0, 12, 0x80], &mut bitmap), Err(DecodeError::Truncated));
+        assert_eq!(decode_into(&[0, 13], &mut bitmap), Err(DecodeError::Overflow));
+        assert_eq!(bitmap.get_ref(), &[0b1111_1001, 0b1111_0000]);
+
+        let mut runs = RunBitMap::new(12);
+        let encoded = encode_to_vec(&bitmap);
+        assert_eq!(decode_into(&encoded, &mut runs), Ok(12));
+        assert_eq!(encode_to_vec(&runs), encoded);
+    }
+}
@@ -0,0 +1,245 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp;
+
+use bitset::{bounds, BitSet, BitSetMut};
+use RangeArgument;
+
+/// A bitmap of the runs of `1` bits, like the run containers of roaring bitmaps.
+///
+/// It takes space by the number of runs instead of the number of bits, and every operation takes
+/// O(log n) in the number of runs. Thus huge bitmaps which are almost empty or almost full are
+/// cheap, while heavily fragmented ones are better off in a `BitMap`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunBitMap {
+    /// start to end of the runs, which neither overlap nor touch
+    runs: BTreeMap<u64, u64>,
+    num_bits: u64,
+    ones: u64,
+}
+
+impl RunBitMap {
+    /// Create a new RunBitMap with given number of bits, all `0`.
+    pub fn new(num_bits: u64) -> RunBitMap {
+        RunBitMap { runs: BTreeMap::new(), num_bits, ones: 0 }
+    }
+
+    /// Create a new RunBitMap with the same bits as `bits`.
+    pub fn from_bits<S: BitSet>(bits: &S) -> RunBitMap {
+        let mut map = RunBitMap::new(bits.num_bits());
+        for (_, run) in bits.iter_runs().filter(|&(value, _)| value) {
+            map.ones += run.end - run.start;
+            map.runs.insert(run.start, run.end);
+        }
+        map
+    }
+
+    /// Returns the number of runs of `1` bits.
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Encodes the number of bits followed by the start and length of every run of `1` bits,
+    /// all as little endian u64.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.runs.len() * 16);
+        bytes.extend_from_slice(&self.num_bits.to_le_bytes());
+        for (&start, &end) in &self.runs {
+            bytes.extend_from_slice(&start.to_le_bytes());
+            bytes.extend_from_slice(&(end - start).to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a RunBitMap encoded with `to_bytes`, returning `None` if the bytes are invalid.
+    pub fn from_bytes(bytes: &[u8]) -> Option<RunBitMap> {
+        if bytes.len() % 16 != 8 {
+            return None;
+        }
+        let mut words = bytes.chunks_exact(8).map(|word| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(word);
+            u64::from_le_bytes(buf)
+        });
+        let mut map = RunBitMap::new(words.next()?);
+        let mut last_end = None;
+        while let (Some(start), Some(len)) = (words.next(), words.next()) {
+            let end = start.checked_add(len)?;
+            // runs must be ordered, non-empty and separated by `0` bits
+            if len == 0 || end > map.num_bits || last_end.map_or(false, |last_end| start <= last_end) {
+                return None;
+            }
+            map.runs.insert(start, end);
+            map.ones += len;
+            last_end = Some(end);
+        }
+        Some(map)
+    }
+
+    #[inline]
+    fn check(&self, bit: u64) {
+        if bit >= self.num_bits {
+            panic!("bit index out of bounds: the number of bits is {} but the the index is {}",
+                   self.num_bits, bit);
+        }
+    }
+
+    /// Returns the end of the run containing `bit`.
+    fn run_end(&self, bit: u64) -> Option<u64> {
+        self.runs.range(..=bit).next_back()
+            .map(|(_, &end)| end)
+            .filter(|&end| bit < end)
+    }
+}
+
+impl BitSet for RunBitMap {
+    fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    fn zeroes(&self) -> u64 {
+        self.num_bits - self.ones
+    }
+
+    fn get(&self, bit: u64) -> bool {
+        self.check(bit);
+        self.run_end(bit).is_some()
+    }
+
+    fn find_next_zero(&self, from: u64) -> Option<u64> {
+        if from >= self.num_bits {
+            return None;
+        }
+        // runs don't touch, so the bit after one is always `0`
+        let bit = self.run_end(from).unwrap_or(from);
+        if bit < self.num_bits { Some(bit) } else { None }
+    }
+
+    fn find_next_one(&self, from: u64) -> Option<u64> {
+        if from >= self.num_bits {
+            return None;
+        }
+        if self.run_end(from).is_some() {
+            return Some(from);
+        }
+        self.runs.range(from..).next().map(|(&start, _)| start)
+    }
+}
+
+impl BitSetMut for RunBitMap {
+    fn set(&mut self, bit: u64, new: bool) -> bool {
+        let old = self.get(bit);
+        if new && !old {
+            self.set_range(bit..bit + 1);
+        } else if !new && old {
+            self.clear_range(bit..bit + 1);
+        }
+        old
+    }
+
+    fn set_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64 {
+        let range = bounds(&range, self.num_bits);
+        if range.start == range.end {
+            return 0;
+        }
+        // merge all runs overlapping or touching the range into a single one
+        let (mut start, mut end) = (range.start, range.end);
+        let mut already = 0;
+        if let Some((&run_start, &run_end)) = self.runs.range(..range.start).next_back() {
+            if run_end >= range.start {
+                self.runs.remove(&run_start);
+                already += cmp::min(run_end, range.end) - range.start;
+                start = run_start;
+                end = cmp::max(end, run_end);
+            }
+        }
+        while let Some((&run_start, &run_end)) = self.runs.range(range.start..=end).next() {
+            self.runs.remove(&run_start);
+            already += cmp::min(run_end, range.end).saturating_sub(run_start);
+            end = cmp::max(end, run_end);
+        }
+        self.runs.insert(start, end);
+        let changed = range.end - range.start - already;
+        self.ones += changed;
+        changed
+    }
+
+    fn clear_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64 {
+        let range = bounds(&range, self.num_bits);
+        let mut changed = 0;
+        if range.start == range.end {
+            return 0;
+        }
+        // cut the run reaching into the range from the left
+        if let Some((&run_start, &run_end)) = self.runs.range(..range.start).next_back() {
+            if run_end > range.start {
+                self.runs.insert(run_start, range.start);
+                if run_end > range.end {
+                    self.runs.insert(range.end, run_end);
+                }
+                changed += cmp::min(run_end, range.end) - range.start;
+            }
+        }
+        while let Some((&run_start, &run_end)) = self.runs.range(range.start..range.end).next() {
+            self.runs.remove(&run_start);
+            if run_end > range.end {
+                self.runs.insert(range.end, run_end);
+            }
+            changed += cmp::min(run_end, range.end) - run_start;
+        }
+        self.ones -= changed;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn huge() {
+        let num_bits = u64::MAX;
+        let mut map = RunBitMap::new(num_bits);
+        assert_eq!(map.set_range(..num_bits - 1), num_bits - 1);
+        assert_eq!(map.find_first_zero(), Some(num_bits - 1));
+        assert_eq!(map.clear_range(1 << 40..(1 << 40) + 3), 3);
+        assert_eq!(map.find_first_zero(), Some(1 << 40));
+        assert_eq!(map.find_next_zero((1 << 40) + 3), Some(num_bits - 1));
+        assert_eq!(map.find_next_one(1 << 40), Some((1 << 40) + 3));
+        assert!(!map.set(num_bits - 1, true));
+        assert_eq!(map.zeroes(), 3);
+        assert_eq!(map.num_runs(), 2);
+        assert_eq!(map.set_range((1 << 40) + 1..(1 << 40) + 2), 1);
+        assert_eq!(map.num_runs(), 3);
+        assert_eq!(map.set_range(..), 2);
+        assert_eq!(map.num_runs(), 1);
+        assert!(map.all());
+    }
+
+    #[test]
+    fn bytes() {
+        let mut map = RunBitMap::new(1000);
+        for &(start, end) in &[(0, 3), (10, 500), (999, 1000)] {
+            map.set_range(start..end);
+        }
+        let bytes = map.to_bytes();
+        assert_eq!(bytes.len(), 8 + 3 * 16);
+        assert_eq!(RunBitMap::from_bytes(&bytes), Some(map.clone()));
+        assert_eq!(RunBitMap::from_bits(&RunBitMap::from_bytes(&bytes).unwrap()), map);
+        assert_eq!(RunBitMap::from_bytes(&RunBitMap::new(0).to_bytes()), Some(RunBitMap::new(0)));
+
+        let encode = |words: &[u64]| words.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+        assert!(RunBitMap::from_bytes(&encode(&[10, 0, 5, 6, 3])).is_some());
+        // empty run
+        assert!(RunBitMap::from_bytes(&encode(&[10, 0, 0])).is_none());
+        // out of bounds
+        assert!(RunBitMap::from_bytes(&encode(&[10, 6, 5])).is_none());
+        assert!(RunBitMap::from_bytes(&encode(&[10, 6, u64::MAX])).is_none());
+        // touching and unordered runs
+        assert!(RunBitMap::from_bytes(&encode(&[10, 0, 5, 5, 3])).is_none());
+        assert!(RunBitMap::from_bytes(&encode(&[10, 6, 2, 0, 3])).is_none());
+        assert!(RunBitMap::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(RunBitMap::from_bytes(&[]).is_none());
+    }
+}
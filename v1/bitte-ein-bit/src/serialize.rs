@@ -0,0 +1,266 @@
+//! Serde support, enabled by the `serde` feature.
+//!
+//! BitMaps and RunBitMaps are both serialized as their number of bits and their runs encoded
+//! with `rle`, thus either can be deserialized from the other. Deserializing a BitMap allocates
+//! a byte per 8 bits, thus it's only done through `MaxBits`, which bounds its length.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use bitset::{BitSet, BitSetMut};
+use {rle, BitMap, RunBitMap};
+
+const FIELDS: &[&str] = &["num_bits", "runs"];
+
+fn serialize<B: BitSet, S: Serializer>(name: &'static str, bits: &B, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut state = serializer.serialize_struct(name, 2)?;
+    state.serialize_field("num_bits", &bits.num_bits())?;
+    state.serialize_field("runs", &Bytes(rle::encode_to_vec(bits)))?;
+    state.end()
+}
+
+impl<T: AsRef<[u8]>> Serialize for BitMap<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize("BitMap", self, serializer)
+    }
+}
+
+impl Serialize for RunBitMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize("RunBitMap", self, serializer)
+    }
+}
+
+/// Deserializes a `BitMap<Vec<u8>>` of at most this many bits, failing before allocating
+/// its bytes otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxBits(pub u64);
+
+impl<'de> DeserializeSeed<'de> for MaxBits {
+    type Value = BitMap<Vec<u8>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<BitMap<Vec<u8>>, D::Error> {
+        let encoded = deserializer.deserialize_struct("BitMap", FIELDS, EncodedVisitor)?;
+        if encoded.num_bits > self.0 {
+            return Err(de::Error::invalid_value(de::Unexpected::Unsigned(encoded.num_bits), &self));
+        }
+        let num_bits = encoded.check()?;
+        let mut bitmap = BitMap::with_length(vec![0; ::div_ceil(num_bits, 8) as usize], num_bits);
+        encoded.decode_into(&mut bitmap)?;
+        Ok(bitmap)
+    }
+}
+
+impl<'de> Deserialize<'de> for RunBitMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = deserializer.deserialize_struct("RunBitMap", FIELDS, EncodedVisitor)?;
+        let mut map = RunBitMap::new(encoded.check()?);
+        encoded.decode_into(&mut map)?;
+        Ok(map)
+    }
+}
+
+impl de::Expected for MaxBits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at most {} bits", self.0)
+    }
+}
+
+/// The serialized fields.
+struct Encoded {
+    num_bits: u64,
+    runs: Vec<u8>,
+}
+
+impl Encoded {
+    /// Checks that the runs cover exactly `num_bits`, returning them.
+    fn check<E: de::Error>(&self) -> Result<u64, E> {
+        let mut covered = 0;
+        for run in rle::decode_runs(&self.runs) {
+            covered = run.map_err(E::custom)?.1.end;
+        }
+        if covered != self.num_bits {
+            return Err(E::invalid_length(covered as usize, &"runs covering num_bits"));
+        }
+        Ok(self.num_bits)
+    }
+
+    fn decode_into<B: BitSetMut, E: de::Error>(&self, bits: &mut B) -> Result<(), E> {
+        rle::decode_into(&self.runs, bits).map(|_| ()).map_err(E::custom)
+    }
+}
+
+struct EncodedVisitor;
+
+impl<'de> Visitor<'de> for EncodedVisitor {
+    type Value = Encoded;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a bitmap of num_bits and runs")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Encoded, A::Error> {
+        let num_bits = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let Bytes(runs) = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok(Encoded { num_bits, runs })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Encoded, A::Error> {
+        let mut num_bits = None;
+        let mut runs = None;
+        while let Some(key) = map.next_key()? {
+            match key {
+                Field::NumBits if num_bits.is_some() => return Err(de::Error::duplicate_field("num_bits")),
+                Field::NumBits => num_bits = Some(map.next_value()?),
+                Field::Runs if runs.is_some() => return Err(de::Error::duplicate_field("runs")),
+                Field::Runs => runs = Some(map.next_value::<Bytes>()?.0),
+            }
+        }
+        Ok(Encoded {
+            num_bits: num_bits.ok_or_else(|| de::Error::missing_field("num_bits"))?,
+            runs: runs.ok_or_else(|| de::Error::missing_field("runs"))?,
+        })
+    }
+}
+
+enum Field {
+    NumBits,
+    Runs,
+}
+
+impl<'de> Deserialize<'de> for Field {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldVisitor;
+
+        impl<'de> Visitor<'de> for FieldVisitor {
+            type Value = Field;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("`num_bits` or `runs`")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Field, E> {
+                match value {
+                    "num_bits" => Ok(Field::NumBits),
+                    "runs" => Ok(Field::Runs),
+                    _ => Err(de::Error::unknown_field(value, FIELDS)),
+                }
+            }
+        }
+
+        deserializer.deserialize_identifier(FieldVisitor)
+    }
+}
+
+/// The encoded runs, as bytes if the format supports them and a sequence otherwise.
+struct Bytes(Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Bytes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("encoded runs")
+            }
+
+            fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Bytes, E> {
+                Ok(Bytes(value.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Bytes, E> {
+                Ok(Bytes(value))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(Bytes(bytes))
+            }
+        }
+
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error, assert_ser_tokens, assert_tokens, Token};
+
+    /// A BitMap deserialized through `MaxBits(16)`.
+    #[derive(Debug, PartialEq)]
+    struct Bounded(BitMap<Vec<u8>>);
+
+    impl<'de> Deserialize<'de> for Bounded {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            MaxBits(16).deserialize(deserializer).map(Bounded)
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        let bitmap = BitMap::with_length(vec![0b1000_1011u8, 0b1111_1111], 12);
+        let tokens = |name| vec![
+            Token::Struct { name, len: 2 },
+            Token::Str("num_bits"),
+            Token::U64(12),
+            Token::Str("runs"),
+            Token::Bytes(&[2, 1, 1, 3, 5]),
+            Token::StructEnd,
+        ];
+        assert_ser_tokens(&bitmap, &tokens("BitMap"));
+        assert_de_tokens(&Bounded(BitMap::with_length(vec![0b1000_1011, 0b1111_1111], 12)), &tokens("BitMap"));
+        assert_tokens(&RunBitMap::from_bits(&bitmap), &tokens("RunBitMap"));
+
+        // formats without bytes encode the runs as sequence
+        assert_de_tokens(&Bounded(bitmap), &[
+            Token::Seq { len: Some(2) },
+            Token::U64(12),
+            Token::Seq { len: Some(5) },
+            Token::U8(2), Token::U8(1), Token::U8(1), Token::U8(3), Token::U8(5),
+            Token::SeqEnd,
+            Token::SeqEnd,
+        ]);
+    }
+
+    #[test]
+    fn invalid() {
+        let tokens = |name, runs| vec![
+            Token::Struct { name, len: 2 },
+            Token::Str("num_bits"),
+            Token::U64(12),
+            Token::Str("runs"),
+            Token::Bytes(runs),
+            Token::StructEnd,
+        ];
+        assert_de_tokens_error::<RunBitMap>(&tokens("RunBitMap", &[2, 1, 1, 3]),
+                                            "invalid length 7, expected runs covering num_bits");
+        assert_de_tokens_error::<RunBitMap>(&tokens("RunBitMap", &[2, 1, 1, 3, 6]),
+                                            "invalid length 13, expected runs covering num_bits");
+        assert_de_tokens_error::<Bounded>(&tokens("BitMap", &[2, 0x80]), "runlengths truncated");
+        // refused before allocating a byte per 8 bits
+        assert_de_tokens_error::<Bounded>(&[
+            Token::Struct { name: "BitMap", len: 2 },
+            Token::Str("num_bits"),
+            Token::U64(1 << 62),
+            Token::Str("runs"),
+            Token::Bytes(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x40]),
+            Token::StructEnd,
+        ], "invalid value: integer `4611686018427387904`, expected at most 16 bits");
+    }
+}
@@ -0,0 +1,196 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use bitset::{bounds, BitSet, BitSetMut};
+use {BitMap, RangeArgument};
+
+/// A BitMap with summary levels, finding the next `0` bit in O(log n).
+///
+/// Bit `i` of the first level is set if word `i` of the bits has a `0`, bit `i` of every further
+/// level if word `i` of the level below is non-zero, up to a level of a single word. A search
+/// thus looks at one word per level instead of scanning the bits, while the levels take 1/63 of
+/// their size.
+///
+/// Only the bits are kept in the buffer, e.g. a memory mapped file. The levels are rebuilt from
+/// them when the SummaryBitMap is created.
+pub struct SummaryBitMap<T> {
+    bits: BitMap<T>,
+    levels: Vec<Vec<u64>>,
+}
+
+impl<T: AsRef<[u8]>> SummaryBitMap<T> {
+    /// Create a new SummaryBitMap, summarising the passed BitMap.
+    pub fn new(bits: BitMap<T>) -> SummaryBitMap<T> {
+        let mut levels = Vec::new();
+        let mut len = bits.num_words();
+        loop {
+            len = ::div_ceil(len, 64).max(1);
+            levels.push(vec![0; len as usize]);
+            if len == 1 {
+                break;
+            }
+        }
+        let mut map = SummaryBitMap { bits, levels };
+        for index in 0..map.bits.num_words() {
+            map.update(index);
+        }
+        map
+    }
+
+    /// Returns word `index` of the bits with its `0` bits set, ignoring those from `num_bits` on.
+    fn zeros(&self, index: u64) -> u64 {
+        let valid = self.bits.num_bits() - index * 64;
+        let mask = if valid < 64 { (1 << valid) - 1 } else { !0 };
+        !self.bits.word(index) & mask
+    }
+
+    /// Updates the levels after word `index` of the bits changed.
+    fn update(&mut self, index: u64) {
+        let mut index = index;
+        let mut set = self.zeros(index) != 0;
+        for level in &mut self.levels {
+            let word = &mut level[(index / 64) as usize];
+            let old = *word;
+            if set {
+                *word |= 1 << (index % 64);
+            } else {
+                *word &= !(1 << (index % 64));
+            }
+            if (old == 0) == (*word == 0) {
+                // the levels above don't change
+                return;
+            }
+            set = *word != 0;
+            index /= 64;
+        }
+    }
+
+    /// Returns the first index at or after `from` whose bit is set in `level`.
+    fn next_set(&self, level: usize, from: u64) -> Option<u64> {
+        let words = &self.levels[level];
+        let index = from / 64;
+        if index >= words.len() as u64 {
+            return None;
+        }
+        let word = words[index as usize] & (!0 << (from % 64));
+        if word != 0 {
+            return Some(index * 64 + word.trailing_zeros() as u64);
+        }
+        if level + 1 == self.levels.len() {
+            return None;
+        }
+        let index = self.next_set(level + 1, index + 1)?;
+        Some(index * 64 + words[index as usize].trailing_zeros() as u64)
+    }
+
+    /// Returns a reference to the summarised BitMap
+    pub fn get_ref(&self) -> &BitMap<T> {
+        &self.bits
+    }
+
+    /// Consumes self, returning the summarised BitMap
+    pub fn into_inner(self) -> BitMap<T> {
+        self.bits
+    }
+}
+
+impl<T: AsRef<[u8]>> BitSet for SummaryBitMap<T> {
+    fn num_bits(&self) -> u64 {
+        self.bits.num_bits()
+    }
+
+    fn zeroes(&self) -> u64 {
+        self.bits.zeroes()
+    }
+
+    fn get(&self, bit: u64) -> bool {
+        self.bits.get(bit)
+    }
+
+    fn find_next_zero(&self, from: u64) -> Option<u64> {
+        if from >= self.bits.num_bits() {
+            return None;
+        }
+        let index = from / 64;
+        let word = self.zeros(index) & (!0 << (from % 64));
+        if word != 0 {
+            return Some(index * 64 + word.trailing_zeros() as u64);
+        }
+        let index = self.next_set(0, index + 1)?;
+        Some(index * 64 + self.zeros(index).trailing_zeros() as u64)
+    }
+
+    /// Scans the bits, only `0` bits are summarised.
+    fn find_next_one(&self, from: u64) -> Option<u64> {
+        self.bits.find_next_one(from)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> BitSetMut for SummaryBitMap<T> {
+    fn set(&mut self, bit: u64, new: bool) -> bool {
+        let old = self.bits.set(bit, new);
+        if old != new {
+            self.update(bit / 64);
+        }
+        old
+    }
+
+    fn set_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64 {
+        let range = bounds(&range, self.bits.num_bits());
+        let changed = self.bits.set_range(range.clone());
+        self.update_range(range.start, range.end, changed);
+        changed
+    }
+
+    fn clear_range<R: RangeArgument<u64>>(&mut self, range: R) -> u64 {
+        let range = bounds(&range, self.bits.num_bits());
+        let changed = self.bits.clear_range(range.clone());
+        self.update_range(range.start, range.end, changed);
+        changed
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> SummaryBitMap<T> {
+    fn update_range(&mut self, start: u64, end: u64, changed: u64) {
+        if changed == 0 {
+            return;
+        }
+        for index in start / 64..(end - 1) / 64 + 1 {
+            self.update(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    #[test]
+    fn levels() {
+        // three levels
+        let num_bits = 64 * 64 * 64 + 100;
+        let bits = BitMap::with_length(vec![0xffu8; (num_bits / 8 + 1) as usize], num_bits);
+        let mut map = SummaryBitMap::new(bits);
+        assert_eq!(map.levels.iter().map(Vec::len).collect::<Vec<_>>(), vec![65, 2, 1]);
+        assert_eq!(map.find_first_zero(), None);
+
+        for &bit in &[num_bits - 1, 64 * 64 * 64 + 3, 64 * 64 * 7 + 5, 64 * 3, 0] {
+            map.set(bit, false);
+            assert_eq!(map.find_first_zero(), Some(bit));
+            assert_eq!(map.find_next_zero(bit + 1), map.get_ref().find_next_zero(bit + 1));
+        }
+        assert_eq!(map.find_next_zero(num_bits - 1), Some(num_bits - 1));
+        assert_eq!(map.find_next_zero(num_bits), None);
+
+        // the levels are rebuilt from the bits
+        let map = SummaryBitMap::new(map.into_inner());
+        let zeros: Vec<_> = map.iter_runs().filter(|&(value, _)| !value).map(|(_, run)| run.start).collect();
+        assert_eq!(zeros, vec![0, 64 * 3, 64 * 64 * 7 + 5, 64 * 64 * 64 + 3, num_bits - 1]);
+
+        let mut map = map;
+        assert_eq!(map.set_range(..), 5);
+        assert_eq!(map.find_first_zero(), None);
+        assert!(map.levels.iter().all(|level| level.iter().all(|&word| word == 0)));
+    }
+}
@@ -0,0 +1,41 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate flate2;
+extern crate csync;
+
+use csync::codec;
+
+use flate2::Decompress;
+use codec::{features, Chunk, CopyRange, EndOfStream, Parity, ZeroRun, MTU};
+
+fuzz_target!(|data: &[u8]| {
+    let mut decompress = Decompress::new(false);
+    for index_field_size in 1..9 {
+        if let Ok(chunk) = Chunk::decode(data.to_vec(), index_field_size) {
+            assert_eq!(chunk.as_ref().len() as u64, data.len() as u64 - index_field_size);
+            // pretend the chunk belongs to a file of a few chunks to exercise decompression
+            let length = chunk.index.saturating_add(3).saturating_mul(MTU as u64);
+            let chunk_info = codec::index_field_size(length, features::PARITY | features::DELTA);
+            if chunk.index < chunk_info.num_chunks && chunk.is_compressed(&chunk_info) {
+                if let Ok(decompressed) = chunk.decompress(&chunk_info, &mut decompress, Vec::new()) {
+                    assert_eq!(decompressed.as_ref().len() as u64, chunk_info.chunk_len(chunk.index));
+                }
+            }
+            // extension messages only look at the payload
+            if let Ok(parity) = Parity::decode(&chunk, &chunk_info) {
+                assert!(parity.first + parity.count <= chunk_info.num_chunks);
+            }
+            if let Ok(run) = ZeroRun::decode(&chunk, &chunk_info) {
+                assert!(run.first + run.count <= chunk_info.num_chunks);
+            }
+            if let Ok(range) = CopyRange::decode(&chunk, &chunk_info) {
+                assert!(range.first + range.count <= chunk_info.num_chunks);
+            }
+        }
+    }
+    if let Ok(chunk) = Chunk::decode_stream(data.to_vec()) {
+        // the id takes at least a byte
+        assert!(chunk.as_ref().len() < data.len());
+        let _ = EndOfStream::decode(&chunk);
+    }
+});
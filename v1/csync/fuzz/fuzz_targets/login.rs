@@ -0,0 +1,17 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate csync;
+
+use csync::codec;
+
+use codec::Login;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(login) = Login::decode(data) {
+        // whatever decodes has to survive a roundtrip
+        let mut buf = Vec::new();
+        login.encode(&mut buf);
+        let again = Login::decode(&buf).unwrap();
+        assert_eq!(login.client_token, again.client_token);
+    }
+});
@@ -0,0 +1,34 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate bitte_ein_bit;
+extern crate csync;
+
+use csync::codec;
+
+use bitte_ein_bit::rle;
+use codec::{MissingRanges, ServerMessage};
+
+fuzz_target!(|data: &[u8]| {
+    let update = match ServerMessage::decode(data) {
+        Ok(ServerMessage::StatusUpdate(update)) => update,
+        Ok(ServerMessage::Signatures(signatures)) => {
+            assert!(signatures.first + signatures.blocks.len() as u64 <= signatures.num_blocks);
+            return;
+        }
+        Ok(ServerMessage::Ack(_)) | Ok(ServerMessage::Cookie(_)) | Ok(ServerMessage::RateHint(_)) | Err(_) => return,
+    };
+    for _ in rle::Runlengths::new(update.runlengths) {}
+    let mut missing = MissingRanges::default();
+    if let Ok(false) = missing.parse_status_update(update.runlengths) {
+        // missing ranges can be huge, only check the first chunks
+        let mut previous = None;
+        for _ in 0..1000 {
+            let chunk = match missing.next_chunk() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            assert!(previous.map_or(true, |p| chunk > p));
+            previous = Some(chunk);
+        }
+    }
+});
@@ -1,31 +1,76 @@
-use std::io::{Seek, SeekFrom};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::time::{Instant, Duration};
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket as StdUdp};
 use std::fs::{self, File as StdFile};
+use std::os::unix::fs::MetadataExt;
 use std::cell::RefCell;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::cmp;
+use std::mem;
+use std::thread;
+use std::fmt;
+use std::error::Error as StdError;
 
 use walkdir::WalkDir;
 use futures::future::{self, ok, loop_fn, Loop, Either};
-use futures::Future;
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream, Async, Poll};
+use tokio::clock;
 use tokio::net::UdpSocket;
 use tokio::io::Error;
 use tokio::reactor::Handle;
 use tokio::runtime::current_thread::Runtime;
 use tokio::reactor::PollEvented2 as PollEvented;
 use tokio::net::RecvDgram;
+use tokio::timer::Delay;
 use tokio_file_unix::File;
 use tokio::io;
 use byteorder::{WriteBytesExt, LE};
+use flate2::{self, Compress};
+use bitte_ein_bit::rle;
+use ring::digest;
 
 use codec::*;
+use progress::Progress;
+use fec::{self, LossEstimator, ParityEncoder};
+use sparse::ZeroRuns;
+use delta::{SignatureSet, CopyRanges};
+use stream::SendWindow;
+use pace::Pacer;
+use transport::{Transport, send_dgram, recv_dgram};
+
+/// Number of chunks compressed to decide whether compression pays off.
+const COMPRESSION_SAMPLES: u64 = 8;
+/// Number of times a `Delete` or `Rename` is sent before giving up.
+const COMMAND_ATTEMPTS: u32 = 5;
+/// Chunks of input read ahead of a streaming upload.
+const STREAM_READ_AHEAD: usize = 64;
+/// Time after which a streaming upload waiting for input sends a keepalive, well below the server's timeout.
+const KEEPALIVE: Duration = Duration::from_secs(1);
+/// Time a finished streaming upload waits for late status updates.
+const LINGER: Duration = Duration::from_millis(100);
+/// Time to wait for the answer to a login before sending it again.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs the futures of the client to completion, a `Runtime` outside of the simulation.
+pub trait BlockOn {
+    fn block_on<F: Future<Error = Error>>(&mut self, future: F) -> Result<F::Item, Error>;
+}
+
+impl BlockOn for Runtime {
+    fn block_on<F: Future<Error = Error>>(&mut self, future: F) -> Result<F::Item, Error> {
+        Runtime::block_on(self, future)
+    }
+}
 
 pub fn client(opt: super::Opt) -> Result<(), Error> {
     for file in WalkDir::new(opt.files.as_ref().unwrap()) {
         let file = file.unwrap();
         if file.file_type().is_file() {
             let file = file.path().strip_prefix(opt.files.as_ref().unwrap()).unwrap();
-            println!("uploading {:?}", file);
+            if !opt.progress_json {
+                println!("uploading {:?}", file);
+            }
             client_once(file.to_str().unwrap(), &opt)?;
         }
     }
@@ -33,111 +78,746 @@ pub fn client(opt: super::Opt) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn client_once(filename: &str, opt: &super::Opt) -> Result<(), Error>  {
+/// Deletes and renames files on the server as given on the command line.
+pub fn manage(opt: &super::Opt) -> Result<(), Error> {
+    let deletes = opt.delete.iter().map(|path| Command::Delete(Delete { path }));
+    let renames = opt.rename.chunks(2).map(|r| Command::Rename(Rename { from: &r[0], to: &r[1] }));
+    for command in deletes.chain(renames) {
+        let description = format!("{:?}", command);
+        match send_command(command, opt)? {
+            Ack::Done => println!("{} done", description),
+            ack => eprintln!("{} failed: {:?}", description, ack),
+        }
+    }
+    Ok(())
+}
+
+/// Sends a command which the server answers with an `Ack`, repeating it if the ack doesn't arrive.
+fn send_command(command: Command, opt: &super::Opt) -> Result<Ack, Error> {
+    let mut runtime = Runtime::new()?;
+    let mut buf = [0; MTU];
+    for _ in 0..COMMAND_ATTEMPTS {
+        // the server only accepts a login from a new port, commands are idempotent
+        let (mut socket, mut socket2) = connect(opt)?;
+        let cookie = fetch_cookie(&mut runtime, &mut socket, &mut socket2, command)?;
+        let mut login = Vec::with_capacity(MTU);
+        Login { client_token: b"roflcopter", cookie: &cookie, command }.encode(&mut login);
+        send(&mut runtime, &mut socket, &login)?;
+        let len = match recv_timeout(&mut runtime, &mut socket2, &mut buf)? {
+            Some(len) => len,
+            None => continue,
+        };
+        match ServerMessage::decode(&buf[..len]) {
+            Ok(ServerMessage::Ack(ack)) => return Ok(ack),
+            message => warn!("Ignoring unexpected answer: {:?}", message),
+        }
+    }
+    Err(Error::new(io::ErrorKind::TimedOut, "no acknowledgement from the server"))
+}
+
+/// Connects a socket to the server, returning a half to send and one to receive.
+fn connect(opt: &super::Opt) -> Result<(UdpSocket, UdpSocket), Error> {
     let socket = StdUdp::bind("0.0.0.0:0")?;
     let server = &(opt.host.as_str(), opt.port).to_socket_addrs().unwrap().next().unwrap();
     socket.connect(server)?;
+    let socket2 = socket.try_clone()?;
+    Ok((UdpSocket::from_std(socket, &Handle::default())?, UdpSocket::from_std(socket2, &Handle::default())?))
+}
 
-    let socket2 = socket.try_clone().unwrap();
+/// Sends a datagram, blocking until it's sent.
+fn send<B: BlockOn, T: Transport>(runtime: &mut B, transport: &mut T, buf: &[u8]) -> Result<usize, Error> {
+    runtime.block_on(future::poll_fn(|| transport.poll_send(buf)))
+}
 
+/// Receives a datagram, giving up after `ANSWER_TIMEOUT`.
+fn recv_timeout<B: BlockOn, T: Transport>(runtime: &mut B, transport: &mut T, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+    let recv = future::poll_fn(|| transport.poll_recv(buf));
+    let timeout = Delay::new(clock::now() + ANSWER_TIMEOUT).map_err(timer_error);
+    match runtime.block_on(recv.select2(timeout).map_err(|e| e.split().0))? {
+        Either::A((len, _)) => Ok(Some(len)),
+        Either::B(_) => Ok(None),
+    }
+}
 
-    let mut send_buf: Vec<u8> = Vec::with_capacity(MTU);
-    let recv_buf: Vec<u8> = vec![0; MTU];
+/// Asks the server for the cookie proving that the client receives its answers, sending through
+/// `socket` and receiving from `socket2`.
+fn fetch_cookie<B, S, R>(runtime: &mut B, socket: &mut S, socket2: &mut R, command: Command) -> Result<Vec<u8>, Error>
+    where B: BlockOn, S: Transport, R: Transport {
+    let mut login = Vec::with_capacity(MTU);
+    Login { client_token: b"roflcopter", cookie: &[], command }.encode(&mut login);
+    if login.len() < MIN_LOGIN_LEN {
+        login.resize(MIN_LOGIN_LEN, 0);
+    }
+    let mut buf = [0; MTU];
+    for _ in 0..COMMAND_ATTEMPTS {
+        send(runtime, socket, &login)?;
+        let len = match recv_timeout(runtime, socket2, &mut buf)? {
+            Some(len) => len,
+            None => continue,
+        };
+        match ServerMessage::decode(&buf[..len]) {
+            Ok(ServerMessage::Cookie(cookie)) => return Ok(cookie.to_vec()),
+            message => warn!("Ignoring unexpected answer: {:?}", message),
+        }
+    }
+    Err(Error::new(io::ErrorKind::TimedOut, "no cookie from the server"))
+}
 
+pub fn client_once(filename: &str, opt: &super::Opt) -> Result<(), Error>  {
+    let (socket, socket2) = connect(opt)?;
+    upload(filename, opt, socket, socket2, &mut Runtime::new()?)
+}
 
-    let mut runtime = Runtime::new()?;
+/// Uploads the file on `runtime`, sending through `socket` and receiving status updates from
+/// `socket2`.
+pub fn upload<S, R, B>(filename: &str, opt: &super::Opt, mut socket: S, mut socket2: R, runtime: &mut B) -> Result<(), Error>
+    where S: Transport + 'static, R: Transport + 'static, B: BlockOn {
+    let mut send_buf: Vec<u8> = Vec::with_capacity(MTU);
+    let recv_buf: Vec<u8> = vec![0; MTU];
 
     let missing = &RefCell::new(MissingRanges::default());
 
-    let file = StdFile::open(Path::new(opt.files.as_ref().unwrap()).join(filename)).unwrap();
+    let path = Path::new(opt.files.as_ref().unwrap()).join(filename);
+    let mut file = StdFile::open(&path).unwrap();
     let filesize = file.metadata().unwrap().len();
-
-    let chunk_info = &index_field_size(filesize);
+    let metadata = file_metadata(&file, opt.owner)?;
+
+    // parity is only sent once the client observes loss, delta only if the server has an old copy
+    let mut requested = features::PARITY | features::DELTA | features::METADATA;
+    // the layout is already that of parity and extension messages, the features added below
+    // don't change it
+    let chunk_info = &index_field_size(filesize, requested);
+    let zero_runs = ZeroRuns::scan(BufReader::with_capacity(1 << 20, &file), chunk_info)?;
+    debug!("{} of {} chunks are all zeros", zero_runs.chunks(), chunk_info.num_chunks);
+    if compression_pays_off(&mut file, chunk_info)? {
+        requested |= features::COMPRESSION;
+    }
+    if !zero_runs.is_empty() {
+        requested |= features::ZERO_RUNS;
+    }
+    debug!("Requesting features {:#x}", requested);
+    let command = Command::UploadRequest(UploadRequest { path: filename, length: filesize, features: requested, metadata: Some(metadata) });
+    let cookie = fetch_cookie(runtime, &mut socket, &mut socket2, command)?;
+    let compression = &RefCell::new(Compression {
+        enabled: false,
+        compress: Compress::new(flate2::Compression::fast(), false),
+        buf: Vec::with_capacity(MTU),
+    });
+    let fec = &RefCell::new(Fec {
+        enabled: false,
+        encoder: ParityEncoder::default(),
+        estimator: LossEstimator::new(Duration::from_secs(0)),
+        start: clock::now(),
+        pending: None,
+    });
+    let sparse = &RefCell::new(Sparse {
+        enabled: false,
+        zero_runs,
+    });
+    let delta = &RefCell::new(Delta {
+        enabled: false,
+        path,
+        signatures: SignatureSet::default(),
+        searched: true,
+        copies: CopyRanges::default(),
+    });
+    let progress = &RefCell::new(Progress::new(filename, filesize, opt.progress_json));
+    let pacer = &RefCell::new(Pacer::new());
 
     let client = future::lazy(move || {
         let reactor: &Handle = &Handle::current();
 
-        let socket = UdpSocket::from_std(socket, reactor).unwrap();
-        let socket2 = UdpSocket::from_std(socket2, reactor).unwrap();
-
-        let chunk_size = chunk_info.chunk_size;
-        let chunk_count = chunk_info.num_chunks;
         let last_chunk_size = chunk_info.last_chunk_size;
         let file = File::new_nb(file).unwrap().into_io(reactor).unwrap();
 
-        Login { client_token: b"roflcopter", command: Command::UploadRequest(UploadRequest { path: filename, length: filesize }) }.encode(&mut send_buf);
+        Login { client_token: b"roflcopter", cookie: &cookie, command }.encode(&mut send_buf);
 
-        let client = socket.send_dgram(send_buf, server)
+        let client = send_dgram(socket, send_buf)
             .and_then(move |(socket, send_buf)| {
-                let server = server.clone();
-	    /*
-                let syn_send_stamp = Instant::now();
-                socket.recv_dgram(recv_buf).map(move |x| (x, send_buf, syn_send_stamp))
-            })
-            .and_then(move |((socket, recv_buf, recv_len, server), mut send_buf, syn_send_stamp)| {
-                // TODO: process contents of this packet?
-                let ack_recv_stamp = Instant::now();
-                let rtt = ack_recv_stamp - syn_send_stamp;
-                // assert_eq!(recv_len, 0); // ???
-
-                //let mut missing_chunks = MissingRanges::default();
-                missing.borrow_mut().parse_status_update(&recv_buf[..recv_len]);
-                //println!("{:?}", missing_chunks);
-
-                //send_buf.clear();
-                // TODO: write something??
-
-                socket.send_dgram(send_buf, &server).map(move |x| (x, recv_buf, server, rtt, missing))
-            })
-            .and_then(move |((socket, send_buf), recv_buf, server, rtt, missing)| {
-                println!("sta rtt={:?}", rtt);
-		*/
-
-                loop_fn((do_chunk(chunk_info, missing, file, socket, send_buf, server), socket2.recv_dgram(recv_buf), last_chunk_size), move |(chunk_send, update_recv, lcs)| {
+                loop_fn((paced(pacer, do_chunk(chunk_info, missing, compression, fec, sparse, delta, file, socket, send_buf)), recv_dgram(socket2, recv_buf), last_chunk_size), move |(chunk_send, update_recv, lcs)| {
                     match chunk_send {
                         Ok(chunk_send) => Box::new(chunk_send.select2(update_recv).then(move |e| Ok(match e {
                             Ok(Either::A(((file, socket, send_buf), update_recv))) => {
                                 // send done, go send another
-                                Loop::Continue((do_chunk(chunk_info, missing, file, socket, send_buf, server), update_recv, lcs))
+                                Loop::Continue((paced(pacer, do_chunk(chunk_info, missing, compression, fec, sparse, delta, file, socket, send_buf)), update_recv, lcs))
                             }
-                            Ok(Either::B(((socket2, recv_buf, recv_len, server), chunk_send))) => {
+                            Ok(Either::B(((socket2, recv_buf, recv_len), chunk_send))) => {
                                 // got a status update!
-                                if missing.borrow_mut().parse_status_update(&recv_buf[..recv_len]) {
-                                    Loop::Break((socket2, recv_buf, server))
+                                if status_update(chunk_info, missing, compression, fec, sparse, delta, progress, pacer, &recv_buf[..recv_len]) {
+                                    Loop::Break(chunk_send)
                                 } else {
                                     // read the next one
-                                    Loop::Continue((Ok(chunk_send), socket2.recv_dgram(recv_buf), lcs))
+                                    Loop::Continue((Ok(chunk_send), recv_dgram(socket2, recv_buf), lcs))
                                 }
                             }
                             Err(Either::A((e, _))) | Err(Either::B((e, _))) => return Err(e), // just forward errors
                         }))) as Box<Future<Item=_, Error=_>>,
                         Err((file, socket, send_buf)) => {
-                            Box::new(update_recv.map(move |(socket2, recv_buf, recv_len, server)| {
+                            Box::new(update_recv.map(move |(socket2, recv_buf, recv_len)| {
                                 // got a status update while sleeping
-                                if missing.borrow_mut().parse_status_update(&recv_buf[..recv_len]) {
-                                    Loop::Break((socket, send_buf, server))
+                                if status_update(chunk_info, missing, compression, fec, sparse, delta, progress, pacer, &recv_buf[..recv_len]) {
+                                    Loop::Break(Box::new(ok((file, socket, send_buf))) as Box<Future<Item=_, Error=_>>)
                                 } else {
                                     // start sending again and read the next one
-                                    Loop::Continue((do_chunk(chunk_info, missing, file, socket, send_buf, server), socket2.recv_dgram(recv_buf), lcs))
+                                    Loop::Continue((paced(pacer, do_chunk(chunk_info, missing, compression, fec, sparse, delta, file, socket, send_buf)), recv_dgram(socket2, recv_buf), lcs))
                                 }
                             })) as Box<Future<Item=_, Error=_>>
                         }
                     }
-                }).and_then(move |(socket, send_buf, server): (UdpSocket, _, SocketAddr)| {
-                    let chunk = Chunk::new(send_buf, chunk_info.num_chunks, chunk_info.index_field_size, 0);
-
-                    socket.send_dgram(chunk.into_vec(), &server)
                 })
+            })
+            // a datagram being sent when the last status update arrived goes out before the FIN
+            .and_then(|chunk_send| chunk_send)
+            .and_then(move |(_, socket, send_buf)| {
+                let chunk = Chunk::new(send_buf, chunk_info.num_chunks + extension::FIN, chunk_info.index_field_size, 0);
+                send_dgram(socket, chunk.into_vec())
             });
         client
     });
 
-    let res = runtime.block_on(client).unwrap();
-    println!("{:?}", res);
+    runtime.block_on(client)?;
+    progress.borrow_mut().finish();
+
+    Ok(())
+}
+
+/// Uploads the input `open` returns as `name`, without knowing its length in advance.
+///
+/// With `resume`, the server keeps what it has of a previous upload of `name` and the input is
+/// only read after that, see `features::RESUME`. If the input doesn't start with it, it's
+/// opened again and uploaded from the start.
+pub fn stream<I, O>(name: &str, mut open: O, resume: bool, opt: &super::Opt) -> Result<(), Error>
+    where I: Read + Send + 'static, O: FnMut() -> Result<I, Error> {
+    let input = open()?;
+    let (socket, socket2) = connect(opt)?;
+    match upload_stream(name, |kept| read_ahead(input, kept), resume, opt, socket, socket2, &mut Runtime::new()?) {
+        Err(ref e) if e.get_ref().map_or(false, |e| e.is::<PrefixMismatch>()) => {
+            warn!("{}, uploading {} from the start", e, name);
+            let input = open()?;
+            let (socket, socket2) = connect(opt)?;
+            upload_stream(name, |kept| read_ahead(input, kept), false, opt, socket, socket2, &mut Runtime::new()?)
+        }
+        result => result,
+    }
+}
+
+/// Chunks the server kept of a previous upload of a stream, see `features::RESUME`.
+pub struct Kept {
+    pub chunks: u64,
+    /// SHA-256 of their bytes, see `StatusUpdate::prefix`
+    pub digest: Vec<u8>,
+}
+
+/// The input of a resumed stream doesn't start with the chunks the server kept.
+#[derive(Debug)]
+pub struct PrefixMismatch;
+
+impl fmt::Display for PrefixMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the input doesn't start with what the server has")
+    }
+}
+
+impl StdError for PrefixMismatch {
+    fn description(&self) -> &str {
+        "input doesn't match the kept chunks"
+    }
+}
 
+/// Uploads the chunks of `input` as `name` on `runtime`, sending through `socket` and receiving
+/// status updates from `socket2`.
+///
+/// `input` is called with the chunks the server kept if it resumes the upload, it has to skip
+/// them and fail with `PrefixMismatch` if they aren't the start of the input, see `read_chunks`.
+pub fn upload_stream<F, C, S, R, B>(name: &str, input: F, resume: bool, opt: &super::Opt, mut socket: S, mut socket2: R,
+                                    runtime: &mut B) -> Result<(), Error>
+    where F: FnOnce(Option<Kept>) -> C, C: Stream<Item = Vec<u8>, Error = Error>, S: Transport, R: Transport, B: BlockOn {
+    let requested = if resume { features::STREAM | features::RESUME } else { features::STREAM };
+    let command = Command::UploadRequest(UploadRequest { path: name, length: 0, features: requested, metadata: None });
+    let cookie = fetch_cookie(runtime, &mut socket, &mut socket2, command)?;
+    let mut login = Vec::with_capacity(MTU);
+    Login { client_token: b"roflcopter", cookie: &cookie, command }.encode(&mut login);
+    send(runtime, &mut socket, &login)?;
+
+    let mut pacer = Pacer::new();
+    let kept = if resume { kept_chunks(runtime, &mut socket2, &mut pacer)? } else { None };
+    let resumed = kept.as_ref().map_or(0, |kept| kept.chunks);
+    let mut upload = StreamUpload {
+        input: input(kept),
+        input_done: false,
+        socket,
+        socket2,
+        window: SendWindow::resume(resumed),
+        progress: Progress::new(name, 0, opt.progress_json),
+        pacer,
+        recv_buf: vec![0; MTU],
+        send_buf: Vec::with_capacity(MTU),
+        outgoing: None,
+        last_send: clock::now(),
+        delay: None,
+        fin: None,
+    };
+    runtime.block_on(&mut upload)?;
+    upload.progress.finish();
     Ok(())
 }
 
+/// Waits for the first status update of a resumed streaming upload, returning the chunks the
+/// server kept, `None` if it doesn't know the feature.
+fn kept_chunks<B: BlockOn, R: Transport>(runtime: &mut B, socket2: &mut R, pacer: &mut Pacer) -> Result<Option<Kept>, Error> {
+    let mut buf = [0; MTU];
+    let mut deadline = Delay::new(clock::now() + ANSWER_TIMEOUT * COMMAND_ATTEMPTS);
+    runtime.block_on(future::poll_fn(|| loop {
+        let len = match socket2.poll_recv(&mut buf)? {
+            Async::Ready(len) => len,
+            Async::NotReady => {
+                try_ready!(deadline.poll().map_err(timer_error));
+                return Err(Error::new(io::ErrorKind::TimedOut, "no status update from the server"));
+            }
+        };
+        match ServerMessage::decode(&buf[..len]) {
+            // a server which doesn't know the feature starts over
+            Ok(ServerMessage::StatusUpdate(ref update)) if update.features & features::RESUME == 0 => return Ok(Async::Ready(None)),
+            Ok(ServerMessage::StatusUpdate(update)) => return Ok(Async::Ready(Some(Kept {
+                chunks: match rle::decode_runs(update.runlengths).next() {
+                    Some(Ok((true, received))) => received.end,
+                    _ => 0,
+                },
+                digest: update.prefix.to_vec(),
+            }))),
+            Ok(ServerMessage::RateHint(rate)) => pacer.set_rate(rate),
+            message => warn!("Ignoring unexpected message: {:?}", message),
+        }
+    }))
+}
+
+/// Reads `input` on a thread of its own, as it may block like a pipe does, staying at most
+/// `STREAM_READ_AHEAD` chunks ahead of the upload.
+fn read_ahead<I: Read + Send + 'static>(input: I, kept: Option<Kept>) -> Box<Stream<Item = Vec<u8>, Error = Error>> {
+    let (mut tx, rx) = mpsc::channel(STREAM_READ_AHEAD);
+    thread::spawn(move || {
+        for chunk in read_chunks(input, kept) {
+            tx = match tx.send(chunk).wait() {
+                Ok(tx) => tx,
+                // the upload is gone
+                Err(_) => return,
+            };
+        }
+    });
+    Box::new(rx.then(|chunk| chunk.expect("channel receivers don't fail")))
+}
+
+/// Reads `input` in chunks of `STREAM_CHUNK_SIZE` after skipping the `kept` chunks, until it
+/// ends.
+///
+/// Fails with `PrefixMismatch` first if the input doesn't start with the kept chunks.
+pub fn read_chunks<R: Read>(input: R, kept: Option<Kept>) -> ReadChunks<R> {
+    ReadChunks { input, kept, done: false }
+}
+
+pub struct ReadChunks<R> {
+    input: R,
+    /// chunks the server has already
+    kept: Option<Kept>,
+    done: bool,
+}
+
+impl<R: Read> ReadChunks<R> {
+    /// Reads the next chunk, `None` at the end of the input.
+    fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(kept) = self.kept.take() {
+            let mut prefix = self.input.by_ref().take(kept.chunks * STREAM_CHUNK_SIZE);
+            let mut context = digest::Context::new(&digest::SHA256);
+            let mut buf = vec![0; STREAM_CHUNK_SIZE as usize];
+            let mut len = 0;
+            loop {
+                match prefix.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        context.update(&buf[..read]);
+                        len += read as u64;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if len != kept.chunks * STREAM_CHUNK_SIZE || context.finish().as_ref() != &kept.digest[..] {
+                return Err(Error::new(io::ErrorKind::InvalidData, PrefixMismatch));
+            }
+        }
+        let mut payload = vec![0; STREAM_CHUNK_SIZE as usize];
+        let mut len = 0;
+        // pipes return short reads, chunks must be full unless they're the last
+        while len < payload.len() {
+            match self.input.read(&mut payload[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        payload.truncate(len);
+        self.done = len < STREAM_CHUNK_SIZE as usize;
+        Ok(if len == 0 { None } else { Some(payload) })
+    }
+}
+
+impl<R: Read> Iterator for ReadChunks<R> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        if self.done {
+            return None;
+        }
+        match self.read_chunk() {
+            Ok(chunk) => chunk.map(Ok),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A streaming upload the server admitted.
+struct StreamUpload<C, S, R> {
+    input: C,
+    input_done: bool,
+    socket: S,
+    socket2: R,
+    window: SendWindow,
+    progress: Progress,
+    pacer: Pacer,
+    recv_buf: Vec<u8>,
+    /// spare buffer for the next message
+    send_buf: Vec<u8>,
+    /// datagram the transport wasn't ready for yet
+    outgoing: Option<Vec<u8>>,
+    last_send: Instant,
+    /// wakes the upload once the pacer allows the next datagram or a keepalive is due
+    delay: Option<Delay>,
+    /// the FIN once the server received everything, and until when late status updates are
+    /// answered with it
+    fin: Option<(Vec<u8>, Delay)>,
+}
+
+impl<C, S, R> StreamUpload<C, S, R> {
+    /// Handles the datagram of `len` bytes the server sent.
+    fn received(&mut self, len: usize) {
+        if let Some((ref fin, ref mut linger)) = self.fin {
+            // the server didn't get the FIN yet
+            self.outgoing = Some(fin.clone());
+            linger.reset(clock::now() + LINGER);
+            return;
+        }
+        if stream_status_update(&mut self.window, &mut self.progress, &mut self.pacer, &self.recv_buf[..len]) {
+            let fin = Chunk::new_stream(Vec::with_capacity(MTU), extension::FIN, 0).into_vec();
+            self.outgoing = Some(fin.clone());
+            self.fin = Some((fin, Delay::new(clock::now() + LINGER)));
+        }
+    }
+
+    /// Waits until `at`.
+    fn sleep(&mut self, at: Instant) -> Poll<(), Error> {
+        match self.delay {
+            Some(ref mut delay) => delay.reset(at),
+            None => self.delay = Some(Delay::new(at)),
+        }
+        self.delay.as_mut().unwrap().poll().map_err(timer_error)
+    }
+}
+
+impl<C, S, R> Future for StreamUpload<C, S, R>
+    where C: Stream<Item = Vec<u8>, Error = Error>, S: Transport, R: Transport {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        loop {
+            while let Async::Ready(len) = self.socket2.poll_recv(&mut self.recv_buf)? {
+                self.received(len);
+            }
+            if let Some(datagram) = self.outgoing.take() {
+                if self.socket.poll_send(&datagram)?.is_not_ready() {
+                    self.outgoing = Some(datagram);
+                    return Ok(Async::NotReady);
+                }
+                self.send_buf = datagram;
+                continue;
+            }
+            if let Some((_, ref mut linger)) = self.fin {
+                // the server is still sending status updates for the last chunks, which a closed
+                // socket refuses, aborting the connection before the FIN is read
+                return linger.poll().map_err(timer_error);
+            }
+
+            while self.window.has_room() && !self.input_done {
+                match self.input.poll()? {
+                    Async::Ready(Some(payload)) => self.window.push(payload),
+                    Async::Ready(None) => {
+                        self.window.finish();
+                        self.input_done = true;
+                    }
+                    Async::NotReady => break,
+                }
+            }
+            self.progress.set_total(self.window.read());
+
+            let now = clock::now();
+            if let Some(wait) = self.pacer.wait(now) {
+                try_ready!(self.sleep(now + wait));
+                continue;
+            }
+            let buf = mem::replace(&mut self.send_buf, Vec::new());
+            match self.window.next_message(buf) {
+                Ok(chunk) => {
+                    self.pacer.sent(now, chunk.buf.len());
+                    self.last_send = now;
+                    self.outgoing = Some(chunk.into_vec());
+                }
+                Err(buf) => {
+                    self.send_buf = buf;
+                    // nothing to send, wait for a status update or more input
+                    if now.duration_since(self.last_send) >= KEEPALIVE {
+                        self.last_send = now;
+                        if let Some(chunk) = self.window.keepalive(Vec::with_capacity(MTU)) {
+                            self.outgoing = Some(chunk.into_vec());
+                            continue;
+                        }
+                    }
+                    let keepalive = self.last_send + KEEPALIVE;
+                    try_ready!(self.sleep(keepalive));
+                }
+            }
+        }
+    }
+}
+
+fn timer_error(e: ::tokio::timer::Error) -> Error {
+    Error::new(io::ErrorKind::Other, e)
+}
+
+/// Parses a status update of a streaming upload, returning `true` if the server received everything.
+fn stream_status_update(window: &mut SendWindow, progress: &mut Progress, pacer: &mut Pacer, update: &[u8]) -> bool {
+    let update = match ServerMessage::decode(update) {
+        Ok(ServerMessage::StatusUpdate(update)) => update,
+        Ok(ServerMessage::RateHint(rate)) => {
+            pacer.set_rate(rate);
+            return false;
+        }
+        message => {
+            warn!("Ignoring unexpected message: {:?}", message);
+            return false;
+        }
+    };
+    match window.status_update(update.runlengths) {
+        Ok(done) => {
+            progress.update(window.acknowledged());
+            done
+        }
+        Err(e) => {
+            warn!("Ignoring malformed status update: {}", e);
+            false
+        }
+    }
+}
+
+/// Collects the metadata the server restores once the upload is complete.
+fn file_metadata(file: &StdFile, owner: bool) -> Result<Metadata, Error> {
+    let metadata = file.metadata()?;
+    Ok(Metadata {
+        mode: metadata.mode(),
+        mtime: metadata.mtime(),
+        mtime_nanos: metadata.mtime_nsec() as u32,
+        owner: if owner { Some((metadata.uid(), metadata.gid())) } else { None },
+    })
+}
+
+/// Compresses a few chunks spread over the file to decide whether compression pays off.
+fn compression_pays_off(file: &mut StdFile, chunk_info: &ChunkInfo) -> Result<bool, Error> {
+    let samples = cmp::min(COMPRESSION_SAMPLES, chunk_info.num_chunks);
+    let mut compress = Compress::new(flate2::Compression::fast(), false);
+    let mut buf = Vec::with_capacity(MTU);
+    let (mut raw, mut compressed) = (0, 0);
+    for i in 0..samples {
+        let index = i * chunk_info.num_chunks / samples;
+        let mut chunk = Chunk::new(Vec::with_capacity(MTU), index, chunk_info.index_field_size,
+                                   chunk_info.chunk_len(index) as usize);
+        file.seek(SeekFrom::Start(index * chunk_info.chunk_size))?;
+        file.read_exact(chunk.as_mut())?;
+        raw += chunk.as_ref().len();
+        buf = match chunk.compress(&mut compress, buf) {
+            Ok(c) => {
+                compressed += c.as_ref().len();
+                c.into_vec()
+            }
+            Err(buf) => {
+                compressed += chunk.as_ref().len();
+                buf
+            }
+        };
+    }
+    // compression costs CPU on both ends, only use it if it saves at least 10%
+    Ok(compressed * 10 < raw * 9)
+}
+
+/// Compression state of a single upload.
+struct Compression {
+    /// the server accepted compression
+    enabled: bool,
+    compress: Compress,
+    /// spare buffer to compress chunks into
+    buf: Vec<u8>,
+}
+
+/// Compresses the chunk if the server accepted compression and it pays off for this chunk.
+fn compress_chunk(compression: &RefCell<Compression>, chunk: Chunk) -> Vec<u8> {
+    let mut compression = compression.borrow_mut();
+    if !compression.enabled {
+        return chunk.into_vec();
+    }
+    let buf = mem::replace(&mut compression.buf, Vec::new());
+    let compression = &mut *compression;
+    match chunk.compress(&mut compression.compress, buf) {
+        Ok(compressed) => {
+            compression.buf = chunk.into_vec();
+            compressed.into_vec()
+        }
+        Err(buf) => {
+            compression.buf = buf;
+            chunk.into_vec()
+        }
+    }
+}
+
+/// Parity state of a single upload.
+struct Fec {
+    /// the server accepted parity
+    enabled: bool,
+    encoder: ParityEncoder,
+    estimator: LossEstimator,
+    /// the estimator's times are relative to the login
+    start: Instant,
+    /// parity message to send before the next chunk
+    pending: Option<Chunk>,
+}
+
+/// All-zero chunks of a single upload.
+struct Sparse {
+    /// the server accepted zero runs
+    enabled: bool,
+    zero_runs: ZeroRuns,
+}
+
+/// Chunks of a single upload found in the server's old copy.
+struct Delta {
+    /// the server accepted delta uploads
+    enabled: bool,
+    path: PathBuf,
+    signatures: SignatureSet,
+    /// `copies` were searched with all signatures received so far
+    searched: bool,
+    copies: CopyRanges,
+}
+
+/// Searches the file for the blocks of the old copy if new signatures arrived.
+///
+/// Signatures are sent right before the first status update, but may arrive after it.
+fn search_copies(chunk_info: &ChunkInfo, delta: &RefCell<Delta>) {
+    let mut delta = delta.borrow_mut();
+    if !delta.enabled || delta.searched {
+        return;
+    }
+    delta.searched = true;
+    let copies = StdFile::open(&delta.path)
+        .and_then(|file| CopyRanges::search(BufReader::with_capacity(1 << 20, file), chunk_info, &delta.signatures));
+    match copies {
+        Ok(copies) => {
+            debug!("{} of {} chunks are in the old copy", copies.chunks(), chunk_info.num_chunks);
+            delta.copies = copies;
+        }
+        Err(e) => warn!("Can't search for chunks of the old copy: {}", e),
+    }
+}
+
+/// Parses a status update, updating the missing chunks and the upload progress.
+///
+/// Returns `true` if the server received all chunks.
+fn status_update(chunk_info: &ChunkInfo, missing: &RefCell<MissingRanges>, compression: &RefCell<Compression>,
+                 fec: &RefCell<Fec>, sparse: &RefCell<Sparse>, delta: &RefCell<Delta>, progress: &RefCell<Progress>,
+                 pacer: &RefCell<Pacer>, update: &[u8]) -> bool {
+    let update = match ServerMessage::decode(update) {
+        Ok(ServerMessage::StatusUpdate(update)) => update,
+        Ok(ServerMessage::Signatures(signatures)) => {
+            {
+                let mut delta = delta.borrow_mut();
+                delta.signatures.add(&signatures);
+                delta.searched = false;
+            }
+            // the last ones are paced after the first status update, the next may be far off
+            if signatures.first + signatures.blocks.len() as u64 == signatures.num_blocks {
+                search_copies(chunk_info, delta);
+            }
+            return false;
+        }
+        Ok(ServerMessage::RateHint(rate)) => {
+            pacer.borrow_mut().set_rate(rate);
+            return false;
+        }
+        Ok(message) => {
+            warn!("Ignoring unexpected message: {:?}", message);
+            return false;
+        }
+        Err(e) => {
+            warn!("Ignoring malformed message: {}", e);
+            return false;
+        }
+    };
+    compression.borrow_mut().enabled = update.features & features::COMPRESSION != 0;
+    sparse.borrow_mut().enabled = update.features & features::ZERO_RUNS != 0;
+    delta.borrow_mut().enabled = update.features & features::DELTA != 0;
+    search_copies(chunk_info, delta);
+    let mut missing = missing.borrow_mut();
+    let done = match missing.parse_status_update(update.runlengths) {
+        Ok(done) => done,
+        Err(e) => {
+            warn!("Ignoring malformed status update: {}", e);
+            return false;
+        }
+    };
+    {
+        let mut fec = fec.borrow_mut();
+        fec.enabled = update.features & features::PARITY != 0;
+        let now = clock::now() - fec.start;
+        fec.estimator.status_update(now, update.rebuilt, |index| missing.is_missing(index));
+        let group_size = if fec.enabled { fec::group_size(fec.estimator.loss()) } else { 0 };
+        if group_size != fec.encoder.group_size() {
+            debug!("Estimated loss {:.3} at rtt {:?}, parity group size {}",
+                   fec.estimator.loss(), fec.estimator.rtt(), group_size);
+            fec.encoder.set_group_size(group_size);
+        }
+    }
+    progress.borrow_mut().update(missing.received() * chunk_info.chunk_size);
+    done
+}
+
+/// Delays sending the next datagram until the server's rate hint allows it.
+fn paced<'a, T: 'a>(pacer: &RefCell<Pacer>, send: Result<Box<Future<Item = T, Error = Error> + 'a>, T>)
+                    -> Result<Box<Future<Item = T, Error = Error> + 'a>, T> {
+    let send = send?;
+    let now = clock::now();
+    let mut pacer = pacer.borrow_mut();
+    let wait = pacer.wait(now);
+    // datagrams are accounted as full, their length is only known once sent
+    pacer.sent(now, MTU);
+    Ok(match wait {
+        Some(wait) => Box::new(Delay::new(now + wait)
+            .map_err(timer_error)
+            .and_then(move |()| send)),
+        None => send,
+    })
+}
+
 struct Client<'a> {
     socket: UdpSocket,
     server: SocketAddr,
@@ -151,23 +831,51 @@ struct Client<'a> {
 }
 
 
-fn do_chunk(chunk_info: &ChunkInfo,
-            missing_chunks: &RefCell<MissingRanges>,
-            mut file: PollEvented<File<StdFile>>,
-            socket: UdpSocket,
-            send_buf: Vec<u8>,
-            server: SocketAddr)
-            -> Result<Box<Future<Item = (PollEvented<File<StdFile>>, UdpSocket, Vec<u8>), Error = Error>>, (PollEvented<File<StdFile>>, UdpSocket, Vec<u8>)> {
+fn do_chunk<'a, S: Transport + 'a>(chunk_info: &'a ChunkInfo,
+                missing_chunks: &RefCell<MissingRanges>,
+                compression: &'a RefCell<Compression>,
+                fec: &'a RefCell<Fec>,
+                sparse: &RefCell<Sparse>,
+                delta: &RefCell<Delta>,
+                mut file: PollEvented<File<StdFile>>,
+                socket: S,
+                send_buf: Vec<u8>)
+                -> Result<Box<Future<Item = (PollEvented<File<StdFile>>, S, Vec<u8>), Error = Error> + 'a>, (PollEvented<File<StdFile>>, S, Vec<u8>)> {
+    // parity of the previous group goes first
+    let parity = fec.borrow_mut().pending.take();
+    if let Some(parity) = parity {
+        return Ok(Box::new(send_dgram(socket, parity.into_vec()).map(move |(socket, send_buf)| {
+            (file, socket, send_buf)
+        })));
+    }
+
     let chunk_cursor = match missing_chunks.borrow_mut().next_chunk() {
         Some(x) => x,
         None => return Err((file, socket, send_buf)),
     };
 
-    let payload = if chunk_cursor == chunk_info.num_chunks - 1 {
-        chunk_info.last_chunk_size
-    } else {
-        chunk_info.chunk_size
-    };
+    // declare a run of zeros instead of reading and sending it
+    let sparse = sparse.borrow();
+    if let (true, Some(end)) = (sparse.enabled, sparse.zero_runs.run_end(chunk_cursor)) {
+        missing_chunks.borrow_mut().skip_to(end);
+        let run = ZeroRun { first: chunk_cursor, count: end - chunk_cursor }.encode(chunk_info, send_buf);
+        return Ok(Box::new(send_dgram(socket, run.into_vec()).map(move |(socket, send_buf)| {
+            (file, socket, send_buf)
+        })));
+    }
+
+
+    // let the server copy chunks from its old copy
+    let delta = delta.borrow();
+    if let (true, Some(range)) = (delta.enabled, delta.copies.range_at(chunk_cursor, chunk_info.chunk_size)) {
+        missing_chunks.borrow_mut().skip_to(range.first + range.count);
+        let range = range.encode(chunk_info, send_buf);
+        return Ok(Box::new(send_dgram(socket, range.into_vec()).map(move |(socket, send_buf)| {
+            (file, socket, send_buf)
+        })));
+    }
+
+    let payload = chunk_info.chunk_len(chunk_cursor);
 
     let chunk = Chunk::new(send_buf, chunk_cursor, chunk_info.index_field_size, payload as usize);
     file.get_mut().seek(SeekFrom::Start(chunk_cursor as u64 * chunk_info.chunk_size)).unwrap();
@@ -175,9 +883,16 @@ fn do_chunk(chunk_info: &ChunkInfo,
     Ok(Box::new(
         io::read_exact(file, chunk)
             .and_then(move |(file, chunk)| {
-                let send_buf = chunk.into_vec();
-                let server = server;
-                socket.send_dgram(send_buf, &server).map(move |(socket, send_buf)| {
+                {
+                    let mut fec = fec.borrow_mut();
+                    if fec.enabled {
+                        fec.pending = fec.encoder.add(chunk_info, &chunk);
+                    }
+                    let now = clock::now() - fec.start;
+                    fec.estimator.sent(now, chunk.index);
+                }
+                let send_buf = compress_chunk(compression, chunk);
+                send_dgram(socket, send_buf).map(move |(socket, send_buf)| {
                     (file, socket, send_buf)
                 })
             })
@@ -1,28 +1,295 @@
-use std::io::{self, Cursor, Write, ErrorKind};
+use std::io::{self, Cursor, Write};
 use std::cmp;
-use std::str::{self, Utf8Error};
-use varmint::{self, ReadVarInt, WriteVarInt};
+use std::fmt;
+use std::error::Error;
+use std::str;
+use varmint::{self, WriteVarInt};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use itertools::Itertools;
-use bitte_ein_bit::BitMap;
+use bitte_ein_bit::{rle, BitSet};
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
 
 pub const MTU: usize = 1460;
 
+/// Optional protocol features a client can request in its `UploadRequest`.
+///
+/// The server echoes the features it accepted in every status update.
+pub mod features {
+    /// Chunks may be sent deflate-compressed, see `Chunk::compress`.
+    pub const COMPRESSION: u64 = 1 << 0;
+    /// The client may send parity over groups of chunks, see `Parity`.
+    pub const PARITY: u64 = 1 << 1;
+    /// The client may declare runs of all-zero chunks, see `ZeroRun`.
+    pub const ZERO_RUNS: u64 = 1 << 2;
+    /// The server sends `Signatures` of an older copy of the file, the client copies
+    /// matching chunks from it with `CopyRange`.
+    pub const DELTA: u64 = 1 << 3;
+    /// The upload request carries the file's `Metadata`, applied once the upload is complete.
+    pub const METADATA: u64 = 1 << 4;
+    /// The upload has no declared length, chunk ids are varints, see `Chunk::new_stream`.
+    ///
+    /// The client sends the length in an `EndOfStream` once its input ended.
+    /// No other feature but `RESUME` is accepted along with it.
+    pub const STREAM: u64 = 1 << 5;
+    /// Along with `STREAM`, the server keeps the chunks it has in a row from the start of a
+    /// previous upload of the file, reported as received in the first status update along with
+    /// their digest, see `StatusUpdate::prefix`. The client skips as much of its input and
+    /// appends the rest if the digest matches.
+    pub const RESUME: u64 = 1 << 6;
+
+    /// Features understood by this implementation.
+    pub const SUPPORTED: u64 = COMPRESSION | PARITY | ZERO_RUNS | DELTA | METADATA | STREAM | RESUME;
+}
+
+/// Extension messages, sent as chunks with index `num_chunks + id`.
+///
+/// In a streaming upload they are sent with their id, see `stream_id`.
+pub mod extension {
+    /// The client received a complete status update and is done.
+    pub const FIN: u64 = 0;
+    /// Parity over a group of chunks, see `Parity`.
+    pub const PARITY: u64 = 1;
+    /// A run of all-zero chunks, see `ZeroRun`.
+    pub const ZERO_RUN: u64 = 2;
+    /// Chunks to copy from the old copy of the file, see `CopyRange`.
+    pub const COPY_RANGE: u64 = 3;
+    /// The input of a streaming upload ended, see `EndOfStream`.
+    pub const END_OF_STREAM: u64 = 4;
+
+    /// Number of ids reserved for extension messages.
+    pub const RESERVED: u64 = 16;
+}
+
+/// Error returned by the decoders for malformed packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The packet ended in the middle of a field.
+    Truncated,
+    /// A varint doesn't fit into 64 bits.
+    InvalidVarint,
+    /// A string isn't valid UTF-8.
+    InvalidUtf8,
+    /// Runlengths add up to more than 64 bits of chunk indices.
+    Overflow,
+    /// A compressed chunk doesn't decompress to its original length.
+    InvalidCompression,
+    /// An extension message is malformed.
+    InvalidExtension,
+    /// Block signatures don't fit the old file they describe.
+    InvalidSignatures,
+    /// File metadata is out of range.
+    InvalidMetadata,
+    UnknownCommand(u8),
+    UnknownMessage(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Truncated => write!(f, "packet truncated"),
+            DecodeError::InvalidVarint => write!(f, "varint too long"),
+            DecodeError::InvalidUtf8 => write!(f, "invalid utf-8"),
+            DecodeError::Overflow => write!(f, "runlengths overflow"),
+            DecodeError::InvalidCompression => write!(f, "invalid compressed chunk"),
+            DecodeError::InvalidExtension => write!(f, "invalid extension message"),
+            DecodeError::InvalidSignatures => write!(f, "invalid block signatures"),
+            DecodeError::InvalidMetadata => write!(f, "invalid file metadata"),
+            DecodeError::UnknownCommand(c) => write!(f, "unknown command {}", c),
+            DecodeError::UnknownMessage(m) => write!(f, "unknown server message {}", m),
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn description(&self) -> &str {
+        "malformed packet"
+    }
+}
+
+impl From<rle::DecodeError> for DecodeError {
+    fn from(e: rle::DecodeError) -> DecodeError {
+        match e {
+            rle::DecodeError::Truncated => DecodeError::Truncated,
+            rle::DecodeError::InvalidVarint => DecodeError::InvalidVarint,
+            rle::DecodeError::Overflow => DecodeError::Overflow,
+        }
+    }
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
 #[derive(Debug)]
 pub struct Login<'a> {
     pub client_token: &'a [u8],
+    /// `Cookie` issued by the server for the client's address, empty to request one
+    pub cookie: &'a [u8],
     pub command: Command<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Command<'a> {
     UploadRequest(UploadRequest<'a>),
+    Delete(Delete<'a>),
+    Rename(Rename<'a>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct UploadRequest<'a> {
     pub path: &'a str,
     pub length: u64,
+    /// requested `features`, omitted by older clients
+    pub features: u64,
+    /// sent if `features::METADATA` is requested
+    pub metadata: Option<Metadata>,
+}
+
+/// Unix metadata of an uploaded file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// permission bits
+    pub mode: u32,
+    /// modification time in seconds since the epoch
+    pub mtime: i64,
+    pub mtime_nanos: u32,
+    /// uid and gid, only restored if the server may change ownership
+    pub owner: Option<(u32, u32)>,
+}
+
+/// Removes an uploaded file, answered with an `Ack`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delete<'a> {
+    pub path: &'a str,
+}
+
+/// Moves an uploaded file to another path, replacing a file there, answered with an `Ack`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rename<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+}
+
+/// Message from the server to the client.
+#[derive(Debug)]
+pub enum ServerMessage<'a> {
+    StatusUpdate(StatusUpdate<'a>),
+    Signatures(Signatures),
+    Ack(Ack),
+    /// answer to a `Login` without a valid cookie, see `write_cookie`
+    Cookie(&'a [u8]),
+    /// bytes per second the client should send at most, see `write_rate_hint`
+    RateHint(u64),
+}
+
+/// Result of a `Delete` or `Rename`.
+///
+/// Both are idempotent, thus a client may repeat them until it receives an ack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ack {
+    Done,
+    /// there's no file to rename
+    NotFound,
+    /// the path is invalid or the file system failed, codes unknown to the client decode to this
+    Failed,
+    /// the file is being uploaded
+    Busy,
+}
+
+impl Ack {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.push(2);
+        buf.push(match *self {
+            Ack::Done => 0,
+            Ack::NotFound => 1,
+            Ack::Failed => 2,
+            Ack::Busy => 3,
+        });
+    }
+}
+
+#[derive(Debug)]
+pub struct StatusUpdate<'a> {
+    /// `features` accepted by the server
+    pub features: u64,
+    /// chunks the server rebuilt from parity in this connection
+    pub rebuilt: u64,
+    /// SHA-256 of the chunks a resumed stream kept, empty unless `RESUME` was accepted
+    pub prefix: &'a [u8],
+    /// runlength encoded bitmap of received chunks, see `bitte_ein_bit::rle`
+    pub runlengths: &'a [u8],
+}
+
+/// Length of `StatusUpdate::prefix`.
+pub const PREFIX_DIGEST_LEN: usize = 32;
+
+/// Length of the strong hash in a `BlockSignature`.
+pub const STRONG_HASH_LEN: usize = 8;
+
+/// Signature of a block of the server's old copy of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSignature {
+    /// rolling checksum
+    pub weak: u32,
+    /// truncated SHA-256
+    pub strong: [u8; STRONG_HASH_LEN],
+}
+
+/// Signatures of a range of blocks of the server's old copy of a file.
+///
+/// Blocks are `block_size` bytes long, a shorter block at the end isn't signed.
+#[derive(Debug, PartialEq)]
+pub struct Signatures {
+    pub block_size: u64,
+    /// blocks of the old copy
+    pub num_blocks: u64,
+    /// index of the first block in `blocks`
+    pub first: u64,
+    pub blocks: Vec<BlockSignature>,
+}
+
+/// Number of block signatures fitting into a single message.
+pub const SIGNATURES_PER_MESSAGE: usize = (MTU - 1 - 3 * 10) / (4 + STRONG_HASH_LEN);
+
+impl Signatures {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        debug_assert!(self.blocks.len() <= SIGNATURES_PER_MESSAGE);
+        buf.clear();
+        buf.write_u8(1).unwrap();
+        buf.write_u64_varint(self.block_size).unwrap();
+        buf.write_u64_varint(self.num_blocks).unwrap();
+        buf.write_u64_varint(self.first).unwrap();
+        for block in &self.blocks {
+            buf.write_u32::<LE>(block.weak).unwrap();
+            buf.extend_from_slice(&block.strong);
+        }
+    }
+
+    fn decode<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> Result<Signatures, DecodeError> {
+        let block_size = read_varint(cursor)?;
+        let num_blocks = read_varint(cursor)?;
+        let first = read_varint(cursor)?;
+        let rest = &cursor.get_ref().as_ref()[cursor.position() as usize..];
+        let entry = 4 + STRONG_HASH_LEN;
+        if rest.len() % entry != 0 {
+            return Err(DecodeError::Truncated);
+        }
+        let count = (rest.len() / entry) as u64;
+        if block_size == 0 || first.checked_add(count).map_or(true, |end| end > num_blocks) {
+            return Err(DecodeError::InvalidSignatures);
+        }
+        let blocks = rest.chunks(entry).map(|entry| {
+            let mut strong = [0; STRONG_HASH_LEN];
+            strong.copy_from_slice(&entry[4..]);
+            BlockSignature {
+                weak: (&entry[..4]).read_u32::<LE>().unwrap(),
+                strong,
+            }
+        }).collect();
+        Ok(Signatures { block_size, num_blocks, first, blocks })
+    }
 }
 
 pub struct Chunk {
@@ -33,28 +300,72 @@ pub struct Chunk {
     pub buf: Vec<u8>,
 }
 
-fn read_bytes<'a>(cursor: &mut Cursor<&'a [u8]>) -> &'a [u8] {
-    let size = cursor.read_usize_varint().unwrap();
-    let pos = cursor.position() as usize;
-    cursor.set_position(pos as u64 + size as u64);
-    &cursor.get_ref()[pos..][..size]
+fn read_u8(cursor: &mut Cursor<&[u8]>) -> Result<u8, DecodeError> {
+    cursor.read_u8().map_err(|_| DecodeError::Truncated)
+}
+
+/// Reads a varint, rejecting ones which don't fit into 64 bits.
+fn read_varint<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let pos = cursor.position() as usize;
+        let byte = match cursor.get_ref().as_ref().get(pos) {
+            Some(&byte) => byte,
+            None => return Err(DecodeError::Truncated),
+        };
+        cursor.set_position(pos as u64 + 1);
+        // the tenth byte may only contribute the highest bit
+        if shift == 63 && byte > 1 {
+            return Err(DecodeError::InvalidVarint);
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn read_bytes<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], DecodeError> {
+    let size = read_varint(cursor)?;
+    let pos = cursor.position();
+    let end = pos.checked_add(size).ok_or(DecodeError::Truncated)?;
+    if end > cursor.get_ref().len() as u64 {
+        return Err(DecodeError::Truncated);
+    }
+    cursor.set_position(end);
+    Ok(&cursor.get_ref()[pos as usize..end as usize])
+}
+
+fn read_str<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<&'a str, DecodeError> {
+    str::from_utf8(read_bytes(cursor)?).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+fn write_bytes<W: Write>(mut dst: W, bytes: &[u8]) -> Result<usize, io::Error> {
+    dst.write_usize_varint(bytes.len())?;
+    dst.write_all(bytes)?;
+    Ok(varmint::len_usize_varint(bytes.len()) + bytes.len())
 }
 
 impl<'a> Login<'a> {
     pub fn encode<W: Write>(&self, mut dst: W) {
         dst.write_usize_varint(self.client_token.len()).unwrap();
         dst.write_all(self.client_token).unwrap();
+        write_bytes(&mut dst, self.cookie).unwrap();
         self.command.encode(dst).unwrap();
     }
 
-    pub fn decode(src: &'a [u8]) -> Result<Login<'a>, io::Error> {
+    pub fn decode(src: &'a [u8]) -> Result<Login<'a>, DecodeError> {
         let mut cursor = Cursor::new(src);
 
-        let client_token = read_bytes(&mut cursor);
+        let client_token = read_bytes(&mut cursor)?;
+        let cookie = read_bytes(&mut cursor)?;
         let command = Command::decode(&mut cursor)?;
 
         Ok(Login {
             client_token,
+            cookie,
             command,
         })
     }
@@ -67,13 +378,23 @@ impl<'a> Command<'a> {
                 dst.write_u8(0).unwrap();
                 Ok(req.encode(dst)? + 1)
             }
+            &Command::Delete(ref delete) => {
+                dst.write_u8(1)?;
+                Ok(write_bytes(&mut dst, delete.path.as_bytes())? + 1)
+            }
+            &Command::Rename(ref rename) => {
+                dst.write_u8(2)?;
+                Ok(write_bytes(&mut dst, rename.from.as_bytes())? + write_bytes(&mut dst, rename.to.as_bytes())? + 1)
+            }
         }
     }
 
-    pub fn decode(src: &mut Cursor<&'a [u8]>) -> Result<Self, io::Error> {
-        Ok(match src.read_u8()? {
+    pub fn decode(src: &mut Cursor<&'a [u8]>) -> Result<Self, DecodeError> {
+        Ok(match read_u8(src)? {
             0 => Command::UploadRequest(UploadRequest::decode(src)?),
-            c => panic!("Unknown Command {}", c)
+            1 => Command::Delete(Delete { path: read_str(src)? }),
+            2 => Command::Rename(Rename { from: read_str(src)?, to: read_str(src)? }),
+            c => return Err(DecodeError::UnknownCommand(c)),
         })
     }
 }
@@ -83,21 +404,328 @@ impl<'a> UploadRequest<'a> {
         dst.write_usize_varint(self.path.len())?;
         dst.write_all(self.path.as_bytes())?;
         dst.write_u64_varint(self.length)?;
-        Ok(varmint::len_usize_varint(self.path.len()) + self.path.as_bytes().len()
-            + varmint::len_u64_varint(self.length))
+        let metadata = match (self.features & features::METADATA != 0, self.metadata) {
+            (true, None) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "metadata requested but missing")),
+            (requested, metadata) => metadata.filter(|_| requested),
+        };
+        dst.write_u64_varint(self.features)?;
+        let mut len = varmint::len_usize_varint(self.path.len()) + self.path.as_bytes().len()
+            + varmint::len_u64_varint(self.length) + varmint::len_u64_varint(self.features);
+        if let Some(ref metadata) = metadata {
+            len += metadata.encode(dst)?;
+        }
+        Ok(len)
     }
 
-    pub fn decode(src: &mut Cursor<&'a [u8]>) -> Result<UploadRequest<'a>, io::Error> {
-        let path = str::from_utf8(read_bytes(src))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        let length = src.read_u64_varint()?;
+    pub fn decode(src: &mut Cursor<&'a [u8]>) -> Result<UploadRequest<'a>, DecodeError> {
+        let path = read_str(src)?;
+        let length = read_varint(src)?;
+        let features = if src.position() < src.get_ref().len() as u64 {
+            read_varint(src)?
+        } else {
+            0
+        };
+        let metadata = if features & features::METADATA != 0 {
+            Some(Metadata::decode(src)?)
+        } else {
+            None
+        };
         Ok(UploadRequest {
             path,
             length,
+            features,
+            metadata,
+        })
+    }
+}
+
+impl Metadata {
+    fn encode<W: Write>(&self, mut dst: W) -> Result<usize, io::Error> {
+        // zigzag encoding keeps times before the epoch short
+        let mtime = ((self.mtime << 1) ^ (self.mtime >> 63)) as u64;
+        let (uid, gid) = self.owner.unwrap_or((0, 0));
+        dst.write_u64_varint(self.mode as u64)?;
+        dst.write_u64_varint(mtime)?;
+        dst.write_u64_varint(self.mtime_nanos as u64)?;
+        dst.write_u8(self.owner.is_some() as u8)?;
+        let mut len = varmint::len_u64_varint(self.mode as u64) + varmint::len_u64_varint(mtime)
+            + varmint::len_u64_varint(self.mtime_nanos as u64) + 1;
+        if self.owner.is_some() {
+            dst.write_u64_varint(uid as u64)?;
+            dst.write_u64_varint(gid as u64)?;
+            len += varmint::len_u64_varint(uid as u64) + varmint::len_u64_varint(gid as u64);
+        }
+        Ok(len)
+    }
+
+    fn decode(src: &mut Cursor<&[u8]>) -> Result<Metadata, DecodeError> {
+        fn read_u32(src: &mut Cursor<&[u8]>) -> Result<u32, DecodeError> {
+            let value = read_varint(src)?;
+            if value > u32::max_value() as u64 {
+                return Err(DecodeError::InvalidMetadata);
+            }
+            Ok(value as u32)
+        }
+        let mode = read_u32(src)?;
+        let mtime = read_varint(src)?;
+        let mtime = (mtime >> 1) as i64 ^ -((mtime & 1) as i64);
+        let mtime_nanos = read_u32(src)?;
+        if mtime_nanos >= 1_000_000_000 {
+            return Err(DecodeError::InvalidMetadata);
+        }
+        let owner = match read_u8(src)? {
+            0 => None,
+            1 => Some((read_u32(src)?, read_u32(src)?)),
+            _ => return Err(DecodeError::InvalidMetadata),
+        };
+        Ok(Metadata { mode, mtime, mtime_nanos, owner })
+    }
+}
+
+impl<'a> ServerMessage<'a> {
+    pub fn decode(src: &'a [u8]) -> Result<ServerMessage<'a>, DecodeError> {
+        let mut cursor = Cursor::new(src);
+        Ok(match read_u8(&mut cursor)? {
+            0 => {
+                let features = read_varint(&mut cursor)?;
+                let rebuilt = if features & features::PARITY != 0 { read_varint(&mut cursor)? } else { 0 };
+                let start = cursor.position() as usize;
+                let prefix_len = if features & features::RESUME != 0 { PREFIX_DIGEST_LEN } else { 0 };
+                let prefix = src.get(start..start + prefix_len).ok_or(DecodeError::Truncated)?;
+                cursor.set_position((start + prefix_len) as u64);
+                ServerMessage::StatusUpdate(StatusUpdate {
+                    features,
+                    rebuilt,
+                    prefix,
+                    runlengths: &src[cursor.position() as usize..],
+                })
+            }
+            1 => ServerMessage::Signatures(Signatures::decode(&mut cursor)?),
+            2 => ServerMessage::Ack(match read_u8(&mut cursor)? {
+                0 => Ack::Done,
+                1 => Ack::NotFound,
+                3 => Ack::Busy,
+                _ => Ack::Failed,
+            }),
+            3 => ServerMessage::Cookie(read_bytes(&mut cursor)?),
+            4 => ServerMessage::RateHint(read_varint(&mut cursor)?),
+            m => return Err(DecodeError::UnknownMessage(m)),
         })
     }
 }
 
+/// Writes a status update into `buf`, truncating the runlengths to fit.
+///
+/// `features` are the accepted ones, `None` if the client requested none and expects the bare
+/// runlengths like before features existed. `rebuilt` is only sent along with `PARITY`, the
+/// `prefix` digest only along with `RESUME`.
+/// Returns the number of bytes written.
+pub fn write_status_update<S: BitSet>(features: Option<u64>, rebuilt: u64, prefix: &[u8], bitmap: &S, buf: &mut [u8])
+    -> io::Result<usize> {
+    let header = match features {
+        Some(features) => write_status_header(features, rebuilt, prefix, buf)?,
+        None => 0,
+    };
+    Ok(header + rle::encode(bitmap, &mut buf[header..]))
+}
+
+/// Writes the header in front of the runlengths of a status update, see `write_status_update`.
+///
+/// Returns the number of bytes written.
+pub fn write_status_header(features: u64, rebuilt: u64, prefix: &[u8], buf: &mut [u8]) -> io::Result<usize> {
+    let mut cursor = Cursor::new(buf);
+    cursor.write_u8(0)?;
+    cursor.write_u64_varint(features)?;
+    if features & features::PARITY != 0 {
+        cursor.write_u64_varint(rebuilt)?;
+    }
+    if features & features::RESUME != 0 {
+        assert_eq!(prefix.len(), PREFIX_DIGEST_LEN);
+        cursor.write_all(prefix)?;
+    }
+    Ok(cursor.position() as usize)
+}
+
+/// Length clients pad logins without a cookie to with zeros after the command.
+///
+/// The server drops logins without a valid cookie which are shorter than the `Cookie` it would
+/// answer with, which is at most this long.
+pub const MIN_LOGIN_LEN: usize = 32;
+
+/// Writes a `Cookie` message.
+///
+/// The server sends it instead of creating any state for a login, it must not be larger
+/// than the login to not amplify spoofed ones.
+pub fn write_cookie(cookie: &[u8], buf: &mut Vec<u8>) {
+    buf.clear();
+    buf.push(3);
+    write_bytes(buf, cookie).unwrap();
+}
+
+/// Writes a `RateHint` message.
+///
+/// The server sends it before status updates if its bandwidth is limited, see `--bandwidth`.
+pub fn write_rate_hint(rate: u64, buf: &mut Vec<u8>) {
+    buf.clear();
+    buf.push(4);
+    buf.write_u64_varint(rate).unwrap();
+}
+
+/// XOR over the payloads of the chunks `first..first + count`.
+///
+/// Shorter payloads are padded with zeroes, thus `xor` is always `chunk_size` long.
+/// This is why `chunk_size` leaves room for the header of this message.
+#[derive(Debug)]
+pub struct Parity<'a> {
+    pub first: u64,
+    pub count: u64,
+    pub xor: &'a [u8],
+}
+
+/// Maximum number of chunks in a parity group.
+pub const MAX_PARITY_GROUP: u64 = 255;
+
+impl<'a> Parity<'a> {
+    /// Size of the header in front of `xor`.
+    pub fn header_len(index_field_size: u64) -> u64 {
+        index_field_size + 1
+    }
+
+    pub fn encode(&self, chunk_info: &ChunkInfo, buf: Vec<u8>) -> Chunk {
+        debug_assert!(0 < self.count && self.count <= MAX_PARITY_GROUP);
+        let header = Parity::header_len(chunk_info.index_field_size) as usize;
+        let mut chunk = Chunk::new(buf, chunk_info.num_chunks + extension::PARITY, chunk_info.index_field_size,
+                                   header + self.xor.len());
+        {
+            let payload = chunk.as_mut();
+            let mut first = [0u8; 8];
+            (&mut first[..]).write_u64::<LE>(self.first).unwrap();
+            payload[..header - 1].copy_from_slice(&first[..header - 1]);
+            payload[header - 1] = self.count as u8;
+            payload[header..].copy_from_slice(self.xor);
+        }
+        chunk
+    }
+
+    pub fn decode(chunk: &'a Chunk, chunk_info: &ChunkInfo) -> Result<Parity<'a>, DecodeError> {
+        let header = Parity::header_len(chunk_info.index_field_size) as usize;
+        let payload = chunk.as_ref();
+        if payload.len() != header + chunk_info.chunk_size as usize {
+            return Err(DecodeError::InvalidExtension);
+        }
+        let mut first = [0u8; 8];
+        first[..header - 1].copy_from_slice(&payload[..header - 1]);
+        let first = (&first[..]).read_u64::<LE>().unwrap();
+        let count = payload[header - 1] as u64;
+        if count == 0 || first.checked_add(count).map_or(true, |end| end > chunk_info.num_chunks) {
+            return Err(DecodeError::InvalidExtension);
+        }
+        Ok(Parity {
+            first,
+            count,
+            xor: &payload[header..],
+        })
+    }
+}
+
+/// A run of chunks containing only zero bytes.
+///
+/// The server marks them as received without writing anything, as the file is
+/// created with its full length and reads as zeros where nothing was written.
+#[derive(Debug, PartialEq)]
+pub struct ZeroRun {
+    pub first: u64,
+    pub count: u64,
+}
+
+impl ZeroRun {
+    pub fn encode(&self, chunk_info: &ChunkInfo, buf: Vec<u8>) -> Chunk {
+        let mut chunk = Chunk::new(buf, chunk_info.num_chunks + extension::ZERO_RUN, chunk_info.index_field_size, 0);
+        chunk.buf.write_u64_varint(self.first).unwrap();
+        chunk.buf.write_u64_varint(self.count).unwrap();
+        chunk
+    }
+
+    pub fn decode(chunk: &Chunk, chunk_info: &ChunkInfo) -> Result<ZeroRun, DecodeError> {
+        let mut cursor = Cursor::new(chunk.as_ref());
+        let first = read_varint(&mut cursor)?;
+        let count = read_varint(&mut cursor)?;
+        if cursor.position() != chunk.as_ref().len() as u64
+            || count == 0 || first.checked_add(count).map_or(true, |end| end > chunk_info.num_chunks) {
+            return Err(DecodeError::InvalidExtension);
+        }
+        Ok(ZeroRun { first, count })
+    }
+}
+
+/// A run of chunks to copy from the server's old copy of the file.
+///
+/// Chunk `first + i` is read from `old_offset + i * chunk_size`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CopyRange {
+    pub first: u64,
+    pub count: u64,
+    pub old_offset: u64,
+}
+
+/// Maximum number of chunks in a single `CopyRange`, so one message can't keep
+/// the server copying for long.
+pub const MAX_COPY_RANGE: u64 = 1024;
+
+impl CopyRange {
+    pub fn encode(&self, chunk_info: &ChunkInfo, buf: Vec<u8>) -> Chunk {
+        let mut chunk = Chunk::new(buf, chunk_info.num_chunks + extension::COPY_RANGE, chunk_info.index_field_size, 0);
+        chunk.buf.write_u64_varint(self.first).unwrap();
+        chunk.buf.write_u64_varint(self.count).unwrap();
+        chunk.buf.write_u64_varint(self.old_offset).unwrap();
+        chunk
+    }
+
+    pub fn decode(chunk: &Chunk, chunk_info: &ChunkInfo) -> Result<CopyRange, DecodeError> {
+        let mut cursor = Cursor::new(chunk.as_ref());
+        let first = read_varint(&mut cursor)?;
+        let count = read_varint(&mut cursor)?;
+        let old_offset = read_varint(&mut cursor)?;
+        if cursor.position() != chunk.as_ref().len() as u64
+            || count == 0 || count > MAX_COPY_RANGE
+            || first.checked_add(count).map_or(true, |end| end > chunk_info.num_chunks)
+            || count.checked_mul(chunk_info.chunk_size).and_then(|len| len.checked_add(old_offset)).is_none() {
+            return Err(DecodeError::InvalidExtension);
+        }
+        Ok(CopyRange { first, count, old_offset })
+    }
+}
+
+/// Final length of a streaming upload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EndOfStream {
+    pub length: u64,
+}
+
+impl EndOfStream {
+    pub fn encode(&self, buf: Vec<u8>) -> Chunk {
+        let mut chunk = Chunk::new_stream(buf, extension::END_OF_STREAM, 0);
+        chunk.buf.write_u64_varint(self.length).unwrap();
+        chunk
+    }
+
+    pub fn decode(chunk: &Chunk) -> Result<EndOfStream, DecodeError> {
+        let mut cursor = Cursor::new(chunk.as_ref());
+        let length = read_varint(&mut cursor)?;
+        if cursor.position() != chunk.as_ref().len() as u64 {
+            return Err(DecodeError::InvalidExtension);
+        }
+        Ok(EndOfStream { length })
+    }
+}
+
+/// XORs `src` into the front of `dst`.
+pub fn xor_into(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
 impl Chunk {
     pub fn new(mut buf: Vec<u8>, index: u64, index_field_size: u64, data_size: usize) -> Chunk {
         buf.clear();
@@ -113,21 +741,100 @@ impl Chunk {
         }
     }
 
-    pub fn decode(src: Vec<u8>, index_field_size: u64) -> Self {
+    pub fn decode(src: Vec<u8>, index_field_size: u64) -> Result<Self, DecodeError> {
+        debug_assert!(0 < index_field_size && index_field_size <= 8);
+        let size = index_field_size as usize;
+        if src.len() < size {
+            return Err(DecodeError::Truncated);
+        }
         let mut buf = [0u8; 8];
-        (&mut buf[..index_field_size as usize]).copy_from_slice(&src[..index_field_size as usize]);
+        (&mut buf[..size]).copy_from_slice(&src[..size]);
         let index = (&buf[..]).read_u64::<LE>().unwrap();
 
-        Chunk {
+        Ok(Chunk {
             index_field_size,
             index,
             buf: src,
+        })
+    }
+
+    /// Creates a message of a streaming upload, whose id is a varint.
+    pub fn new_stream(mut buf: Vec<u8>, id: u64, data_size: usize) -> Chunk {
+        buf.clear();
+        buf.write_u64_varint(id).unwrap();
+        let index_field_size = buf.len() as u64;
+        buf.resize(index_field_size as usize + data_size, 0);
+
+        Chunk {
+            index_field_size,
+            index: id,
+            buf,
         }
     }
 
+    /// Decodes a message of a streaming upload, its `index` is the id.
+    pub fn decode_stream(src: Vec<u8>) -> Result<Self, DecodeError> {
+        let (index, index_field_size) = {
+            let mut cursor = Cursor::new(&src[..]);
+            (read_varint(&mut cursor)?, cursor.position())
+        };
+
+        Ok(Chunk {
+            index_field_size,
+            index,
+            buf: src,
+        })
+    }
+
     pub fn into_vec(self) -> Vec<u8> {
         self.buf
     }
+
+    /// Returns whether the payload is compressed, i.e. shorter than the chunk.
+    pub fn is_compressed(&self, chunk_info: &ChunkInfo) -> bool {
+        (self.as_ref().len() as u64) < chunk_info.chunk_len(self.index)
+    }
+
+    /// Compresses the payload into `buf`.
+    ///
+    /// A compressed payload is the original length as varint followed by the raw deflate
+    /// stream, which is only used if it is shorter than the original payload.
+    /// Otherwise `buf` is returned.
+    pub fn compress(&self, compress: &mut Compress, buf: Vec<u8>) -> Result<Chunk, Vec<u8>> {
+        let len = self.as_ref().len();
+        let header = varmint::len_usize_varint(len);
+        if header + 1 >= len {
+            return Err(buf);
+        }
+        let mut chunk = Chunk::new(buf, self.index, self.index_field_size, len - 1);
+        (&mut chunk.as_mut()[..header]).write_usize_varint(len).unwrap();
+        compress.reset();
+        match compress.compress(self.as_ref(), &mut chunk.as_mut()[header..], FlushCompress::Finish) {
+            Ok(Status::StreamEnd) => {
+                let size = self.index_field_size as usize + header + compress.total_out() as usize;
+                chunk.buf.truncate(size);
+                Ok(chunk)
+            }
+            // doesn't fit, send it raw
+            _ => Err(chunk.into_vec()),
+        }
+    }
+
+    /// Decompresses a compressed payload into `buf`.
+    pub fn decompress(&self, chunk_info: &ChunkInfo, decompress: &mut Decompress, buf: Vec<u8>) -> Result<Chunk, DecodeError> {
+        let mut cursor = Cursor::new(self.as_ref());
+        let len = read_varint(&mut cursor)?;
+        if len != chunk_info.chunk_len(self.index) {
+            return Err(DecodeError::InvalidCompression);
+        }
+        let data = &self.as_ref()[cursor.position() as usize..];
+        let mut chunk = Chunk::new(buf, self.index, self.index_field_size, len as usize);
+        decompress.reset(false);
+        match decompress.decompress(data, chunk.as_mut(), FlushDecompress::Finish) {
+            Ok(Status::StreamEnd) if decompress.total_out() == len => Ok(chunk),
+            _ => Err(DecodeError::InvalidCompression),
+        }
+    }
 }
 
 impl AsRef<[u8]> for Chunk {
@@ -150,76 +857,78 @@ pub struct ChunkInfo {
     pub last_chunk_size: u64,
 }
 
-/// Calculates and returns the ChunkInfo for the given file length.
-pub fn index_field_size(length: u64) -> ChunkInfo {
+impl ChunkInfo {
+    /// Returns the payload size of the chunk with given index.
+    pub fn chunk_len(&self, index: u64) -> u64 {
+        if index + 1 == self.num_chunks {
+            self.last_chunk_size
+        } else {
+            self.chunk_size
+        }
+    }
+}
+
+/// Payload size of the chunks of a streaming upload, leaving room for the longest varint id.
+pub const STREAM_CHUNK_SIZE: u64 = MTU as u64 - 10;
+
+/// Id of chunk `index` of a streaming upload, which come after the extension messages.
+pub fn stream_id(index: u64) -> u64 {
+    extension::RESERVED + index
+}
+
+/// Calculates the ChunkInfo of a streaming upload once its length is known.
+///
+/// `index_field_size` is meaningless, as chunk ids are varints.
+pub fn stream_chunk_info(length: u64) -> ChunkInfo {
+    let num_chunks = length / STREAM_CHUNK_SIZE + (length % STREAM_CHUNK_SIZE != 0) as u64;
+    ChunkInfo {
+        index_field_size: 0,
+        chunk_size: STREAM_CHUNK_SIZE,
+        num_chunks,
+        last_chunk_size: length - num_chunks.saturating_sub(1) * STREAM_CHUNK_SIZE,
+    }
+}
+
+/// Calculates and returns the ChunkInfo for the given file length and the features the client
+/// requested.
+///
+/// Without parity and extension messages besides FIN the layout is the one of clients which
+/// don't know about features.
+pub fn index_field_size(length: u64, features: u64) -> ChunkInfo {
+    let reserved = if features & (features::PARITY | features::ZERO_RUNS | features::DELTA) != 0 {
+        extension::RESERVED
+    } else {
+        extension::FIN + 1
+    };
     let mut index_field_size = 1;
     let mut chunk_size;
     let mut num_chunks;
     loop {
         chunk_size = MTU as u64 - index_field_size;
+        if features & features::PARITY != 0 {
+            // leave room for the header of parity messages
+            chunk_size -= Parity::header_len(index_field_size);
+        }
         // prevent overflow
-        // + 1 as additional space for extension messages
-        num_chunks = length / chunk_size + (length % chunk_size != 0) as u64 + 1;
-        if num_chunks <= 1 << (index_field_size * 8) {
+        // reserve additional space for extension messages
+        num_chunks = length / chunk_size + (length % chunk_size != 0) as u64 + reserved;
+        if index_field_size == 8 || num_chunks <= 1 << (index_field_size * 8) {
             break;
         }
         index_field_size += 1;
     }
 
+    // the last chunk is a full one if the length is a multiple of the chunk size
+    let last_chunk_size = match length % chunk_size {
+        0 if length > 0 => chunk_size,
+        rest => rest,
+    };
+
     ChunkInfo {
         index_field_size,
         chunk_size,
         num_chunks: length / chunk_size + (length % chunk_size != 0) as u64,
-        last_chunk_size: length % chunk_size,
-    }
-}
-
-pub fn write_runlength_encoded<T, W>(bitmap: &BitMap<T>, mut w: W) -> io::Result<usize>
-where
-    T: AsRef<[u8]>,
-    W: Write,
-{
-    let mut count = 0;
-    let mut prev_val = true;
-    let mut written = 0;
-    for bit in bitmap.iter() {
-        if bit == prev_val {
-            count += 1;
-            continue;
-        }
-        match w.write_u64_varint(count) {
-            Ok(()) => written += varmint::len_u64_varint(count),
-            Err(ref e) if e.kind() == io::ErrorKind::WriteZero => return Ok(written),
-            e => e?
-        }
-        prev_val = bit;
-        count = 1;
-    }
-    match w.write_u64_varint(count) {
-        Ok(()) => written += varmint::len_u64_varint(count),
-        Err(ref e) if e.kind() == io::ErrorKind::WriteZero => return Ok(written),
-        e => e?
-    }
-    Ok(written)
-}
-
-pub struct RunlengthIter<T: AsRef<[u8]>>(Cursor<T>);
-
-impl<T: AsRef<[u8]>> RunlengthIter<T> {
-    pub fn new(t: T) -> RunlengthIter<T> {
-        RunlengthIter(Cursor::new(t))
-    }
-}
-
-impl<T: AsRef<[u8]>> Iterator for RunlengthIter<T> {
-    type Item = u64;
-
-    fn next(&mut self) -> Option<u64> {
-        match self.0.read_u64_varint() {
-            Ok(x) => Some(x),
-            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => None,
-            Err(e) => panic!("unexpected read error {:?}", e),
-        }
+        last_chunk_size,
     }
 }
 
@@ -230,15 +939,58 @@ struct MissingRange(pub u64, pub u64);
 pub struct MissingRanges {
     missing: Vec<MissingRange>,
     cursor: u64,
+    received: u64,
+    /// chunks covered by the last status update
+    known: u64,
 }
 
 impl MissingRanges {
-    pub fn parse_status_update(&mut self, update: &[u8]) -> bool {
-        self.missing.clear();
-        self.missing.extend(RunlengthIter::new(update).scan(0, |a, x| { *a += x; Some(*a) })
-                            .tuples().map(|(from, to)| MissingRange(from, to)));
+    /// Parses a status update, returning `true` if no chunks are missing.
+    ///
+    /// If the status update is malformed, the previous state is kept.
+    pub fn parse_status_update(&mut self, update: &[u8]) -> Result<bool, DecodeError> {
+        let mut missing = Vec::new();
+        let mut received = 0u64;
+        let mut position = 0u64;
+        for run in rle::decode_runs(update) {
+            let (received_run, range) = run?;
+            if received_run {
+                received += range.end - range.start;
+            } else {
+                missing.push(MissingRange(range.start, range.end));
+            }
+            position = range.end;
+        }
+        self.missing = missing;
+        self.received = received;
+        self.known = position;
         self.cursor = 0;
-        self.missing.is_empty()
+        Ok(self.missing.is_empty())
+    }
+
+    /// Number of chunks the last status update reported as received.
+    ///
+    /// If the status update was truncated, chunks after the truncation are not counted.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Returns whether the last status update reported the chunk as missing.
+    ///
+    /// Returns `None` if the status update was truncated before the chunk.
+    pub fn is_missing(&self, index: u64) -> Option<bool> {
+        if index >= self.known {
+            return None;
+        }
+        Some(self.missing.binary_search_by(|&MissingRange(from, to)| {
+            if to <= index {
+                cmp::Ordering::Less
+            } else if from > index {
+                cmp::Ordering::Greater
+            } else {
+                cmp::Ordering::Equal
+            }
+        }).is_ok())
     }
 
     pub fn advance_cursor(&self, cursor: u64) -> Option<u64> {
@@ -247,6 +999,11 @@ impl MissingRanges {
         Some(cmp::max(cursor, from))
     }
 
+    /// Moves the cursor past the chunks before `index`.
+    pub fn skip_to(&mut self, index: u64) {
+        self.cursor = cmp::max(self.cursor, index);
+    }
+
     pub fn next_chunk(&mut self) -> Option<u64> {
         let &MissingRange(from, _) = self.missing.iter().find(|&&MissingRange(from, to)| to > self.cursor)?;
         self.cursor = cmp::max(self.cursor, from);
@@ -260,52 +1017,381 @@ impl MissingRanges {
 #[cfg(test)]
 mod test {
     use super::*;
+    use bitte_ein_bit::{AtomicBitMap, BitMap};
 
-    fn test_rle_enc(bitmap: &[u8], result: &[u8]) {
-        let mut enc = Vec::new();
-        let mut bitmap = BitMap::new(bitmap);
-        let written = write_runlength_encoded(&bitmap, &mut enc).unwrap();
-        assert_eq!(enc, result);
-        assert_eq!(written, result.len());
+    #[test]
+    fn test_status_update_malformed() {
+        let mut mr = MissingRanges::default();
+        assert_eq!(mr.parse_status_update(&[2, 2]), Ok(false));
+        assert_eq!(mr.parse_status_update(&[2, 0x80]), Err(DecodeError::Truncated));
+        let overflow = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 2];
+        assert_eq!(mr.parse_status_update(&overflow), Err(DecodeError::Overflow));
+        // the previous status update is kept
+        assert_eq!(mr.missing.as_slice(), [MissingRange(2, 4)]);
     }
 
     #[test]
-    fn test_runlength_encode() {
-        test_rle_enc(&[0b1111_1111], &[8]);
-        test_rle_enc(&[0b0000_0000], &[0, 8]);
-        test_rle_enc(&[0b0000_0001], &[1, 7]);
-        test_rle_enc(&[0b1000_0000], &[0, 7, 1]);
-        test_rle_enc(&[0b0000_1011], &[2, 1, 1, 4]);
-        test_rle_enc(&[0b1000_1011], &[2, 1, 1, 3, 1]);
-        test_rle_enc(&[0b1000_1011, 0b0000_1111], &[2, 1, 1, 3, 5, 4]);
+    fn test_login_decode_malformed() {
+        let mut login = Vec::new();
+        Login {
+            client_token: b"token",
+            cookie: b"cookie",
+            command: Command::UploadRequest(UploadRequest { path: "foo/bar", length: 1337, features: 1, metadata: None }),
+        }.encode(&mut login);
+        Login::decode(&login).unwrap();
+
+        // features are optional, every shorter prefix of a login is truncated
+        match Login::decode(&login[..login.len() - 1]).unwrap().command {
+            Command::UploadRequest(req) => assert_eq!((req.length, req.features), (1337, 0)),
+            command => panic!("{:?}", command),
+        }
+        for len in 0..login.len() - 1 {
+            assert_eq!(Login::decode(&login[..len]).unwrap_err(), DecodeError::Truncated);
+        }
+
+        assert_eq!(Login::decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).unwrap_err(),
+                   DecodeError::Truncated);
+        assert_eq!(Login::decode(&[1, 42, 0, 0xfe]).unwrap_err(), DecodeError::UnknownCommand(0xfe));
+        assert_eq!(Login::decode(&[0, 0, 0, 2, 0xc3, 0x28, 0]).unwrap_err(), DecodeError::InvalidUtf8);
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut buf = Vec::new();
+        Login { client_token: b"token", cookie: &[], command: Command::Delete(Delete { path: "foo/bar" }) }.encode(&mut buf);
+        match Login::decode(&buf).unwrap().command {
+            Command::Delete(delete) => assert_eq!(delete, Delete { path: "foo/bar" }),
+            command => panic!("{:?}", command),
+        }
+        let rename = Rename { from: "foo", to: "bär" };
+        let mut buf = Vec::new();
+        Login { client_token: b"token", cookie: &[], command: Command::Rename(rename) }.encode(&mut buf);
+        match Login::decode(&buf).unwrap().command {
+            Command::Rename(decoded) => assert_eq!(decoded, rename),
+            command => panic!("{:?}", command),
+        }
+        for len in 0..buf.len() {
+            assert_eq!(Login::decode(&buf[..len]).unwrap_err(), DecodeError::Truncated);
+        }
+
+        for &ack in &[Ack::Done, Ack::NotFound, Ack::Failed, Ack::Busy] {
+            ack.encode(&mut buf);
+            match ServerMessage::decode(&buf).unwrap() {
+                ServerMessage::Ack(decoded) => assert_eq!(decoded, ack),
+                message => panic!("{:?}", message),
+            }
+        }
+        match ServerMessage::decode(&[2, 42]).unwrap() {
+            ServerMessage::Ack(ack) => assert_eq!(ack, Ack::Failed),
+            message => panic!("{:?}", message),
+        }
+        assert_eq!(ServerMessage::decode(&[2]).unwrap_err(), DecodeError::Truncated);
+
+        write_cookie(b"cookie", &mut buf);
+        match ServerMessage::decode(&buf).unwrap() {
+            ServerMessage::Cookie(cookie) => assert_eq!(cookie, b"cookie"),
+            message => panic!("{:?}", message),
+        }
+        assert_eq!(ServerMessage::decode(&buf[..buf.len() - 1]).unwrap_err(), DecodeError::Truncated);
+
+        write_rate_hint(1 << 20, &mut buf);
+        match ServerMessage::decode(&buf).unwrap() {
+            ServerMessage::RateHint(rate) => assert_eq!(rate, 1 << 20),
+            message => panic!("{:?}", message),
+        }
+        assert_eq!(ServerMessage::decode(&buf[..buf.len() - 1]).unwrap_err(), DecodeError::Truncated);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let encode = |metadata: Option<Metadata>| {
+            let features = if metadata.is_some() { features::METADATA } else { 0 };
+            let mut login = Vec::new();
+            Login {
+                client_token: b"token",
+                cookie: &[],
+                command: Command::UploadRequest(UploadRequest { path: "foo", length: 42, features, metadata }),
+            }.encode(&mut login);
+            login
+        };
+        for &owner in &[None, Some((1000, 100))] {
+            for &mtime in &[0, 1_500_000_000, -1, i64::min_value(), i64::max_value()] {
+                let metadata = Metadata { mode: 0o100644, mtime, mtime_nanos: 999_999_999, owner };
+                match Login::decode(&encode(Some(metadata))).unwrap().command {
+                    Command::UploadRequest(req) => {
+                        assert_eq!(req.features, features::METADATA);
+                        assert_eq!(req.metadata, Some(metadata));
+                    }
+                    command => panic!("{:?}", command),
+                }
+            }
+        }
+
+        let metadata = Metadata { mode: 0o755, mtime: 0, mtime_nanos: 0, owner: None };
+        let login = encode(Some(metadata));
+        // metadata is only sent if requested
+        let mut unrequested = Vec::new();
+        let req = UploadRequest { path: "foo", length: 42, features: 0, metadata: Some(metadata) };
+        req.encode(&mut unrequested).unwrap();
+        assert_eq!(UploadRequest::decode(&mut Cursor::new(&unrequested[..])).unwrap().metadata, None);
+        let req = UploadRequest { features: features::METADATA, metadata: None, ..req };
+        assert!(req.encode(Vec::new()).is_err());
+        // apart from the request without features, every prefix is truncated
+        let without_features = encode(None).len() - 1;
+        for len in (0..login.len()).filter(|&len| len != without_features) {
+            assert_eq!(Login::decode(&login[..len]).unwrap_err(), DecodeError::Truncated);
+        }
+        // nanoseconds out of range, unknown owner flag, uid beyond 32 bits
+        for invalid in &[&[0xed, 0x03, 0, 0x80, 0x94, 0xeb, 0xdc, 0x03, 0][..], &[0xed, 0x03, 0, 0, 2],
+                         &[0xed, 0x03, 0, 0, 1, 0x80, 0x80, 0x80, 0x80, 0x10, 0]] {
+            let mut login = encode(None);
+            // request metadata by hand
+            let features = login.len() - 1;
+            login[features] = features::METADATA as u8;
+            login.extend_from_slice(invalid);
+            assert_eq!(Login::decode(&login).unwrap_err(), DecodeError::InvalidMetadata);
+        }
+    }
+
+    #[test]
+    fn test_chunk_decode_malformed() {
+        for index_field_size in 1..9 {
+            for len in 0..index_field_size {
+                assert!(Chunk::decode(vec![0; len as usize], index_field_size).is_err());
+            }
+            let chunk = Chunk::decode(vec![1; index_field_size as usize], index_field_size).unwrap();
+            assert!(chunk.as_ref().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_status_update() {
+        let bitmap = BitMap::with_length(vec![0b0000_0011, 0], 11);
+        let mut buf = [0; MTU];
+        let size = write_status_update(Some(features::PARITY), 300, &[], &bitmap, &mut buf).unwrap();
+        let update = match ServerMessage::decode(&buf[..size]).unwrap() {
+            ServerMessage::StatusUpdate(update) => update,
+            message => panic!("{:?}", message),
+        };
+        assert_eq!(update.features, features::PARITY);
+        assert_eq!(update.rebuilt, 300);
+        assert_eq!(update.runlengths, &[2, 9]);
+
+        // rebuilt chunks are only reported with parity
+        let size = write_status_update(Some(features::COMPRESSION), 300, &[], &bitmap, &mut buf).unwrap();
+        assert_eq!(&buf[..size], &[0, features::COMPRESSION as u8, 2, 9]);
+
+        let size = write_status_update(Some(0), 0, &[], &bitmap, &mut buf).unwrap();
+        assert_eq!(&buf[..size], &[0, 0, 2, 9]);
+
+        // a client which requested no features gets just the runlengths
+        let size = write_status_update(None, 300, &[], &bitmap, &mut buf).unwrap();
+        assert_eq!(&buf[..size], &[2, 9]);
+
+        // truncation only affects the runlengths
+        let size = write_status_update(Some(features::COMPRESSION), 0, &[], &bitmap, &mut buf[..3]).unwrap();
+        assert_eq!(&buf[..size], &[0, features::COMPRESSION as u8, 2]);
+        // the server encodes straight from the bitmap chunks are received into
+        let atomic = AtomicBitMap::with_length(vec![0b0000_0011, 0], 11);
+        let size = write_status_update(Some(features::COMPRESSION), 0, &[], &atomic, &mut buf[..3]).unwrap();
+        assert_eq!(&buf[..size], &[0, features::COMPRESSION as u8, 2]);
+
+        // the digest of a resumed stream's kept chunks follows the features
+        let resume = features::STREAM | features::RESUME;
+        let size = write_status_update(Some(resume), 0, &[7; PREFIX_DIGEST_LEN], &bitmap, &mut buf).unwrap();
+        let update = match ServerMessage::decode(&buf[..size]).unwrap() {
+            ServerMessage::StatusUpdate(update) => update,
+            message => panic!("{:?}", message),
+        };
+        assert_eq!((update.prefix, update.runlengths), (&[7; PREFIX_DIGEST_LEN][..], &[2, 9][..]));
+        assert_eq!(ServerMessage::decode(&buf[..size - 3]).unwrap_err(), DecodeError::Truncated);
+        assert_eq!(ServerMessage::decode(&[7]).unwrap_err(), DecodeError::UnknownMessage(7));
+    }
+
+    #[test]
+    fn test_signatures() {
+        let blocks: Vec<_> = (0..SIGNATURES_PER_MESSAGE as u32).map(|i| BlockSignature {
+            weak: i * 0x0101_0101,
+            strong: [i as u8; STRONG_HASH_LEN],
+        }).collect();
+        let signatures = Signatures { block_size: 1400, num_blocks: 1000, first: 500, blocks };
+        let mut buf = Vec::new();
+        signatures.encode(&mut buf);
+        assert!(buf.len() <= MTU);
+        match ServerMessage::decode(&buf).unwrap() {
+            ServerMessage::Signatures(decoded) => assert_eq!(decoded, signatures),
+            message => panic!("{:?}", message),
+        }
+
+        // a partial entry or more blocks than the old file has
+        assert_eq!(ServerMessage::decode(&buf[..buf.len() - 1]).unwrap_err(), DecodeError::Truncated);
+        let too_many = Signatures { num_blocks: 600, ..signatures };
+        too_many.encode(&mut buf);
+        assert_eq!(ServerMessage::decode(&buf).unwrap_err(), DecodeError::InvalidSignatures);
+    }
+
+    #[test]
+    fn test_chunk_layout() {
+        // layout before features existed
+        fn baseline(length: u64) -> (u64, u64, u64) {
+            let mut index_field_size = 1;
+            loop {
+                let chunk_size = MTU as u64 - index_field_size;
+                let num_chunks = length / chunk_size + (length % chunk_size != 0) as u64;
+                // room for FIN
+                if num_chunks < 1 << (index_field_size * 8) {
+                    return (index_field_size, chunk_size, num_chunks);
+                }
+                index_field_size += 1;
+            }
+        }
+        let chunk_size = MTU as u64 - 1;
+        for &length in &[0, 1, chunk_size, 255 * chunk_size, 255 * chunk_size + 1, 256 * chunk_size,
+                         (1 << 16) * (chunk_size - 1), 1 << 40] {
+            for &features in &[0, features::COMPRESSION | features::METADATA] {
+                let chunk_info = index_field_size(length, features);
+                assert_eq!((chunk_info.index_field_size, chunk_info.chunk_size, chunk_info.num_chunks),
+                           baseline(length), "length {}", length);
+            }
+
+            // extension messages fit into the index, parity headers into the chunk size
+            let chunk_info = index_field_size(length, features::PARITY);
+            assert!(chunk_info.index_field_size == 8
+                    || chunk_info.num_chunks + extension::RESERVED <= 1 << (chunk_info.index_field_size * 8));
+            assert_eq!(chunk_info.chunk_size + chunk_info.index_field_size
+                       + Parity::header_len(chunk_info.index_field_size), MTU as u64);
+            let chunk_info = index_field_size(length, features::ZERO_RUNS);
+            assert_eq!(chunk_info.chunk_size + chunk_info.index_field_size, MTU as u64);
+        }
+        assert_eq!(index_field_size(255 * chunk_size, 0).index_field_size, 1);
+        assert_eq!(index_field_size(255 * chunk_size, features::DELTA).index_field_size, 2);
+    }
+
+    #[test]
+    fn test_copy_range() {
+        let chunk_info = index_field_size(4000 * MTU as u64, features::DELTA);
+        let range = CopyRange { first: 10, count: 20, old_offset: 12345 };
+        let chunk = range.encode(&chunk_info, Vec::new());
+        assert_eq!(chunk.index, chunk_info.num_chunks + extension::COPY_RANGE);
+        let decoded = Chunk::decode(chunk.into_vec(), chunk_info.index_field_size).unwrap();
+        assert_eq!(CopyRange::decode(&decoded, &chunk_info), Ok(range));
+
+        let invalid = [
+            CopyRange { count: 0, ..range },
+            CopyRange { count: MAX_COPY_RANGE + 1, ..range },
+            CopyRange { first: chunk_info.num_chunks, count: 1, ..range },
+            CopyRange { old_offset: u64::max_value() - 1000, ..range },
+        ];
+        for range in &invalid {
+            let chunk = range.encode(&chunk_info, Vec::new());
+            assert_eq!(CopyRange::decode(&chunk, &chunk_info), Err(DecodeError::InvalidExtension));
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let chunk_info = index_field_size(10 * MTU as u64, features::COMPRESSION);
+        let mut compress = Compress::new(::flate2::Compression::fast(), false);
+        let mut decompress = Decompress::new(false);
+
+        let mut chunk = Chunk::new(Vec::new(), 3, chunk_info.index_field_size, chunk_info.chunk_size as usize);
+        for (i, b) in chunk.as_mut().iter_mut().enumerate() {
+            *b = (i % 7) as u8;
+        }
+        let compressed = chunk.compress(&mut compress, Vec::new()).unwrap();
+        assert_eq!(compressed.index, 3);
+        assert!(compressed.is_compressed(&chunk_info));
+        let decoded = Chunk::decode(compressed.into_vec(), chunk_info.index_field_size).unwrap();
+        let decompressed = decoded.decompress(&chunk_info, &mut decompress, Vec::new()).unwrap();
+        assert_eq!(decompressed.index, 3);
+        assert_eq!(decompressed.as_ref(), chunk.as_ref());
+
+        // a compressed chunk for the wrong index or with garbage doesn't decompress
+        let mut compressed = chunk.compress(&mut compress, Vec::new()).unwrap();
+        compressed.index = chunk_info.num_chunks - 1;
+        assert_eq!(compressed.decompress(&chunk_info, &mut decompress, Vec::new()).err(),
+                   Some(DecodeError::InvalidCompression));
+        let mut garbage = chunk.compress(&mut compress, Vec::new()).unwrap();
+        for b in &mut garbage.as_mut()[2..] {
+            *b = 0xff;
+        }
+        assert!(garbage.decompress(&chunk_info, &mut decompress, Vec::new()).is_err());
+
+        // incompressible data is sent raw
+        let mut x = 1u32;
+        for b in chunk.as_mut() {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *b = (x >> 16) as u8;
+        }
+        assert!(chunk.compress(&mut compress, Vec::new()).is_err());
+        let tiny = Chunk::new(Vec::new(), 0, 1, 2);
+        assert!(tiny.compress(&mut compress, Vec::new()).is_err());
     }
 
     #[test]
-    fn test_runlength_decode() {
-        let vector: &[(&[u8], &[u64])] = &[
-            (&[], &[]),
-            (&[0], &[0]),
-            (&[1, 2, 3], &[1, 2, 3]),
-            (&[1, 0x80, 1, 2], &[1, 0x80, 2]),
-            (&[1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 2], &[1, 0xffffffff_ffffffff, 2]),
-            (&[1, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x40, 2], &[1, 0x40000000_00000000, 2]),
+    fn test_zero_run() {
+        let chunk_info = index_field_size(1000 * MTU as u64, features::ZERO_RUNS);
+        let run = ZeroRun { first: 200, count: 300 };
+        let chunk = run.encode(&chunk_info, Vec::new());
+        assert_eq!(chunk.index, chunk_info.num_chunks + extension::ZERO_RUN);
+        let decoded = Chunk::decode(chunk.into_vec(), chunk_info.index_field_size).unwrap();
+        assert_eq!(ZeroRun::decode(&decoded, &chunk_info), Ok(run));
+
+        // empty, out of range, truncated or with trailing bytes
+        let invalid = [
+            ZeroRun { first: 0, count: 0 }.encode(&chunk_info, Vec::new()),
+            ZeroRun { first: 1, count: chunk_info.num_chunks }.encode(&chunk_info, Vec::new()),
+            ZeroRun { first: u64::max_value(), count: 2 }.encode(&chunk_info, Vec::new()),
         ];
+        for chunk in &invalid {
+            assert_eq!(ZeroRun::decode(chunk, &chunk_info), Err(DecodeError::InvalidExtension));
+        }
+        let mut buf = ZeroRun { first: 200, count: 300 }.encode(&chunk_info, Vec::new()).into_vec();
+        buf.push(0);
+        let trailing = Chunk::decode(buf.clone(), chunk_info.index_field_size).unwrap();
+        assert_eq!(ZeroRun::decode(&trailing, &chunk_info), Err(DecodeError::InvalidExtension));
+        buf.truncate(buf.len() - 2);
+        let truncated = Chunk::decode(buf, chunk_info.index_field_size).unwrap();
+        assert_eq!(ZeroRun::decode(&truncated, &chunk_info), Err(DecodeError::Truncated));
+    }
 
-        for &(message, numbers) in vector {
-            let decoded: Vec<_> = RunlengthIter::new(message).collect();
-            assert_eq!(decoded, numbers);
+    #[test]
+    fn test_stream() {
+        for &(id, len) in &[(extension::FIN, 1), (stream_id(0), 1), (stream_id(1 << 20), 3), (u64::max_value(), 10)] {
+            let mut chunk = Chunk::new_stream(Vec::new(), id, 5);
+            chunk.as_mut().copy_from_slice(b"hello");
+            assert_eq!(chunk.buf.len(), len + 5);
+            let decoded = Chunk::decode_stream(chunk.into_vec()).unwrap();
+            assert_eq!((decoded.index, decoded.as_ref()), (id, &b"hello"[..]));
         }
+        assert_eq!(Chunk::decode_stream(vec![0x80]).err(), Some(DecodeError::Truncated));
+
+        let end = EndOfStream { length: 1 << 40 }.encode(Vec::new());
+        assert_eq!(end.index, extension::END_OF_STREAM);
+        let decoded = Chunk::decode_stream(end.into_vec()).unwrap();
+        assert_eq!(EndOfStream::decode(&decoded), Ok(EndOfStream { length: 1 << 40 }));
+        let mut buf = decoded.into_vec();
+        buf.push(0);
+        assert_eq!(EndOfStream::decode(&Chunk::decode_stream(buf).unwrap()), Err(DecodeError::InvalidExtension));
+
+        let info = stream_chunk_info(0);
+        assert_eq!((info.num_chunks, info.last_chunk_size), (0, 0));
+        let info = stream_chunk_info(2 * STREAM_CHUNK_SIZE);
+        assert_eq!((info.num_chunks, info.last_chunk_size), (2, STREAM_CHUNK_SIZE));
+        let info = stream_chunk_info(2 * STREAM_CHUNK_SIZE + 1);
+        assert_eq!((info.num_chunks, info.chunk_len(2)), (3, 1));
     }
 
     #[test]
     fn test_missing_ranges() {
         let mut mr = MissingRanges::default();
-        mr.parse_status_update(&[2, 2, 3, 4]);
+        assert_eq!(mr.parse_status_update(&[2, 2, 3, 4]), Ok(false));
+        assert_eq!(mr.received(), 5);
         // index:  0123456789a
         // bitmap: 11001110000
         // missing:  --   ----
         //          2,4   7,11
-        assert_eq!(mr.0.as_slice(), [MissingRange(2, 4), MissingRange(7, 11)]);
+        assert_eq!(mr.missing.as_slice(), [MissingRange(2, 4), MissingRange(7, 11)]);
         assert_eq!(mr.advance_cursor(0), Some(2));
         assert_eq!(mr.advance_cursor(1), Some(2));
         assert_eq!(mr.advance_cursor(2), Some(3));
@@ -316,5 +1402,8 @@ mod test {
         assert_eq!(mr.advance_cursor(9), Some(10));
         assert_eq!(mr.advance_cursor(10), None);
         assert_eq!(mr.advance_cursor(11), None);
+        let missing: Vec<_> = (0..12).map(|i| mr.is_missing(i)).collect();
+        assert_eq!(missing, [Some(false), Some(false), Some(true), Some(true), Some(false), Some(false), Some(false),
+                             Some(true), Some(true), Some(true), Some(true), None]);
     }
 }
@@ -0,0 +1,356 @@
+//! Delta uploads against an older copy of a file on the server, like rsync.
+//!
+//! The server signs the blocks of its old copy with a rolling checksum and a strong
+//! hash. The client looks for these blocks at every offset of its file and declares
+//! chunks found in the old copy with a `CopyRange` instead of sending them.
+//! Blocks are as long as chunks, so unchanged data shifted by an insertion still
+//! covers whole chunks.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+use ring::digest;
+
+use codec::{ChunkInfo, BlockSignature, Signatures, CopyRange, MAX_COPY_RANGE, STRONG_HASH_LEN, SIGNATURES_PER_MESSAGE};
+
+/// Start of the window is dropped from the search buffer once it's this far in.
+const SEARCH_BUFFER: usize = 1 << 20;
+
+/// rsync's rolling checksum over a window of bytes.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Rolling {
+        let len = data.len() as u32;
+        let mut rolling = Rolling { a: 0, b: 0, len };
+        for (i, &x) in data.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(x as u32);
+            rolling.b = rolling.b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        rolling
+    }
+
+    /// Moves the window one byte ahead.
+    fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(data: &[u8]) -> [u8; STRONG_HASH_LEN] {
+    let mut strong = [0; STRONG_HASH_LEN];
+    strong.copy_from_slice(&digest::digest(&digest::SHA256, data).as_ref()[..STRONG_HASH_LEN]);
+    strong
+}
+
+fn sign(block: &[u8]) -> BlockSignature {
+    BlockSignature {
+        weak: Rolling::new(block).value(),
+        strong: strong_hash(block),
+    }
+}
+
+/// Signs the full blocks of the old copy and encodes the signatures into messages, one
+/// message at a time.
+pub struct SignatureMessages<R> {
+    old: R,
+    block: Vec<u8>,
+    num_blocks: u64,
+    /// first block of the next message
+    first: u64,
+}
+
+impl<R: Read> SignatureMessages<R> {
+    pub fn new(old: R, old_len: u64, block_size: u64) -> SignatureMessages<R> {
+        SignatureMessages { old, block: vec![0; block_size as usize], num_blocks: old_len / block_size, first: 0 }
+    }
+}
+
+/// Ends after the first error.
+impl<R: Read> Iterator for SignatureMessages<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.first >= self.num_blocks {
+            return None;
+        }
+        let count = cmp::min(self.num_blocks - self.first, SIGNATURES_PER_MESSAGE as u64);
+        let mut blocks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            if let Err(e) = self.old.read_exact(&mut self.block) {
+                self.first = self.num_blocks;
+                return Some(Err(e));
+            }
+            blocks.push(sign(&self.block));
+        }
+        let mut buf = Vec::new();
+        Signatures { block_size: self.block.len() as u64, num_blocks: self.num_blocks, first: self.first, blocks }
+            .encode(&mut buf);
+        self.first += count;
+        Some(Ok(buf))
+    }
+}
+
+/// Signatures of the server's old copy received so far.
+#[derive(Debug, Default)]
+pub struct SignatureSet {
+    block_size: u64,
+    /// old block indices by weak checksum
+    blocks: HashMap<u32, Vec<(u64, [u8; STRONG_HASH_LEN])>>,
+}
+
+impl SignatureSet {
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Adds the signatures of a message, ignoring ones for other block sizes.
+    pub fn add(&mut self, signatures: &Signatures) {
+        if self.is_empty() {
+            self.block_size = signatures.block_size;
+        } else if signatures.block_size != self.block_size {
+            return;
+        }
+        for (i, block) in signatures.blocks.iter().enumerate() {
+            let candidates = self.blocks.entry(block.weak).or_insert_with(Vec::new);
+            let index = signatures.first + i as u64;
+            if !candidates.iter().any(|&(old, _)| old == index) {
+                candidates.push((index, block.strong));
+            }
+        }
+    }
+
+    /// Returns the old block with the given contents, preferring `preferred`.
+    fn find(&self, weak: u32, window: &[u8], preferred: u64) -> Option<u64> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong = strong_hash(window);
+        let mut matching = candidates.iter().filter(|&&(_, s)| s == strong).map(|&(index, _)| index);
+        let first = matching.next()?;
+        if first == preferred {
+            return Some(first);
+        }
+        Some(matching.find(|&index| index == preferred).unwrap_or(first))
+    }
+}
+
+/// Chunks of the new file which can be copied from the old copy.
+#[derive(Debug, Default)]
+pub struct CopyRanges {
+    ranges: Vec<CopyRange>,
+}
+
+impl CopyRanges {
+    /// Searches the new file for blocks of the old copy.
+    pub fn search<R: Read>(mut file: R, chunk_info: &ChunkInfo, signatures: &SignatureSet) -> io::Result<CopyRanges> {
+        let block_size = chunk_info.chunk_size;
+        if signatures.is_empty() || signatures.block_size != block_size {
+            return Ok(CopyRanges::default());
+        }
+        let bs = block_size as usize;
+        // contiguous runs of matched blocks as (new offset, old offset, length)
+        let mut runs: Vec<(u64, u64, u64)> = Vec::new();
+        let mut buf = Vec::new();
+        // file offset of `buf[0]`
+        let mut base = 0u64;
+        let mut pos = 0;
+        let mut rolling = None;
+        loop {
+            if pos >= SEARCH_BUFFER {
+                buf.drain(..pos);
+                base += pos as u64;
+                pos = 0;
+            }
+            // make sure the window and the byte after it are buffered
+            if buf.len() < pos + bs + 1 {
+                let len = buf.len();
+                buf.resize(pos + bs + SEARCH_BUFFER, 0);
+                let read = read_full(&mut file, &mut buf[len..])?;
+                buf.truncate(len + read);
+            }
+            if buf.len() < pos + bs {
+                break;
+            }
+            let window = &buf[pos..pos + bs];
+            let weak = *rolling.get_or_insert_with(|| Rolling::new(window));
+            let offset = base + pos as u64;
+            let preferred = runs.last()
+                .filter(|&&(new, _, len)| new + len == offset)
+                .map_or(u64::max_value(), |&(_, old, len)| (old + len) / block_size);
+            if let Some(block) = signatures.find(weak.value(), window, preferred) {
+                let old = block * block_size;
+                match runs.last_mut() {
+                    Some(&mut (new, old_start, ref mut len)) if new + *len == offset && old_start + *len == old => {
+                        *len += block_size
+                    }
+                    _ => runs.push((offset, old, block_size)),
+                }
+                pos += bs;
+                rolling = None;
+            } else {
+                if let Some(&next) = buf.get(pos + bs) {
+                    rolling.as_mut().unwrap().roll(buf[pos], next);
+                }
+                pos += 1;
+            }
+        }
+        Ok(CopyRanges::from_runs(chunk_info, &runs))
+    }
+
+    /// Collects the chunks lying completely within a run.
+    fn from_runs(chunk_info: &ChunkInfo, runs: &[(u64, u64, u64)]) -> CopyRanges {
+        let chunk_size = chunk_info.chunk_size;
+        let mut ranges = Vec::new();
+        for &(new, old, len) in runs {
+            let first = (new + chunk_size - 1) / chunk_size;
+            let mut end = first;
+            while end < chunk_info.num_chunks && end * chunk_size + chunk_info.chunk_len(end) <= new + len {
+                end += 1;
+            }
+            if end > first {
+                ranges.push(CopyRange { first, count: end - first, old_offset: old + first * chunk_size - new });
+            }
+        }
+        CopyRanges { ranges }
+    }
+
+    /// Number of chunks which can be copied.
+    pub fn chunks(&self) -> u64 {
+        self.ranges.iter().map(|range| range.count).sum()
+    }
+
+    /// Returns the chunks from `index` on which can be copied, if any.
+    pub fn range_at(&self, index: u64, chunk_size: u64) -> Option<CopyRange> {
+        let i = match self.ranges.binary_search_by_key(&index, |range| range.first) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let range = self.ranges[i];
+        let end = range.first + range.count;
+        if index >= end {
+            return None;
+        }
+        Some(CopyRange {
+            first: index,
+            count: cmp::min(end - index, MAX_COPY_RANGE),
+            old_offset: range.old_offset + (index - range.first) * chunk_size,
+        })
+    }
+}
+
+/// Reads until `buf` is full or the end of the file.
+fn read_full<R: Read>(file: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::{features, index_field_size, ServerMessage};
+    use sim::Rng;
+
+    fn random(len: usize, seed: u64) -> Vec<u8> {
+        let mut rng = Rng::new(seed);
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    fn signature_set(old: &[u8], block_size: u64) -> SignatureSet {
+        let mut set = SignatureSet::default();
+        for message in SignatureMessages::new(old, old.len() as u64, block_size) {
+            match ServerMessage::decode(&message.unwrap()).unwrap() {
+                ServerMessage::Signatures(signatures) => set.add(&signatures),
+                message => panic!("{:?}", message),
+            }
+        }
+        set
+    }
+
+    /// Applies the copy ranges to the old copy and checks the copied chunks.
+    fn check(old: &[u8], new: &[u8], chunk_info: &ChunkInfo, copies: &CopyRanges) {
+        for range in &copies.ranges {
+            for i in 0..range.count {
+                let index = range.first + i;
+                let len = chunk_info.chunk_len(index) as usize;
+                let from = (range.old_offset + i * chunk_info.chunk_size) as usize;
+                let to = (index * chunk_info.chunk_size) as usize;
+                assert_eq!(&old[from..from + len], &new[to..to + len], "chunk {}", index);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rolling() {
+        let data = random(300, 1);
+        let mut rolling = Rolling::new(&data[..100]);
+        for i in 0..200 {
+            rolling.roll(data[i], data[i + 100]);
+            assert_eq!(rolling.value(), Rolling::new(&data[i + 1..i + 101]).value());
+        }
+    }
+
+    #[test]
+    fn test_signature_messages() {
+        let old = random(100_000, 5);
+        let messages: Vec<_> = SignatureMessages::new(&old[..], old.len() as u64, 100).collect();
+        assert_eq!(messages.len(), (1000 + SIGNATURES_PER_MESSAGE - 1) / SIGNATURES_PER_MESSAGE);
+        assert!(messages.iter().all(Result::is_ok));
+
+        // the old copy got shorter while signing it
+        let mut messages = SignatureMessages::new(&old[..], 2 * old.len() as u64, 100);
+        let ok = messages.by_ref().take_while(Result::is_ok).count();
+        assert_eq!(ok, 1000 / SIGNATURES_PER_MESSAGE);
+        assert!(messages.next().is_none());
+    }
+
+    #[test]
+    fn test_search() {
+        let old = random(300_000, 2);
+        let chunk_info = index_field_size(old.len() as u64 + 100, features::DELTA);
+        let block_size = chunk_info.chunk_size;
+        let signatures = signature_set(&old, block_size);
+
+        // insertion near the front and a changed byte in the middle
+        let mut new = old.clone();
+        new.splice(5000..5000, random(100, 3));
+        new[150_000] ^= 1;
+        let copies = CopyRanges::search(&new[..], &chunk_info, &signatures).unwrap();
+        check(&old, &new, &chunk_info, &copies);
+        // all but the chunks around the insertion and the change are copied
+        assert!(copies.chunks() + 6 >= chunk_info.num_chunks, "{} of {}", copies.chunks(), chunk_info.num_chunks);
+
+        let range = copies.range_at(100, block_size).unwrap();
+        assert_eq!(range.first, 100);
+        assert_eq!(range.old_offset, 100 * block_size - 100);
+        assert!(copies.range_at(chunk_info.num_chunks, block_size).is_none());
+
+        // nothing in common
+        let other = random(new.len(), 4);
+        let copies = CopyRanges::search(&other[..], &chunk_info, &signatures).unwrap();
+        assert_eq!(copies.chunks(), 0);
+
+        // signatures for another block size are ignored
+        let other_info = index_field_size(1 << 40, features::DELTA);
+        assert_ne!(other_info.chunk_size, block_size);
+        let copies = CopyRanges::search(&new[..], &other_info, &signatures).unwrap();
+        assert_eq!(copies.chunks(), 0);
+    }
+}
@@ -0,0 +1,348 @@
+//! Forward error correction with XOR parity over groups of consecutively sent chunks.
+//!
+//! A single lost chunk of a group can be rebuilt by the server from the parity and
+//! the other chunks, saving a retransmission round trip.
+//! The group size adapts to the loss rate the client observes.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use codec::{MTU, Chunk, ChunkInfo, Parity, MAX_PARITY_GROUP, xor_into};
+
+/// Below this loss rate no parity is sent.
+const MIN_LOSS: f64 = 0.01;
+/// Largest group used for low loss rates.
+const MAX_GROUP: u64 = 64;
+/// Minimum number of judged chunks per loss sample.
+const MIN_SAMPLE: u64 = 32;
+/// Weight of a new loss sample in the moving average.
+const SAMPLE_WEIGHT: f64 = 0.25;
+
+/// Returns the parity group size for the given loss rate, 0 to send no parity.
+///
+/// Groups are sized to lose about one datagram in two groups, so most losses can be
+/// repaired while the overhead stays close to the loss rate.
+pub fn group_size(loss: f64) -> u64 {
+    if loss < MIN_LOSS {
+        return 0;
+    }
+    let size = (0.5 / loss) as u64;
+    cmp::max(2, cmp::min(size, cmp::min(MAX_GROUP, MAX_PARITY_GROUP)))
+}
+
+/// Estimates the loss rate from the chunks status updates report as missing.
+///
+/// A chunk is judged by the first status update received a retransmission timeout
+/// after it was sent, when it can't be in flight anymore. Copies sent while another
+/// one is still in flight are skipped, as it's unknown which copy arrived. Chunks the
+/// server rebuilt from parity count as lost, so parity doesn't hide the losses it repairs.
+/// Times are given relative to the start of the upload.
+#[derive(Debug)]
+pub struct LossEstimator {
+    start: Duration,
+    /// send time and index of chunks not judged yet, and whether no other copy was in flight
+    in_flight: VecDeque<(Duration, u64, bool)>,
+    /// number of copies of each chunk in `in_flight`
+    copies: HashMap<u64, u32>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// chunks judged and lost since the last sample
+    judged: u64,
+    lost: u64,
+    /// highest rebuilt count reported by the server
+    rebuilt: u64,
+    loss: f64,
+}
+
+impl LossEstimator {
+    /// Creates an estimator for an upload whose login was sent at `start`.
+    pub fn new(start: Duration) -> LossEstimator {
+        LossEstimator {
+            start,
+            in_flight: VecDeque::new(),
+            copies: HashMap::new(),
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            judged: 0,
+            lost: 0,
+            rebuilt: 0,
+            loss: 0.0,
+        }
+    }
+
+    /// Counts a chunk sent to the server.
+    pub fn sent(&mut self, now: Duration, index: u64) {
+        let copies = self.copies.entry(index).or_insert(0);
+        self.in_flight.push_back((now, index, *copies == 0));
+        *copies += 1;
+    }
+
+    /// Smoothed round trip time, once known.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Time after which a chunk which isn't reported as received is taken as lost.
+    fn timeout(&self) -> Option<Duration> {
+        self.srtt.map(|srtt| srtt + self.rttvar * 4)
+    }
+
+    /// Updates the estimate with a status update.
+    ///
+    /// `rebuilt` is the count reported by the server, `missing` tells whether the
+    /// update reports a chunk as missing, `None` if it doesn't cover it.
+    pub fn status_update<F>(&mut self, now: Duration, rebuilt: u64, missing: F)
+    where
+        F: Fn(u64) -> Option<bool>,
+    {
+        // The first status update answers the login, later ones the most recently
+        // sent chunk they report as received.
+        let sample = if self.srtt.is_none() {
+            Some(now - self.start)
+        } else {
+            self.in_flight.iter().rev()
+                .find(|&&(_, index, single)| single && missing(index) == Some(false))
+                .map(|&(time, _, _)| now - time)
+        };
+        if let Some(sample) = sample {
+            self.update_rtt(sample);
+        }
+
+        let timeout = self.timeout().unwrap();
+        while self.in_flight.front().map_or(false, |&(time, _, _)| time + timeout <= now) {
+            let (_, index, single) = self.in_flight.pop_front().unwrap();
+            let copies = {
+                let copies = self.copies.get_mut(&index).unwrap();
+                *copies -= 1;
+                *copies
+            };
+            if copies == 0 {
+                self.copies.remove(&index);
+            }
+            if !single {
+                continue;
+            }
+            if let Some(missing) = missing(index) {
+                self.judged += 1;
+                self.lost += missing as u64;
+            }
+        }
+
+        // reordered status updates and new connections report less
+        if rebuilt > self.rebuilt {
+            self.lost += rebuilt - self.rebuilt;
+            self.rebuilt = rebuilt;
+        }
+        if self.judged >= MIN_SAMPLE {
+            let sample = (self.lost as f64 / self.judged as f64).min(1.0);
+            self.loss = (1.0 - SAMPLE_WEIGHT) * self.loss + SAMPLE_WEIGHT * sample;
+            self.judged = 0;
+            self.lost = 0;
+        }
+    }
+
+    /// Updates the round trip time like TCP does.
+    fn update_rtt(&mut self, sample: Duration) {
+        self.srtt = Some(match self.srtt {
+            None => {
+                self.rttvar = sample / 2;
+                sample
+            }
+            Some(srtt) => {
+                let diff = if srtt > sample { srtt - sample } else { sample - srtt };
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                (srtt * 7 + sample) / 8
+            }
+        });
+    }
+
+    pub fn loss(&self) -> f64 {
+        self.loss
+    }
+}
+
+/// Builds parity messages for runs of consecutively sent chunks.
+#[derive(Default)]
+pub struct ParityEncoder {
+    /// chunks per group, 0 if disabled
+    group_size: u64,
+    first: u64,
+    count: u64,
+    xor: Vec<u8>,
+}
+
+impl ParityEncoder {
+    pub fn group_size(&self) -> u64 {
+        self.group_size
+    }
+
+    /// Sets the group size, 0 disables parity.
+    pub fn set_group_size(&mut self, group_size: u64) {
+        self.group_size = cmp::min(group_size, MAX_PARITY_GROUP);
+        if self.group_size == 0 {
+            self.count = 0;
+        }
+    }
+
+    /// Adds a chunk which is about to be sent.
+    ///
+    /// Returns the parity message to send after it if the chunk completes a group.
+    pub fn add(&mut self, chunk_info: &ChunkInfo, chunk: &Chunk) -> Option<Chunk> {
+        if self.group_size == 0 {
+            return None;
+        }
+        // a group only covers consecutive chunks, start a new one otherwise
+        if self.count == 0 || chunk.index != self.first + self.count {
+            self.first = chunk.index;
+            self.count = 0;
+            self.xor.clear();
+            self.xor.resize(chunk_info.chunk_size as usize, 0);
+        }
+        xor_into(&mut self.xor, chunk.as_ref());
+        self.count += 1;
+        if self.count < self.group_size && chunk.index + 1 < chunk_info.num_chunks {
+            return None;
+        }
+        let parity = Parity {
+            first: self.first,
+            count: self.count,
+            xor: &self.xor,
+        };
+        self.count = 0;
+        Some(parity.encode(chunk_info, Vec::with_capacity(MTU)))
+    }
+}
+
+/// Rebuilds the single missing chunk of a parity group.
+///
+/// `read` reads the payload of a received chunk into the given buffer.
+/// Returns `None` if no or more than one chunk of the group is missing.
+pub fn rebuild<M, R, E>(chunk_info: &ChunkInfo, parity: &Parity, missing: M, mut read: R, buf: Vec<u8>)
+    -> Result<Option<Chunk>, E>
+where
+    M: Fn(u64) -> bool,
+    R: FnMut(u64, &mut [u8]) -> Result<(), E>,
+{
+    let mut lost = (parity.first..parity.first + parity.count).filter(|&index| missing(index));
+    let index = match (lost.next(), lost.next()) {
+        (Some(index), None) => index,
+        _ => return Ok(None),
+    };
+    let mut chunk = Chunk::new(buf, index, chunk_info.index_field_size, chunk_info.chunk_size as usize);
+    chunk.as_mut().copy_from_slice(parity.xor);
+    let mut other = vec![0; chunk_info.chunk_size as usize];
+    for i in parity.first..parity.first + parity.count {
+        if i == index {
+            continue;
+        }
+        let len = chunk_info.chunk_len(i) as usize;
+        read(i, &mut other[..len])?;
+        xor_into(chunk.as_mut(), &other[..len]);
+    }
+    let len = chunk_info.index_field_size + chunk_info.chunk_len(index);
+    chunk.buf.truncate(len as usize);
+    Ok(Some(chunk))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::{features, index_field_size};
+
+    #[test]
+    fn test_group_size() {
+        assert_eq!(group_size(0.0), 0);
+        assert_eq!(group_size(0.005), 0);
+        assert_eq!(group_size(0.01), 50);
+        assert_eq!(group_size(0.1), 5);
+        assert_eq!(group_size(0.5), 2);
+        assert_eq!(group_size(1.0), 2);
+    }
+
+    #[test]
+    fn test_loss_estimator() {
+        let ms = Duration::from_millis;
+        // one chunk per millisecond, 10ms round trip, 20% lost of which half are rebuilt
+        let mut estimator = LossEstimator::new(ms(0));
+        estimator.status_update(ms(10), 0, |_| None);
+        for now in 10..1000 {
+            estimator.sent(ms(now), now);
+            if now % 5 == 0 {
+                let rebuilt = (10..now.saturating_sub(10)).filter(|i| i % 10 == 5).count() as u64;
+                estimator.status_update(ms(now), rebuilt, |i| Some(i % 10 == 0 || i + 10 > now));
+            }
+        }
+        let rtt = estimator.rtt().unwrap();
+        assert!(rtt >= ms(10) && rtt < ms(12), "{:?}", rtt);
+        assert!((estimator.loss() - 0.2).abs() < 0.05, "{}", estimator.loss());
+
+        // without loss, jitter and chunks in flight aren't taken as lost
+        let mut estimator = LossEstimator::new(ms(0));
+        estimator.status_update(ms(10), 0, |_| None);
+        let delay = |i: u64| 5 + i * 7 % 5;
+        for now in 10..1000 {
+            estimator.sent(ms(now), now);
+            // a retransmission of a chunk in flight
+            if now % 50 == 0 {
+                estimator.sent(ms(now), now - 1);
+            }
+            if now % 3 == 0 {
+                estimator.status_update(ms(now), 0, |i| Some(i + delay(i) + 5 > now));
+            }
+        }
+        let rtt = estimator.rtt().unwrap();
+        assert!(rtt >= ms(10) && rtt < ms(15), "{:?}", rtt);
+        assert_eq!(estimator.loss(), 0.0);
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let length = 10 * 1400 + 100;
+        let chunk_info = index_field_size(length, features::PARITY);
+        let data: Vec<u8> = (0..length).map(|i| (i * 7 + i / 13) as u8).collect();
+        let chunk = |index: u64| {
+            let offset = (index * chunk_info.chunk_size) as usize;
+            let len = chunk_info.chunk_len(index) as usize;
+            let mut chunk = Chunk::new(Vec::new(), index, chunk_info.index_field_size, len);
+            chunk.as_mut().copy_from_slice(&data[offset..offset + len]);
+            chunk
+        };
+        let mut encoder = ParityEncoder::default();
+        encoder.set_group_size(4);
+
+        // groups of 4 and a last group cut short by the end of the file
+        let mut parities = Vec::new();
+        for index in 3..chunk_info.num_chunks {
+            parities.extend(encoder.add(&chunk_info, &chunk(index)));
+        }
+        assert_eq!(parities.len(), 2);
+
+        for parity in &parities {
+            let parity = Parity::decode(parity, &chunk_info).unwrap();
+            for lost in parity.first..parity.first + parity.count {
+                let read = |index: u64, buf: &mut [u8]| -> Result<(), ()> {
+                    assert!(index != lost);
+                    buf.copy_from_slice(chunk(index).as_ref());
+                    Ok(())
+                };
+                let rebuilt = rebuild(&chunk_info, &parity, |index| index == lost, read, Vec::new())
+                    .unwrap().unwrap();
+                assert_eq!(rebuilt.index, lost);
+                assert_eq!(rebuilt.as_ref(), chunk(lost).as_ref());
+            }
+            // nothing or too much lost
+            let nothing = rebuild(&chunk_info, &parity, |_| false, |_, _| Err(()), Vec::new());
+            assert!(nothing.unwrap().is_none());
+            let first = parity.first;
+            let too_much = rebuild(&chunk_info, &parity, |index| index <= first + 1, |_, _| Err(()), Vec::new());
+            assert!(too_much.unwrap().is_none());
+        }
+
+        // non-consecutive chunks start a new group
+        assert!(encoder.add(&chunk_info, &chunk(0)).is_none());
+        assert!(encoder.add(&chunk_info, &chunk(2)).is_none());
+        let parity = encoder.add(&chunk_info, &chunk(3));
+        assert!(parity.is_none());
+    }
+}
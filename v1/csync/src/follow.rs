@@ -0,0 +1,118 @@
+//! Reads a growing file like `tail -f`, to upload log files as they are written.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+/// Interval in which a file is checked for new data once everything was read.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Set by the first SIGINT or SIGTERM once `stop_on_signals` was called.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn stop(_: libc::c_int) {
+    if STOP.swap(true, Ordering::SeqCst) {
+        // the second signal kills the client, e.g. if the server is gone
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Makes SIGINT and SIGTERM end following files, so the upload is finished instead of killed.
+pub fn stop_on_signals() {
+    let handler: extern "C" fn(libc::c_int) = stop;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as *const () as libc::sighandler_t);
+    }
+}
+
+/// Reader of a file which waits for more data at its end.
+///
+/// The input ends once the file didn't grow for the idle timeout, on a signal (see
+/// `stop_on_signals`), or if the file got truncated, e.g. by log rotation.
+pub struct Follow {
+    file: File,
+    idle_timeout: Duration,
+    last_growth: Instant,
+    offset: u64,
+}
+
+impl Follow {
+    pub fn new(file: File, idle_timeout: Duration) -> Follow {
+        Follow {
+            file,
+            idle_timeout,
+            last_growth: Instant::now(),
+            offset: 0,
+        }
+    }
+}
+
+impl Read for Follow {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 {
+                self.offset += read as u64;
+                self.last_growth = Instant::now();
+                return Ok(read);
+            }
+            if STOP.load(Ordering::SeqCst) {
+                debug!("Stopped following at {} bytes", self.offset);
+                return Ok(0);
+            }
+            if self.file.metadata()?.len() < self.offset {
+                warn!("File was truncated, stopped following at {} bytes", self.offset);
+                return Ok(0);
+            }
+            if self.last_growth.elapsed() >= self.idle_timeout {
+                debug!("File didn't grow for {:?}, stopped following at {} bytes", self.idle_timeout, self.offset);
+                return Ok(0);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::process;
+
+    #[test]
+    fn test_follow() {
+        let path = env::temp_dir().join(format!("csync-follow-{}", process::id()));
+        fs::write(&path, b"foo").unwrap();
+        let mut follow = Follow::new(File::open(&path).unwrap(), Duration::from_millis(500));
+        let mut buf = [0; 16];
+        assert_eq!(follow.read(&mut buf).unwrap(), 3);
+
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                OpenOptions::new().append(true).open(path).unwrap().write_all(b"bar").unwrap();
+            })
+        };
+        assert_eq!(follow.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"bar");
+        writer.join().unwrap();
+
+        let start = Instant::now();
+        assert_eq!(follow.read(&mut buf).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        let mut follow = Follow::new(File::open(&path).unwrap(), Duration::from_secs(60));
+        assert_eq!(follow.read(&mut buf).unwrap(), 6);
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(2).unwrap();
+        assert_eq!(follow.read(&mut buf).unwrap(), 0);
+        fs::remove_file(path).unwrap();
+    }
+}
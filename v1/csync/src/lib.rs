@@ -0,0 +1,8 @@
+//! The wire format of csync, shared by the client, the server and tools decoding captures.
+
+extern crate varmint;
+extern crate byteorder;
+extern crate bitte_ein_bit;
+extern crate flate2;
+
+pub mod codec;
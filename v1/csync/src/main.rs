@@ -16,14 +16,41 @@ extern crate take_mut;
 extern crate structopt;
 extern crate bitte_ein_bit;
 extern crate memmap;
-extern crate itertools;
 extern crate walkdir;
+extern crate atty;
+extern crate flate2;
+extern crate libc;
+extern crate csync;
+#[cfg(test)]
+extern crate tokio_current_thread;
+#[cfg(test)]
+extern crate tokio_executor;
+#[cfg(test)]
+extern crate tokio_reactor;
+#[cfg(test)]
+extern crate tokio_timer;
 
 
 mod server;
 mod client;
-mod codec;
 mod timeout;
+mod progress;
+mod fec;
+mod sparse;
+mod delta;
+mod stream;
+mod pace;
+mod follow;
+mod transport;
+#[cfg(test)]
+mod sim;
+
+use csync::codec;
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
 
 use structopt::StructOpt;
 
@@ -42,6 +69,51 @@ pub struct Opt {
     /// Directory to upload files from
     #[structopt(short = "f", long = "files")]
     files: Option<String>,
+    /// Upload standard input as this file, e.g. to upload the output of a pipe
+    #[structopt(long = "stdin", value_name = "NAME")]
+    stdin: Option<String>,
+    /// Upload this file and keep uploading what is appended to it, e.g. to a log file
+    ///
+    /// Stops once the file didn't grow for --idle-timeout, on SIGINT or SIGTERM, or if it's truncated.
+    /// Resumes after what the server has of an earlier upload of the file.
+    #[structopt(long = "follow", value_name = "FILE")]
+    follow: Option<String>,
+    /// Seconds a followed file may stay unchanged before its upload is finished
+    #[structopt(long = "idle-timeout", default_value = "60")]
+    idle_timeout: u64,
+    /// Print upload progress as JSON lines to stdout instead of showing a progress bar
+    #[structopt(long = "progress-json")]
+    progress_json: bool,
+    /// Also restore owner and group of uploaded files, which needs a server running as root with --restore-owner
+    #[structopt(long = "owner")]
+    owner: bool,
+    /// Server: restore owner and group of uploaded files if the client sends them
+    #[structopt(long = "restore-owner")]
+    restore_owner: bool,
+    /// Delete a file on the server, may be given multiple times
+    #[structopt(long = "delete")]
+    delete: Vec<String>,
+    /// Rename a file on the server, may be given multiple times
+    #[structopt(long = "rename", raw(number_of_values = "2", value_names = r#"&["FROM", "TO"]"#))]
+    rename: Vec<String>,
+    /// Maximum number of concurrent connections to the server
+    #[structopt(long = "max-connections", default_value = "1024")]
+    max_connections: usize,
+    /// Maximum number of concurrent connections from a single IP address
+    #[structopt(long = "max-connections-per-ip", default_value = "16")]
+    max_connections_per_ip: usize,
+    /// Bytes per second the server receives in total, split among uploads, 0 for unlimited
+    #[structopt(long = "bandwidth", default_value = "0")]
+    bandwidth: u64,
+    /// Weight of a client token in splitting --bandwidth, 1 by default, may be given multiple times
+    #[structopt(long = "token-weight", value_name = "TOKEN=WEIGHT")]
+    token_weight: Vec<server::bandwidth::TokenWeight>,
+    /// Seconds after which the server removes an incomplete upload nobody resumed, 0 for never
+    #[structopt(long = "reap-after", default_value = "604800")]
+    reap_after: u64,
+    /// Bytes the uploads may take on the server's disk before the oldest incomplete ones are removed, 0 for unlimited
+    #[structopt(long = "reap-above", default_value = "0")]
+    reap_above: u64,
 }
 
 fn main() {
@@ -52,10 +124,33 @@ fn main() {
     if opt.server {
         server::run(opt);
     } else {
-        if opt.files.is_none() {
-            eprintln!("Files required for client mode. Execute --help for help.");
+        if opt.files.is_none() && opt.stdin.is_none() && opt.follow.is_none() && opt.delete.is_empty() && opt.rename.is_empty() {
+            eprintln!("Files, --stdin, --follow, --delete or --rename required for client mode. Execute --help for help.");
             return;
         }
-        client::client(opt).unwrap();
+        client::manage(&opt).unwrap();
+        if let Some(ref name) = opt.stdin {
+            client::stream(name, || Ok(io::stdin()), false, &opt).unwrap();
+        }
+        if let Some(ref path) = opt.follow {
+            let name = match Path::new(path).file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => {
+                    eprintln!("--follow needs the path of a file, got {}.", path);
+                    return;
+                }
+            };
+            follow::stop_on_signals();
+            let idle_timeout = Duration::from_secs(opt.idle_timeout);
+            // opened again to upload it from the start if it doesn't match what the server has
+            let open = || Ok(follow::Follow::new(File::open(path)?, idle_timeout));
+            if let Err(e) = client::stream(name, open, true, &opt) {
+                eprintln!("Can't upload {}: {}", path, e);
+                return;
+            }
+        }
+        if opt.files.is_some() {
+            client::client(opt).unwrap();
+        }
     }
 }
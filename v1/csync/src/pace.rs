@@ -0,0 +1,71 @@
+//! Client side of the server's bandwidth allocation, spacing datagrams by the last rate hint.
+//!
+//! The server spaces the signatures of a delta upload the same way.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use tokio::clock;
+
+/// Spaces datagrams so that at most `rate` bytes are sent per second.
+pub struct Pacer {
+    /// bytes per second, `None` until the server sends a rate hint
+    rate: Option<u64>,
+    /// earliest time the next datagram may be sent
+    next: Instant,
+}
+
+impl Pacer {
+    pub fn new() -> Pacer {
+        Pacer { rate: None, next: clock::now() }
+    }
+
+    pub fn set_rate(&mut self, rate: u64) {
+        if self.rate != Some(rate) {
+            debug!("Pacing to {} bytes/s", rate);
+        }
+        self.rate = Some(rate);
+    }
+
+    /// Time left until the next datagram may be sent, `None` if it may be sent right away.
+    pub fn wait(&self, now: Instant) -> Option<Duration> {
+        match self.rate {
+            Some(_) if self.next > now => Some(self.next - now),
+            _ => None,
+        }
+    }
+
+    /// Accounts for a datagram of `len` bytes sent at `now`.
+    pub fn sent(&mut self, now: Instant, len: usize) {
+        if let Some(rate) = self.rate {
+            // idle time doesn't add up to a burst
+            let start = cmp::max(self.next, now);
+            self.next = start + Duration::from_nanos(len as u64 * 1_000_000_000 / rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pacer() {
+        let mut pacer = Pacer::new();
+        let now = Instant::now();
+        pacer.sent(now, 1000);
+        assert_eq!(pacer.wait(now), None);
+
+        pacer.set_rate(10_000);
+        pacer.sent(now, 1000);
+        pacer.sent(now, 1000);
+        assert_eq!(pacer.wait(now), Some(Duration::from_millis(200)));
+        assert_eq!(pacer.wait(now + Duration::from_millis(150)), Some(Duration::from_millis(50)));
+        assert_eq!(pacer.wait(now + Duration::from_millis(200)), None);
+
+        // no credit for being idle
+        let later = now + Duration::from_secs(10);
+        pacer.sent(later, 1000);
+        assert_eq!(pacer.wait(later), Some(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,226 @@
+use std::cmp;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use atty;
+
+/// Minimum time between two progress reports, except for the final one.
+const BAR_INTERVAL_MS: u64 = 200;
+const JSON_INTERVAL_MS: u64 = 1000;
+const BAR_WIDTH: usize = 30;
+
+/// Tracks the progress of a single upload.
+///
+/// The progress is driven by the status updates of the server: only chunks the
+/// server acknowledged as received count as uploaded.
+pub struct Progress {
+    file: String,
+    total: u64,
+    acked: u64,
+    mode: Mode,
+    start: Instant,
+    last_update: Instant,
+    last_report: Option<Instant>,
+    /// smoothed rate in bytes per second
+    rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Bar,
+    Json,
+    Silent,
+}
+
+impl Progress {
+    pub fn new(file: &str, total: u64, json: bool) -> Progress {
+        let mode = if json {
+            Mode::Json
+        } else if atty::is(atty::Stream::Stderr) {
+            Mode::Bar
+        } else {
+            Mode::Silent
+        };
+        let now = Instant::now();
+        let progress = Progress {
+            file: file.to_string(),
+            total,
+            acked: 0,
+            mode,
+            start: now,
+            last_update: now,
+            last_report: None,
+            rate: 0.0,
+        };
+        if mode == Mode::Json {
+            progress.json("start");
+        }
+        progress
+    }
+
+    /// Updates the number of bytes the server acknowledged.
+    ///
+    /// A truncated status update may acknowledge less than a previous one, the progress never
+    /// goes backwards.
+    pub fn update(&mut self, acked: u64) {
+        let acked = cmp::max(cmp::min(acked, self.total), self.acked);
+        let now = Instant::now();
+        let elapsed = secs(now.duration_since(self.last_update));
+        if elapsed > 0.0 {
+            let current = (acked - self.acked) as f64 / elapsed;
+            // exponential moving average, the first measurement is taken as is
+            self.rate = if self.rate == 0.0 { current } else { 0.7 * self.rate + 0.3 * current };
+        }
+        self.acked = acked;
+        self.last_update = now;
+        self.report(false);
+    }
+
+    /// Updates the total, which grows while the input of a streaming upload is read.
+    pub fn set_total(&mut self, total: u64) {
+        self.total = total;
+    }
+
+    /// Reports the upload as finished.
+    pub fn finish(&mut self) {
+        self.acked = self.total;
+        self.report(true);
+        match self.mode {
+            Mode::Bar => eprintln!(),
+            Mode::Json => self.json("done"),
+            Mode::Silent => {}
+        }
+    }
+
+    /// Estimated time until all bytes are acknowledged, if a rate is known.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None;
+        }
+        let secs = (self.total - self.acked) as f64 / self.rate;
+        Some(Duration::from_millis((secs * 1000.0) as u64))
+    }
+
+    fn report(&mut self, force: bool) {
+        let interval = match self.mode {
+            Mode::Bar => BAR_INTERVAL_MS,
+            Mode::Json => JSON_INTERVAL_MS,
+            Mode::Silent => return,
+        };
+        let now = Instant::now();
+        if !force {
+            if let Some(last) = self.last_report {
+                if now.duration_since(last) < Duration::from_millis(interval) {
+                    return;
+                }
+            }
+        }
+        self.last_report = Some(now);
+        match self.mode {
+            Mode::Bar => self.bar(),
+            Mode::Json => self.json("progress"),
+            Mode::Silent => unreachable!(),
+        }
+    }
+
+    fn bar(&self) {
+        let fraction = if self.total == 0 { 1.0 } else { self.acked as f64 / self.total as f64 };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let eta = match self.eta() {
+            Some(eta) => {
+                let secs = eta.as_secs();
+                format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+            }
+            None => "--:--:--".to_string(),
+        };
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        // errors writing to the terminal are not worth aborting the upload for
+        let _ = write!(stderr, "\r{} [{}{}] {:3.0}% {}/{} {}/s ETA {}\x1b[K",
+                       self.file, "#".repeat(filled), " ".repeat(BAR_WIDTH - filled),
+                       fraction * 100.0, human(self.acked as f64), human(self.total as f64),
+                       human(self.rate), eta);
+        let _ = stderr.flush();
+    }
+
+    fn json(&self, event: &str) {
+        let eta = match self.eta() {
+            Some(eta) => format!("{:.3}", secs(eta)),
+            None => "null".to_string(),
+        };
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = writeln!(stdout, "{{\"event\":\"{}\",\"file\":\"{}\",\"acked\":{},\"total\":{},\"rate\":{:.0},\"eta\":{},\"elapsed\":{:.3}}}",
+                         event, json_escape(&self.file), self.acked, self.total, self.rate, eta,
+                         secs(self.start.elapsed()));
+        let _ = stdout.flush();
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+fn human(bytes: f64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_human() {
+        assert_eq!(human(0.0), "0 B");
+        assert_eq!(human(1023.0), "1023 B");
+        assert_eq!(human(1024.0), "1.0 KiB");
+        assert_eq!(human(1536.0 * 1024.0), "1.5 MiB");
+        assert_eq!(human(2048.0 * 1024.0f64.powi(6)), "2048.0 EiB");
+    }
+
+    #[test]
+    fn test_json_escape() {
+        assert_eq!(json_escape("plain/file.txt"), "plain/file.txt");
+        assert_eq!(json_escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(json_escape("a\nb\tc\r"), "a\\nb\\tc\\r");
+        assert_eq!(json_escape("\u{1}\u{1f}"), "\\u0001\\u001f");
+        assert_eq!(json_escape("ümlaut"), "ümlaut");
+    }
+
+    #[test]
+    fn test_never_backwards() {
+        let mut progress = Progress::new("file", 1000, false);
+        progress.update(600);
+        progress.update(400);
+        assert_eq!(progress.acked, 600);
+        progress.update(2000);
+        assert_eq!(progress.acked, 1000);
+    }
+}
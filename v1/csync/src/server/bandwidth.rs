@@ -0,0 +1,100 @@
+//! Server-wide bandwidth budget, split among the active uploads by the weight of their token.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Weight of a client token, given as `TOKEN=WEIGHT` on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenWeight {
+    pub token: Vec<u8>,
+    pub weight: u64,
+}
+
+impl FromStr for TokenWeight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TokenWeight, String> {
+        // tokens may contain `=`, weights don't
+        let pos = s.rfind('=').ok_or_else(|| format!("expected TOKEN=WEIGHT, got {:?}", s))?;
+        let weight = s[pos + 1..].parse().map_err(|e| format!("invalid weight in {:?}: {}", s, e))?;
+        if weight == 0 {
+            return Err(format!("weight of {:?} must be positive", s));
+        }
+        Ok(TokenWeight { token: s.as_bytes()[..pos].to_vec(), weight })
+    }
+}
+
+#[derive(Clone)]
+pub struct Bandwidth {
+    /// bytes per second, 0 for unlimited
+    total: u64,
+    weights: Arc<HashMap<Vec<u8>, u64>>,
+    /// sum of the weights of all active shares
+    active: Arc<Mutex<u64>>,
+}
+
+/// Share of an upload in the budget, counted until dropped.
+pub struct Share {
+    total: u64,
+    weight: u64,
+    active: Arc<Mutex<u64>>,
+}
+
+impl Bandwidth {
+    pub fn new(total: u64, weights: &[TokenWeight]) -> Bandwidth {
+        Bandwidth {
+            total,
+            weights: Arc::new(weights.iter().map(|w| (w.token.clone(), w.weight)).collect()),
+            active: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Adds an upload of a client with `token`, tokens without a configured weight weigh 1.
+    pub fn share(&self, token: &[u8]) -> Share {
+        let weight = self.weights.get(token).cloned().unwrap_or(1);
+        *self.active.lock().unwrap() += weight;
+        Share { total: self.total, weight, active: Arc::clone(&self.active) }
+    }
+}
+
+impl Share {
+    /// Bytes per second this upload may currently use, `None` if the bandwidth is unlimited.
+    pub fn rate(&self) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+        let active = *self.active.lock().unwrap();
+        // at least a byte per second, a rate of 0 would mean unlimited to the client
+        Some(::std::cmp::max(self.total * self.weight / active, 1))
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        *self.active.lock().unwrap() -= self.weight;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shares() {
+        let weights = ["fast=3".parse().unwrap(), "a=b=2".parse().unwrap()];
+        assert_eq!(weights[1], TokenWeight { token: b"a=b".to_vec(), weight: 2 });
+        assert!("token".parse::<TokenWeight>().is_err());
+        assert!("token=0".parse::<TokenWeight>().is_err());
+
+        let bandwidth = Bandwidth::new(1000, &weights);
+        let slow = bandwidth.share(b"slow");
+        assert_eq!(slow.rate(), Some(1000));
+        let fast = bandwidth.share(b"fast");
+        assert_eq!((slow.rate(), fast.rate()), (Some(250), Some(750)));
+        drop(fast);
+        assert_eq!(slow.rate(), Some(1000));
+
+        assert_eq!(Bandwidth::new(0, &[]).share(b"slow").rate(), None);
+    }
+}
@@ -1,11 +1,15 @@
 use std::time::{Duration, Instant};
 use std::collections::VecDeque;
 use std::mem;
+use std::cmp;
 
+use tokio::clock;
 use tokio::timer::Delay;
 use tokio::prelude::task;
 use futures::{Async, Stream, Future};
 
+use codec::MTU;
+
 pub struct CongestionInfo {
     rtt_start: Option<Instant>,
     #[allow(unused)]
@@ -18,6 +22,8 @@ pub struct CongestionInfo {
     packets_since_last_notify: u32,
     delay: Option<Delay>,
     done: bool,
+    /// bytes per second allotted to the client, see `set_rate`
+    rate: Option<u64>,
 }
 
 impl CongestionInfo {
@@ -29,10 +35,11 @@ impl CongestionInfo {
             last_ipt: None,
             ipts: VecDeque::with_capacity(10),
             ipt: Duration::from_millis(0),
-            last_notify: Instant::now(),
+            last_notify: clock::now(),
             packets_since_last_notify: 0,
             delay: None,
             done: false,
+            rate: None,
         }
     }
 
@@ -41,14 +48,14 @@ impl CongestionInfo {
         if self.rtt_start.is_some() {
             panic!("Called `start_rtt` with ongoing RTT");
         }
-        self.rtt_start = Some(Instant::now());
+        self.rtt_start = Some(clock::now());
     }
 
     pub fn stop_rtt(&mut self) {
         // TODO: moving average
         match self.rtt_start.take() {
             Some(rtt_start) => {
-                self.rtt = rtt_start.elapsed();
+                self.rtt = clock::now() - rtt_start;
                 self.rtts.push_back(self.rtt);
             },
             None => panic!("Called `stop_rtt` without a previous `start_rtt`")
@@ -69,10 +76,10 @@ impl CongestionInfo {
     pub fn ipt_packet(&mut self) {
         // IPT calculation
         if let None = self.last_ipt {
-            self.last_ipt = Some(Instant::now());
+            self.last_ipt = Some(clock::now());
             return;
         }
-        let now = Instant::now();
+        let now = clock::now();
         let old = mem::replace(&mut self.last_ipt, Some(now));
         let diff = now.duration_since(old.unwrap());
         if self.ipts.len() == 10 {
@@ -92,15 +99,32 @@ impl CongestionInfo {
         }
     }
 
+    /// Spaces status updates as if the client sent no faster than `rate` bytes per second.
+    pub fn set_rate(&mut self, rate: u64) {
+        self.rate = Some(rate);
+    }
+
+    /// Inter-packet time, at least the one of full datagrams at the allotted rate.
+    fn paced_ipt(&self) -> Duration {
+        match self.rate {
+            Some(rate) => {
+                let nanos = MTU as u64 * 1_000_000_000 / cmp::max(rate, 1);
+                cmp::max(self.ipt, Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32))
+            }
+            None => self.ipt,
+        }
+    }
+
     pub fn num_packets(&self) -> u32 {
-        let ipt = self.ipt.as_secs() as f64 + self.ipt.subsec_nanos() as f64 * 1e-9;
+        let ipt = self.paced_ipt();
+        let ipt = ipt.as_secs() as f64 + ipt.subsec_nanos() as f64 * 1e-9;
         let pps = 1.0 / ipt;
         let num_packets = pps / (pps + 1.0).ln();
         num_packets as u32
     }
 
     fn update_delay(&mut self) {
-        let time_left = self.ipt * self.num_packets() + self.rtt;
+        let time_left = self.paced_ipt() * self.num_packets() + self.rtt;
         if let Some(ref mut delay) = self.delay {
             delay.reset(self.last_notify + time_left);
         } else {
@@ -127,13 +151,13 @@ impl Stream for CongestionInfo {
         match self.delay.as_mut().unwrap().poll() {
             Ok(Async::NotReady) if self.packets_since_last_notify >= self.num_packets() => {
                 self.packets_since_last_notify = 0;
-                self.last_notify = Instant::now();
+                self.last_notify = clock::now();
                 self.update_delay();
                 return Ok(Async::Ready(Some(())));
             }
             Ok(Async::Ready(())) => {
                 self.packets_since_last_notify = 0;
-                self.last_notify = Instant::now();
+                self.last_notify = clock::now();
                 self.update_delay();
                 return Ok(Async::Ready(Some(())));
             }
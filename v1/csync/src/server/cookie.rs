@@ -0,0 +1,87 @@
+//! Stateless cookies proving that a client receives packets sent to its source address.
+//!
+//! A login without a valid cookie is answered with a single small `Cookie` message,
+//! nothing else is created for it. Thus spoofed logins can neither fill the disk nor
+//! make the server flood the forged address with status updates.
+
+use std::net::{IpAddr, SocketAddr};
+
+use byteorder::{ByteOrder, WriteBytesExt, LE};
+use ring::{constant_time, digest, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Seconds a cookie stays valid.
+pub const COOKIE_LIFETIME: u64 = 30;
+const MAC_LEN: usize = 16;
+/// A cookie is the time it was issued followed by a truncated MAC.
+pub const COOKIE_LEN: usize = 8 + MAC_LEN;
+
+pub struct CookieJar {
+    key: hmac::SigningKey,
+}
+
+impl CookieJar {
+    /// Creates a jar with a random key, cookies of other jars aren't accepted.
+    pub fn new() -> CookieJar {
+        let mut key = [0; 32];
+        SystemRandom::new().fill(&mut key).expect("Can't generate cookie key");
+        CookieJar { key: hmac::SigningKey::new(&digest::SHA256, &key) }
+    }
+
+    /// Issues a cookie for `addr` at `now` seconds since the epoch.
+    pub fn issue(&self, addr: &SocketAddr, now: u64) -> Vec<u8> {
+        let mut cookie = Vec::with_capacity(COOKIE_LEN);
+        cookie.write_u64::<LE>(now).unwrap();
+        cookie.extend_from_slice(&self.mac(addr, now)[..MAC_LEN]);
+        cookie
+    }
+
+    /// Checks that the cookie was issued for `addr` within the last `COOKIE_LIFETIME` seconds.
+    pub fn verify(&self, cookie: &[u8], addr: &SocketAddr, now: u64) -> bool {
+        if cookie.len() != COOKIE_LEN {
+            return false;
+        }
+        let issued = LE::read_u64(&cookie[..8]);
+        if issued > now || now - issued > COOKIE_LIFETIME {
+            return false;
+        }
+        constant_time::verify_slices_are_equal(&cookie[8..], &self.mac(addr, issued)[..MAC_LEN]).is_ok()
+    }
+
+    fn mac(&self, addr: &SocketAddr, issued: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(16 + 2 + 8);
+        match addr.ip() {
+            IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+        }
+        data.write_u16::<LE>(addr.port()).unwrap();
+        data.write_u64::<LE>(issued).unwrap();
+        hmac::sign(&self.key, &data).as_ref().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cookie() {
+        let jar = CookieJar::new();
+        let addr: SocketAddr = "192.0.2.1:4242".parse().unwrap();
+        let cookie = jar.issue(&addr, 1000);
+        assert_eq!(cookie.len(), COOKIE_LEN);
+        assert!(jar.verify(&cookie, &addr, 1000));
+        assert!(jar.verify(&cookie, &addr, 1000 + COOKIE_LIFETIME));
+
+        // expired, from the future, for another address or port, tampered with, truncated
+        assert!(!jar.verify(&cookie, &addr, 1001 + COOKIE_LIFETIME));
+        assert!(!jar.verify(&cookie, &addr, 999));
+        assert!(!jar.verify(&cookie, &"192.0.2.2:4242".parse().unwrap(), 1000));
+        assert!(!jar.verify(&cookie, &"192.0.2.1:4243".parse().unwrap(), 1000));
+        let mut tampered = cookie.clone();
+        tampered[0] ^= 1;
+        assert!(!jar.verify(&tampered, &addr, 1000));
+        assert!(!jar.verify(&cookie[..COOKIE_LEN - 1], &addr, 1000));
+        assert!(!jar.verify(&[], &addr, 1000));
+    }
+}
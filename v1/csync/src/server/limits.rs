@@ -0,0 +1,75 @@
+//! Limits on the number of concurrent connections, in total and per client IP.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct Connections {
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    max_total: usize,
+    max_per_ip: usize,
+}
+
+/// An open connection, counted until dropped.
+pub struct Connection {
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Connections {
+    pub fn new(max_total: usize, max_per_ip: usize) -> Connections {
+        Connections {
+            open: Arc::new(Mutex::new(HashMap::new())),
+            max_total,
+            max_per_ip,
+        }
+    }
+
+    /// Counts a new connection from `ip`, `None` if that exceeds a limit.
+    pub fn open(&self, ip: IpAddr) -> Option<Connection> {
+        let mut open = self.open.lock().unwrap();
+        let total: usize = open.values().sum();
+        let from_ip = open.get(&ip).cloned().unwrap_or(0);
+        if total >= self.max_total || from_ip >= self.max_per_ip {
+            return None;
+        }
+        open.insert(ip, from_ip + 1);
+        Some(Connection { open: Arc::clone(&self.open), ip })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        let remove = {
+            let count = open.get_mut(&self.ip).unwrap();
+            *count -= 1;
+            *count == 0
+        };
+        if remove {
+            open.remove(&self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let connections = Connections::new(3, 2);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let a1 = connections.open(a).unwrap();
+        let _a2 = connections.open(a).unwrap();
+        assert!(connections.open(a).is_none());
+        let _b1 = connections.open(b).unwrap();
+        // the total is reached
+        assert!(connections.open(b).is_none());
+        drop(a1);
+        assert!(connections.open(b).is_some());
+        let _a3 = connections.open(a).unwrap();
+    }
+}
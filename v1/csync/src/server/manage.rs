@@ -0,0 +1,190 @@
+//! Deleting and renaming the uploaded files of a client.
+//!
+//! The upload of `path` lives in the directory `<folder>/<path>/`, holding the `file`,
+//! the `bitmap` of an incomplete upload, the `file.old` of a delta upload and the empty
+//! `stream` marking the bitmap of a streaming upload.
+//! Uploads can be nested, thus only these entries are touched, not whole directories.
+//! Uploads in progress are left alone.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use codec::Ack;
+use server::reaper::Uploads;
+
+const ENTRIES: [&str; 4] = ["file", "bitmap", "file.old", "stream"];
+
+/// Directory of the upload of `path`, `None` if it's outside of the client's folder.
+pub fn upload_dir(folder: &Path, path: &str) -> Option<PathBuf> {
+    let mut dir = folder.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => dir.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    if dir == folder {
+        return None;
+    }
+    Some(dir)
+}
+
+/// Removes the upload of `path`, which is done as well if there is none.
+pub fn delete(folder: &Path, uploads: &Uploads, path: &str) -> Ack {
+    let dir = match upload_dir(folder, path) {
+        Some(dir) => dir,
+        None => {
+            warn!("Refusing to delete {:?}", path);
+            return Ack::Failed;
+        }
+    };
+    match uploads.unless_active(&[&dir], || remove_upload(&dir)) {
+        Some(Ok(())) => {
+            remove_empty_dirs(folder, &dir);
+            Ack::Done
+        }
+        Some(Err(e)) => {
+            error!("Can't delete {}: {}", dir.display(), e);
+            Ack::Failed
+        }
+        None => {
+            warn!("Not deleting {}, it's being uploaded", dir.display());
+            Ack::Busy
+        }
+    }
+}
+
+/// Moves the upload of `from` to `to`, replacing the upload there.
+///
+/// If only `to` exists, an earlier rename whose ack got lost already moved it.
+pub fn rename(folder: &Path, uploads: &Uploads, from: &str, to: &str) -> Ack {
+    let (from, to) = match (upload_dir(folder, from), upload_dir(folder, to)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            warn!("Refusing to rename {:?} to {:?}", from, to);
+            return Ack::Failed;
+        }
+    };
+    let moved = uploads.unless_active(&[&from, &to], || -> io::Result<bool> {
+        if !from.join("file").exists() || from == to {
+            return Ok(to.join("file").exists());
+        }
+        remove_upload(&to)?;
+        fs::create_dir_all(&to)?;
+        for entry in &ENTRIES {
+            let source = from.join(entry);
+            if source.exists() {
+                fs::rename(source, to.join(entry))?;
+            }
+        }
+        remove_empty_dirs(folder, &from);
+        Ok(true)
+    });
+    match moved {
+        Some(Ok(true)) => Ack::Done,
+        Some(Ok(false)) => Ack::NotFound,
+        Some(Err(e)) => {
+            error!("Can't rename {} to {}: {}", from.display(), to.display(), e);
+            Ack::Failed
+        }
+        None => {
+            warn!("Not renaming {} to {}, one of them is being uploaded", from.display(), to.display());
+            Ack::Busy
+        }
+    }
+}
+
+fn remove_upload(dir: &Path) -> io::Result<()> {
+    for entry in &ENTRIES {
+        remove_file(&dir.join(entry))?;
+    }
+    Ok(())
+}
+
+/// Removes the file at `path`, which is done as well if there is none.
+pub fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Removes `dir` and its parents within `folder` as long as they are empty.
+pub fn remove_empty_dirs(folder: &Path, dir: &Path) {
+    let mut dir = dir;
+    while dir != folder && fs::remove_dir(dir).is_ok() {
+        dir = match dir.parent() {
+            Some(parent) => parent,
+            None => return,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn upload(folder: &Path, path: &str, entries: &[&str]) {
+        let dir = folder.join(path);
+        fs::create_dir_all(&dir).unwrap();
+        for entry in entries {
+            fs::write(dir.join(entry), path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_upload_dir() {
+        let folder = Path::new("files/abc");
+        assert_eq!(upload_dir(folder, "a/b"), Some(folder.join("a/b")));
+        assert_eq!(upload_dir(folder, "/a/./b"), Some(folder.join("a/b")));
+        assert_eq!(upload_dir(folder, "a/../../b"), None);
+        assert_eq!(upload_dir(folder, "/"), None);
+        assert_eq!(upload_dir(folder, ""), None);
+    }
+
+    #[test]
+    fn test_delete_rename() {
+        let folder = env::temp_dir().join(format!("csync-manage-{}", process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        upload(&folder, "a", &["file"]);
+        upload(&folder, "a/nested", &["file", "bitmap"]);
+        upload(&folder, "b/c", &["file", "bitmap", "file.old"]);
+        let uploads = Uploads::default();
+
+        // an incomplete upload is deleted with its state, empty directories are removed
+        assert_eq!(delete(&folder, &uploads, "b/c"), Ack::Done);
+        assert!(!folder.join("b").exists());
+        assert_eq!(delete(&folder, &uploads, "b/c"), Ack::Done);
+        // nested uploads survive
+        assert_eq!(delete(&folder, &uploads, "a"), Ack::Done);
+        assert!(!folder.join("a/file").exists());
+        assert!(folder.join("a/nested/bitmap").exists());
+        assert_eq!(delete(&folder, &uploads, "../a"), Ack::Failed);
+
+        upload(&folder, "x", &["file"]);
+        assert_eq!(rename(&folder, &uploads, "a/nested", "x"), Ack::Done);
+        assert!(!folder.join("a").exists());
+        assert_eq!(fs::read_to_string(folder.join("x/file")).unwrap(), "a/nested");
+        assert_eq!(fs::read_to_string(folder.join("x/bitmap")).unwrap(), "a/nested");
+        // repeated after a lost ack
+        assert_eq!(rename(&folder, &uploads, "a/nested", "x"), Ack::Done);
+        assert_eq!(rename(&folder, &uploads, "y", "z"), Ack::NotFound);
+        assert_eq!(rename(&folder, &uploads, "x", "/"), Ack::Failed);
+
+        // uploads in progress are left alone, also as the target of a rename
+        upload(&folder, "y", &["file"]);
+        let active = uploads.start(&folder.join("x"));
+        assert_eq!(delete(&folder, &uploads, "x"), Ack::Busy);
+        assert_eq!(rename(&folder, &uploads, "x", "z"), Ack::Busy);
+        assert_eq!(rename(&folder, &uploads, "y", "x"), Ack::Busy);
+        assert!(folder.join("x/bitmap").exists() && folder.join("y/file").exists());
+        drop(active);
+        assert_eq!(delete(&folder, &uploads, "x"), Ack::Done);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -1,9 +1,9 @@
 use std::net::SocketAddr;
 use std::io;
 use std::net::UdpSocket as StdUdpSocket;
-use std::time::Duration;
-use std::sync::{Arc, Mutex};
-use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
 
 use futures::sync::mpsc;
 use futures::{Future, Stream, Sink, future};
@@ -12,31 +12,78 @@ use tokio::reactor::Handle;
 use tokio;
 use net2;
 use memmap::MmapMut;
-use bitte_ein_bit::BitMap;
+use bitte_ein_bit::AtomicBitMap;
 
-use codec::{MTU, Login};
+use codec::{self, MTU, Login, Command, Ack};
 use timeout::TimeoutStream;
+use transport::Transport;
 use Opt;
 
 mod listener;
 mod receiver;
 mod sender;
 mod congestion;
+mod manage;
+mod cookie;
+mod limits;
+mod reaper;
+pub mod bandwidth;
+
+pub use self::cookie::CookieJar;
+use self::limits::Connections;
+use self::bandwidth::{Bandwidth, Share};
+use self::reaper::Reaper;
+pub use self::reaper::Uploads;
+
+/// Folder the uploaded files are kept in.
+const FILES: &str = "./files";
+
+/// State shared by all connections.
+struct Shared {
+    /// the main socket, answering logins without a valid cookie
+    replies: StdUdpSocket,
+    jar: CookieJar,
+    connections: Connections,
+    bandwidth: Bandwidth,
+    uploads: Uploads,
+}
 
 pub enum ChannelMessage {
-    UploadStart(Arc<Mutex<BitMap<MmapMut>>>),
-    UploadStatus,
+    /// bitmap of the upload, the accepted features, `None` if the client requested none, and
+    /// the digest of the chunks a resumed stream kept, see `StatusUpdate::prefix`
+    UploadStart(Arc<RwLock<AtomicBitMap<MmapMut>>>, Option<u64>, Vec<u8>),
+    /// send a status update, with the number of chunks rebuilt from parity
+    UploadStatus(u64),
+    /// send the encoded `Signatures` messages of the old copy, signed as they're sent, paced
+    Signatures(Box<Iterator<Item = io::Result<Vec<u8>>> + Send>),
+    /// answer a `Delete` or `Rename`
+    Ack(Ack),
+    /// send the bytes per second the client may use
+    RateHint(u64),
 }
 
 pub fn run(opt: Opt) {
-    let listener = get_socket(&opt).expect("Can't bind main UdpSocket");
-    let listener = listener::Listener::new(listener);
-
-    let server = listener.for_each(move |(buf, size, addr)| {
-        trace!("connection from {}: {:?}", addr, &buf[..size]);
-        let client = handle_client(buf, size, addr, &opt);
-        tokio::spawn(client);
-        Ok(())
+    let listener = get_std_socket(&opt).expect("Can't bind main UdpSocket");
+    // cookies are sent from the main socket, before anything is created for the client
+    let shared = Shared {
+        replies: listener.try_clone().expect("Can't clone main UdpSocket"),
+        jar: CookieJar::new(),
+        connections: Connections::new(opt.max_connections, opt.max_connections_per_ip),
+        bandwidth: Bandwidth::new(opt.bandwidth, &opt.token_weight),
+        uploads: Uploads::default(),
+    };
+    let reaper = Reaper::new(Path::new(FILES), shared.uploads.clone(),
+                             Duration::from_secs(opt.reap_after), opt.reap_above);
+
+    let server = future::lazy(move || {
+        tokio::spawn(reaper.run());
+        let listener = UdpSocket::from_std(listener, &Handle::current()).expect("Can't register main UdpSocket");
+        listener::Listener::new(listener).for_each(move |(buf, size, addr)| {
+            trace!("connection from {}: {:?}", addr, &buf[..size]);
+            let client = handle_client(buf, size, addr, &opt, &shared);
+            tokio::spawn(client);
+            Ok(())
+        })
     }).map_err(|e| eprintln!("Error during server: {:?}", e));
 
     tokio::run(server);
@@ -48,10 +95,6 @@ fn get_std_socket(opt: &Opt) -> io::Result<StdUdpSocket> {
         .bind((opt.host.as_str(), opt.port))
 }
 
-fn get_socket(opt: &Opt) -> io::Result<UdpSocket> {
-    UdpSocket::from_std(get_std_socket(opt)?, &Handle::current())
-}
-
 fn get_sockets(opt: &Opt) -> io::Result<(UdpSocket, UdpSocket)> {
     let std_sock = get_std_socket(opt)?;
     let std_sock2 = std_sock.try_clone()?;
@@ -60,23 +103,78 @@ fn get_sockets(opt: &Opt) -> io::Result<(UdpSocket, UdpSocket)> {
     Ok((sock, sock2))
 }
 
-type BoxedFuture = Box<Future<Item = (), Error = ()> + Send>;
+pub type BoxedFuture = Box<Future<Item = (), Error = ()> + Send>;
 
-fn handle_client(buf: [u8; MTU], size: usize, addr: SocketAddr, opt: &Opt) -> BoxedFuture {
+fn handle_client(buf: [u8; MTU], size: usize, addr: SocketAddr, opt: &Opt, shared: &Shared) -> BoxedFuture {
+    let reply = |reply: &[u8]| if let Err(e) = shared.replies.send_to(reply, addr) {
+        warn!("Can't send cookie to {}: {}", addr, e);
+    };
+    let login = match admit(&buf[..size], &addr, &shared.jar, reply) {
+        Some(login) => login,
+        None => return Box::new(future::ok(())),
+    };
+    let connection = match shared.connections.open(addr.ip()) {
+        Some(connection) => connection,
+        None => {
+            warn!("Too many connections, dropping login from {}", addr);
+            return Box::new(future::ok(()));
+        }
+    };
     let (sock, sock2) = get_sockets(opt).expect("Can't create client UdpSocket");
     sock.connect(&addr).expect("Can't connect to client");
     sock2.connect(&addr).expect("Can't connect to client");
 
-    let (tx, rx) = mpsc::unbounded();
-    let login = match Login::decode(&buf[..size]) {
+    let share = match login.command {
+        Command::UploadRequest(_) => Some(shared.bandwidth.share(login.client_token)),
+        _ => None,
+    };
+    let client = serve(sock, sock2, login, share, shared.uploads.clone(), Path::new(FILES), opt.restore_owner)
+        .then(move |result| {
+            drop(connection);
+            result
+        });
+    Box::new(client)
+}
+
+/// Decodes a login, answering it with a cookie through `reply` unless it carries a valid one.
+///
+/// Returns the login once the client proved that it receives what is sent to `addr`.
+pub fn admit<'a, F: FnOnce(&[u8])>(buf: &'a [u8], addr: &SocketAddr, jar: &CookieJar, reply: F) -> Option<Login<'a>> {
+    // don't bother creating sockets for garbage
+    let login = match Login::decode(buf) {
         Ok(login) => login,
         Err(e) => {
-            error!("Invalid Login Message: {}", e);
-            return Box::new(future::err(()));
+            debug!("Invalid Login Message from {}: {}", addr, e);
+            return None;
         }
     };
-    let stream = receiver::Receiver::new(sock, login, tx);
-    let sink = sender::Sender::new(sock2);
+    // the source address may be spoofed, answer with a single cookie until it's proven
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if !jar.verify(login.cookie, addr, now) {
+        let mut cookie = Vec::with_capacity(MTU);
+        codec::write_cookie(&jar.issue(addr, now), &mut cookie);
+        // no larger answers than the spoofed login, see `codec::MIN_LOGIN_LEN`
+        if buf.len() < cookie.len() {
+            debug!("Dropping login of {} bytes without cookie from {}", buf.len(), addr);
+            return None;
+        }
+        debug!("Sending cookie to {}", addr);
+        reply(&cookie);
+        return None;
+    }
+    Some(login)
+}
+
+/// Serves an admitted client, receiving from `recv` and sending through `send` until it's done
+/// or times out.
+///
+/// The owner of uploaded files is only restored if `restore_owner` is set.
+pub fn serve<R, S>(recv: R, send: S, login: Login, share: Option<Share>, uploads: Uploads, root: &Path,
+                   restore_owner: bool) -> BoxedFuture
+    where R: Transport + Send + 'static, S: Transport + Send + 'static {
+    let (tx, rx) = mpsc::unbounded();
+    let stream = receiver::Receiver::new(recv, login, tx, share, uploads, root, restore_owner);
+    let sink = sender::Sender::new(send);
 
     let sender = TimeoutStream::new(rx, Duration::from_secs(10))
         .map_err(|e| eprintln!("Error in channel-receiver: {:?}", e))
@@ -91,5 +189,42 @@ fn handle_client(buf: [u8; MTU], size: usize, addr: SocketAddr, opt: &Opt) -> Bo
         .map(|(res, _)| println!("Client finished successfully: {:?}", res))
         .map_err(|(err, _)| println!("Client finished with error: {:?}", err));
     Box::new(client)
+}
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::{Delete, ServerMessage, MIN_LOGIN_LEN};
+
+    #[test]
+    fn test_admit() {
+        let jar = CookieJar::new();
+        let addr: SocketAddr = "192.0.2.1:4242".parse().unwrap();
+        let login = |cookie: &[u8], padded: bool| {
+            let mut login = Vec::new();
+            Login { client_token: b"t", cookie, command: Command::Delete(Delete { path: "f" }) }.encode(&mut login);
+            if padded {
+                login.resize(MIN_LOGIN_LEN, 0);
+            }
+            login
+        };
+
+        // too short to be answered
+        let mut replies = Vec::new();
+        assert!(admit(&login(&[], false), &addr, &jar, |reply| replies.push(reply.to_vec())).is_none());
+        assert!(replies.is_empty());
+
+        assert!(admit(&login(&[], true), &addr, &jar, |reply| replies.push(reply.to_vec())).is_none());
+        let cookie = match ServerMessage::decode(&replies[0]).unwrap() {
+            ServerMessage::Cookie(cookie) => cookie.to_vec(),
+            message => panic!("{:?}", message),
+        };
+        assert!(replies[0].len() <= MIN_LOGIN_LEN);
+
+        let padded = login(&cookie, true);
+        match admit(&padded, &addr, &jar, |_| panic!("answered a valid cookie")).unwrap().command {
+            Command::Delete(delete) => assert_eq!(delete.path, "f"),
+            command => panic!("{:?}", command),
+        }
+    }
 }
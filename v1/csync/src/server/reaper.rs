@@ -0,0 +1,242 @@
+//! Removal of stale incomplete uploads.
+//!
+//! An aborted upload leaves its preallocated `file` and its `bitmap` behind, to be resumed.
+//! Those untouched for too long, or the oldest ones while the uploads take too much disk
+//! space, are reaped. An interrupted delta upload gets its `file.old` back.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{Future, Stream};
+use tokio::timer::Interval;
+use walkdir::WalkDir;
+
+use server::manage;
+
+/// Interval in which the uploads are checked.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Directories of the uploads in progress, which are never reaped.
+#[derive(Clone, Default)]
+pub struct Uploads {
+    active: Arc<Mutex<HashMap<PathBuf, usize>>>,
+}
+
+/// An upload in progress, counted until dropped.
+pub struct ActiveUpload {
+    active: Arc<Mutex<HashMap<PathBuf, usize>>>,
+    dir: PathBuf,
+}
+
+impl Uploads {
+    /// Marks the upload in `dir` as active, call before touching its files.
+    pub fn start(&self, dir: &Path) -> ActiveUpload {
+        *self.active.lock().unwrap().entry(dir.to_path_buf()).or_insert(0) += 1;
+        ActiveUpload { active: Arc::clone(&self.active), dir: dir.to_path_buf() }
+    }
+
+    /// Runs `f` unless one of the uploads in `dirs` is active, returning `None` then.
+    ///
+    /// No upload starts while `f` runs, so it can't touch the files of one.
+    pub fn unless_active<R, F: FnOnce() -> R>(&self, dirs: &[&Path], f: F) -> Option<R> {
+        let active = self.active.lock().unwrap();
+        if dirs.iter().any(|dir| active.contains_key(*dir)) {
+            return None;
+        }
+        Some(f())
+    }
+}
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        let remove = {
+            let count = active.get_mut(&self.dir).unwrap();
+            *count -= 1;
+            *count == 0
+        };
+        if remove {
+            active.remove(&self.dir);
+        }
+    }
+}
+
+pub struct Reaper {
+    /// folder of all clients' uploads
+    root: PathBuf,
+    uploads: Uploads,
+    /// age after which an incomplete upload is reaped, zero for never
+    max_age: Duration,
+    /// bytes the uploads may take on disk before the oldest incomplete ones are reaped, zero for unlimited
+    max_usage: u64,
+}
+
+/// Space reclaimed by a single run of the `Reaper`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reclaimed {
+    pub uploads: u64,
+    pub bytes: u64,
+}
+
+struct Incomplete {
+    dir: PathBuf,
+    modified: SystemTime,
+    /// bytes on disk of the `file` and the `bitmap`
+    size: u64,
+}
+
+impl Reaper {
+    pub fn new(root: &Path, uploads: Uploads, max_age: Duration, max_usage: u64) -> Reaper {
+        Reaper { root: root.to_path_buf(), uploads, max_age, max_usage }
+    }
+
+    /// Reaps every `REAP_INTERVAL`, forever.
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        Interval::new(Instant::now() + REAP_INTERVAL, REAP_INTERVAL)
+            .map_err(|e| error!("Reaper timer failed: {}", e))
+            .for_each(move |_| {
+                match self.reap(SystemTime::now()) {
+                    Ok(Reclaimed { uploads: 0, .. }) => {}
+                    Ok(reclaimed) => info!("Reaped {} incomplete uploads, reclaiming {} bytes",
+                                           reclaimed.uploads, reclaimed.bytes),
+                    Err(e) => error!("Can't reap incomplete uploads in {}: {}", self.root.display(), e),
+                }
+                Ok(())
+            })
+    }
+
+    /// Removes the incomplete uploads which are too old or, oldest first, take too much space.
+    pub fn reap(&self, now: SystemTime) -> io::Result<Reclaimed> {
+        if self.max_age == Duration::from_secs(0) && self.max_usage == 0 {
+            return Ok(Reclaimed::default());
+        }
+        let (mut usage, mut incomplete) = self.scan()?;
+        incomplete.sort_by_key(|upload| upload.modified);
+
+        let mut reclaimed = Reclaimed::default();
+        for upload in incomplete {
+            let age = now.duration_since(upload.modified).unwrap_or_default();
+            let too_old = self.max_age != Duration::from_secs(0) && age >= self.max_age;
+            let too_big = self.max_usage != 0 && usage > self.max_usage;
+            if !too_old && !too_big {
+                continue;
+            }
+            // held until the upload is gone, so it can't be resumed halfway through
+            let active = self.uploads.active.lock().unwrap();
+            if active.contains_key(&upload.dir) {
+                debug!("Not reaping {}, it's being uploaded", upload.dir.display());
+                continue;
+            }
+            info!("Reaping incomplete upload {}, last modified {}s ago", upload.dir.display(), age.as_secs());
+            reap_upload(&upload.dir)?;
+            manage::remove_empty_dirs(&self.root, &upload.dir);
+            usage = usage.saturating_sub(upload.size);
+            reclaimed.uploads += 1;
+            reclaimed.bytes += upload.size;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Returns the bytes all uploads take on disk and the incomplete uploads.
+    fn scan(&self) -> io::Result<(u64, Vec<Incomplete>)> {
+        let mut usage = 0;
+        let mut incomplete = Vec::new();
+        if !self.root.exists() {
+            return Ok((usage, incomplete));
+        }
+        for entry in WalkDir::new(&self.root) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            // preallocated files are sparse, only count what they take
+            usage += entry.metadata()?.blocks() * 512;
+            if entry.file_name() != "bitmap" {
+                continue;
+            }
+            let dir = entry.path().parent().unwrap().to_path_buf();
+            let mut modified = UNIX_EPOCH;
+            let mut size = 0;
+            for name in &["file", "bitmap"] {
+                match fs::metadata(dir.join(name)) {
+                    Ok(metadata) => {
+                        modified = cmp::max(modified, metadata.modified()?);
+                        size += metadata.blocks() * 512;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            incomplete.push(Incomplete { dir, modified, size });
+        }
+        Ok((usage, incomplete))
+    }
+}
+
+/// Removes the incomplete upload in `dir`, restoring the previous copy of a delta upload.
+fn reap_upload(dir: &Path) -> io::Result<()> {
+    let old = dir.join("file.old");
+    if old.exists() {
+        fs::rename(old, dir.join("file"))?;
+    } else {
+        manage::remove_file(&dir.join("file"))?;
+    }
+    manage::remove_file(&dir.join("stream"))?;
+    manage::remove_file(&dir.join("bitmap"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn upload(root: &Path, path: &str, entries: &[&str]) -> PathBuf {
+        let dir = root.join(path);
+        fs::create_dir_all(&dir).unwrap();
+        for entry in entries {
+            fs::write(dir.join(entry), entry).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_reap() {
+        let root = env::temp_dir().join(format!("csync-reaper-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let uploads = Uploads::default();
+        let stale = upload(&root, "token/a/stale", &["file", "bitmap"]);
+        let active = upload(&root, "token/active", &["file", "bitmap"]);
+        let complete = upload(&root, "token/complete", &["file"]);
+        let delta = upload(&root, "token/delta", &["file", "bitmap", "file.old"]);
+        let guard = uploads.start(&active);
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let reaper = Reaper::new(&root, uploads.clone(), day, 0);
+        assert_eq!(reaper.reap(SystemTime::now()).unwrap().uploads, 0);
+        assert_eq!(reaper.reap(SystemTime::now() + day).unwrap().uploads, 2);
+        assert!(!stale.exists());
+        assert!(!root.join("token/a").exists());
+        assert!(active.join("bitmap").exists());
+        assert!(complete.join("file").exists());
+        assert!(!delta.join("bitmap").exists());
+        assert_eq!(fs::read_to_string(delta.join("file")).unwrap(), "file.old");
+        assert!(!delta.join("file.old").exists());
+
+        // the active upload is kept regardless of the space it takes
+        let reaper = Reaper::new(&root, uploads.clone(), Duration::from_secs(0), 1);
+        assert_eq!(reaper.reap(SystemTime::now()).unwrap().uploads, 0);
+        drop(guard);
+        assert_eq!(reaper.reap(SystemTime::now()).unwrap().uploads, 1);
+        assert!(!active.exists());
+        assert!(complete.join("file").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
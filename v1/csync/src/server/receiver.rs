@@ -1,30 +1,77 @@
-use std::io::{Seek, SeekFrom, Error as IoError};
+use std::io::{BufRead, BufReader, Read, Write, Seek, SeekFrom, Error as IoError};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::mem;
 use std::fs::{self, File as StdFile, OpenOptions};
 use std::path::{PathBuf, Path};
-use std::sync::{Arc, Mutex};
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use futures::{Stream, Async, Poll, Future};
+use futures::{task, Stream, Async, Poll, Future};
 use futures::sync::mpsc::UnboundedSender;
+use tokio::clock;
 use tokio::io;
-use tokio::net::UdpSocket;
 use tokio::reactor::{PollEvented2, Handle};
 use tokio_file_unix::File;
 use ring::digest;
 use hex::ToHex;
-use bitte_ein_bit::BitMap;
+use bitte_ein_bit::{AtomicBitMap, BitMap};
 use memmap::{MmapMut, MmapOptions};
+use flate2::Decompress;
+use libc;
 
-use codec::{self, MTU, Login, Command, UploadRequest, Chunk, ChunkInfo};
+use codec::{self, MTU, Login, Command, UploadRequest, Ack, Chunk, ChunkInfo, Parity, ZeroRun, CopyRange, Metadata,
+            EndOfStream, STREAM_CHUNK_SIZE, features, extension};
+use fec;
+use delta;
+use stream;
+use server::bandwidth::Share;
 use server::congestion::CongestionInfo;
+use server::reaper::{Uploads, ActiveUpload};
+use server::manage;
 use server::ChannelMessage;
+use transport::Transport;
 
-pub struct Receiver {
+/// Interval in which an unchanged rate hint is repeated, in case it got lost.
+const RATE_HINT_INTERVAL: Duration = Duration::from_secs(1);
+/// Chunks copied from the old copy per poll, so copying doesn't hold up the event loop.
+const COPY_STEP: u64 = 16;
+/// Copy ranges waiting to be copied, further ones are dropped and sent again by the client.
+const PENDING_COPIES: usize = 256;
+
+pub struct Receiver<T> {
     state: State,
-    socket: UdpSocket,
+    socket: T,
     tx: UnboundedSender<ChannelMessage>,
     folder: PathBuf,
     congestion: CongestionInfo,
+    /// share of the server's bandwidth while uploading, sent to the client as rate hints
+    share: Option<Share>,
+    /// last rate hint sent and when
+    last_hint: Option<(u64, Instant)>,
+    /// features accepted for the current upload
+    features: u64,
+    /// the client requested no features, it only understands bare status updates
+    bare: bool,
+    decompress: Decompress,
+    /// chunks rebuilt from parity, reported in status updates
+    rebuilt: u64,
+    /// previous copy of the file for a delta upload
+    old: Option<StdFile>,
+    /// ranges still to copy from `old`, a few chunks per poll
+    copies: VecDeque<CopyRange>,
+    /// applied to the file once the upload is complete
+    metadata: Option<Metadata>,
+    /// the owner in `metadata` is applied, otherwise it's ignored
+    restore_owner: bool,
+    /// length of a streaming upload, once the client sent it
+    stream_length: Option<u64>,
+    uploads: Uploads,
+    /// keeps the reaper away from the upload's files
+    active: Option<ActiveUpload>,
 }
 
 pub enum State {
@@ -37,7 +84,7 @@ pub enum State {
 
 pub struct WaitForChunk {
     file: PollEvented2<File<StdFile>>,
-    bitmap: Arc<Mutex<BitMap<MmapMut>>>,
+    bitmap: Arc<RwLock<AtomicBitMap<MmapMut>>>,
     bitmap_path: PathBuf,
     buf: Vec<u8>,
     chunk_info: ChunkInfo,
@@ -46,21 +93,22 @@ pub struct WaitForChunk {
 type WriteChunk = io::WriteAll<PollEvented2<File<StdFile>>, Chunk>;
 
 pub struct WritingChunk {
-    bitmap: Arc<Mutex<BitMap<MmapMut>>>,
+    bitmap: Arc<RwLock<AtomicBitMap<MmapMut>>>,
     bitmap_path: PathBuf,
     chunk_info: ChunkInfo,
     future: WriteChunk,
 }
 
-impl Receiver {
-    pub fn new(socket: UdpSocket, login: Login, tx: UnboundedSender<ChannelMessage>) -> Receiver {
+impl<T: Transport> Receiver<T> {
+    /// Creates the receiver of a client whose files are kept in a folder of `root`.
+    pub fn new(socket: T, login: Login, tx: UnboundedSender<ChannelMessage>, share: Option<Share>,
+               uploads: Uploads, root: &Path, restore_owner: bool) -> Receiver<T> {
         debug!("Login");
         trace!("Client Token: {:?}", login);
         let sha = digest::digest(&digest::SHA256, login.client_token);
         let mut hex = String::with_capacity(digest::SHA256_OUTPUT_LEN * 2);
         sha.as_ref().write_hex(&mut hex).unwrap();
-        let mut path = PathBuf::from("./files/");
-        path.push(hex);
+        let path = root.join(hex);
         debug!("Folder: {}", path.display());
         let mut receiver = Receiver {
             state: State::Invalid,
@@ -68,9 +116,21 @@ impl Receiver {
             tx,
             folder: path,
             congestion: CongestionInfo::new(),
+            share,
+            last_hint: None,
+            features: 0,
+            bare: false,
+            decompress: Decompress::new(false),
+            rebuilt: 0,
+            old: None,
+            copies: VecDeque::new(),
+            metadata: None,
+            restore_owner,
+            stream_length: None,
+            uploads,
+            active: None,
         };
         receiver.command(login.command);
-        receiver.tx.unbounded_send(ChannelMessage::UploadStatus).unwrap();
         receiver.congestion.start_rtt();
         receiver
     }
@@ -78,24 +138,99 @@ impl Receiver {
     pub fn command(&mut self, command: Command) {
         match command {
             Command::UploadRequest(req) => self.upload_request(req),
+            Command::Delete(delete) => {
+                debug!("delete: {:?}", delete);
+                let ack = manage::delete(&self.folder, &self.uploads, delete.path);
+                self.acknowledge(ack);
+            }
+            Command::Rename(rename) => {
+                debug!("rename: {:?}", rename);
+                let ack = manage::rename(&self.folder, &self.uploads, rename.from, rename.to);
+                self.acknowledge(ack);
+            }
         }
     }
 
+    /// Answers a command which is done right away and waits for the connection to time out.
+    fn acknowledge(&mut self, ack: Ack) {
+        debug!("Ack {:?}", ack);
+        self.tx.unbounded_send(ChannelMessage::Ack(ack)).unwrap();
+        self.congestion.shutdown();
+        self.state = State::Shutdown(Vec::with_capacity(MTU));
+    }
+
 
     pub fn upload_request(&mut self, req: UploadRequest) {
         debug!("upload request: {:?}", req);
 
-        let chunk_info = codec::index_field_size(req.length);
+        self.features = req.features & features::SUPPORTED;
+        self.bare = req.features == 0;
+        let stream = self.features & features::STREAM != 0;
+        if stream {
+            self.features &= features::STREAM | features::RESUME;
+        } else {
+            self.features &= !features::RESUME;
+        }
+        let chunk_info = if stream { codec::stream_chunk_info(0) } else { codec::index_field_size(req.length, req.features) };
+        debug!("Accepted features: {:#x}", self.features);
+        let restore_owner = self.restore_owner;
+        self.metadata = req.metadata.map(|m| Metadata { owner: m.owner.filter(|_| restore_owner), ..m });
         let mut req_path = Path::new(req.path);
         if req_path.has_root() {
             req_path = req_path.strip_prefix("/").unwrap();
         }
         let path = self.folder.join(req_path);
+        self.active = Some(self.uploads.start(&path));
         fs::create_dir_all(&path).unwrap();
         let file_path = path.join("file");
         let bitmap_path = path.join("bitmap");
+        let old_path = path.join("file.old");
+        let stream_path = path.join("stream");
+        let resumed = if self.features & features::RESUME != 0 {
+            resumable_chunks(&file_path, &bitmap_path, &stream_path)
+        } else {
+            0
+        };
+        // the client only appends to the kept chunks if their digest matches its input
+        let (resumed, prefix) = if self.features & features::RESUME != 0 {
+            match prefix_digest(&file_path, resumed * STREAM_CHUNK_SIZE) {
+                Ok(prefix) => (resumed, prefix.as_ref().to_vec()),
+                Err(e) => {
+                    warn!("Can't read the kept chunks to resume: {}", e);
+                    (0, digest::digest(&digest::SHA256, b"").as_ref().to_vec())
+                }
+            }
+        } else {
+            (0, Vec::new())
+        };
+        if resumed > 0 {
+            debug!("Resuming after {} chunks", resumed);
+        }
 
-        let continue_upload = bitmap_path.exists();
+        // a streaming upload at most keeps the chunks in a row, see `resumable_chunks`, and the
+        // bitmap doesn't fit a file whose length changed or one of stream chunks
+        let continue_upload = !stream && bitmap_path.exists() && !stream_path.exists()
+            && fs::metadata(&file_path).map(|m| m.len() == req.length).unwrap_or(false);
+
+        if !continue_upload {
+            if self.features & features::DELTA != 0 && file_path.exists() {
+                debug!("Keeping the previous upload for a delta upload");
+                fs::rename(&file_path, &old_path).unwrap();
+            } else if old_path.exists() {
+                fs::remove_file(&old_path).unwrap();
+            }
+        }
+        self.copies.clear();
+        if self.features & features::DELTA != 0 && old_path.exists() {
+            match StdFile::open(&old_path) {
+                Ok(old) => {
+                    let signatures = sign_old_copy(&old_path, chunk_info.chunk_size);
+                    self.tx.unbounded_send(ChannelMessage::Signatures(signatures)).unwrap();
+                    self.old = Some(old);
+                }
+                Err(e) => warn!("Can't open the old copy, uploading in full: {}", e),
+            }
+        }
 
         let bitmap_file = OpenOptions::new()
             .read(true)
@@ -104,9 +239,13 @@ impl Receiver {
             .open(&bitmap_path)
             .unwrap();
 
-        let bitmap_file_len = (chunk_info.num_chunks + 7) / 8;
+        let num_bits = if stream { stream::initial_len(resumed) } else { chunk_info.num_chunks };
+        // an empty file can't be mapped
+        let bitmap_file_len = cmp::max((num_bits + 7) / 8, 1);
         if !continue_upload {
             debug!("New File");
+            // clears the bitmap of an abandoned upload
+            bitmap_file.set_len(0).unwrap();
             bitmap_file.set_len(bitmap_file_len).unwrap();
         }
 
@@ -115,7 +254,13 @@ impl Receiver {
                 .map_mut(&bitmap_file)
                 .unwrap()
         };
-        let mut bitmap = BitMap::with_length(mmap, chunk_info.num_chunks);
+        let bitmap = AtomicBitMap::with_length(mmap, num_bits);
+        bitmap.set_range(..resumed);
+        // marks the bitmap as one of stream chunks, which a resumed stream can keep
+        let marked = if stream { fs::write(&stream_path, b"") } else { manage::remove_file(&stream_path) };
+        if let Err(e) = marked {
+            warn!("Can't mark the upload as streamed or not: {}", e);
+        }
 
 
         let mut file = OpenOptions::new();
@@ -124,15 +269,22 @@ impl Receiver {
             if bitmap.all() {
                 warn!("Continue upload, but all chunks are already received.");
             }
-            file.append(true);
+        } else if resumed == 0 {
+            // chunks are written at their offset, the rest of a new file must read as zeros
+            file.truncate(true);
         }
         let file = file.create(true)
+            .read(true)
             .write(true)
             .open(file_path).unwrap();
-        file.set_len(req.length as u64).unwrap();
+        // a resumed stream drops what follows the kept chunks
+        let length = if stream { resumed * STREAM_CHUNK_SIZE } else { req.length };
+        file.set_len(length).unwrap();
 
-        let bitmap = Arc::new(Mutex::new(bitmap));
-        self.tx.unbounded_send(ChannelMessage::UploadStart(Arc::clone(&bitmap))).unwrap();
+        let bitmap = Arc::new(RwLock::new(bitmap));
+        self.tx.unbounded_send(ChannelMessage::UploadStart(Arc::clone(&bitmap),
+                                                             if self.bare { None } else { Some(self.features) },
+                                                             prefix)).unwrap();
 
         self.state = State::WaitForChunk(WaitForChunk {
             file: File::new_nb(file).unwrap().into_io(&Handle::current()).unwrap(),
@@ -141,6 +293,26 @@ impl Receiver {
             buf: Vec::with_capacity(MTU),
             chunk_info,
         });
+        self.rate_hint();
+        self.tx.unbounded_send(ChannelMessage::UploadStatus(0)).unwrap();
+    }
+
+    /// Tells the client its current share of the server's bandwidth, if that is limited and
+    /// changed or wasn't repeated for a while.
+    fn rate_hint(&mut self) {
+        let rate = match self.share.as_ref().and_then(Share::rate) {
+            Some(rate) => rate,
+            None => return,
+        };
+        self.congestion.set_rate(rate);
+        let due = match self.last_hint {
+            Some((last, sent)) => last != rate || clock::now() - sent >= RATE_HINT_INTERVAL,
+            None => true,
+        };
+        if due && !self.bare {
+            self.tx.unbounded_send(ChannelMessage::RateHint(rate)).unwrap();
+            self.last_hint = Some((rate, clock::now()));
+        }
     }
 
     pub fn ack(&mut self) {
@@ -154,9 +326,9 @@ impl Receiver {
     }
 
     pub fn chunk(&mut self, chunk: Chunk, chunk_info: ChunkInfo, bitmap_path: PathBuf,
-                 mut file: PollEvented2<File<StdFile>>, bitmap: Arc<Mutex<BitMap<MmapMut>>>) {
+                 mut file: PollEvented2<File<StdFile>>, bitmap: Arc<RwLock<AtomicBitMap<MmapMut>>>) {
         self.congestion.ipt_packet();
-        if bitmap.lock().unwrap().get(chunk.index) {
+        if bitmap.read().unwrap().get(chunk.index) {
             info!("Chunk {} already received, skipping", chunk.index);
             self.state = State::WaitForChunk(WaitForChunk {
                 file,
@@ -178,16 +350,338 @@ impl Receiver {
         });
         // TODO: length check of chunks to ensure max usage of MTU
     }
+
+    /// Finishes the upload if all chunks were received and shuts down.
+    fn fin(&mut self, chunk: Chunk, bitmap: &RwLock<AtomicBitMap<MmapMut>>, bitmap_path: &Path) {
+        let all = bitmap.read().unwrap().all();
+        if !all {
+            error!("Got FIN from client, but bitmap is not full???");
+        }
+        debug!("Moving to shutdown");
+        if all && bitmap_path.exists() {
+            info!("Remove bitmap file");
+            fs::remove_file(bitmap_path).unwrap();
+            if let Err(e) = manage::remove_file(&bitmap_path.with_file_name("stream")) {
+                warn!("Can't remove the stream marker: {}", e);
+            }
+        }
+        if let (true, Some(metadata)) = (all, self.metadata) {
+            let file_path = bitmap_path.with_file_name("file");
+            if let Err(e) = apply_metadata(&file_path, &metadata) {
+                warn!("Can't apply metadata {:?}: {}", metadata, e);
+            }
+        }
+        let old_path = bitmap_path.with_file_name("file.old");
+        if all && old_path.exists() {
+            debug!("Remove old copy");
+            self.old = None;
+            self.copies.clear();
+            fs::remove_file(old_path).unwrap();
+        }
+        // the other uploads needn't wait for the connection to time out
+        self.share = None;
+        self.congestion.shutdown();
+        self.state = State::Shutdown(chunk.into_vec());
+    }
+
+    /// Handles a message of a streaming upload, whose ids are those of `Chunk::new_stream`.
+    fn stream_message(&mut self, mut chunk: Chunk, mut state: WaitForChunk) {
+        match chunk.index {
+            extension::FIN => return self.fin(chunk, &state.bitmap, &state.bitmap_path),
+            extension::END_OF_STREAM => self.end_of_stream(&chunk, &mut state),
+            id if id < extension::RESERVED => warn!("Ignoring unknown extension message {}", id),
+            id => {
+                let index = id - extension::RESERVED;
+                let len = chunk.as_ref().len() as u64;
+                let valid = match self.stream_length {
+                    Some(_) => index < state.chunk_info.num_chunks && len == state.chunk_info.chunk_len(index),
+                    // only the last chunk may be shorter, which isn't known yet
+                    None => len > 0 && len <= STREAM_CHUNK_SIZE && self.grow_bitmap(index, &state),
+                };
+                if valid {
+                    chunk.index = index;
+                    return self.chunk(chunk, state.chunk_info, state.bitmap_path, state.file, state.bitmap);
+                }
+                warn!("Dropping chunk {} of {} bytes", index, len);
+            }
+        }
+        self.state = State::WaitForChunk(WaitForChunk { buf: chunk.into_vec(), ..state });
+    }
+
+    /// Grows the bitmap of a streaming upload to hold chunk `index`.
+    ///
+    /// Returns `false` if the chunk is too far ahead or the bitmap can't grow.
+    fn grow_bitmap(&mut self, index: u64, state: &WaitForChunk) -> bool {
+        let (num_bits, received) = {
+            let bitmap = state.bitmap.read().unwrap();
+            (bitmap.num_bits(), bitmap.ones())
+        };
+        match stream::grown_len(num_bits, received, index) {
+            Some(len) if len == num_bits => true,
+            Some(len) => {
+                debug!("Growing the bitmap to {} chunks", len);
+                if let Err(e) = resize_bitmap(&state.bitmap, &state.bitmap_path, len) {
+                    error!("Can't grow the bitmap to {} chunks: {}", len, e);
+                    return false;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fixes the length of a streaming upload, after which it completes like any other.
+    fn end_of_stream(&mut self, chunk: &Chunk, state: &mut WaitForChunk) {
+        let end = match EndOfStream::decode(chunk) {
+            Ok(end) => end,
+            Err(e) => {
+                warn!("Dropping end of stream: {}", e);
+                return;
+            }
+        };
+        if let Some(length) = self.stream_length {
+            if length != end.length {
+                warn!("Ignoring end of stream at {}, the stream already ended at {}", end.length, length);
+            }
+            // the status update answering the first one got lost
+            self.tx.unbounded_send(ChannelMessage::UploadStatus(self.rebuilt)).unwrap();
+            return;
+        }
+        let chunk_info = codec::stream_chunk_info(end.length);
+        let (num_bits, received) = {
+            let bitmap = state.bitmap.read().unwrap();
+            (bitmap.num_bits(), bitmap.ones())
+        };
+        if stream::grown_len(num_bits, received, chunk_info.num_chunks.saturating_sub(1)).is_none() {
+            warn!("Dropping end of stream at {}, too far ahead of the received chunks", end.length);
+            return;
+        }
+        debug!("End of stream at {} bytes in {} chunks", end.length, chunk_info.num_chunks);
+        // chunks are written at their offset, the file may be too short if the last ones are missing
+        let resized = OpenOptions::new().write(true).open(state.bitmap_path.with_file_name("file"))
+            .and_then(|file| file.set_len(end.length))
+            .and_then(|_| resize_bitmap(&state.bitmap, &state.bitmap_path, chunk_info.num_chunks));
+        if let Err(e) = resized {
+            error!("Dropping end of stream at {}: {}", end.length, e);
+            return;
+        }
+        state.chunk_info = chunk_info;
+        self.stream_length = Some(end.length);
+        self.tx.unbounded_send(ChannelMessage::UploadStatus(self.rebuilt)).unwrap();
+    }
+
+    /// Marks a run of all-zero chunks as received.
+    ///
+    /// Nothing needs to be written, as the file reads as zeros wherever no chunk was.
+    fn zero_run(&mut self, chunk: &Chunk, chunk_info: &ChunkInfo, bitmap: &RwLock<AtomicBitMap<MmapMut>>) {
+        let run = match ZeroRun::decode(chunk, chunk_info) {
+            Ok(run) => run,
+            Err(e) => {
+                warn!("Dropping zero run: {}", e);
+                return;
+            }
+        };
+        debug!("Zero run of {} chunks at {}", run.count, run.first);
+        let bitmap = bitmap.read().unwrap();
+        let changed = bitmap.set_range(run.first..run.first + run.count);
+        if changed != 0 && (bitmap.zeroes().is_power_of_two() || bitmap.zeroes() == 0) {
+            self.tx.unbounded_send(ChannelMessage::UploadStatus(self.rebuilt)).unwrap();
+        }
+    }
+
+    /// Queues a range of chunks to copy from the old copy of the file, see `copy_chunks`.
+    fn copy_range(&mut self, chunk: &Chunk, chunk_info: &ChunkInfo) {
+        let range = match CopyRange::decode(chunk, chunk_info) {
+            Ok(range) => range,
+            Err(e) => {
+                warn!("Dropping copy range: {}", e);
+                return;
+            }
+        };
+        if self.old.is_none() {
+            warn!("Dropping copy range without an old copy");
+        } else if self.copies.len() >= PENDING_COPIES {
+            debug!("Dropping copy range at {}, {} are still pending", range.first, self.copies.len());
+        } else {
+            debug!("Copying {} chunks at {} from offset {}", range.count, range.first, range.old_offset);
+            self.copies.push_back(range);
+        }
+    }
+
+    /// Rebuilds a missing chunk from a parity message if possible.
+    fn parity(&mut self, chunk: Chunk, chunk_info: ChunkInfo, bitmap_path: PathBuf,
+              mut file: PollEvented2<File<StdFile>>, bitmap: Arc<RwLock<AtomicBitMap<MmapMut>>>) {
+        let rebuilt = match Parity::decode(&chunk, &chunk_info) {
+            Ok(parity) => {
+                let bitmap = bitmap.read().unwrap();
+                let chunk_size = chunk_info.chunk_size;
+                let file = file.get_mut();
+                let read = |index: u64, buf: &mut [u8]| {
+                    file.seek(SeekFrom::Start(index * chunk_size))?;
+                    file.read_exact(buf)
+                };
+                fec::rebuild(&chunk_info, &parity, |index| !bitmap.get(index), read, Vec::with_capacity(MTU))
+                    .unwrap_or_else(|e| {
+                        error!("Can't read chunks to rebuild from parity: {}", e);
+                        None
+                    })
+            }
+            Err(e) => {
+                warn!("Dropping parity: {}", e);
+                None
+            }
+        };
+        match rebuilt {
+            Some(rebuilt) => {
+                debug!("Rebuilt chunk {} from parity", rebuilt.index);
+                self.rebuilt += 1;
+                self.chunk(rebuilt, chunk_info, bitmap_path, file, bitmap);
+            }
+            None => {
+                self.state = State::WaitForChunk(WaitForChunk {
+                    file,
+                    bitmap,
+                    bitmap_path,
+                    buf: chunk.into_vec(),
+                    chunk_info,
+                });
+            }
+        }
+    }
+}
+
+/// Returns the number of chunks a resumed streaming upload keeps: the ones the server has in
+/// a row from the start of a previous upload of the file.
+///
+/// The bitmap of an incomplete upload only tells that of a previous stream.
+fn resumable_chunks(file_path: &Path, bitmap_path: &Path, stream_path: &Path) -> u64 {
+    let full = match fs::metadata(file_path) {
+        Ok(metadata) => metadata.len() / STREAM_CHUNK_SIZE,
+        Err(_) => return 0,
+    };
+    if !bitmap_path.exists() {
+        return full;
+    }
+    if !stream_path.exists() {
+        return 0;
+    }
+    match fs::read(bitmap_path) {
+        Ok(bytes) => {
+            let bitmap = BitMap::new(bytes);
+            cmp::min(full, bitmap.find_first_zero().unwrap_or_else(|| bitmap.num_bits()))
+        }
+        Err(e) => {
+            warn!("Can't read the bitmap to resume: {}", e);
+            0
+        }
+    }
 }
 
-impl Stream for Receiver {
+/// SHA-256 of the first `len` bytes of the file at `path`, the chunks a resumed stream keeps.
+fn prefix_digest(path: &Path, len: u64) -> io::Result<digest::Digest> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    if len > 0 {
+        let mut file = BufReader::with_capacity(1 << 20, StdFile::open(path)?).take(len);
+        loop {
+            let read = {
+                let buf = file.fill_buf()?;
+                context.update(buf);
+                buf.len()
+            };
+            if read == 0 {
+                break;
+            }
+            file.consume(read);
+        }
+    }
+    Ok(context.finish())
+}
+
+/// Signs the old copy a message at a time, as the sender asks for them.
+///
+/// An error opening it is returned as the only message, which aborts the upload.
+fn sign_old_copy(path: &Path, block_size: u64) -> Box<Iterator<Item = io::Result<Vec<u8>>> + Send> {
+    let messages = StdFile::open(path).and_then(|old| {
+        let old_len = old.metadata()?.len();
+        Ok(delta::SignatureMessages::new(BufReader::with_capacity(1 << 20, old), old_len, block_size))
+    });
+    match messages {
+        Ok(messages) => Box::new(messages),
+        Err(e) => Box::new(Some(Err(e)).into_iter()),
+    }
+}
+
+/// Copies up to `max` chunks of `range` from the old copy into `file` and moves `range` past them.
+///
+/// Chunks which arrived in the meantime are skipped.
+fn copy_chunks<R: Read + Seek, W: Write + Seek, B>(old: &mut R, range: &mut CopyRange, max: u64,
+                                                   chunk_info: &ChunkInfo, file: &mut W,
+                                                   bitmap: &AtomicBitMap<B>) -> io::Result<()> {
+    let mut buf = vec![0; chunk_info.chunk_size as usize];
+    for _ in 0..cmp::min(max, range.count) {
+        let index = range.first;
+        if !bitmap.get(index) {
+            let buf = &mut buf[..chunk_info.chunk_len(index) as usize];
+            old.seek(SeekFrom::Start(range.old_offset))?;
+            old.read_exact(buf)?;
+            file.seek(SeekFrom::Start(index * chunk_info.chunk_size))?;
+            file.write_all(buf)?;
+            bitmap.set(index, true);
+        }
+        range.first += 1;
+        range.count -= 1;
+        range.old_offset += chunk_info.chunk_size;
+    }
+    Ok(())
+}
+
+/// Remaps the bitmap of a streaming upload with `num_bits`, keeping the bits it has.
+fn resize_bitmap(bitmap: &RwLock<AtomicBitMap<MmapMut>>, path: &Path, num_bits: u64) -> io::Result<()> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    // an empty file can't be mapped
+    let len = cmp::max((num_bits + 7) / 8, 1);
+    let mut bitmap = bitmap.write().unwrap();
+    if len > file.metadata()?.len() {
+        file.set_len(len)?;
+    }
+    let mmap = unsafe { MmapOptions::new().len(len as usize).map_mut(&file)? };
+    *bitmap = AtomicBitMap::with_length(mmap, num_bits);
+    // shrinking the file only once the old mapping is gone
+    file.set_len(len)
+}
+
+/// Sets permissions, modification time and owner of the uploaded file.
+fn apply_metadata(path: &Path, metadata: &Metadata) -> io::Result<()> {
+    debug!("Applying {:?}", metadata);
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if let Some((uid, gid)) = metadata.owner {
+        // before the mode, as chown clears setuid and setgid bits
+        if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+            warn!("Can't change owner to {}:{}: {}", uid, gid, io::Error::last_os_error());
+        }
+    }
+    // no setuid, setgid or sticky bits from clients
+    fs::set_permissions(path, fs::Permissions::from_mode(metadata.mode & 0o777))?;
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: metadata.mtime as libc::time_t, tv_nsec: metadata.mtime_nanos as libc::c_long },
+    ];
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl<T: Transport> Stream for Receiver<T> {
     type Item = ();
     type Error = IoError;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         match self.congestion.poll() {
             Ok(Async::Ready(Some(()))) => {
-                self.tx.unbounded_send(ChannelMessage::UploadStatus).unwrap();
+                // the share changes as other uploads start and finish
+                self.rate_hint();
+                self.tx.unbounded_send(ChannelMessage::UploadStatus(self.rebuilt)).unwrap();
             }
             Ok(Async::NotReady) => {}
             Ok(Async::Ready(None)) => unreachable!(),
@@ -204,12 +698,12 @@ impl Stream for Receiver {
             let state = mem::replace(&mut self.state, State::Invalid);
             let mut state = if let State::WritingChunk(state) = state { state } else { unreachable!() };
             {
-                let mut bitmap = state.bitmap.lock().unwrap();
+                let bitmap = state.bitmap.read().unwrap();
                 bitmap.set(chunk.index, true);
 
                 if bitmap.zeroes().is_power_of_two() || bitmap.zeroes() == 0 {
                     debug!("Power of 2: {}", bitmap.zeroes());
-                    self.tx.unbounded_send(ChannelMessage::UploadStatus).unwrap();
+                    self.tx.unbounded_send(ChannelMessage::UploadStatus(self.rebuilt)).unwrap();
                 }
 
                 // if last chunk
@@ -227,6 +721,34 @@ impl Stream for Receiver {
             });
         }
 
+        // a few chunks of the pending copy ranges each time, coming back for the rest
+        if let State::WaitForChunk(ref mut state) = self.state {
+            let done = match (self.old.as_mut(), self.copies.front_mut()) {
+                (Some(old), Some(range)) => {
+                    let bitmap = state.bitmap.read().unwrap();
+                    let before = bitmap.zeroes();
+                    let copied = copy_chunks(old, range, COPY_STEP, &state.chunk_info, state.file.get_mut(), &bitmap);
+                    if bitmap.zeroes() != before && (bitmap.zeroes().is_power_of_two() || bitmap.zeroes() == 0) {
+                        self.tx.unbounded_send(ChannelMessage::UploadStatus(self.rebuilt)).unwrap();
+                    }
+                    match copied {
+                        Ok(()) => range.count == 0,
+                        Err(e) => {
+                            warn!("Can't copy chunk {} from the old copy: {}", range.first, e);
+                            true
+                        }
+                    }
+                }
+                _ => false,
+            };
+            if done {
+                self.copies.pop_front();
+            }
+            if !self.copies.is_empty() {
+                task::current().notify();
+            }
+        }
+
         match self.state {
             State::Invalid => unreachable!(),
             State::WaitForAck(_) => {
@@ -242,22 +764,57 @@ impl Stream for Receiver {
                     buf.truncate(size);
                 } else { unreachable!() };
                 let state = mem::replace(&mut self.state, State::Invalid);
-                let state = if let State::WaitForChunk(state) = state { state } else { unreachable!() };
-                let chunk = Chunk::decode(state.buf, state.chunk_info.index_field_size);
-                match chunk.index.wrapping_sub(state.chunk_info.num_chunks) {
-                    0 => {
-                        let all = state.bitmap.lock().unwrap().all();
-                        if !all {
-                            error!("Got FIN from client, but bitmap is not full???");
-                        }
-                        debug!("Moving to shutdown");
-                        if all && state.bitmap_path.exists() {
-                            info!("Remove bitmap file");
-                            fs::remove_file(state.bitmap_path).unwrap();
+                let mut state = if let State::WaitForChunk(state) = state { state } else { unreachable!() };
+                let stream = self.features & features::STREAM != 0;
+                let buf = mem::replace(&mut state.buf, Vec::new());
+                let chunk = if stream {
+                    Chunk::decode_stream(buf)
+                } else {
+                    Chunk::decode(buf, state.chunk_info.index_field_size)
+                };
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        warn!("Dropping malformed chunk: {}", e);
+                        self.state = State::WaitForChunk(WaitForChunk { buf: Vec::with_capacity(MTU), ..state });
+                        return Ok(Async::Ready(Some(())));
+                    }
+                };
+                if stream {
+                    self.stream_message(chunk, state);
+                    return Ok(Async::Ready(Some(())));
+                }
+                let num_chunks = state.chunk_info.num_chunks;
+                match chunk.index {
+                    index if index == num_chunks => self.fin(chunk, &state.bitmap, &state.bitmap_path),
+                    index if index == num_chunks + extension::PARITY && self.features & features::PARITY != 0 => {
+                        self.parity(chunk, state.chunk_info, state.bitmap_path, state.file, state.bitmap)
+                    }
+                    index if index == num_chunks + extension::ZERO_RUN && self.features & features::ZERO_RUNS != 0 => {
+                        self.zero_run(&chunk, &state.chunk_info, &state.bitmap);
+                        self.state = State::WaitForChunk(WaitForChunk { buf: chunk.into_vec(), ..state });
+                    }
+                    index if index == num_chunks + extension::COPY_RANGE && self.features & features::DELTA != 0 => {
+                        self.copy_range(&chunk, &state.chunk_info);
+                        self.state = State::WaitForChunk(WaitForChunk { buf: chunk.into_vec(), ..state });
+                    }
+                    index if index > num_chunks => {
+                        warn!("Ignoring unknown extension message {}", index - num_chunks);
+                        self.state = State::WaitForChunk(WaitForChunk { buf: chunk.into_vec(), ..state });
+                    }
+                    index if chunk.is_compressed(&state.chunk_info) && self.features & features::COMPRESSION != 0 => {
+                        match chunk.decompress(&state.chunk_info, &mut self.decompress, Vec::with_capacity(MTU)) {
+                            Ok(chunk) => self.chunk(chunk, state.chunk_info, state.bitmap_path, state.file, state.bitmap),
+                            Err(e) => {
+                                warn!("Dropping chunk {}: {}", index, e);
+                                self.state = State::WaitForChunk(WaitForChunk { buf: chunk.into_vec(), ..state });
+                            }
                         }
-                        self.congestion.shutdown();
-                        self.state = State::Shutdown(chunk.into_vec());
-                    },
+                    }
+                    index if chunk.as_ref().len() as u64 != state.chunk_info.chunk_len(index) => {
+                        warn!("Dropping chunk {} with invalid length {}", index, chunk.as_ref().len());
+                        self.state = State::WaitForChunk(WaitForChunk { buf: chunk.into_vec(), ..state });
+                    }
                     _ => self.chunk(chunk, state.chunk_info, state.bitmap_path, state.file, state.bitmap)
                 }
             }
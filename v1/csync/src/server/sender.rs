@@ -1,20 +1,34 @@
 use std::io;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 use std::path::PathBuf;
 use std::fs;
 
-use futures::{Sink, Async, AsyncSink, Poll, StartSend};
-use tokio::net::UdpSocket;
+use futures::{Future, Sink, Async, AsyncSink, Poll, StartSend};
+use tokio::clock;
+use tokio::timer::Delay;
 use memmap::MmapMut;
-use bitte_ein_bit::BitMap;
+use bitte_ein_bit::AtomicBitMap;
 
 use codec::{self, MTU};
+use pace::Pacer;
 use server::ChannelMessage;
+use transport::Transport;
 
-pub struct Sender {
-    socket: UdpSocket,
+/// Bytes per second signatures are sent with, so they don't flood the client.
+const SIGNATURE_RATE: u64 = 4_000_000;
+
+pub struct Sender<T> {
+    socket: T,
     vec: Vec<u8>,
-    bitmap: Option<Arc<Mutex<BitMap<MmapMut>>>>,
+    bitmap: Option<Arc<RwLock<AtomicBitMap<MmapMut>>>>,
+    features: Option<u64>,
+    /// digest of the chunks a resumed stream kept
+    prefix: Vec<u8>,
+    /// signature messages still to send, in between the other messages
+    signatures: Option<Box<Iterator<Item = io::Result<Vec<u8>>> + Send>>,
+    pacer: Pacer,
+    /// until the pacer allows the next signatures
+    delay: Option<Delay>,
     state: State,
 }
 
@@ -24,65 +38,130 @@ pub enum State {
     Waiting,
 }
 
-impl Sender {
-    pub fn new(socket: UdpSocket) -> Sender {
+impl<T: Transport> Sender<T> {
+    pub fn new(socket: T) -> Sender<T> {
         Sender {
             socket,
             vec: vec![0u8; MTU],
             bitmap: None,
+            features: None,
+            prefix: Vec::new(),
+            signatures: None,
+            pacer: Pacer::new(),
+            delay: None,
             state: State::Waiting,
         }
     }
 }
 
-impl Sink for Sender {
+impl<T: Transport> Sender<T> {
+    /// Sends the datagram in `vec` if there's one.
+    fn flush(&mut self) -> Poll<(), io::Error> {
+        if self.state == State::Waiting {
+            return Ok(Async::Ready(()))
+        }
+
+        let written = try_ready!(self.socket.poll_send(&self.vec));
+
+        if written == self.vec.len() {
+            self.state = State::Waiting;
+            Ok(Async::Ready(()))
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "failed to write entire datagram to socket").into())
+        }
+    }
+
+    /// Signs and sends signature messages as the pacer allows.
+    ///
+    /// Fails if the old copy can't be read, which aborts the upload.
+    fn poll_signatures(&mut self) -> Poll<(), io::Error> {
+        loop {
+            try_ready!(self.flush());
+            if let Some(ref mut delay) = self.delay {
+                try_ready!(delay.poll().map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+            }
+            self.delay = None;
+            let now = clock::now();
+            if let Some(wait) = self.pacer.wait(now) {
+                self.delay = Some(Delay::new(now + wait));
+                continue;
+            }
+            let message = match self.signatures {
+                Some(ref mut signatures) => signatures.next(),
+                None => return Ok(Async::Ready(())),
+            };
+            match message {
+                Some(Ok(message)) => {
+                    self.pacer.sent(now, message.len());
+                    self.vec = message;
+                    self.state = State::Sending;
+                }
+                Some(Err(e)) => {
+                    error!("Can't sign the old copy: {}", e);
+                    return Err(e);
+                }
+                None => self.signatures = None,
+            }
+        }
+    }
+}
+
+impl<T: Transport> Sink for Sender<T> {
     type SinkItem = ChannelMessage;
     type SinkError = io::Error;
 
     fn start_send(&mut self, item: ChannelMessage) -> StartSend<ChannelMessage, Self::SinkError> {
         if self.state == State::Sending {
-            match self.poll_complete()? {
+            match self.flush()? {
                 Async::Ready(()) => {},
                 Async::NotReady => return Ok(AsyncSink::NotReady(item)),
             }
         }
 
         match item {
-            ChannelMessage::UploadStart(bitmap) => {
+            ChannelMessage::UploadStart(bitmap, features, prefix) => {
                 if self.bitmap.is_some() {
                     panic!("Bitmap is already some");
                 }
                 self.bitmap = Some(bitmap);
+                self.features = features;
+                self.prefix = prefix;
             }
-            ChannelMessage::UploadStatus => {
+            ChannelMessage::UploadStatus(rebuilt) => {
                 self.vec.resize(MTU, 0u8);
-                let bitmap = self.bitmap.as_ref().unwrap().lock().unwrap();
-                let size = codec::write_runlength_encoded(&bitmap, &mut self.vec[..]).unwrap();
+                // encoded while chunks keep arriving, only as far as the runs fit
+                let size = {
+                    let bitmap = self.bitmap.as_ref().unwrap().read().unwrap();
+                    codec::write_status_update(self.features, rebuilt, &self.prefix, &*bitmap, &mut self.vec[..]).unwrap()
+                };
                 self.vec.truncate(size);
                 trace!("Sending UploadStatus: {:?}", self.vec);
                 self.state = State::Sending;
             }
+            ChannelMessage::Signatures(signatures) => {
+                self.signatures = Some(signatures);
+                self.pacer.set_rate(SIGNATURE_RATE);
+            }
+            ChannelMessage::Ack(ack) => {
+                ack.encode(&mut self.vec);
+                self.state = State::Sending;
+            }
+            ChannelMessage::RateHint(rate) => {
+                codec::write_rate_hint(rate, &mut self.vec);
+                self.state = State::Sending;
+            }
         }
         Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), io::Error> {
-        if self.state == State::Waiting {
-            return Ok(Async::Ready(()))
-        }
-
-        let written = try_ready!(self.socket.poll_send(&self.vec));
-
-        if written == self.vec.len() {
-            self.state = State::Waiting;
-            Ok(Async::Ready(()))
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other,
-                               "failed to write entire datagram to socket").into())
-        }
+        // signatures keep being sent while other messages wait for the channel
+        self.poll_signatures()?;
+        self.flush()
     }
 
     fn close(&mut self) -> Poll<(), io::Error> {
-        try_ready!(self.poll_complete());
+        try_ready!(self.flush());
         Ok(().into())
     }}
\ No newline at end of file
@@ -0,0 +1,1152 @@
+//! In-process simulation of csync uploads over a lossy network.
+//!
+//! The real client and server run over a simulated link instead of a socket, see
+//! `transport::Transport`. The link loses, duplicates, reorders and delays datagrams and
+//! follows the upload through them to report what was sent.
+//! Every decision of the network is drawn from a seeded generator, and client and server run
+//! on a single thread in virtual time, thus an upload with the same seed always sends the same
+//! datagrams at the same times.
+
+use std::cmp::{self, Ordering};
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Cursor};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant};
+
+use bitte_ein_bit::rle;
+use futures::{stream, Future, Poll, Async};
+use futures::future::{loop_fn, Loop};
+use futures::task::{self, Task};
+use libc;
+use structopt::StructOpt;
+use tokio::reactor::Reactor;
+use tokio_current_thread::CurrentThread;
+use tokio_executor::park::{Park, Unpark};
+use tokio_reactor;
+use tokio_timer::{self, Timer};
+use tokio_timer::clock::{Clock, Now};
+
+use client::{self, BlockOn};
+use codec::*;
+use server::{self, CookieJar, Uploads};
+use transport::{Transport, recv_dgram};
+use Opt;
+
+/// Time since the link was created in microseconds.
+pub type Time = u64;
+
+/// A datagram sent over the link: when, in which direction and how long it was.
+pub type Sent = (Time, Direction, usize);
+
+/// Time to wait for the server to handle what is still on the link once the client is done.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// xorshift64*, small and reproducible on every platform.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns `true` with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    /// Uniformly distributed number in `from..to`.
+    pub fn range(&mut self, from: u64, to: u64) -> u64 {
+        if to <= from {
+            from
+        } else {
+            from + self.next_u64() % (to - from)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+#[derive(Clone)]
+pub struct LinkConfig {
+    /// probability of a datagram getting lost
+    pub loss: f64,
+    /// probability of a datagram being delivered twice
+    pub duplicate: f64,
+    /// probability of a datagram being held back by `reorder_delay`
+    pub reorder: f64,
+    pub reorder_delay: Time,
+    /// one-way delay, uniformly distributed in `delay.0..delay.1`
+    pub delay: (Time, Time),
+    /// Never lose the login, the cookie or the first status update.
+    ///
+    /// The handshake isn't retransmitted by csync (see README), a lost login or
+    /// initial status update stalls the client forever.
+    pub spare_handshake: bool,
+    /// Additionally drops every datagram this returns `true` for.
+    pub drop_if: Option<fn(Direction, &[u8]) -> bool>,
+    /// Bytes per second sent in each direction, a side sending faster has to wait like on a
+    /// full socket buffer.
+    pub bandwidth: u64,
+    /// Datagrams delivered but not yet received that each side buffers, more are dropped
+    /// like by a full socket buffer.
+    pub receive_buffer: usize,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: 0,
+            delay: (500, 1500),
+            spare_handshake: true,
+            drop_if: None,
+            bandwidth: 20_000_000,
+            receive_buffer: 256,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LinkStats {
+    pub sent: u64,
+    /// bytes sent in datagrams to the server
+    pub bytes_to_server: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delivered: u64,
+    /// datagrams dropped by a full receive buffer
+    pub overflowed: u64,
+}
+
+struct InFlight {
+    at: Time,
+    seq: u64,
+    direction: Direction,
+    data: Vec<u8>,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &InFlight) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &InFlight) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    // reversed to make `BinaryHeap` a min-heap
+    fn cmp(&self, other: &InFlight) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+/// Network between one client and the server, injecting loss, duplication,
+/// reordering and delay.
+pub struct Network {
+    config: LinkConfig,
+    rng: Rng,
+    seq: u64,
+    in_flight: BinaryHeap<InFlight>,
+    pub stats: LinkStats,
+}
+
+impl Network {
+    pub fn new(seed: u64, config: LinkConfig) -> Network {
+        Network {
+            config,
+            rng: Rng::new(seed),
+            seq: 0,
+            in_flight: BinaryHeap::new(),
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// Sends a datagram, which is never lost if it's part of the handshake and
+    /// `spare_handshake` is set.
+    pub fn send(&mut self, now: Time, direction: Direction, data: Vec<u8>, handshake: bool) {
+        let spared = handshake && self.config.spare_handshake;
+        self.stats.sent += 1;
+        if direction == Direction::ToServer {
+            self.stats.bytes_to_server += data.len() as u64;
+        }
+        if self.config.drop_if.map_or(false, |f| f(direction, &data)) {
+            self.stats.lost += 1;
+            return;
+        }
+        if !spared && self.rng.chance(self.config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        if !spared && self.rng.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            let at = now + self.delay();
+            self.push(at, direction, data.clone());
+        }
+        let mut at = now + self.delay();
+        if !spared && self.rng.chance(self.config.reorder) {
+            self.stats.reordered += 1;
+            at += self.config.reorder_delay;
+        }
+        self.push(at, direction, data);
+    }
+
+    fn delay(&mut self) -> Time {
+        let (from, to) = self.config.delay;
+        self.rng.range(from, to)
+    }
+
+    fn push(&mut self, at: Time, direction: Direction, data: Vec<u8>) {
+        self.seq += 1;
+        self.in_flight.push(InFlight { at, seq: self.seq, direction, data });
+    }
+
+    pub fn next_delivery(&self) -> Option<Time> {
+        self.in_flight.peek().map(|f| f.at)
+    }
+
+    /// Forgets everything in flight, e.g. because the client opened a new UDP flow.
+    pub fn new_connection(&mut self) {
+        self.in_flight.clear();
+    }
+
+    /// Returns whether a datagram is on its way in `direction`.
+    pub fn in_flight(&self, direction: Direction) -> bool {
+        self.in_flight.iter().any(|f| f.direction == direction)
+    }
+
+    /// Returns the next datagram due at `now`.
+    pub fn deliver(&mut self, now: Time) -> Option<(Direction, Vec<u8>)> {
+        if self.next_delivery().map_or(true, |at| at > now) {
+            return None;
+        }
+        let InFlight { direction, data, .. } = self.in_flight.pop().unwrap();
+        self.stats.delivered += 1;
+        Some((direction, data))
+    }
+}
+/// What was sent over the link during an upload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outcome {
+    /// client received a status update with all chunks and sent its FIN
+    pub client_done: bool,
+    /// server received the FIN and removed the bitmap
+    pub server_done: bool,
+    /// chunks sent including retransmissions
+    pub chunks_sent: u64,
+    /// different chunks sent
+    pub distinct_chunks: u64,
+    pub parity_sent: u64,
+    pub zero_runs_sent: u64,
+    pub copy_ranges_sent: u64,
+    /// chunks the server rebuilt from parity
+    pub rebuilt: u64,
+    pub status_updates: u64,
+}
+
+/// Follows an upload through the datagrams on the link.
+struct Tap {
+    /// features the client is told the server accepted
+    features: u64,
+    status_mtu: usize,
+    /// the client fails to send more chunks than this
+    abort_after: Option<u64>,
+    /// layout of the upload and whether it's streamed, once the client logged in
+    upload: Option<(ChunkInfo, bool)>,
+    /// a status update was sent
+    status_sent: bool,
+    sent: HashSet<u64>,
+    outcome: Outcome,
+}
+
+impl Tap {
+    /// Classifies a datagram to the server, returning what to send and whether it's part of
+    /// the handshake.
+    fn server_bound(&mut self, buf: &[u8]) -> io::Result<(Vec<u8>, bool)> {
+        let (num_chunks, index_field_size, stream) = match self.upload {
+            Some((ref chunk_info, stream)) => (chunk_info.num_chunks, chunk_info.index_field_size, stream),
+            None => return Ok(self.login(buf)),
+        };
+        let index = if stream {
+            match Chunk::decode_stream(buf.to_vec()).map(|chunk| chunk.index) {
+                Ok(id) if id >= extension::RESERVED => Some(id - extension::RESERVED),
+                _ => None,
+            }
+        } else {
+            match Chunk::decode(buf.to_vec(), index_field_size).map(|chunk| chunk.index) {
+                Ok(index) if index < num_chunks => Some(index),
+                Ok(index) if index == num_chunks + extension::PARITY => {
+                    self.outcome.parity_sent += 1;
+                    None
+                }
+                Ok(index) if index == num_chunks + extension::ZERO_RUN => {
+                    self.outcome.zero_runs_sent += 1;
+                    None
+                }
+                Ok(index) if index == num_chunks + extension::COPY_RANGE => {
+                    self.outcome.copy_ranges_sent += 1;
+                    None
+                }
+                _ => None,
+            }
+        };
+        if let Some(index) = index {
+            if self.abort_after == Some(self.outcome.chunks_sent) {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "client aborted"));
+            }
+            self.outcome.chunks_sent += 1;
+            self.sent.insert(index);
+        }
+        Ok((buf.to_vec(), false))
+    }
+
+    /// Learns the layout of the upload once the login carries a cookie.
+    fn login(&mut self, buf: &[u8]) -> (Vec<u8>, bool) {
+        let login = match Login::decode(buf) {
+            Ok(login) => login,
+            Err(_) => return (buf.to_vec(), false),
+        };
+        if let (Command::UploadRequest(req), false) = (login.command, login.cookie.is_empty()) {
+            let stream = req.features & features::STREAM != 0;
+            let chunk_info = if stream { stream_chunk_info(0) } else { index_field_size(req.length, req.features) };
+            self.upload = Some((chunk_info, stream));
+        }
+        (buf.to_vec(), true)
+    }
+
+    /// Classifies a datagram to the client, masking the accepted features of status updates
+    /// and truncating them to `status_mtu`.
+    fn client_bound(&mut self, buf: &[u8]) -> (Vec<u8>, bool) {
+        let update = match ServerMessage::decode(buf) {
+            Ok(ServerMessage::Cookie(_)) => return (buf.to_vec(), true),
+            Ok(ServerMessage::StatusUpdate(update)) => update,
+            _ => return (buf.to_vec(), false),
+        };
+        self.outcome.status_updates += 1;
+        self.outcome.rebuilt = cmp::max(self.outcome.rebuilt, update.rebuilt);
+        let first = !self.status_sent;
+        self.status_sent = true;
+        let mut data = vec![0; cmp::min(self.status_mtu, buf.len())];
+        let header = write_status_header(update.features & self.features, update.rebuilt, update.prefix, &mut data)
+            .expect("status MTU too small");
+        // like a server with a smaller MTU
+        let runs = rle::decode_runs(update.runlengths).map(|run| {
+            let (value, range) = run.expect("malformed status update");
+            (value, range.end - range.start)
+        });
+        let len = header + rle::encode_runs(runs, &mut data[header..]);
+        data.truncate(len);
+        (data, first)
+    }
+}
+
+/// Time of a simulation, which only moves when `Driver` advances it.
+#[derive(Clone)]
+struct VirtualClock {
+    start: Instant,
+    elapsed: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    fn new() -> VirtualClock {
+        VirtualClock { start: Instant::now(), elapsed: Arc::new(AtomicU64::new(0)) }
+    }
+
+    fn elapsed(&self) -> Time {
+        self.elapsed.load(AtomicOrdering::SeqCst)
+    }
+
+    fn advance_to(&self, at: Time) {
+        self.elapsed.fetch_max(at, AtomicOrdering::SeqCst);
+    }
+}
+
+impl Now for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + Duration::from_micros(self.elapsed())
+    }
+}
+
+/// State of the link shared by both endpoints and the driver delivering datagrams.
+struct Link {
+    network: Network,
+    clock: VirtualClock,
+    /// delivered datagrams not received yet, by direction
+    inbox: [VecDeque<Vec<u8>>; 2],
+    /// task waiting to receive, by direction
+    waiting: [Option<Task>; 2],
+    /// the link is busy sending the previous datagram until then, by direction
+    busy_until: [Time; 2],
+    /// task waiting for the link to be free, by direction
+    waiting_send: [Option<Task>; 2],
+    tap: Tap,
+    trace: Vec<Sent>,
+}
+
+impl Link {
+    fn now(&self) -> Time {
+        self.clock.elapsed()
+    }
+
+    /// Time of the next datagram arriving or waiting sender being able to send.
+    fn next_event(&self) -> Option<Time> {
+        let senders = (0..2).filter(|&index| self.waiting_send[index].is_some()).map(|index| self.busy_until[index]);
+        senders.chain(self.network.next_delivery()).min()
+    }
+
+    /// Moves datagrams from the network into the inboxes once they are due and wakes senders
+    /// once the link is free.
+    fn deliver(&mut self) {
+        let now = self.now();
+        for index in 0..2 {
+            if self.waiting_send[index].is_some() && now >= self.busy_until[index] {
+                self.waiting_send[index].take().unwrap().notify();
+            }
+        }
+        while let Some((direction, data)) = self.network.deliver(now) {
+            let index = direction as usize;
+            if self.inbox[index].len() >= self.network.config.receive_buffer {
+                self.network.stats.overflowed += 1;
+                continue;
+            }
+            self.inbox[index].push_back(data);
+            if let Some(task) = self.waiting[index].take() {
+                task.notify();
+            }
+        }
+    }
+
+    /// Returns whether the server handled everything sent to it and waits for more.
+    fn settled(&self) -> bool {
+        let to_server = Direction::ToServer as usize;
+        !self.network.in_flight(Direction::ToServer) && self.inbox[to_server].is_empty()
+            && self.waiting[to_server].is_some()
+    }
+}
+
+/// One side of the link.
+#[derive(Clone)]
+pub struct Endpoint {
+    link: Arc<Mutex<Link>>,
+    /// direction of the datagrams this side sends
+    sends: Direction,
+    receives: Direction,
+}
+
+impl Transport for Endpoint {
+    fn poll_send(&mut self, buf: &[u8]) -> Poll<usize, io::Error> {
+        let mut link = self.link.lock().unwrap();
+        let index = self.sends as usize;
+        let now = link.now();
+        if now < link.busy_until[index] {
+            link.waiting_send[index] = Some(task::current());
+            return Ok(Async::NotReady);
+        }
+        let (data, handshake) = match self.sends {
+            Direction::ToServer => link.tap.server_bound(buf)?,
+            Direction::ToClient => link.tap.client_bound(buf),
+        };
+        link.busy_until[index] = now + data.len() as u64 * 1_000_000 / link.network.config.bandwidth;
+        link.trace.push((now, self.sends, data.len()));
+        link.network.send(now, self.sends, data, handshake);
+        Ok(Async::Ready(buf.len()))
+    }
+
+    fn poll_recv(&mut self, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        let mut link = self.link.lock().unwrap();
+        let index = self.receives as usize;
+        match link.inbox[index].pop_front() {
+            Some(data) => {
+                let len = cmp::min(data.len(), buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok(Async::Ready(len))
+            }
+            None => {
+                link.waiting[index] = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Runs the client and the server in virtual time: once every task waits, time jumps to
+/// whatever happens next on the link, or to the next timer.
+struct Driver {
+    /// files only tell that they are ready through a reactor
+    reactor: Reactor,
+    link: Arc<Mutex<Link>>,
+    /// a task was woken since the driver last parked
+    woken: Arc<AtomicBool>,
+}
+
+struct Wakeup(Arc<AtomicBool>);
+
+impl Unpark for Wakeup {
+    fn unpark(&self) {
+        self.0.store(true, AtomicOrdering::SeqCst);
+    }
+}
+
+impl Park for Driver {
+    type Unpark = Wakeup;
+    type Error = io::Error;
+
+    fn unpark(&self) -> Wakeup {
+        Wakeup(self.woken.clone())
+    }
+
+    fn park(&mut self) -> io::Result<()> {
+        self.advance(None)
+    }
+
+    fn park_timeout(&mut self, duration: Duration) -> io::Result<()> {
+        self.advance(Some(duration))
+    }
+}
+
+impl Driver {
+    /// Moves time on to the next event on the link, at most by `timeout`, unless a task is
+    /// ready to run already.
+    fn advance(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.reactor.turn(Some(Duration::from_secs(0)))?;
+        if self.woken.swap(false, AtomicOrdering::SeqCst) {
+            return Ok(());
+        }
+        let mut link = self.link.lock().unwrap();
+        // rounded up, timers fire once their time has come
+        let timeout = timeout.map(|timeout| {
+            link.now() + timeout.as_secs() * 1_000_000 + (timeout.subsec_nanos() as u64 + 999) / 1000
+        });
+        let at = match (link.next_event(), timeout) {
+            (Some(event), Some(timeout)) => cmp::min(event, timeout),
+            (event, timeout) => event.or(timeout)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "simulation stalled, nothing left to happen"))?,
+        };
+        link.clock.advance_to(at);
+        link.deliver();
+        Ok(())
+    }
+}
+
+type Scheduler = CurrentThread<Timer<Driver, Clock>>;
+
+impl BlockOn for Scheduler {
+    fn block_on<F: Future<Error = io::Error>>(&mut self, future: F) -> io::Result<F::Item> {
+        // the error of `future` is only handed out as its result
+        match CurrentThread::block_on(self, future.then(Ok::<_, ()>)) {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "the simulation failed")),
+        }
+    }
+}
+
+/// Answers logins like `server::run` until one is admitted, then serves that client.
+fn serve_login(endpoint: Endpoint, root: PathBuf) -> server::BoxedFuture {
+    let addr: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+    let jar = Arc::new(CookieJar::new());
+    let admitted = loop_fn((endpoint, vec![0; MTU]), move |(endpoint, buf)| {
+        let (jar, root) = (jar.clone(), root.clone());
+        recv_dgram(endpoint, buf).map(move |(endpoint, buf, len)| {
+            let mut replies = endpoint.clone();
+            let login = server::admit(&buf[..len], &addr, &jar, |cookie| {
+                replies.poll_send(cookie).ok();
+            });
+            match login {
+                Some(login) => Loop::Break(server::serve(endpoint.clone(), endpoint, login, None,
+                                                         Uploads::default(), &root, false)),
+                None => Loop::Continue((endpoint, buf)),
+            }
+        })
+    });
+    Box::new(admitted.map_err(|e| eprintln!("Error during login: {}", e)).and_then(|client| client))
+}
+
+/// Sets the permissions and modification time of a file.
+fn set_metadata(path: &Path, metadata: &Metadata) {
+    fs::set_permissions(path, fs::Permissions::from_mode(metadata.mode & 0o7777)).unwrap();
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: metadata.mtime as libc::time_t, tv_nsec: metadata.mtime_nanos as libc::c_long },
+    ];
+    assert_eq!(unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) }, 0);
+}
+
+/// What the server stores for an uploaded file.
+pub struct StoredFile {
+    pub data: Vec<u8>,
+    /// bitmap of an incomplete upload, removed once the upload finished
+    pub bitmap: Option<Vec<u8>>,
+    pub metadata: fs::Metadata,
+}
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs uploads of the real client to the real server over a simulated network.
+pub struct Simulation {
+    pub network: Network,
+    /// features the client may request, the others are removed from its login
+    pub features: u64,
+    /// status updates are truncated to this many bytes
+    pub status_mtu: usize,
+    /// applied to the file before uploading it
+    pub metadata: Option<Metadata>,
+    /// datagrams sent during the last upload
+    pub trace: Vec<Sent>,
+    /// the client's files in `source`, the server's in `files`
+    dir: PathBuf,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, config: LinkConfig) -> Simulation {
+        let dir = env::temp_dir().join(format!("csync-sim-{}-{}", process::id(),
+                                               NEXT_DIR.fetch_add(1, AtomicOrdering::SeqCst)));
+        Simulation {
+            network: Network::new(seed, config),
+            features: 0,
+            status_mtu: MTU,
+            metadata: None,
+            trace: Vec::new(),
+            dir,
+        }
+    }
+
+    /// Uploads `source` to `path` in a new connection, returning once the server handled
+    /// everything the client sent.
+    ///
+    /// If `abort_after` is given, the client fails after sending that many chunks,
+    /// like a client being killed mid-upload.
+    pub fn upload(&mut self, path: &str, source: &[u8], abort_after: Option<u64>) -> Outcome {
+        let source_dir = self.dir.join("source");
+        let source_path = source_dir.join(path);
+        fs::create_dir_all(source_path.parent().unwrap()).unwrap();
+        fs::write(&source_path, source).unwrap();
+        // the login carries the modification time, a fixed one keeps the uploads of a seed alike
+        let metadata = self.metadata.unwrap_or(Metadata { mode: 0o644, mtime: 1_000_000_000, mtime_nanos: 0, owner: None });
+        set_metadata(&source_path, &metadata);
+
+        let mut network = mem::replace(&mut self.network, Network::new(0, LinkConfig::default()));
+        network.new_connection();
+        let clock = VirtualClock::new();
+        let link = Arc::new(Mutex::new(Link {
+            network,
+            clock: clock.clone(),
+            inbox: [VecDeque::new(), VecDeque::new()],
+            waiting: [None, None],
+            busy_until: [0, 0],
+            waiting_send: [None, None],
+            tap: Tap {
+                features: self.features,
+                status_mtu: self.status_mtu,
+                abort_after,
+                upload: None,
+                status_sent: false,
+                sent: HashSet::new(),
+                outcome: Outcome::default(),
+            },
+            trace: Vec::new(),
+        }));
+        let client_end = Endpoint { link: link.clone(), sends: Direction::ToServer, receives: Direction::ToClient };
+        let server_end = Endpoint { link: link.clone(), sends: Direction::ToClient, receives: Direction::ToServer };
+
+        let reactor = Reactor::new().unwrap();
+        let _reactor = tokio_reactor::set_default(&reactor.handle());
+        let clock = Clock::new_with_now(clock);
+        let _clock = tokio_timer::clock::set_default(&clock);
+        let driver = Driver { reactor, link: link.clone(), woken: Arc::new(AtomicBool::new(false)) };
+        let timer = Timer::new_with_now(driver, clock);
+        let _timer = tokio_timer::timer::set_default(&timer.handle());
+        let mut scheduler = CurrentThread::new_with_park(timer);
+        scheduler.spawn(serve_login(server_end, self.dir.join("files")));
+
+        let opt = Opt::from_iter(&["csync", "--files", source_dir.to_str().unwrap()]);
+        let result = if self.features & features::STREAM != 0 {
+            let resume = self.features & features::RESUME != 0;
+            let input = |kept| stream::iter_result(client::read_chunks(Cursor::new(source), kept));
+            client::upload_stream(path, input, resume, &opt, client_end.clone(), client_end, &mut scheduler)
+        } else {
+            client::upload(path, &opt, client_end.clone(), client_end, &mut scheduler)
+        };
+
+        // e.g. the FIN may still be on its way
+        let deadline = link.lock().unwrap().now() + SETTLE_TIMEOUT.as_secs() * 1_000_000;
+        loop {
+            let now = {
+                let link = link.lock().unwrap();
+                if link.settled() || link.now() >= deadline {
+                    break;
+                }
+                link.now()
+            };
+            scheduler.turn(Some(Duration::from_micros(deadline - now))).unwrap();
+        }
+        // stops the server
+        drop(scheduler);
+
+        let mut link = link.lock().unwrap();
+        self.network = mem::replace(&mut link.network, Network::new(0, LinkConfig::default()));
+        self.trace = mem::replace(&mut link.trace, Vec::new());
+        let mut outcome = link.tap.outcome.clone();
+        outcome.client_done = result.is_ok();
+        outcome.server_done = self.stored(path).map_or(false, |stored| stored.bitmap.is_none());
+        outcome.distinct_chunks = link.tap.sent.len() as u64;
+        outcome
+    }
+
+    /// Content and bitmap the server stores for `path`.
+    pub fn stored(&self, path: &str) -> Option<StoredFile> {
+        // all uploads use the same client token, its folder is the only one
+        let folder = fs::read_dir(self.dir.join("files")).ok()?.next()?.ok()?.path().join(path);
+        let file = folder.join("file");
+        Some(StoredFile {
+            data: fs::read(&file).ok()?,
+            bitmap: fs::read(folder.join("bitmap")).ok(),
+            metadata: fs::metadata(&file).ok()?,
+        })
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::MetadataExt;
+
+    use bitte_ein_bit::BitMap;
+
+    use super::*;
+
+    fn source(len: usize, seed: u64) -> Vec<u8> {
+        let mut rng = Rng::new(seed);
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    fn lossy() -> LinkConfig {
+        LinkConfig {
+            loss: 0.1,
+            duplicate: 0.05,
+            reorder: 0.1,
+            reorder_delay: 3000,
+            ..LinkConfig::default()
+        }
+    }
+
+    /// Uploads the file, repeating the upload if the FIN got lost like a user would.
+    fn upload(sim: &mut Simulation, path: &str, data: &[u8]) -> Outcome {
+        let outcome = sim.upload(path, data, None);
+        assert!(outcome.client_done, "{:?}", outcome);
+        if outcome.server_done {
+            return outcome;
+        }
+        // the server still has a full bitmap and is only waiting for the FIN
+        for _ in 0..10 {
+            let retry = sim.upload(path, data, None);
+            assert!(retry.client_done, "{:?}", retry);
+            assert_eq!(retry.distinct_chunks, 0);
+            if retry.server_done {
+                return outcome;
+            }
+        }
+        panic!("FIN never arrived");
+    }
+
+    /// The layout the client uploads a file of `length` bytes with.
+    fn layout(length: u64) -> ChunkInfo {
+        index_field_size(length, features::PARITY | features::DELTA)
+    }
+
+    fn assert_complete(sim: &Simulation, path: &str, data: &[u8]) {
+        let stored = sim.stored(path).expect("file not stored");
+        assert!(stored.bitmap.is_none(), "bitmap not removed");
+        assert!(stored.data == data, "stored data differs from source");
+    }
+
+    #[test]
+    fn clean_upload() {
+        let data = source(1_000_000, 1);
+        let mut sim = Simulation::new(1, LinkConfig::default());
+        let outcome = sim.upload("clean", &data, None);
+        assert!(outcome.client_done && outcome.server_done);
+        assert_eq!(outcome.distinct_chunks, layout(data.len() as u64).num_chunks);
+        assert_eq!(sim.network.stats.lost, 0);
+        assert_complete(&sim, "clean", &data);
+    }
+
+    #[test]
+    fn empty_and_tiny_files() {
+        let chunk_size = layout(1).chunk_size as usize;
+        for &len in &[0, 1, chunk_size - 1, chunk_size, chunk_size + 1, 2 * chunk_size, 2 * chunk_size + 1] {
+            let data = source(len, len as u64);
+            let mut sim = Simulation::new(2, lossy());
+            upload(&mut sim, "tiny", &data);
+            assert_complete(&sim, "tiny", &data);
+        }
+    }
+
+    #[test]
+    fn lossy_upload() {
+        let data = source(2_000_000, 3);
+        for seed in 0..8 {
+            let mut sim = Simulation::new(seed, lossy());
+            upload(&mut sim, "lossy", &data);
+            assert!(sim.network.stats.lost > 0);
+            assert!(sim.network.stats.duplicated > 0);
+            assert!(sim.network.stats.reordered > 0);
+            assert_complete(&sim, "lossy", &data);
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let run = || {
+            let mut network = Network::new(42, lossy());
+            let mut delivered = Vec::new();
+            for i in 0..1000u64 {
+                let direction = if i % 3 == 0 { Direction::ToClient } else { Direction::ToServer };
+                network.send(i * 10, direction, i.to_string().into_bytes(), i == 0);
+                while let Some(datagram) = network.deliver(i * 10) {
+                    delivered.push(datagram);
+                }
+            }
+            while let Some(at) = network.next_delivery() {
+                delivered.push(network.deliver(at).unwrap());
+            }
+            (delivered, network.stats)
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn deterministic_upload() {
+        let data = source(1_000_000, 19);
+        let run = |features| {
+            let mut sim = Simulation::new(19, lossy());
+            sim.features = features;
+            let outcome = upload(&mut sim, "same", &data);
+            assert_complete(&sim, "same", &data);
+            (outcome, sim.trace.clone(), sim.network.stats.clone())
+        };
+        let all = features::PARITY | features::COMPRESSION | features::ZERO_RUNS | features::DELTA;
+        let (outcome, trace, stats) = run(all);
+        assert!(trace.len() as u64 > outcome.chunks_sent);
+        assert_eq!((outcome, trace, stats), run(all));
+        assert_eq!(run(features::STREAM), run(features::STREAM));
+    }
+
+    #[test]
+    fn resume_after_abort() {
+        let data = source(1_500_000, 5);
+        let num_chunks = layout(data.len() as u64).num_chunks;
+        let mut sim = Simulation::new(5, lossy());
+
+        let first = sim.upload("resume", &data, Some(num_chunks / 2));
+        assert!(!first.client_done && !first.server_done);
+        let received = {
+            let bitmap = sim.stored("resume").unwrap().bitmap.expect("bitmap removed");
+            BitMap::with_length(&bitmap[..], num_chunks).ones()
+        };
+        assert!(received > 0);
+
+        let second = upload(&mut sim, "resume", &data);
+        // chunks received in the first connection aren't sent again
+        assert_eq!(second.distinct_chunks, num_chunks - received);
+        assert_complete(&sim, "resume", &data);
+    }
+
+    #[test]
+    fn resume_after_growth() {
+        let mut data = source(1_500_000, 6);
+        let num_chunks = layout(data.len() as u64).num_chunks;
+        let mut sim = Simulation::new(6, lossy());
+        let first = sim.upload("growing", &data, Some(num_chunks / 2));
+        assert!(!first.server_done);
+
+        // the bitmap of the shorter file is discarded
+        data.extend(source(100_000, 7));
+        let second = upload(&mut sim, "growing", &data);
+        assert_eq!(second.distinct_chunks, layout(data.len() as u64).num_chunks);
+        assert_complete(&sim, "growing", &data);
+    }
+
+    #[test]
+    fn fin_loss() {
+        fn is_fin(direction: Direction, data: &[u8]) -> bool {
+            // a FIN is a chunk with an empty payload, the index of this file fits into one byte
+            direction == Direction::ToServer && data.len() == 1
+        }
+        let data = source(100_000, 6);
+        let num_chunks = layout(data.len() as u64).num_chunks;
+        let mut sim = Simulation::new(6, LinkConfig { drop_if: Some(is_fin), ..LinkConfig::default() });
+
+        let first = sim.upload("fin", &data, None);
+        assert!(first.client_done && !first.server_done);
+        {
+            let stored = sim.stored("fin").unwrap();
+            assert!(stored.data == data);
+            let bitmap = stored.bitmap.expect("bitmap removed without FIN");
+            assert!(BitMap::with_length(&bitmap[..], num_chunks).all());
+        }
+
+        // the server resumes with a full bitmap, which the client acknowledges with the FIN
+        sim.network = Network::new(7, LinkConfig::default());
+        let second = sim.upload("fin", &data, None);
+        assert!(second.client_done && second.server_done);
+        assert_eq!(second.chunks_sent, 0);
+        assert_complete(&sim, "fin", &data);
+    }
+
+    #[test]
+    fn compressed_upload() {
+        let data: Vec<u8> = (0..100_000u32).flat_map(|i| format!("line {}\n", i / 7).into_bytes()).collect();
+        // chunks sent including the ones resent while in flight, and bytes sent: about a tenth
+        // of the raw chunks
+        let expected = [(1444, 150_890), (1250, 130_832), (1138, 118_989), (1332, 139_638)];
+        for seed in 0..4 {
+            let mut sim = Simulation::new(seed, lossy());
+            sim.features = features::COMPRESSION;
+            let outcome = upload(&mut sim, "compressed", &data);
+            assert_complete(&sim, "compressed", &data);
+            assert_eq!((outcome.chunks_sent, sim.network.stats.bytes_to_server), expected[seed as usize]);
+        }
+
+        // incompressible chunks are sent raw
+        let data = source(500_000, 9);
+        let mut sim = Simulation::new(9, lossy());
+        sim.features = features::COMPRESSION;
+        upload(&mut sim, "random", &data);
+        assert_complete(&sim, "random", &data);
+    }
+
+    #[test]
+    fn parity_upload() {
+        let data = source(2_000_000, 10);
+        // chunks and parity sent and chunks rebuilt: once loss is observed, about one parity per
+        // 16 chunks at 10% loss
+        let expected = [(1698, 117, 23), (1681, 72, 6), (1697, 111, 21), (1744, 91, 14)];
+        for seed in 0..4 {
+            let mut sim = Simulation::new(seed, lossy());
+            sim.features = features::PARITY;
+            let outcome = upload(&mut sim, "parity", &data);
+            assert_complete(&sim, "parity", &data);
+            assert_eq!((outcome.chunks_sent, outcome.parity_sent, outcome.rebuilt), expected[seed as usize]);
+        }
+
+        // too little loss for parity
+        let mut sim = Simulation::new(12, LinkConfig { loss: 0.02, ..LinkConfig::default() });
+        sim.features = features::PARITY;
+        let outcome = upload(&mut sim, "low", &data);
+        assert_complete(&sim, "low", &data);
+        assert_eq!((outcome.chunks_sent, outcome.parity_sent), (1586, 0));
+
+        // no loss, no parity
+        let mut sim = Simulation::new(11, LinkConfig::default());
+        sim.features = features::PARITY;
+        let outcome = upload(&mut sim, "clean", &data);
+        assert_eq!(outcome.parity_sent, 0);
+        assert_complete(&sim, "clean", &data);
+    }
+
+    #[test]
+    fn sparse_upload() {
+        // a few islands of data in a file of zeros
+        let mut data = vec![0; 4_000_000];
+        for (i, island) in [10_000, 1_500_000, 3_999_000].iter().enumerate() {
+            data[*island..*island + 1000].copy_from_slice(&source(1000, i as u64));
+        }
+        let mut full_sim = Simulation::new(13, LinkConfig::default());
+        let full = upload(&mut full_sim, "full", &data);
+        assert_eq!(full.zero_runs_sent, 0);
+        assert_complete(&full_sim, "full", &data);
+
+        let mut sim = Simulation::new(13, LinkConfig::default());
+        sim.features = features::ZERO_RUNS;
+        let sparse = upload(&mut sim, "sparse", &data);
+        assert_complete(&sim, "sparse", &data);
+        // only the chunks of the islands are sent
+        assert_eq!((sparse.zero_runs_sent, sparse.distinct_chunks), (4, 6));
+        assert_eq!((sim.network.stats.bytes_to_server, full_sim.network.stats.bytes_to_server), (10_522, 4_370_049));
+
+        // lost zero runs are declared again: chunks and zero runs sent
+        let expected = [(10, 5), (6, 4), (7, 3), (6, 3)];
+        for seed in 0..4 {
+            let mut sim = Simulation::new(seed, lossy());
+            sim.features = features::ZERO_RUNS;
+            let outcome = upload(&mut sim, "sparse", &data);
+            assert_complete(&sim, "sparse", &data);
+            assert_eq!((outcome.chunks_sent, outcome.zero_runs_sent), expected[seed as usize]);
+        }
+    }
+
+    #[test]
+    fn delta_upload() {
+        let old = source(3_000_000, 14);
+        // an insertion shifting the rest of the file and a few changed bytes
+        let mut data = old.clone();
+        data.splice(1_000_000..1_000_000, source(300, 15));
+        data[2_000_000] ^= 1;
+        data[2_500_000] ^= 1;
+        let num_chunks = layout(data.len() as u64).num_chunks;
+
+        // different and all chunks and copy ranges sent, the chunks sent before the signatures
+        // arrived aren't copied
+        let expected = [(254, 254, 5), (486, 549, 9), (347, 375, 7), (483, 504, 10)];
+        for seed in 0..4 {
+            let config = if seed == 0 { LinkConfig::default() } else { lossy() };
+            let mut sim = Simulation::new(seed, config);
+            sim.features = features::DELTA;
+            // nothing to copy from yet
+            let first = upload(&mut sim, "delta", &old);
+            assert_eq!(first.copy_ranges_sent, 0);
+            assert_complete(&sim, "delta", &old);
+
+            let outcome = upload(&mut sim, "delta", &data);
+            assert_complete(&sim, "delta", &data);
+            assert_eq!((outcome.distinct_chunks, outcome.chunks_sent, outcome.copy_ranges_sent), expected[seed as usize]);
+        }
+
+        // without delta the file is uploaded in full
+        let mut sim = Simulation::new(16, LinkConfig::default());
+        upload(&mut sim, "full", &old);
+        let outcome = upload(&mut sim, "full", &data);
+        assert_complete(&sim, "full", &data);
+        assert_eq!(outcome.distinct_chunks, num_chunks);
+    }
+
+    #[test]
+    fn metadata_upload() {
+        let data = source(500_000, 17);
+        let num_chunks = layout(data.len() as u64).num_chunks;
+        let mut sim = Simulation::new(17, lossy());
+        // neither the setuid bit nor an owner the server wasn't told to restore are applied
+        let owner = Some((12345, 12345));
+        sim.metadata = Some(Metadata { mode: 0o104640, mtime: 1_500_000_000, mtime_nanos: 42, owner });
+
+        // only applied once the upload is complete
+        sim.upload("meta", &data, Some(num_chunks / 2));
+        assert_ne!(sim.stored("meta").unwrap().metadata.mtime(), 1_500_000_000);
+        upload(&mut sim, "meta", &data);
+        assert_complete(&sim, "meta", &data);
+        let metadata = sim.stored("meta").unwrap().metadata;
+        assert_eq!(metadata.mode() & 0o7777, 0o640);
+        assert_ne!(metadata.uid(), 12345);
+        assert_eq!((metadata.mtime(), metadata.mtime_nsec()), (1_500_000_000, 42));
+    }
+
+    #[test]
+    fn truncated_status_updates() {
+        let data = source(500_000, 8);
+        let config = LinkConfig { loss: 0.3, ..lossy() };
+        for seed in 0..4 {
+            let mut sim = Simulation::new(seed, config.clone());
+            // only a handful of runs fit into a status update
+            sim.status_mtu = 16;
+            upload(&mut sim, "truncated", &data);
+            assert_complete(&sim, "truncated", &data);
+        }
+    }
+
+    #[test]
+    fn stream_upload() {
+        for &len in &[0, 1, STREAM_CHUNK_SIZE as usize, 2_000_000] {
+            let data = source(len, 9);
+            for seed in 0..4 {
+                let mut sim = Simulation::new(seed, lossy());
+                sim.features = features::STREAM;
+                let outcome = sim.upload("stream", &data, None);
+                assert!(outcome.client_done, "{:?}", outcome);
+                assert_eq!(outcome.distinct_chunks, stream_chunk_info(len as u64).num_chunks);
+                let stored = sim.stored("stream").unwrap();
+                assert!(stored.data == data, "stored data differs from source");
+                // a lost FIN leaves the bitmap
+                assert_eq!(stored.bitmap.is_none(), outcome.server_done);
+            }
+        }
+    }
+
+    #[test]
+    fn stream_resume() {
+        let data = source(2_000_000, 18);
+        let num_chunks = stream_chunk_info(data.len() as u64).num_chunks;
+        for seed in 0..2 {
+            // the chunks lost before the abort would end what the server has in a row
+            let mut sim = Simulation::new(seed, LinkConfig::default());
+            sim.features = features::STREAM | features::RESUME;
+
+            // the chunks the server got in a row from the start aren't sent again
+            sim.upload("log", &data[..1_000_000], Some(num_chunks / 4));
+            let outcome = sim.upload("log", &data[..1_000_000], None);
+            assert!(outcome.client_done, "{:?}", outcome);
+            assert_eq!(outcome.distinct_chunks, num_chunks / 4);
+            assert!(sim.stored("log").unwrap().data[..] == data[..1_000_000], "stored data differs from source");
+
+            // like a log file written to since, only the partial last chunk is sent again
+            let outcome = sim.upload("log", &data, None);
+            assert!(outcome.client_done, "{:?}", outcome);
+            assert_eq!(outcome.distinct_chunks, num_chunks / 2 + 1);
+            assert!(sim.stored("log").unwrap().data == data, "stored data differs from source");
+
+            let outcome = sim.upload("log", &data, None);
+            assert!(outcome.client_done, "{:?}", outcome);
+            assert_eq!(outcome.distinct_chunks, 1);
+            assert!(sim.stored("log").unwrap().data == data, "stored data differs from source");
+        }
+    }
+
+    #[test]
+    fn stream_resume_mismatch() {
+        let data = source(1_000_000, 20);
+        let num_chunks = stream_chunk_info(data.len() as u64).num_chunks;
+        // like a rotated log file, one which starts with other bytes and a shorter one
+        let rotated = source(1_000_000, 21);
+        for input in &[&rotated[..], &data[..10_000]] {
+            let mut sim = Simulation::new(22, LinkConfig::default());
+            sim.features = features::STREAM | features::RESUME;
+            sim.upload("log", &data, Some(num_chunks / 2));
+
+            // the client sends nothing after the kept chunks which don't match its input
+            let outcome = sim.upload("log", input, None);
+            assert!(!outcome.client_done, "{:?}", outcome);
+            assert_eq!(outcome.distinct_chunks, 0);
+            assert!(sim.stored("log").unwrap().data[..] == data[..num_chunks as usize / 2 * STREAM_CHUNK_SIZE as usize]);
+
+            // and uploads it from the start instead
+            sim.features = features::STREAM;
+            let outcome = sim.upload("log", input, None);
+            assert!(outcome.client_done, "{:?}", outcome);
+            assert!(sim.stored("log").unwrap().data[..] == input[..], "stored data differs from source");
+        }
+    }
+}
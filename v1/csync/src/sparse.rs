@@ -0,0 +1,89 @@
+//! Detection of all-zero chunks, which are declared with a `ZeroRun` instead of being sent.
+
+use std::io::{self, Read};
+
+use codec::ChunkInfo;
+
+/// Sorted, non-overlapping runs of all-zero chunks of a file.
+#[derive(Debug, Default)]
+pub struct ZeroRuns {
+    /// `(first, end)` of each run
+    runs: Vec<(u64, u64)>,
+}
+
+impl ZeroRuns {
+    /// Reads the whole file chunk by chunk to find the all-zero ones.
+    pub fn scan<R: Read>(mut file: R, chunk_info: &ChunkInfo) -> io::Result<ZeroRuns> {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        let mut buf = vec![0; chunk_info.chunk_size as usize];
+        for index in 0..chunk_info.num_chunks {
+            let buf = &mut buf[..chunk_info.chunk_len(index) as usize];
+            file.read_exact(buf)?;
+            if buf.iter().any(|&b| b != 0) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(&mut (_, ref mut end)) if *end == index => *end += 1,
+                _ => runs.push((index, index + 1)),
+            }
+        }
+        Ok(ZeroRuns { runs })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Number of all-zero chunks.
+    pub fn chunks(&self) -> u64 {
+        self.runs.iter().map(|&(first, end)| end - first).sum()
+    }
+
+    /// Returns the end of the run containing `index`, if it's an all-zero chunk.
+    pub fn run_end(&self, index: u64) -> Option<u64> {
+        let i = match self.runs.binary_search_by_key(&index, |&(first, _)| first) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let (_, end) = self.runs[i];
+        if index < end {
+            Some(end)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::{features, index_field_size};
+
+    #[test]
+    fn test_scan() {
+        let chunk_info = index_field_size(10 * 1000 + 10, features::ZERO_RUNS);
+        let chunk_size = chunk_info.chunk_size as usize;
+        let mut data = vec![0; 10 * 1000 + 10];
+        // chunks 1, 4 and the last one have data
+        data[chunk_size + 17] = 1;
+        data[4 * chunk_size] = 1;
+        let len = data.len();
+        data[len - 1] = 1;
+        let runs = ZeroRuns::scan(&data[..], &chunk_info).unwrap();
+        let last = chunk_info.num_chunks - 1;
+        assert_eq!(runs.runs, [(0, 1), (2, 4), (5, last)]);
+        assert_eq!(runs.chunks(), last - 2);
+
+        assert_eq!(runs.run_end(0), Some(1));
+        assert_eq!(runs.run_end(1), None);
+        assert_eq!(runs.run_end(2), Some(4));
+        assert_eq!(runs.run_end(3), Some(4));
+        assert_eq!(runs.run_end(4), None);
+        assert_eq!(runs.run_end(last - 1), Some(last));
+        assert_eq!(runs.run_end(last), None);
+
+        // a file shorter than its length is an error
+        assert!(ZeroRuns::scan(&data[..len - 1], &chunk_info).is_err());
+    }
+}
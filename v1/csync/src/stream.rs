@@ -0,0 +1,238 @@
+//! Streaming uploads of input with unknown length, e.g. from a pipe.
+//!
+//! The input can't be read again, thus the client keeps every chunk until a status
+//! update reports it as received. Reading stops while `WINDOW` chunks are unacknowledged.
+//! The server's bitmap grows as chunks arrive and always keeps a spare bit at its end,
+//! so status updates are only complete once the server knows the length.
+
+use std::cmp;
+use std::collections::VecDeque;
+
+use codec::{stream_chunk_info, stream_id, Chunk, DecodeError, EndOfStream, MissingRanges, STREAM_CHUNK_SIZE};
+
+/// Chunks the client keeps in memory for retransmissions.
+pub const WINDOW: u64 = 1 << 14;
+/// Chunks the server's bitmap holds initially.
+pub const INITIAL_CHUNKS: u64 = 1 << 16;
+
+/// Number of bits the server's bitmap of `num_bits` needs to hold chunk `index`, once
+/// `received` chunks arrived.
+///
+/// Returns `None` for chunks `WINDOW` or more ahead of the received ones, which no client
+/// sends as it keeps at most `WINDOW` chunks, and which would make the bitmap huge.
+pub fn grown_len(num_bits: u64, received: u64, index: u64) -> Option<u64> {
+    if index >= received.saturating_add(WINDOW) {
+        return None;
+    }
+    let mut len = cmp::max(num_bits, 1);
+    while index + 1 >= len {
+        len = len.checked_mul(2)?;
+    }
+    Some(len)
+}
+
+/// Number of bits the server's bitmap starts with if it keeps the first `resumed` chunks.
+pub fn initial_len(resumed: u64) -> u64 {
+    let mut len = INITIAL_CHUNKS;
+    // with a spare bit
+    while len <= resumed {
+        len *= 2;
+    }
+    len
+}
+
+/// Chunks of a streaming upload which aren't acknowledged yet.
+#[derive(Default)]
+pub struct SendWindow {
+    /// payloads of chunks from `base` on, `None` once acknowledged
+    ///
+    /// The newest chunk sent is kept for `keepalive`.
+    chunks: VecDeque<Option<Vec<u8>>>,
+    base: u64,
+    next: u64,
+    /// bytes read from the input
+    read: u64,
+    /// set once the input ended
+    finished: bool,
+    missing: MissingRanges,
+    /// missing chunks before this one are retransmitted
+    retransmit_end: u64,
+    end_of_stream_due: bool,
+}
+
+impl SendWindow {
+    /// Creates the window of a resumed upload whose first `resumed` chunks the server has.
+    pub fn resume(resumed: u64) -> SendWindow {
+        SendWindow { base: resumed, next: resumed, read: resumed * STREAM_CHUNK_SIZE, ..SendWindow::default() }
+    }
+
+    /// Returns whether another chunk of input fits into the window.
+    pub fn has_room(&self) -> bool {
+        !self.finished && (self.chunks.len() as u64) < WINDOW
+    }
+
+    /// Adds the next chunk of input, which must be `STREAM_CHUNK_SIZE` long unless it's the last.
+    pub fn push(&mut self, payload: Vec<u8>) {
+        debug_assert!(self.has_room() && !payload.is_empty());
+        self.read += payload.len() as u64;
+        self.chunks.push_back(Some(payload));
+    }
+
+    /// Marks the end of the input, repeated calls do nothing.
+    pub fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.end_of_stream_due = true;
+        }
+    }
+
+    /// Bytes read from the input so far.
+    pub fn read(&self) -> u64 {
+        self.read
+    }
+
+    /// Bytes acknowledged by the last status update.
+    pub fn acknowledged(&self) -> u64 {
+        cmp::min(self.missing.received() * STREAM_CHUNK_SIZE, self.read)
+    }
+
+    /// Parses the runlengths of a status update, returning `true` if the upload is complete.
+    pub fn status_update(&mut self, runlengths: &[u8]) -> Result<bool, DecodeError> {
+        let done = self.missing.parse_status_update(runlengths)?;
+        for index in self.base..self.next.saturating_sub(1) {
+            if self.missing.is_missing(index) == Some(false) {
+                self.chunks[(index - self.base) as usize] = None;
+            }
+        }
+        while self.base < self.next && self.chunks.front().map_or(false, Option::is_none) {
+            self.chunks.pop_front();
+            self.base += 1;
+        }
+        self.retransmit_end = self.next;
+        if self.finished {
+            self.end_of_stream_due = true;
+        }
+        Ok(done && self.finished && self.missing.received() == stream_chunk_info(self.read).num_chunks)
+    }
+
+    /// Returns the next message to send: retransmissions, new chunks, then the end of the stream.
+    ///
+    /// Hands `buf` back if there's nothing to send.
+    pub fn next_message(&mut self, buf: Vec<u8>) -> Result<Chunk, Vec<u8>> {
+        while let Some(index) = self.missing.next_chunk() {
+            if index >= self.retransmit_end {
+                break;
+            }
+            if index < self.base {
+                continue;
+            }
+            if let Some(ref payload) = self.chunks[(index - self.base) as usize] {
+                return Ok(chunk(buf, index, payload));
+            }
+        }
+        self.retransmit_end = 0;
+        let sent = self.next - self.base;
+        if let Some(&Some(ref payload)) = self.chunks.get(sent as usize) {
+            let chunk = chunk(buf, self.next, payload);
+            self.next += 1;
+            return Ok(chunk);
+        }
+        if self.end_of_stream_due && self.next - self.base == self.chunks.len() as u64 {
+            self.end_of_stream_due = false;
+            return Ok(EndOfStream { length: self.read }.encode(buf));
+        }
+        Err(buf)
+    }
+
+    /// Repeats the newest chunk sent to keep the connection alive while waiting for input.
+    ///
+    /// Once all input is sent, the end of the stream is repeated instead, in case it got lost
+    /// and no status update asks for it again.
+    pub fn keepalive(&self, buf: Vec<u8>) -> Option<Chunk> {
+        if self.finished && self.next - self.base == self.chunks.len() as u64 {
+            return Some(EndOfStream { length: self.read }.encode(buf));
+        }
+        // nothing was sent yet after resuming
+        let newest = self.next.checked_sub(1).filter(|&newest| newest >= self.base)?;
+        self.chunks[(newest - self.base) as usize].as_ref().map(|payload| chunk(buf, newest, payload))
+    }
+}
+
+fn chunk(buf: Vec<u8>, index: u64, payload: &[u8]) -> Chunk {
+    let mut chunk = Chunk::new_stream(buf, stream_id(index), payload.len());
+    chunk.as_mut().copy_from_slice(payload);
+    chunk
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::extension;
+
+    #[test]
+    fn test_grown_len() {
+        assert_eq!(grown_len(16, 0, 0), Some(16));
+        assert_eq!(grown_len(16, 0, 14), Some(16));
+        // the last bit is spare
+        assert_eq!(grown_len(16, 0, 15), Some(32));
+        assert_eq!(grown_len(16, 0, 30), Some(32));
+        assert_eq!(grown_len(16, 0, 31), Some(64));
+        // no further ahead of the received chunks than the client's window
+        assert_eq!(grown_len(16, 0, WINDOW - 1), Some(2 * WINDOW));
+        assert_eq!(grown_len(16, 0, WINDOW), None);
+        assert_eq!(grown_len(2 * WINDOW, WINDOW, 2 * WINDOW - 1), Some(4 * WINDOW));
+        assert_eq!(grown_len(16, 0, u64::max_value()), None);
+        assert_eq!(grown_len(16, u64::max_value(), u64::max_value() - 1), None);
+    }
+
+    #[test]
+    fn test_send_window() {
+        let mut window = SendWindow::default();
+        let full = vec![1; STREAM_CHUNK_SIZE as usize];
+        for payload in &[&full[..], &full[..], b"baz"] {
+            window.push(payload.to_vec());
+        }
+        let ids: Vec<_> = (0..3).map(|_| window.next_message(Vec::new()).unwrap().index).collect();
+        assert_eq!(ids, [stream_id(0), stream_id(1), stream_id(2)]);
+        assert!(window.next_message(Vec::new()).is_err());
+
+        // chunks 0 and 2 of a bitmap of 16 chunks received
+        assert_eq!(window.status_update(&[1, 1, 1, 13]), Ok(false));
+        assert_eq!(window.acknowledged(), 2 * STREAM_CHUNK_SIZE);
+        let retransmit = window.next_message(Vec::new()).unwrap();
+        assert_eq!((retransmit.index, retransmit.as_ref()), (stream_id(1), &full[..]));
+        assert!(window.next_message(Vec::new()).is_err());
+        assert_eq!(window.keepalive(Vec::new()).unwrap().index, stream_id(2));
+
+        window.finish();
+        assert!(!window.has_room());
+        let end = window.next_message(Vec::new()).unwrap();
+        assert_eq!(end.index, extension::END_OF_STREAM);
+        assert_eq!(EndOfStream::decode(&Chunk::decode_stream(end.into_vec()).unwrap()), Ok(EndOfStream { length: 2 * STREAM_CHUNK_SIZE + 3 }));
+        assert!(window.next_message(Vec::new()).is_err());
+        assert_eq!(window.keepalive(Vec::new()).unwrap().index, extension::END_OF_STREAM);
+
+        // the end of the stream is repeated until the server knows the length
+        assert_eq!(window.status_update(&[3, 13]), Ok(false));
+        assert_eq!(window.next_message(Vec::new()).unwrap().index, extension::END_OF_STREAM);
+        assert_eq!(window.status_update(&[3]), Ok(true));
+    }
+
+    #[test]
+    fn test_resume() {
+        assert_eq!(initial_len(0), INITIAL_CHUNKS);
+        assert_eq!(initial_len(INITIAL_CHUNKS - 1), INITIAL_CHUNKS);
+        assert_eq!(initial_len(INITIAL_CHUNKS), 2 * INITIAL_CHUNKS);
+
+        let mut window = SendWindow::resume(5);
+        assert_eq!(window.read(), 5 * STREAM_CHUNK_SIZE);
+        assert!(window.keepalive(Vec::new()).is_none());
+        window.push(b"foo".to_vec());
+        assert_eq!(window.next_message(Vec::new()).unwrap().index, stream_id(5));
+        assert_eq!(window.keepalive(Vec::new()).unwrap().index, stream_id(5));
+        window.finish();
+        let end = window.next_message(Vec::new()).unwrap();
+        assert_eq!(EndOfStream::decode(&Chunk::decode_stream(end.into_vec()).unwrap()), Ok(EndOfStream { length: 5 * STREAM_CHUNK_SIZE + 3 }));
+        assert_eq!(window.status_update(&[6]), Ok(true));
+    }
+}
@@ -1,6 +1,7 @@
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use futures::{Stream, Async, Poll};
+use tokio::clock;
 use tokio::timer::Interval;
 
 pub struct TimeoutStream<S: Stream> {
@@ -14,7 +15,7 @@ impl<S: Stream> TimeoutStream<S> {
         TimeoutStream {
             got_something: false,
             stream: s,
-            interval: Interval::new(Instant::now() + timeout, timeout),
+            interval: Interval::new(clock::now() + timeout, timeout),
         }
     }
 }
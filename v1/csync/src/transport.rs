@@ -0,0 +1,73 @@
+//! Datagrams between client and server, sent over a connected `UdpSocket` or the simulated link
+//! of the tests.
+
+use std::io;
+
+use futures::{Future, Poll, Async};
+use tokio::net::UdpSocket;
+
+/// A connected datagram transport.
+pub trait Transport {
+    /// Sends a datagram to the other side, like `UdpSocket::poll_send`.
+    fn poll_send(&mut self, buf: &[u8]) -> Poll<usize, io::Error>;
+    /// Receives a datagram from the other side, like `UdpSocket::poll_recv`.
+    fn poll_recv(&mut self, buf: &mut [u8]) -> Poll<usize, io::Error>;
+}
+
+impl Transport for UdpSocket {
+    fn poll_send(&mut self, buf: &[u8]) -> Poll<usize, io::Error> {
+        UdpSocket::poll_send(self, buf)
+    }
+
+    fn poll_recv(&mut self, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        UdpSocket::poll_recv(self, buf)
+    }
+}
+
+/// Sends `buf`, returning the transport and the buffer.
+pub fn send_dgram<T: Transport>(transport: T, buf: Vec<u8>) -> SendDgram<T> {
+    SendDgram { state: Some((transport, buf)) }
+}
+
+/// Receives a datagram into `buf`, returning the transport, the buffer and the datagram's length.
+pub fn recv_dgram<T: Transport>(transport: T, buf: Vec<u8>) -> RecvDgram<T> {
+    RecvDgram { state: Some((transport, buf)) }
+}
+
+pub struct SendDgram<T> {
+    state: Option<(T, Vec<u8>)>,
+}
+
+impl<T: Transport> Future for SendDgram<T> {
+    type Item = (T, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(T, Vec<u8>), io::Error> {
+        {
+            let (ref mut transport, ref buf) = *self.state.as_mut().expect("polled SendDgram after completion");
+            let written = try_ready!(transport.poll_send(buf));
+            if written != buf.len() {
+                return Err(io::Error::new(io::ErrorKind::Other, "failed to write entire datagram"));
+            }
+        }
+        Ok(Async::Ready(self.state.take().unwrap()))
+    }
+}
+
+pub struct RecvDgram<T> {
+    state: Option<(T, Vec<u8>)>,
+}
+
+impl<T: Transport> Future for RecvDgram<T> {
+    type Item = (T, Vec<u8>, usize);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(T, Vec<u8>, usize), io::Error> {
+        let len = {
+            let (ref mut transport, ref mut buf) = *self.state.as_mut().expect("polled RecvDgram after completion");
+            try_ready!(transport.poll_recv(buf))
+        };
+        let (transport, buf) = self.state.take().unwrap();
+        Ok(Async::Ready((transport, buf, len)))
+    }
+}
@@ -0,0 +1,365 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use serde_cbor;
+use tiny_keccak;
+
+use super::{Block, BlockId, BlockRef, BlockStore, Full, Partial};
+
+/// Content-addressed block files in a folder:
+///
+/// * `blocks/<hex blockid>` contains the (encrypted) data of each full block
+/// * `partial/<hex blockid>` contains the data received so far of each partial block
+/// * `partial/<hex blockid>.received` contains the byte ranges received so far of each partial
+///   block, their start and end as little-endian u64, appended as chunks arrive
+/// * `roots` contains the root and pending root as CBOR, including the key of the root block
+///
+/// Files are only readable by their owner. Full blocks and roots are written to a temporary file
+/// first and renamed, so a crash never leaves half a file behind. Chunks are written in place
+/// without syncing, chunks lost in a crash are caught by the hash check when the block is promoted.
+///
+/// Full blocks are read from disk and checked against their id whenever they are needed, only
+/// the partial blocks of the transfers in progress are kept in memory, along with their open files.
+#[derive(Debug)]
+pub struct DiskStore {
+    folder: PathBuf,
+    full: HashSet<BlockId>,
+    partial: HashMap<BlockId, Block>,
+    files: HashMap<BlockId, PartialFiles>,
+    roots: Option<Roots>,
+}
+
+/// Files of a partial block, opened by the first chunk and kept open for the following ones.
+#[derive(Debug)]
+struct PartialFiles {
+    data: File,
+    received: File,
+}
+
+/// Bytes of a received range in the `.received` file.
+const RANGE_LEN: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Roots {
+    root: BlockRef,
+    pending_root: Option<BlockRef>,
+}
+
+impl DiskStore {
+    /// Opens the store in `folder`, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(folder: P) -> io::Result<DiskStore> {
+        let folder = folder.as_ref().to_owned();
+        fs::create_dir_all(folder.join("blocks"))?;
+        fs::create_dir_all(folder.join("partial"))?;
+
+        let mut full = HashSet::new();
+        for entry in fs::read_dir(folder.join("blocks"))? {
+            let path = entry?.path();
+            match path.file_name().and_then(|name| name.to_str()).and_then(parse_id) {
+                Some(id) => full.insert(id),
+                // leftover temporary file
+                None => continue,
+            };
+        }
+
+        let mut partial = HashMap::new();
+        for entry in fs::read_dir(folder.join("partial"))? {
+            let path = entry?.path();
+            let id = match path.file_name().and_then(|name| name.to_str()).and_then(parse_id) {
+                Some(id) => id,
+                // received ranges or leftover temporary file
+                None => continue,
+            };
+            if full.contains(&id) {
+                // crashed while promoting the block
+                fs::remove_file(path.with_extension("received")).ok();
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let received = match fs::read(path.with_extension("received")) {
+                Ok(received) => received,
+                // crashed before the block was set up, or left by a version which kept the
+                // availability per chunk, it will be requested again
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    fs::remove_file(path.with_extension("available")).ok();
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if received.len() % RANGE_LEN != 0 {
+                // a range cut short by a crash is dropped, so the next ones line up
+                OpenOptions::new().write(true).open(path.with_extension("received"))?
+                    .set_len((received.len() - received.len() % RANGE_LEN) as u64)?;
+            }
+            let data = fs::read(&path)?;
+            let mut block = Partial { id, data, available: Vec::new() };
+            for range in received.chunks(RANGE_LEN).filter(|range| range.len() == RANGE_LEN) {
+                let (from, to) = (read_u64(&range[..8]), read_u64(&range[8..]));
+                if from > to || to > block.data.len() as u64 {
+                    warn!("{} has an invalid range {}..{}, receiving it again", path.display(), from, to);
+                    block.available.clear();
+                    write_atomic(&path.with_extension("received"), &[])?;
+                    break;
+                }
+                block.mark(from, to);
+            }
+            if block.is_complete() {
+                // crashed before the block was promoted
+                if tiny_keccak::keccak256(&block.data) == id {
+                    write_atomic(&folder.join("blocks").join(format_id(id)), &block.data)?;
+                    fs::remove_file(path.with_extension("received"))?;
+                    fs::remove_file(&path)?;
+                    full.insert(id);
+                    continue;
+                }
+                warn!("chunks of {} were lost, receiving it again", path.display());
+                block.available.clear();
+                write_atomic(&path.with_extension("received"), &[])?;
+            }
+            partial.insert(id, Block::Partial(block));
+        }
+
+        let roots = match fs::read(folder.join("roots")) {
+            Ok(roots) => Some(serde_cbor::from_slice(&roots)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        trace!("opened blockdb {} with {} full and {} partial blocks", folder.display(), full.len(), partial.len());
+
+        Ok(DiskStore {
+            folder,
+            full,
+            partial,
+            files: HashMap::new(),
+            roots,
+        })
+    }
+
+    fn block_path(&self, id: BlockId) -> PathBuf {
+        self.folder.join("blocks").join(format_id(id))
+    }
+
+    fn partial_path(&self, id: BlockId) -> PathBuf {
+        self.folder.join("partial").join(format_id(id))
+    }
+
+    fn received_path(&self, id: BlockId) -> PathBuf {
+        self.partial_path(id).with_extension("received")
+    }
+
+    /// Reads the full block, failing if its file doesn't match the id.
+    fn read_full(&self, id: BlockId) -> io::Result<Full> {
+        let path = self.block_path(id);
+        let data = fs::read(&path)?;
+        if tiny_keccak::keccak256(&data) != id {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("corrupted block file {}", path.display())));
+        }
+        Ok(Full { id, data })
+    }
+}
+
+impl BlockStore for DiskStore {
+    fn contains(&self, id: BlockId) -> bool {
+        self.full.contains(&id) || self.partial.contains_key(&id)
+    }
+
+    fn get(&self, id: BlockId) -> io::Result<Option<Cow<'_, Block>>> {
+        if let Some(block) = self.partial.get(&id) {
+            return Ok(Some(Cow::Borrowed(block)));
+        }
+        if !self.full.contains(&id) {
+            return Ok(None);
+        }
+        Ok(Some(Cow::Owned(Block::Full(self.read_full(id)?))))
+    }
+
+    fn is_partial(&self, id: BlockId) -> io::Result<bool> {
+        Ok(self.partial.contains_key(&id))
+    }
+
+    fn block_len(&self, id: BlockId) -> io::Result<Option<u64>> {
+        if let Some(block) = self.partial.get(&id) {
+            return Ok(Some(block.len()));
+        }
+        if !self.full.contains(&id) {
+            return Ok(None);
+        }
+        Ok(Some(fs::metadata(self.block_path(id))?.len()))
+    }
+
+    fn read_at(&self, id: BlockId, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.block_path(id))?;
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut data = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn insert(&mut self, block: Block) -> io::Result<()> {
+        let id = block.id();
+        // the received ranges are replaced by a new file
+        self.files.remove(&id);
+        match block {
+            Block::Full(ref full) => {
+                write_atomic(&self.block_path(id), &full.data)?;
+                self.partial.remove(&id);
+                self.full.insert(id);
+            }
+            Block::Partial(ref partial) => {
+                // the data first, the partial only exists with its received ranges
+                private_file(&self.partial_path(id))?.write_all(&partial.data)?;
+                let mut received = Vec::with_capacity(RANGE_LEN * partial.available.len());
+                for &(from, to) in &partial.available {
+                    write_u64(&mut received, from);
+                    write_u64(&mut received, to);
+                }
+                write_atomic(&self.received_path(id), &received)?;
+                self.full.remove(&id);
+            }
+        }
+        if block.is_partial() {
+            self.partial.insert(id, block);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, id: BlockId) -> io::Result<Option<Block>> {
+        self.files.remove(&id);
+        if let Some(block) = self.partial.remove(&id) {
+            fs::remove_file(self.received_path(id))?;
+            fs::remove_file(self.partial_path(id))?;
+            return Ok(Some(block));
+        }
+        if !self.full.contains(&id) {
+            return Ok(None);
+        }
+        let full = self.read_full(id)?;
+        fs::remove_file(self.block_path(id))?;
+        self.full.remove(&id);
+        Ok(Some(Block::Full(full)))
+    }
+
+    fn write_chunk(&mut self, id: BlockId, offset: usize, data: &[u8]) -> io::Result<()> {
+        self.partial.get_mut(&id).expect("block not in blockdb").partial_mut().write_chunk(offset, data);
+        if !self.files.contains_key(&id) {
+            let files = PartialFiles {
+                data: OpenOptions::new().write(true).open(self.partial_path(id))?,
+                received: OpenOptions::new().append(true).open(self.received_path(id))?,
+            };
+            self.files.insert(id, files);
+        }
+        let files = &self.files[&id];
+        files.data.write_all_at(data, offset as u64)?;
+        let mut range = Vec::with_capacity(RANGE_LEN);
+        write_u64(&mut range, offset as u64);
+        write_u64(&mut range, (offset + data.len()) as u64);
+        (&files.received).write_all(&range)
+    }
+
+    fn roots(&self) -> Option<(BlockRef, Option<BlockRef>)> {
+        self.roots.as_ref().map(|roots| (roots.root.clone(), roots.pending_root.clone()))
+    }
+
+    fn save_roots(&mut self, root: &BlockRef, pending_root: Option<&BlockRef>) -> io::Result<()> {
+        let roots = Roots {
+            root: root.clone(),
+            pending_root: pending_root.cloned(),
+        };
+        let data = serde_cbor::to_vec(&roots).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        write_atomic(&self.folder.join("roots"), &data)?;
+        self.roots = Some(roots);
+        Ok(())
+    }
+}
+
+/// Creates or truncates the file at `path`, only readable by its owner.
+fn private_file(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // the mode only applies to new files, e.g. not to a leftover temporary one
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = private_file(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    // the rename itself only survives a crash once the directory is synced
+    File::open(path.parent().expect("file in the store's folder"))?.sync_all()
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+fn write_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend((0..8).map(|i| (value >> (8 * i)) as u8));
+}
+
+fn format_id(id: BlockId) -> String {
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_id(name: &str) -> Option<BlockId> {
+    if name.len() != 64 {
+        return None;
+    }
+    let mut id = [0; 32];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(name.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn test_partial_ranges() {
+        let folder = env::temp_dir().join(format!("scsync-disk-{}", process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let id = [1; 32];
+        let mut store = DiskStore::open(&folder).unwrap();
+        store.insert(Block::Partial(Partial::new(id, 25))).unwrap();
+        store.write_chunk(id, 10, &[7; 10]).unwrap();
+        store.write_chunk(id, 0, &[7; 5]).unwrap();
+        drop(store);
+
+        // the received bytes survive a restart, a range cut short by a crash doesn't
+        let received = folder.join("partial").join(format_id(id)).with_extension("received");
+        OpenOptions::new().append(true).open(&received).unwrap().write_all(&[20, 0, 0]).unwrap();
+        let store = DiskStore::open(&folder).unwrap();
+        let block = store.get(id).unwrap().unwrap();
+        let partial = block.partial();
+        assert_eq!(partial.available, [(0, 5), (10, 20)]);
+        assert_eq!(partial.data[10..20], [7; 10]);
+        drop(block);
+        let mut store = store;
+        store.write_chunk(id, 20, &[7; 5]).unwrap();
+        drop(store);
+        let store = DiskStore::open(&folder).unwrap();
+        assert_eq!(store.get(id).unwrap().unwrap().partial().available, [(0, 5), (10, 25)]);
+        drop(store);
+
+        // the availability per chunk of older versions can't be resumed
+        fs::remove_file(&received).unwrap();
+        fs::write(received.with_extension("available"), [0, 1, 0]).unwrap();
+        let store = DiskStore::open(&folder).unwrap();
+        assert!(!store.contains(id));
+        assert!(fs::read_dir(folder.join("partial")).unwrap().next().is_none());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+
+use super::{Block, BlockId, BlockRef, BlockStore};
+
+/// Keeps all blocks in memory, everything is lost on exit.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    blocks: HashMap<BlockId, Block>,
+}
+
+impl BlockStore for MemoryStore {
+    fn contains(&self, id: BlockId) -> bool {
+        self.blocks.contains_key(&id)
+    }
+
+    fn get(&self, id: BlockId) -> io::Result<Option<Cow<'_, Block>>> {
+        Ok(self.blocks.get(&id).map(Cow::Borrowed))
+    }
+
+    fn insert(&mut self, block: Block) -> io::Result<()> {
+        self.blocks.insert(block.id(), block);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: BlockId) -> io::Result<Option<Block>> {
+        Ok(self.blocks.remove(&id))
+    }
+
+    fn write_chunk(&mut self, id: BlockId, offset: usize, data: &[u8]) -> io::Result<()> {
+        self.blocks.get_mut(&id).expect("block not in blockdb").partial_mut().write_chunk(offset, data);
+        Ok(())
+    }
+
+    fn roots(&self) -> Option<(BlockRef, Option<BlockRef>)> {
+        None
+    }
+
+    fn save_roots(&mut self, _root: &BlockRef, _pending_root: Option<&BlockRef>) -> io::Result<()> {
+        Ok(())
+    }
+}
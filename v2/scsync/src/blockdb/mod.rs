@@ -0,0 +1,368 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::fmt::Debug;
+use std::io;
+use std::mem;
+
+use tiny_keccak;
+
+mod memory;
+mod disk;
+
+pub use self::memory::MemoryStore;
+pub use self::disk::DiskStore;
+
+pub type BlockId = [u8; 32];
+pub type Key = [u8; 16];
+
+/// Storage of the blocks and roots behind a `BlockDb`.
+pub trait BlockStore: Debug + Send {
+    fn contains(&self, id: BlockId) -> bool;
+
+    /// Returns the block, which may be read from storage.
+    fn get(&self, id: BlockId) -> io::Result<Option<Cow<'_, Block>>>;
+
+    /// Returns whether the block is there, but only partially.
+    fn is_partial(&self, id: BlockId) -> io::Result<bool> {
+        Ok(self.get(id)?.map_or(false, |block| block.is_partial()))
+    }
+
+    /// Returns the length of the block's data.
+    fn block_len(&self, id: BlockId) -> io::Result<Option<u64>> {
+        Ok(self.get(id)?.map(|block| block.len()))
+    }
+
+    /// Reads up to `len` bytes of the full block at `offset`, without reading all of it.
+    fn read_at(&self, id: BlockId, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let block = self.get(id)?.expect("block not in blockdb");
+        Ok(block.full().data.iter().cloned().skip(offset).take(len).collect())
+    }
+
+    /// Stores the block, replacing any block with the same id.
+    fn insert(&mut self, block: Block) -> io::Result<()>;
+
+    fn remove(&mut self, id: BlockId) -> io::Result<Option<Block>>;
+
+    /// Writes a chunk of a partial block at `offset` and marks its bytes available.
+    fn write_chunk(&mut self, id: BlockId, offset: usize, data: &[u8]) -> io::Result<()>;
+
+    /// Returns the root and pending root last saved, if any.
+    fn roots(&self) -> Option<(BlockRef, Option<BlockRef>)>;
+
+    fn save_roots(&mut self, root: &BlockRef, pending_root: Option<&BlockRef>) -> io::Result<()>;
+}
+
+#[derive(Debug)]
+pub struct BlockDb {
+    /// Current official root
+    root: BlockRef,
+    /// Client: Pending update to the server
+    pending_root: Option<BlockRef>,
+    store: Box<dyn BlockStore>,
+}
+
+impl BlockDb {
+    /// Creates a BlockDb with given root, whose blocks are already in the store.
+    pub fn new(root: BlockRef, mut store: Box<dyn BlockStore>) -> BlockDb {
+        store.save_roots(&root, None).expect("can't save roots");
+        BlockDb {
+            root,
+            pending_root: None,
+            store,
+        }
+    }
+
+    /// Continues with the roots saved in the store, returning the store if it has none.
+    pub fn open(store: Box<dyn BlockStore>) -> Result<BlockDb, Box<dyn BlockStore>> {
+        match store.roots() {
+            Some((root, pending_root)) => Ok(BlockDb {
+                root,
+                pending_root,
+                store,
+            }),
+            None => Err(store),
+        }
+    }
+
+    pub fn root(&self) -> &BlockRef {
+        &self.root
+    }
+
+    pub fn pending_root(&self) -> Option<&BlockRef> {
+        self.pending_root.as_ref()
+    }
+
+    pub fn set_pending_root(&mut self, pending_root: BlockRef) {
+        self.pending_root = Some(pending_root);
+        self.save_roots();
+    }
+
+    /// returns old root
+    pub fn apply_pending(&mut self) -> BlockRef {
+        let old = mem::replace(&mut self.root, self.pending_root.take().unwrap());
+        self.save_roots();
+        old
+    }
+
+    fn save_roots(&mut self) {
+        self.store.save_roots(&self.root, self.pending_root.as_ref()).expect("can't save roots");
+    }
+
+    pub fn contains(&self, id: BlockId) -> bool {
+        self.store.contains(id)
+    }
+
+    pub fn get(&self, id: BlockId) -> Cow<'_, Block> {
+        self.store.get(id).expect("can't read block").expect("block not in blockdb")
+    }
+
+    pub fn is_partial(&self, id: BlockId) -> bool {
+        self.store.is_partial(id).expect("can't read block")
+    }
+
+    pub fn block_len(&self, id: BlockId) -> u64 {
+        self.store.block_len(id).expect("can't read block").expect("block not in blockdb")
+    }
+
+    /// Reads up to `len` bytes of the full block at `offset`.
+    pub fn read_at(&self, id: BlockId, offset: usize, len: usize) -> Vec<u8> {
+        self.store.read_at(id, offset, len).expect("can't read block")
+    }
+
+    pub fn write_chunk(&mut self, id: BlockId, offset: usize, data: &[u8]) {
+        self.store.write_chunk(id, offset, data).expect("can't store chunk");
+    }
+
+    /// Returns the byte ranges of the block which are there, e.g. received before a restart.
+    pub fn available(&self, id: BlockId) -> Vec<(u64, u64)> {
+        match self.store.get(id).expect("can't read block") {
+            Some(block) => match *block {
+                Block::Partial(ref partial) => partial.available.clone(),
+                Block::Full(ref full) => vec![(0, full.data.len() as u64)],
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Adds an empty partial block of `len` bytes, keeping the bytes received so far if it's
+    /// already there with that length.
+    pub fn add_partial(&mut self, id: BlockId, len: usize) {
+        let keep = match self.store.get(id).expect("can't read block") {
+            Some(block) => match *block {
+                Block::Partial(ref existing) => existing.data.len() == len,
+                Block::Full(_) => true,
+            },
+            None => false,
+        };
+        if !keep {
+            self.store.insert(Block::Partial(Partial::new(id, len))).expect("can't store block");
+        }
+    }
+
+    /// Turns the partial block into a full one once all bytes are available.
+    ///
+    /// If the data doesn't match the id, e.g. because chunks were lost in a crash, the block is
+    /// reset to be received again.
+    pub fn try_promote(&mut self, id: BlockId) -> bool {
+        let reset = {
+            let block = self.get(id);
+            let partial = block.partial();
+            if !partial.is_complete() {
+                return false;
+            }
+            if tiny_keccak::keccak256(&partial.data) == id {
+                None
+            } else {
+                Some(Partial::new(id, partial.data.len()))
+            }
+        };
+        if let Some(reset) = reset {
+            trace!("block {:x?} doesn't match its id, receiving it again", id);
+            self.store.insert(Block::Partial(reset)).expect("can't store block");
+            return false;
+        }
+        let partial = match self.store.remove(id).expect("can't remove block").unwrap() {
+            Block::Full(_) => unreachable!(),
+            Block::Partial(p) => p,
+        };
+        self.store.insert(Block::Full(Full {
+            id: partial.id,
+            data: partial.data,
+        })).expect("can't store block");
+        true
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockRef {
+    pub blockid: BlockId,
+    pub key: Key,
+    pub hints: Vec<Hint>,
+}
+
+impl BlockRef {
+    pub fn new(blockid: BlockId, key: Key, hints: Vec<Hint>) -> BlockRef {
+        BlockRef {
+            blockid,
+            key,
+            hints,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Hint {
+    pub blockref: BlockRef,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Block {
+    Partial(Partial),
+    Full(Full),
+}
+
+impl Block {
+    pub fn id(&self) -> BlockId {
+        match self {
+            Block::Partial(p) => p.id,
+            Block::Full(f) => f.id,
+        }
+    }
+
+    pub fn is_partial(&self) -> bool {
+        match self {
+            Block::Partial(_) => true,
+            Block::Full(_) => false,
+        }
+    }
+
+    pub fn full(&self) -> &Full {
+        match self {
+            Block::Full(full) => full,
+            Block::Partial(_) => panic!("expected full block, got partial one")
+        }
+    }
+
+    pub fn partial(&self) -> &Partial {
+        match self {
+            Block::Full(_) => panic!("expected partial block, got full one"),
+            Block::Partial(par) => par,
+        }
+    }
+
+    pub fn partial_mut(&mut self) -> &mut Partial {
+        match self {
+            Block::Full(_) => panic!("expected partial block, got full one"),
+            Block::Partial(par) => par,
+        }
+    }
+    pub fn len(&self) -> u64 {
+        match self {
+            Block::Full(f) => f.data.len() as u64,
+            Block::Partial(p) => p.data.len() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Partial {
+    pub id: BlockId,
+    pub data: Vec<u8>,
+    /// byte ranges of `data` received so far as `(from, to)`, sorted and merged
+    ///
+    /// They don't depend on the chunkids of a transfer, so they stay valid for the next one.
+    pub available: Vec<(u64, u64)>,
+}
+
+impl Partial {
+    /// Creates a partial block of `len` bytes, none of them received yet.
+    pub fn new(id: BlockId, len: usize) -> Partial {
+        Partial {
+            id,
+            data: vec![0; len],
+            available: Vec::new(),
+        }
+    }
+
+    pub fn write_chunk(&mut self, offset: usize, data: &[u8]) {
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        self.mark(offset as u64, (offset + data.len()) as u64);
+    }
+
+    /// Marks the bytes from `from` to `to` available.
+    pub fn mark(&mut self, from: u64, to: u64) {
+        if from >= to {
+            return;
+        }
+        let pos = self.available.iter().position(|&(_, end)| end >= from).unwrap_or(self.available.len());
+        let mut range = (from, to);
+        while pos < self.available.len() && self.available[pos].0 <= range.1 {
+            let (start, end) = self.available.remove(pos);
+            range = (cmp::min(start, range.0), cmp::max(end, range.1));
+        }
+        self.available.insert(pos, range);
+    }
+
+    /// Returns whether all bytes from `from` to `to` are available.
+    pub fn has(&self, from: u64, to: u64) -> bool {
+        from >= to || self.available.iter().any(|&(start, end)| start <= from && to <= end)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.has(0, self.data.len() as u64)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Full {
+    pub id: BlockId,
+    pub data: Vec<u8>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resume() {
+        let data = vec![9; 25];
+        let id = tiny_keccak::keccak256(&data);
+        let mut db = BlockDb::new(BlockRef::new([0; 32], [0; 16], Vec::new()), Box::new(MemoryStore::default()));
+        db.add_partial(id, 25);
+        db.write_chunk(id, 10, &data[10..20]);
+
+        // the same length keeps the bytes
+        db.add_partial(id, 25);
+        assert_eq!(db.available(id), [(10, 20)]);
+        // another length discards them
+        db.add_partial(id, 24);
+        assert_eq!(db.available(id), []);
+        db.add_partial(id, 25);
+
+        for &(from, to) in &[(20, 25), (0, 5), (3, 12)] {
+            db.write_chunk(id, from, &data[from..to]);
+            assert!(!db.try_promote(id));
+        }
+        assert_eq!(db.available(id), [(0, 12), (20, 25)]);
+        db.write_chunk(id, 10, &data[10..20]);
+        assert!(db.try_promote(id));
+        assert_eq!(db.get(id).full().data, data);
+        assert_eq!(db.available(id), [(0, 25)]);
+    }
+
+    #[test]
+    fn test_mark() {
+        let mut partial = Partial::new([0; 32], 100);
+        for &(from, to) in &[(50, 60), (10, 20), (30, 40), (20, 25), (38, 50), (70, 70)] {
+            partial.mark(from, to);
+        }
+        assert_eq!(partial.available, [(10, 25), (30, 60)]);
+        assert!(partial.has(12, 25) && partial.has(30, 60) && partial.has(80, 80));
+        assert!(!partial.has(24, 31) && !partial.has(0, 100));
+        partial.mark(0, 100);
+        assert_eq!(partial.available, [(0, 100)]);
+        assert!(partial.is_complete());
+    }
+}
@@ -1,17 +1,149 @@
 use std::io::Cursor;
 use std::io::ErrorKind;
+use std::{cmp, u64};
 
 use tokio_io::codec::{Encoder, Decoder};
 use bytes::{Bytes, BytesMut, BufMut};
 use serde_cbor;
 use varmint::{self, ReadVarInt, WriteVarInt};
-use itertools::Itertools;
+use bitte_ein_bit::rle;
 
 use blockdb::{BlockId, BlockRef, Key};
+use seal::{self, MasterKey, Nonce, Replay, Replays, NONCE_LEN};
 
 pub const MTU: usize = 1460;
 
-pub struct MyCodec;
+/// Number of bytes of block data in the `TransferPayload` with `chunkid`, filling the MTU after
+/// the discriminator and the chunkid varint.
+pub fn chunk_len(chunkid: u64) -> usize {
+    MTU - 1 - varmint::len_u64_varint(chunkid)
+}
+
+/// First chunkid after `chunkid` with a longer varint.
+fn next_varint_len(chunkid: u64) -> u64 {
+    match varmint::len_u64_varint(chunkid) {
+        10 => u64::MAX,
+        len => 1 << (7 * len),
+    }
+}
+
+/// Offset of the data in `chunkid` from the start of a range of a block sent from `start_id` on.
+pub fn chunk_offset(start_id: u64, chunkid: u64) -> usize {
+    // chunkids with the same varint length carry the same amount of data
+    let (mut id, mut offset) = (start_id, 0);
+    while id < chunkid {
+        let end = cmp::min(next_varint_len(id), chunkid);
+        offset += (end - id) as usize * chunk_len(id);
+        id = end;
+    }
+    offset
+}
+
+/// End of the chunkids needed to transfer `len` bytes starting at `start_id`.
+pub fn chunk_end(start_id: u64, len: usize) -> u64 {
+    let (mut id, mut remaining) = (start_id, len);
+    while remaining > 0 {
+        let chunk_len = chunk_len(id);
+        let chunks = cmp::min(((remaining + chunk_len - 1) / chunk_len) as u64, next_varint_len(id) - id);
+        remaining = remaining.saturating_sub(chunks as usize * chunk_len);
+        id += chunks;
+    }
+    id
+}
+
+/// Returns the byte ranges of a block of `len` bytes which aren't in `have`, as `(from, to)`.
+pub fn missing_ranges(have: &[(u64, u64)], len: u64) -> Vec<(u64, u64)> {
+    let mut have = have.to_vec();
+    have.sort();
+    let mut missing = Vec::new();
+    let mut position = 0;
+    for (from, to) in have {
+        if from > position {
+            missing.push((position, cmp::min(from, len)));
+        }
+        position = cmp::max(position, to);
+        if position >= len {
+            break;
+        }
+    }
+    if position < len {
+        missing.push((position, len));
+    }
+    missing.retain(|&(from, to)| from < to);
+    missing
+}
+
+/// End of the chunkids needed to transfer `ranges` of a block starting at `start_id`, each range
+/// starting with a chunk of its own.
+pub fn ranges_end(start_id: u64, ranges: &[(u64, u64)]) -> u64 {
+    ranges.iter().fold(start_id, |id, &(from, to)| chunk_end(id, (to - from) as usize))
+}
+
+/// Offset within the block and length of the data in `chunkid` of a transfer of `ranges` starting
+/// at `start_id`, see `ranges_end`.
+pub fn range_chunk(start_id: u64, ranges: &[(u64, u64)], chunkid: u64) -> Option<(u64, usize)> {
+    let mut id = start_id;
+    for &(from, to) in ranges {
+        let end = chunk_end(id, (to - from) as usize);
+        if id <= chunkid && chunkid < end {
+            let offset = from + chunk_offset(id, chunkid) as u64;
+            return Some((offset, cmp::min(chunk_len(chunkid) as u64, to - offset) as usize));
+        }
+        id = end;
+    }
+    None
+}
+
+const ROOT_UPDATE: u8 = 2;
+const ROOT_UPDATE_RESPONSE: u8 = 3;
+
+/// Seals root updates and their responses under the master key, see `seal`.
+pub struct MyCodec {
+    key: MasterKey,
+    /// `None` if replayed messages are accepted
+    replays: Option<Replays>,
+}
+
+impl MyCodec {
+    pub fn new(key: MasterKey) -> MyCodec {
+        MyCodec { key, replays: Some(Replays::default()) }
+    }
+
+    /// Creates a codec which accepts replayed and stale messages, for looking at captured traffic.
+    pub fn accepting_replays(key: MasterKey) -> MyCodec {
+        MyCodec { key, replays: None }
+    }
+
+    /// Opens a sealed message, failing if it isn't authentic or a replay.
+    fn open(&mut self, discriminator: u8, sealed: &[u8]) -> Result<(Nonce, Vec<u8>), ::std::io::Error> {
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid_data("sealed message truncated"));
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&sealed[..NONCE_LEN]);
+        let plaintext = seal::open(&self.key, &nonce, &[discriminator], &sealed[NONCE_LEN..])
+            .ok_or_else(|| invalid_data("message not authentic"))?;
+        if let Some(ref mut replays) = self.replays {
+            match replays.check(&nonce, seal::now()) {
+                Ok(()) => (),
+                Err(Replay::Seen) => return Err(invalid_data("message replayed")),
+                Err(Replay::Skewed(skew)) => {
+                    warn!("dropping sealed message stamped {:.1} s {} our clock, the peers' clocks must be within {} s",
+                        skew.abs() as f64 / 1000., if skew < 0 { "ahead of" } else { "behind" },
+                        seal::NONCE_LIFETIME / 1000);
+                    return Err(invalid_data("message stale or clocks out of sync"));
+                }
+            }
+        }
+        Ok((nonce, plaintext))
+    }
+
+    fn put_sealed(&self, discriminator: u8, nonce: &Nonce, plaintext: &[u8], dst: &mut BytesMut) {
+        dst.put_u8(discriminator);
+        dst.extend_from_slice(nonce);
+        dst.extend_from_slice(&seal::seal(&self.key, nonce, &[discriminator], plaintext));
+    }
+}
 
 impl Decoder for MyCodec {
     type Item = Msg;
@@ -38,39 +170,39 @@ impl Decoder for MyCodec {
                 }
             }),
             1 => Msg::TransferStatus({
-                TransferStatus { missing_ranges: VarintIter(buf).scan(0, |a, x| { *a += x; Some(*a) }).tuples().collect() }
+                let mut missing_ranges = Vec::new();
+                for run in rle::decode_runs(buf.get_ref()) {
+                    let (received, range) = run.map_err(|e| invalid_data(e.to_string()))?;
+                    if !received {
+                        missing_ranges.push((range.start, range.end));
+                    }
+                }
+                TransferStatus { missing_ranges }
             }),
-            2 => Msg::RootUpdate(serde_cbor::from_reader(buf).unwrap()),
-            3 => Msg::RootUpdateResponse(serde_cbor::from_reader(buf).unwrap()),
-            4 => Msg::BlockRequest(serde_cbor::from_reader(buf).unwrap()),
-            5 => Msg::BlockRequestResponse(serde_cbor::from_reader(buf).unwrap()),
-            _ => unreachable!()
+            ROOT_UPDATE => Msg::RootUpdate({
+                let (nonce, plaintext) = self.open(ROOT_UPDATE, buf.get_ref())?;
+                let (from_blockid, to_blockref) = serde_cbor::from_slice(&plaintext).map_err(invalid_data)?;
+                RootUpdate {
+                    nonce,
+                    from_blockid,
+                    to_blockref,
+                }
+            }),
+            ROOT_UPDATE_RESPONSE => Msg::RootUpdateResponse({
+                let (_nonce, plaintext) = self.open(ROOT_UPDATE_RESPONSE, buf.get_ref())?;
+                serde_cbor::from_slice(&plaintext).map_err(invalid_data)?
+            }),
+            4 => Msg::BlockRequest(serde_cbor::from_reader(buf).map_err(invalid_data)?),
+            5 => Msg::BlockRequestResponse(serde_cbor::from_reader(buf).map_err(invalid_data)?),
+            d => return Err(invalid_data(format!("unknown message {}", d))),
         }))
     }
 }
 
-pub struct VarintIter<T: AsRef<[u8]>>(Cursor<T>);
-
-impl<T: AsRef<[u8]>> VarintIter<T> {
-    #[allow(unused)]
-    pub fn new(t: T) -> VarintIter<T> {
-        VarintIter(Cursor::new(t))
-    }
+fn invalid_data<E: Into<Box<::std::error::Error + Send + Sync>>>(e: E) -> ::std::io::Error {
+    ::std::io::Error::new(ErrorKind::InvalidData, e)
 }
 
-impl<T: AsRef<[u8]>> Iterator for VarintIter<T> {
-    type Item = u64;
-
-    fn next(&mut self) -> Option<u64> {
-        match self.0.read_u64_varint() {
-            Ok(x) => Some(x),
-            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => None,
-            Err(e) => panic!("unexpected read error {:?}", e),
-        }
-    }
-}
-
-
 impl Encoder for MyCodec {
     type Item = Msg;
     type Error = ::std::io::Error;
@@ -90,19 +222,25 @@ impl Encoder for MyCodec {
             Msg::TransferStatus(status) => {
                 use std::iter::once;
                 dst.put_u8(1);
-                for value in status.missing_ranges.into_iter().flat_map(|(from, to)| once(from).chain(once(to)))
-                    .scan(0, |a, mut x| { x -= *a; *a += x; Some(x) }) {
-                    dst.writer().write_u64_varint(value)?;
-                }
-                dst.truncate(MTU);
+                // the chunks between the missing ranges are received
+                let mut position = 0;
+                let runs = status.missing_ranges.into_iter().flat_map(|(from, to)| {
+                    let received = from - position;
+                    position = to;
+                    once((true, received)).chain(once((false, to - from)))
+                });
+                let mut buf = [0; MTU - 1];
+                let len = rle::encode_runs(runs, &mut buf);
+                dst.extend_from_slice(&buf[..len]);
             }
             Msg::RootUpdate(update) => {
-                dst.put_u8(2);
-                serde_cbor::to_writer(&mut dst.writer(), &update).unwrap()
+                let plaintext = serde_cbor::to_vec(&(&update.from_blockid, &update.to_blockref)).unwrap();
+                self.put_sealed(ROOT_UPDATE, &update.nonce, &plaintext, dst);
             },
             Msg::RootUpdateResponse(res) => {
-                dst.put_u8(3);
-                serde_cbor::to_writer(&mut dst.writer(), &res).unwrap()
+                // responses don't carry a nonce of their own, each packet gets a fresh one
+                let plaintext = serde_cbor::to_vec(&res).unwrap();
+                self.put_sealed(ROOT_UPDATE_RESPONSE, &seal::fresh_nonce(), &plaintext, dst);
             },
             Msg::BlockRequest(req) => {
                 dst.put_u8(4);
@@ -139,7 +277,8 @@ pub struct TransferStatus {
     pub missing_ranges: Vec<(u64, u64)>, // from, to
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Sealed with its nonce, which must be fresh, see `seal::fresh_nonce`.
+#[derive(Debug, Clone)]
 pub struct RootUpdate {
     pub nonce: [u8; 12],
     pub from_blockid: BlockId,
@@ -156,6 +295,8 @@ pub struct RootUpdateResponse {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BlockRequest {
     pub blockid: BlockId,
+    /// byte ranges of the block the requester has, e.g. from before a restart, which aren't sent
+    pub have: Vec<(u64, u64)>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -164,4 +305,78 @@ pub struct BlockRequestResponse {
     pub start_id: u64,
     pub end_id: u64,
     pub len: u64,
+    /// byte ranges of the block sent with the chunkids from `start_id` on, see `ranges_end`
+    pub ranges: Vec<(u64, u64)>,
+}
+
+#[cfg(test)]
+mod test {
+    use blockdb::{BlockDb, BlockRef, DiskStore};
+    use std::{env, fs, process};
+    use tiny_keccak;
+
+    use super::*;
+
+    #[test]
+    fn test_missing_ranges() {
+        assert_eq!(missing_ranges(&[], 100), [(0, 100)]);
+        assert_eq!(missing_ranges(&[(50, 60), (0, 10), (5, 20), (90, 200)], 100), [(20, 50), (60, 90)]);
+        assert_eq!(missing_ranges(&[(0, 100)], 100), []);
+    }
+
+    #[test]
+    fn test_range_chunk() {
+        let ranges = [(0, 10), (100, 100 + 3 * 1457)];
+        let start_id = 200;
+        assert_eq!(ranges_end(start_id, &ranges), 204);
+        assert_eq!(range_chunk(start_id, &ranges, 199), None);
+        assert_eq!(range_chunk(start_id, &ranges, 200), Some((0, 10)));
+        assert_eq!(range_chunk(start_id, &ranges, 201), Some((100, 1457)));
+        assert_eq!(range_chunk(start_id, &ranges, 203), Some((100 + 2 * 1457, 1457)));
+        assert_eq!(range_chunk(start_id, &ranges, 204), None);
+    }
+
+    /// Sends the chunks of `ranges` from `start_id` on, except those `lost`, returning the bytes sent.
+    fn transfer(db: &mut BlockDb, data: &[u8], id: BlockId, start_id: u64, ranges: &[(u64, u64)],
+                lost: &dyn Fn(u64) -> bool) -> usize {
+        let mut sent = 0;
+        for chunkid in start_id..ranges_end(start_id, ranges) {
+            let (offset, len) = range_chunk(start_id, ranges, chunkid).unwrap();
+            sent += len;
+            if !lost(chunkid) {
+                db.write_chunk(id, offset as usize, &data[offset as usize..offset as usize + len]);
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn test_resume_after_restart() {
+        let folder = env::temp_dir().join(format!("scsync-resume-{}", process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let id = tiny_keccak::keccak256(&data);
+        let root = BlockRef::new([0; 32], [0; 16], Vec::new());
+
+        // the first transfer loses every third chunk before the receiver restarts
+        let mut db = BlockDb::new(root.clone(), Box::new(DiskStore::open(&folder).unwrap()));
+        db.add_partial(id, data.len());
+        let ranges = missing_ranges(&db.available(id), data.len() as u64);
+        assert_eq!(transfer(&mut db, &data, id, 1000, &ranges, &|chunkid| chunkid % 3 == 0), data.len());
+        assert!(!db.try_promote(id));
+        drop(db);
+
+        // the second one, with other chunkids, only sends the bytes which are missing
+        let mut db = BlockDb::new(root, Box::new(DiskStore::open(&folder).unwrap()));
+        db.add_partial(id, data.len());
+        let have = db.available(id);
+        let ranges = missing_ranges(&have, data.len() as u64);
+        let missing: u64 = ranges.iter().map(|&(from, to)| to - from).sum();
+        assert!(missing > 0 && missing < data.len() as u64 / 2, "{}", missing);
+        let sent = transfer(&mut db, &data, id, 1 << 20, &ranges, &|_| false);
+        assert_eq!(sent as u64, missing);
+        assert!(db.try_promote(id));
+        assert_eq!(db.get(id).full().data, data);
+        fs::remove_dir_all(&folder).unwrap();
+    }
 }
@@ -14,11 +14,11 @@ use crypto::aes::KeySize;
 use rand::{Rng, OsRng};
 use aesstream::{AesWriter, AesReader};
 
-use blockdb::{Full, BlockRef, BlockDb, Block, Key, BlockId};
+use blockdb::{Full, BlockRef, BlockDb, BlockStore, Block, Key, BlockId};
 
 mod visitor;
 
-use self::visitor::{ResolveBlockVisitor, VerifyVisitor};
+use self::visitor::{ResolveBlockVisitor, VerifyVisitor, IncompleteVisitor};
 
 #[derive(Debug)]
 pub struct Frontend<'a> {
@@ -32,12 +32,12 @@ impl<'a> Frontend<'a> {
         }
     }
 
-    pub fn blockdb_from_folder<P: AsRef<Path>>(folder: P) -> BlockDb {
+    /// Encrypts the folder into blocks of `store`.
+    pub fn blockdb_from_folder<P: AsRef<Path>>(folder: P, mut store: Box<dyn BlockStore>) -> BlockDb {
         let folder = folder.as_ref();
         assert!(folder.is_dir(), "root is not a folder");
         let walkdir = WalkDir::new(folder).contents_first(true);
         let mut map: HashMap<_, Vec<_>> = HashMap::new();
-        let mut blocks = HashMap::new();
 
         let mut root = None;
         for file in walkdir {
@@ -65,7 +65,7 @@ impl<'a> Frontend<'a> {
                     },
                 };
                 map.entry(parent).or_insert_with(Default::default).push(child);
-                blocks.insert(block.id(), block);
+                store.insert(block).unwrap();
             }
 
             if file.file_type().is_dir() {
@@ -73,7 +73,7 @@ impl<'a> Frontend<'a> {
                     children: map.remove(file.path()).unwrap_or_default(),
                 }.to_block();
                 let id = block.id();
-                blocks.insert(id, block);
+                store.insert(block).unwrap();
                 map.entry(file.path().parent().unwrap().to_owned()).or_insert_with(Default::default).push(Child {
                     name: file.file_name().to_string_lossy().into_owned(),
                     metadata: (),
@@ -90,7 +90,7 @@ impl<'a> Frontend<'a> {
                 }
             }
         }
-        BlockDb::new(root.unwrap(), blocks)
+        BlockDb::new(root.unwrap(), store)
     }
 
     pub fn write_to_dir<P: AsRef<Path>>(&self, folder: P) {
@@ -223,6 +223,22 @@ pub fn verify(blockdb: &BlockDb, pending: bool) -> bool {
     visitor::traverse(blockdb, root, &mut VerifyVisitor).is_none()
 }
 
+/// Returns the blocks to request to complete the root, those missing below an incomplete block
+/// can only be known once it's complete.
+pub fn incomplete(blockdb: &BlockDb, pending: bool) -> Vec<BlockId> {
+    let root = if pending {
+        blockdb.pending_root().unwrap()
+    } else {
+        blockdb.root()
+    };
+    if !blockdb.contains(root.blockid) || blockdb.is_partial(root.blockid) {
+        return vec![root.blockid];
+    }
+    let mut visitor = IncompleteVisitor(Vec::new());
+    visitor::traverse(blockdb, root, &mut visitor);
+    visitor.0
+}
+
 fn from_full<T: Deserialize<'static>>(full: &Full, key: &Key) -> Result<T> {
     trace!("start decryption: {:?}", full.id);
     let enc = if util::supports_aesni() {
@@ -13,7 +13,8 @@ pub trait Visitor<T> {
 }
 
 pub fn traverse<T, V: Visitor<T>>(blockdb: &BlockDb, root: &BlockRef, visitor: &mut V) -> Option<T> {
-    let full = blockdb.get(root.blockid).full();
+    let block = blockdb.get(root.blockid);
+    let full = block.full();
     let dir = Dir::from_full(full, &root.key).unwrap();
     let path = PathBuf::new();
     if let Some(t) = visitor.visit_dir(&path, full, &dir) {
@@ -27,7 +28,7 @@ fn traverse_rec<T, V: Visitor<T>>(blockdb: &BlockDb, path: PathBuf, bref: &Block
         return visitor.visit_missing(path, bref.blockid);
     }
     let block = blockdb.get(bref.blockid);
-    if let Block::Partial(partial) = block {
+    if let Block::Partial(ref partial) = *block {
         return visitor.visit_partial(path, partial);
     }
     let full = block.full();
@@ -40,13 +41,13 @@ fn traverse_rec<T, V: Visitor<T>>(blockdb: &BlockDb, path: PathBuf, bref: &Block
 
             for Child { name, _type, blockref, .. } in dir.children {
                 if !blockdb.contains(blockref.blockid) {
-                    if let Some(t) = visitor.visit_missing(&path, bref.blockid) {
+                    if let Some(t) = visitor.visit_missing(&path, blockref.blockid) {
                         return Some(t);
                     }
                     continue;
                 }
                 let block = blockdb.get(blockref.blockid);
-                if let Block::Partial(partial) = block {
+                if let Block::Partial(ref partial) = *block {
                     if let Some(t) = visitor.visit_partial(&path, partial) {
                         return Some(t);
                     }
@@ -138,4 +139,37 @@ impl Visitor<()> for VerifyVisitor {
     fn visit_missing<P: AsRef<Path>>(&mut self, _path: P, _blockid: [u8; 32]) -> Option<()> {
         Some(())
     }
-}
\ No newline at end of file
+}
+
+/// Collects the blocks which are missing or only partially received, as far as they are known.
+pub struct IncompleteVisitor(pub Vec<BlockId>);
+
+impl Visitor<()> for IncompleteVisitor {
+    fn visit_dir<P: AsRef<Path>>(&mut self, _path: P, _block: &Full, _dir: &Dir) -> Option<()> {
+        None
+    }
+
+    fn visit_meta<P: AsRef<Path>>(&mut self, blockdb: &BlockDb, _path: P, _block: &Full, meta: Meta) -> Option<()> {
+        // meta-leaves aren't visited
+        for leaf in meta.blocks {
+            if !blockdb.contains(leaf.blockid) || blockdb.is_partial(leaf.blockid) {
+                self.0.push(leaf.blockid);
+            }
+        }
+        None
+    }
+
+    fn visit_leaf<P: AsRef<Path>>(&mut self, _path: P, _block: &Full, _leaf: Leaf) -> Option<()> {
+        None
+    }
+
+    fn visit_partial<P: AsRef<Path>>(&mut self, _path: P, block: &Partial) -> Option<()> {
+        self.0.push(block.id);
+        None
+    }
+
+    fn visit_missing<P: AsRef<Path>>(&mut self, _path: P, blockid: [u8; 32]) -> Option<()> {
+        self.0.push(blockid);
+        None
+    }
+}
@@ -12,17 +12,12 @@ use futures::sync::{mpsc, oneshot};
 use tokio;
 use tokio::timer::Delay;
 use rand;
-use itertools::Itertools;
 
-use blockdb::{BlockDb, BlockId, Block, Partial};
-use codec::{RootUpdate, RootUpdateResponse, Msg, BlockRequest, BlockRequestResponse,
+use blockdb::{BlockDb, BlockId};
+use codec::{self, RootUpdate, RootUpdateResponse, Msg, BlockRequest, BlockRequestResponse,
     TransferPayload, TransferStatus};
 use frontend::{self, Decoded, Frontend};
-
-// TODO dynamic chunk size scaling
-// not yet implemented
-// for now maximal constant size given u64 varints and hope for the best
-const CHUNK_SIZE: usize = 1450;
+use seal;
 
 #[derive(Default)]
 struct Client {
@@ -35,7 +30,7 @@ struct TransferOut {
     todo: Vec<(u64, u64)>, // [(from, to)] in chunkids
     cursor: Option<u64>,
 
-    transfers: Vec<(u64, u64, BlockId)>, // [(from, to, block)] in chunks
+    transfers: Vec<Transfer>,
     transfer_cursor: u64,
 }
 
@@ -71,6 +66,8 @@ struct Transfer {
     blockid: BlockId,
     /// Range of ChunkIDs assigned for this transfer
     id_range: Range<u64>,
+    /// Byte ranges of the block sent with these ChunkIDs, see `codec::ranges_end`
+    ranges: Vec<(u64, u64)>,
 }
 
 impl TransferIn {
@@ -121,7 +118,7 @@ impl<'a> Handler<'a> {
         self.tx.clone().send((Msg::RootUpdate(RootUpdate {
             from_blockid: [0; 32], // TODO: what is the empty state?
             to_blockref: self.blockdb.lock().unwrap().root().clone(),
-            nonce: rand::random(),
+            nonce: seal::fresh_nonce(),
         }), srv)).map(|_sender| trace!("initial rootupdate sent")).map_err(|_| unreachable!())
     }
 
@@ -143,17 +140,25 @@ impl<'a> Handler<'a> {
                 .map(|_sender| ()).map_err(|_| unreachable!()));
         } else {
             let mut clients = self.clients.lock().unwrap();
-            if blockdb.pending_root().is_some() {
-                // ignore request until new root is done
-                return;
+            if let Some(pending) = blockdb.pending_root() {
+                // ignore request until new root is done, unless it's the one we were
+                // receiving before a restart
+                if pending.blockid != update.to_blockref.blockid {
+                    return;
+                }
             }
             // open connection
             clients.insert(addr, Client::default());
-            let blockid = update.to_blockref.blockid;
             blockdb.set_pending_root(update.to_blockref);
-            // request new root
+            // request new root, or what's left of it after a restart
             trace!("register client");
-            self.send_block_request(clients.get_mut(&addr).unwrap(), BlockRequest { blockid }, addr);
+            let incomplete = frontend::incomplete(&*blockdb, true);
+            if incomplete.is_empty() {
+                self.apply_pending(&mut *blockdb, &mut *clients, addr);
+            }
+            for blockid in incomplete {
+                self.send_block_request(clients.get_mut(&addr).unwrap(), new_block_request(&blockdb, blockid), addr);
+            }
         }
     }
 
@@ -163,6 +168,7 @@ impl<'a> Handler<'a> {
 
     pub fn root_update_response(&self, addr: SocketAddr, res: RootUpdateResponse) -> impl Future<Item = (), Error = io::Error> {
         trace!("got RootUpdateResponse: {:?}", res);
+        let req = new_block_request(&self.blockdb.lock().unwrap(), res.to_blockid);
         let mut r = self.clients.lock().unwrap();
         let client = r.get_mut(&addr).unwrap();
         // prepare to receive a block request response, then send one out
@@ -177,7 +183,7 @@ impl<'a> Handler<'a> {
 
         let tx = self.tx.clone();
         request_retry(orx, move || {
-            (&tx).clone().send((Msg::BlockRequest(BlockRequest { blockid: res.to_blockid }), addr.clone()))
+            (&tx).clone().send((Msg::BlockRequest(req.clone()), addr.clone()))
                 .map(|_| ())
         })
     }
@@ -189,22 +195,27 @@ impl<'a> Handler<'a> {
         let client = r.get_mut(&addr).unwrap();
 
         let out = &mut client.transfer_out;
+        // only what the client doesn't have yet, e.g. after a restart
+        let len = bdb.block_len(req.blockid);
+        let ranges = codec::missing_ranges(&req.have, len);
         // we are idempotent: is this block already allocated for transfer?
-        let transfer = out.transfers.iter().find(|&(_from, _to, ref id)| id == &req.blockid).map(Clone::clone).unwrap_or_else(|| {
+        // allocations for other ranges stay, their chunks may still be requested
+        let transfer = out.transfers.iter().find(|t| t.blockid == req.blockid && t.ranges == ranges).map(Clone::clone).unwrap_or_else(|| {
             // allocate transfer ids
             let id = out.transfer_cursor;
-            let len = bdb.get(req.blockid.clone()).len();
-            out.transfer_cursor = id + len;
-            out.transfers.push((id, id + len, req.blockid.clone()));
+            let end = codec::ranges_end(id, &ranges);
+            out.transfer_cursor = end;
+            out.transfers.push(Transfer { blockid: req.blockid, id_range: id..end, ranges });
             out.transfers.last().unwrap().clone() // clone cause i dont wanna fight with borrowck about this
         });
 
         // if this is lost it doesn't matter, the client will request again
         self.tx.clone().send((Msg::BlockRequestResponse(BlockRequestResponse {
             blockid: req.blockid,
-            start_id: transfer.0,
-            end_id: transfer.1,
-            len: bdb.get(req.blockid).len(),
+            start_id: transfer.id_range.start,
+            end_id: transfer.id_range.end,
+            len,
+            ranges: transfer.ranges,
         }), addr))
             .map(|_sender| ()).map_err(|_| unreachable!())
     }
@@ -217,15 +228,21 @@ impl<'a> Handler<'a> {
             task.send(()).unwrap();
         }
         let tin = &mut client.transfer_in;
-        tin.transfers.push(Transfer {
-            blockid: res.blockid,
-            id_range: res.start_id..res.end_id,
-        });
-        self.blockdb.lock().unwrap().add(Block::Partial(Partial {
-            id: res.blockid,
-            data: vec![0; res.len as usize],
-            available: vec![false; (res.len as usize + CHUNK_SIZE - 1) / CHUNK_SIZE],
-        }));
+        {
+            let mut blockdb = self.blockdb.lock().unwrap();
+            if blockdb.contains(res.blockid) && !blockdb.is_partial(res.blockid)
+                || tin.transfers.iter().any(|t| t.blockid == res.blockid) {
+                // duplicate response of a completed or running transfer
+                return;
+            }
+            tin.transfers.push(Transfer {
+                blockid: res.blockid,
+                id_range: res.start_id..res.end_id,
+                ranges: res.ranges,
+            });
+            // keeps the bytes already received before a restart, which the request told about
+            blockdb.add_partial(res.blockid, res.len as usize);
+        }
 
         if tin.transfers.len() == 1 {
             // launch new status-update sender
@@ -268,14 +285,21 @@ impl<'a> Handler<'a> {
                         let mut rle = Vec::new();
                         // if transfer is outstanding, fill rle
                         if let Some(client) = clients.lock().unwrap().get(&addr) {
+                            let blockdb = blockdb.lock().unwrap();
                             for t in &client.transfer_in.transfers {
-                                let mut start = t.id_range.start;
-                                for (b, group) in blockdb.lock().unwrap().get(t.blockid).partial().available.iter().cloned().group_by(|&x| x).into_iter() {
-                                    let count = group.count();
-                                    if !b {
-                                        rle.push((start, start + count as u64));
+                                let block = blockdb.get(t.blockid);
+                                let partial = block.partial();
+                                for chunkid in t.id_range.clone() {
+                                    // bytes received before a restart count, whatever chunk they came in
+                                    let missing = codec::range_chunk(t.id_range.start, &t.ranges, chunkid)
+                                        .map_or(false, |(offset, len)| !partial.has(offset, offset + len as u64));
+                                    if !missing {
+                                        continue;
+                                    }
+                                    match rle.last_mut() {
+                                        Some(&mut (_, ref mut to)) if *to == chunkid => *to += 1,
+                                        _ => rle.push((chunkid, chunkid + 1)),
                                     }
-                                    start += count as u64;
                                 }
                             }
                         }
@@ -316,13 +340,11 @@ impl<'a> Handler<'a> {
             tin.transfer(chunk.chunkid)
         };
         {
-            let partial = blockdb.get_mut(transfer.blockid).partial_mut();
-            let id = (chunk.chunkid - transfer.id_range.start) as usize;
-            trace!("id within transfer: {}", id);
-            let from = id * CHUNK_SIZE;
-            let to = from + cmp::min(CHUNK_SIZE, chunk.data.len());
-            partial.data[from..to].copy_from_slice(&chunk.data);
-            partial.available[id] = true;
+            let (offset, len) = codec::range_chunk(transfer.id_range.start, &transfer.ranges, chunk.chunkid)
+                .expect("chunkid within the transfer");
+            trace!("offset within block: {}", offset);
+            let len = cmp::min(len, chunk.data.len());
+            blockdb.write_chunk(transfer.blockid, offset as usize, &chunk.data[..len]);
         }
         if !blockdb.try_promote(transfer.blockid) {
             return;
@@ -342,12 +364,12 @@ impl<'a> Handler<'a> {
         match dec {
             Decoded::Dir(dir) => for child in dir.children {
                 if !blockdb.contains(child.blockref.blockid) {
-                    self.send_block_request(clients.get_mut(&addr).unwrap(), BlockRequest { blockid: child.blockref.blockid }, addr);
+                    self.send_block_request(clients.get_mut(&addr).unwrap(), new_block_request(&blockdb, child.blockref.blockid), addr);
                 }
             }
             Decoded::Meta(meta) => for leaf in meta.blocks {
                 if !blockdb.contains(leaf.blockid) {
-                    self.send_block_request(clients.get_mut(&addr).unwrap(), BlockRequest { blockid: leaf.blockid }, addr);
+                    self.send_block_request(clients.get_mut(&addr).unwrap(), new_block_request(&blockdb, leaf.blockid), addr);
                 }
             }
             Decoded::Leaf(_) => (),
@@ -358,6 +380,11 @@ impl<'a> Handler<'a> {
         }
         // all transfers complete
         trace!("all transfers complete");
+        self.apply_pending(&mut *blockdb, &mut *clients, addr);
+    }
+
+    /// Makes the completely received pending root the root and notifies the clients.
+    fn apply_pending(&self, blockdb: &mut BlockDb, clients: &mut HashMap<SocketAddr, Client>, addr: SocketAddr) {
         let from = blockdb.apply_pending();
         { Frontend::from_blockdb(&*blockdb).write_to_dir(&self.folder); }
 
@@ -410,12 +437,13 @@ impl<'a> Handler<'a> {
                     // if we send, we update the cursor to the next valid todo
 
                     // need an allocated transfer where the cursor points
-                    if let Some(&(from, _, bid)) = out.transfers.iter().find(|&(from, to, _)| cursor >= *from && cursor < *to) {
-                        let cib = (cursor - from) as usize; // todo checked cast
+                    if let Some(transfer) = out.transfers.iter().find(|t| t.id_range.start <= cursor && cursor < t.id_range.end) {
+                        let (offset, len) = codec::range_chunk(transfer.id_range.start, &transfer.ranges, cursor)
+                            .expect("chunkid within the transfer");
 
                         // grab data from block so we can send
                         let mut bdb = bdb.lock().unwrap();
-                        let payload: Vec<_> = bdb.get(bid.clone()).full().data.iter().cloned().skip(cib * CHUNK_SIZE).take(CHUNK_SIZE).collect();
+                        let payload = bdb.read_at(transfer.blockid, offset as usize, len);
 
                         // find next valid chunk
                         let next = cursor + 1;
@@ -450,6 +478,14 @@ impl<'a> Handler<'a> {
     }
 }
 
+/// Requests the block, telling which bytes of it are already there.
+fn new_block_request(blockdb: &BlockDb, blockid: BlockId) -> BlockRequest {
+    BlockRequest {
+        blockid,
+        have: blockdb.available(blockid),
+    }
+}
+
 fn request_retry<T, F, B>(rx: oneshot::Receiver<T>, mut f: F) -> impl Future<Item = T, Error = io::Error>
     where F: FnMut() -> B, B: IntoFuture<Item = ()>, T: ::std::fmt::Debug {
     future::loop_fn(rx, move |rx| {
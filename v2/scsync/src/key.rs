@@ -0,0 +1,263 @@
+//! Provisioning of the master key shared by all devices of a user, see `scsync key --help`.
+//!
+//! The key is either generated randomly on one device and moved to the others with a printable
+//! code, or derived from a passphrase and the user's name on every device.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::path::Path;
+use std::process;
+
+use crypto::scrypt::{self, ScryptParams};
+use libc;
+use rand::{Rng, OsRng};
+use tiny_keccak;
+
+use seal::{self, MasterKey};
+
+/// scrypt with N = 2^16 and r = 8 takes 64 MiB
+const SCRYPT_LOG_N: u8 = 16;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+/// Followed by the user's name, so every device of a user derives the same key from the same
+/// passphrase, but users with the same passphrase get different keys.
+const SCRYPT_SALT: &str = "scsync master key for ";
+
+/// RFC 4648 base32, all of it is in the alphanumeric mode of QR codes
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+/// Bytes of the key hash appended to the exported code to catch typos
+const CHECKSUM_LEN: usize = 2;
+const GROUP_LEN: usize = 5;
+
+#[derive(StructOpt)]
+#[structopt(name = "scsync key", about = "Manages the master key shared by all devices")]
+pub enum KeyCommand {
+    /// Creates the keyfile with a random key, or one derived from a passphrase read from stdin
+    #[structopt(name = "init")]
+    Init {
+        #[structopt(short = "k", long = "keyfile", default_value = "scsync.key")]
+        keyfile: String,
+        /// Derive the key from a passphrase, the same passphrase and user give the same key on
+        /// every device
+        #[structopt(long = "passphrase", requires = "user")]
+        passphrase: bool,
+        /// Name of the user the key is derived for, e.g. the account or server name
+        #[structopt(long = "user")]
+        user: Option<String>,
+        /// Overwrite an existing keyfile, its key is lost
+        #[structopt(long = "force")]
+        force: bool,
+    },
+    /// Prints the key as code to import on other devices
+    #[structopt(name = "export")]
+    Export {
+        #[structopt(short = "k", long = "keyfile", default_value = "scsync.key")]
+        keyfile: String,
+    },
+    /// Creates the keyfile from an exported code read from stdin
+    #[structopt(name = "import")]
+    Import {
+        #[structopt(short = "k", long = "keyfile", default_value = "scsync.key")]
+        keyfile: String,
+        /// Overwrite an existing keyfile, its key is lost
+        #[structopt(long = "force")]
+        force: bool,
+    },
+}
+
+pub fn run(command: KeyCommand) {
+    let res = match command {
+        KeyCommand::Init { keyfile, passphrase, user, force } => {
+            let key = match user {
+                Some(ref user) if passphrase => read_passphrase().map(|passphrase| derive(user, &passphrase)),
+                _ => Ok(OsRng::new().unwrap().gen()),
+            };
+            key.and_then(|key| write_keyfile(&keyfile, &key, force))
+        }
+        KeyCommand::Export { keyfile } => seal::read_keyfile(&keyfile).map(|key| println!("{}", export(&key))),
+        KeyCommand::Import { keyfile, force } => {
+            eprint!("code: ");
+            read_line().and_then(|code| match import(&code) {
+                Some(key) => write_keyfile(&keyfile, &key, force),
+                None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid code, check for typos")),
+            })
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Reads a line from stdin without the line break.
+fn read_line() -> io::Result<String> {
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Prompts for the passphrase, without echoing it if stdin is a terminal, and rejects an empty one.
+fn read_passphrase() -> io::Result<String> {
+    eprint!("passphrase: ");
+    let fd = libc::STDIN_FILENO;
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    // fails if stdin isn't a terminal, e.g. a pipe, which doesn't echo anyways
+    let terminal = unsafe { libc::tcgetattr(fd, &mut termios) } == 0;
+    if terminal {
+        let mut hidden = termios;
+        hidden.c_lflag &= !libc::ECHO;
+        hidden.c_lflag |= libc::ECHONL;
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &hidden) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let passphrase = read_line();
+    if terminal {
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &termios) };
+    }
+    let passphrase = passphrase?;
+    if passphrase.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty passphrase"));
+    }
+    Ok(passphrase)
+}
+
+/// Derives the key of `user` from a passphrase with scrypt.
+pub fn derive(user: &str, passphrase: &str) -> MasterKey {
+    let mut key = [0; 32];
+    let params = ScryptParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P);
+    let salt = format!("{}{}", SCRYPT_SALT, user);
+    scrypt::scrypt(passphrase.as_bytes(), salt.as_bytes(), &params, &mut key);
+    key
+}
+
+/// Writes the keyfile, readable only by the user.
+///
+/// With `force`, the key is written to a temporary file first and renamed over the existing
+/// keyfile, so a crash never leaves it without a key.
+pub fn write_keyfile<P: AsRef<Path>>(path: P, key: &MasterKey, force: bool) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    let mut file = {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(if force { &tmp } else { path })?;
+        // the mode only applies to new files, e.g. not to a leftover temporary one
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file
+    };
+    #[cfg(not(unix))]
+    let mut file = options.open(if force { &tmp } else { path })?;
+    file.write_all(key)?;
+    file.sync_all()?;
+    if force {
+        fs::rename(tmp, path)?;
+    }
+    Ok(())
+}
+
+/// Encodes the key and a checksum as base32 in dash-separated groups.
+pub fn export(key: &MasterKey) -> String {
+    let mut bytes = key.to_vec();
+    bytes.extend_from_slice(&tiny_keccak::keccak256(key)[..CHECKSUM_LEN]);
+
+    let mut code = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    let push = |index: u32, code: &mut String| {
+        if code.len() % (GROUP_LEN + 1) == GROUP_LEN {
+            code.push('-');
+        }
+        code.push(BASE32[index as usize] as char);
+    };
+    for &byte in &bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            push((buffer >> bits) & 0x1f, &mut code);
+        }
+    }
+    if bits > 0 {
+        push((buffer << (5 - bits)) & 0x1f, &mut code);
+    }
+    code
+}
+
+/// Decodes an exported code, ignoring case, dashes and whitespace. Returns `None` if the code is
+/// malformed or the checksum doesn't match.
+pub fn import(code: &str) -> Option<MasterKey> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in code.chars().filter(|&c| c != '-' && !c.is_whitespace()) {
+        let index = BASE32.iter().position(|&b| b as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | index;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    if bytes.len() != 32 + CHECKSUM_LEN {
+        return None;
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes[..32]);
+    if tiny_keccak::keccak256(&key)[..CHECKSUM_LEN] != bytes[32..] {
+        return None;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_export_import() {
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = i as u8 * 7;
+        }
+        let code = export(&key);
+        // 34 bytes are 55 characters in groups of 5
+        assert_eq!(code.len(), 55 + 10);
+        assert!(code.split('-').all(|group| group.len() == GROUP_LEN));
+        assert_eq!(import(&code), Some(key));
+        assert_eq!(import(&format!(" {}\n", code.to_lowercase().replace('-', ""))), Some(key));
+
+        // typos
+        let mut typo = code.clone().into_bytes();
+        typo[3] = if typo[3] == b'A' { b'B' } else { b'A' };
+        assert_eq!(import(&String::from_utf8(typo).unwrap()), None);
+        assert_eq!(import(&code[..code.len() - 1]), None);
+        assert_eq!(import(&format!("{}A", code)), None);
+        // not base32
+        assert_eq!(import(&format!("1{}", &code[1..])), None);
+    }
+
+    #[test]
+    fn test_derive() {
+        // scrypt N = 2^16, r = 8, p = 1 of the passphrase with the salt "scsync master key for alice"
+        assert_eq!(derive("alice", "correct horse battery staple"), [
+            0x46, 0x2e, 0x69, 0xdf, 0x76, 0x85, 0x75, 0x30,
+            0x8d, 0x20, 0xf6, 0xb9, 0x49, 0xe5, 0x27, 0x52,
+            0xad, 0xf4, 0xac, 0x54, 0x33, 0x50, 0x2e, 0xb7,
+            0xfc, 0x95, 0x39, 0x3e, 0x26, 0x41, 0xa7, 0x8b,
+        ]);
+        assert_ne!(derive("bob", "correct horse battery staple"), derive("alice", "correct horse battery staple"));
+    }
+}
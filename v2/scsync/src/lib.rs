@@ -0,0 +1,20 @@
+//! The wire format of scsync and the blocks and seals it carries, shared with tools decoding
+//! captures.
+
+#[macro_use]
+extern crate log;
+extern crate tokio_io;
+extern crate bytes;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_cbor;
+extern crate varmint;
+extern crate bitte_ein_bit;
+extern crate tiny_keccak;
+extern crate crypto;
+extern crate rand;
+
+pub mod blockdb;
+pub mod codec;
+pub mod seal;
@@ -10,14 +10,17 @@ extern crate serde;
 extern crate serde_derive;
 extern crate serde_cbor;
 extern crate varmint;
+extern crate bitte_ein_bit;
 extern crate itertools;
 extern crate walkdir;
 extern crate tiny_keccak;
 extern crate crypto;
 extern crate rand;
 extern crate aesstream;
+extern crate libc;
 #[macro_use]
 extern crate structopt;
+extern crate scsync;
 
 use structopt::StructOpt;
 
@@ -36,6 +39,13 @@ pub struct Opt {
     /// Directory to upload files from
     #[structopt(short = "f", long = "files")]
     files: String,
+    /// File containing the master key shared by all devices, see `scsync key`. The clocks of the
+    /// devices must be within 5 minutes of each other, root updates are dropped otherwise
+    #[structopt(short = "k", long = "keyfile", default_value = "scsync.key")]
+    keyfile: String,
+    /// Directory to persist the blockdb in, continuing with its root if it has one
+    #[structopt(short = "d", long = "db")]
+    db: Option<String>,
     #[structopt(short = "cc", long = "packet-rate")]
     pps: u32,
 }
@@ -49,12 +59,14 @@ use std::usize;
 use std::net::{SocketAddr, ToSocketAddrs};
 
 mod frontend;
-mod blockdb;
-mod codec;
 mod handler;
+mod key;
+
+use scsync::{blockdb, codec, seal};
 
 use std::time::Duration;
 
+use blockdb::{BlockDb, DiskStore, MemoryStore};
 use codec::{Msg, MyCodec};
 use frontend::Frontend;
 use handler::{Handler, ClientState};
@@ -62,6 +74,11 @@ use handler::{Handler, ClientState};
 fn main() {
     env_logger::init();
 
+    if std::env::args().nth(1).map_or(false, |arg| arg == "key") {
+        key::run(key::KeyCommand::from_iter(std::env::args().skip(1)));
+        return;
+    }
+
     let opt = Opt::from_args();
     let addr = SocketAddr::new(opt.host.parse().unwrap(), opt.port);
     let bind_addr = if opt.server {
@@ -70,11 +87,19 @@ fn main() {
         "0.0.0.0:0".to_socket_addrs().unwrap().next().unwrap()
     };
 
-    let blockdb = Frontend::blockdb_from_folder(&opt.files);
+    let blockdb = match opt.db {
+        Some(ref db) => {
+            let store = DiskStore::open(db).unwrap();
+            BlockDb::open(Box::new(store))
+                .unwrap_or_else(|store| Frontend::blockdb_from_folder(&opt.files, store))
+        }
+        None => Frontend::blockdb_from_folder(&opt.files, Box::new(MemoryStore::default())),
+    };
 
     let socket = UdpSocket::bind(&bind_addr).unwrap();
 
-    let framed = UdpFramed::new(socket, MyCodec);
+    let key = seal::read_keyfile(&opt.keyfile).unwrap();
+    let framed = UdpFramed::new(socket, MyCodec::new(key));
     let (utx, rx) = framed.split();
     let (tx, crx) = mpsc::channel(1); // would like this to be 0 but impossibruh
     // fuck 'static
@@ -97,6 +122,17 @@ fn main() {
     let send_task = crx.inspect(|msg| trace!("send {:?}", msg))
         .forward(utx).map(|(_, _)| ());
 
+    // drop messages which are malformed or not authentic before they reach the handler
+    let rx = rx.then(|res| -> Result<_, std::io::Error> {
+        match res {
+            Ok(msg) => Ok(Some(msg)),
+            Err(e) => {
+                trace!("dropping invalid message: {}", e);
+                Ok(None)
+            }
+        }
+    }).filter_map(|msg| msg);
+
     let recv_task = rx.map(move |(msg, addr)| {
         trace!("received message from {}", addr);
         match handler.client_state(&addr) {
@@ -0,0 +1,179 @@
+//! AES-GCM sealing of the control messages carrying roots under the user's master key.
+//!
+//! A sealed message is its nonce followed by the ciphertext and the tag. The message type is
+//! authenticated as additional data, so a response can't be passed off as an update.
+//!
+//! Nonces start with the sender's clock, which lets the receiver reject recorded messages, see
+//! `Replays`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use rand;
+
+pub type MasterKey = [u8; 32];
+pub type Nonce = [u8; 12];
+
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+/// Milliseconds a nonce's time may be off from the receiver's clock, covering both the delay
+/// of the message and the difference of the clocks.
+pub const NONCE_LIFETIME: u64 = 5 * 60 * 1000;
+
+/// Milliseconds since the epoch.
+pub fn now() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).expect("clock before 1970");
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64
+}
+
+/// Returns a nonce made of the current time in milliseconds followed by random bytes.
+pub fn fresh_nonce() -> Nonce {
+    let mut nonce: Nonce = rand::random();
+    let now = now();
+    for (i, byte) in nonce[..8].iter_mut().enumerate() {
+        *byte = (now >> (56 - 8 * i)) as u8;
+    }
+    nonce
+}
+
+/// Time in milliseconds at which a nonce was made by `fresh_nonce`.
+fn nonce_time(nonce: &Nonce) -> u64 {
+    nonce[..8].iter().fold(0, |time, &byte| time << 8 | byte as u64)
+}
+
+/// Why `Replays::check` rejected a message.
+#[derive(Debug, PartialEq)]
+pub enum Replay {
+    /// the nonce was seen before
+    Seen,
+    /// the nonce's time is this many milliseconds behind the receiver's clock, or ahead of it if
+    /// negative, which is more than `NONCE_LIFETIME`
+    Skewed(i64),
+}
+
+/// Nonces of the messages received within the last `NONCE_LIFETIME`.
+///
+/// Messages with a nonce seen before or further than `NONCE_LIFETIME` away from the current time
+/// are replays, which could e.g. roll the root back to an older one.
+#[derive(Debug, Default)]
+pub struct Replays {
+    seen: HashSet<Nonce>,
+}
+
+impl Replays {
+    /// Checks that a message with `nonce` received at `now` is new, remembering its nonce.
+    ///
+    /// Only call this for authentic messages, forged ones would fill up the nonces.
+    pub fn check(&mut self, nonce: &Nonce, now: u64) -> Result<(), Replay> {
+        let time = nonce_time(nonce);
+        if time.saturating_add(NONCE_LIFETIME) < now || time > now.saturating_add(NONCE_LIFETIME) {
+            return Err(Replay::Skewed(now.wrapping_sub(time) as i64));
+        }
+        // older nonces are rejected by their time anyways
+        self.seen.retain(|nonce| nonce_time(nonce).saturating_add(NONCE_LIFETIME) >= now);
+        if self.seen.insert(*nonce) {
+            Ok(())
+        } else {
+            Err(Replay::Seen)
+        }
+    }
+}
+
+/// Reads the master key from a keyfile containing its raw bytes.
+pub fn read_keyfile<P: AsRef<Path>>(path: P) -> io::Result<MasterKey> {
+    let data = fs::read(path)?;
+    if data.len() != 32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "keyfile doesn't contain a 32 byte key"));
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&data);
+    Ok(key)
+}
+
+/// Encrypts `plaintext`, returning the ciphertext followed by the tag over it and `aad`.
+pub fn seal(key: &MasterKey, nonce: &Nonce, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut sealed = vec![0; plaintext.len() + TAG_LEN];
+    {
+        let (ciphertext, tag) = sealed.split_at_mut(plaintext.len());
+        AesGcm::new(KeySize::KeySize256, key, nonce, aad).encrypt(plaintext, ciphertext, tag);
+    }
+    sealed
+}
+
+/// Decrypts the output of `seal`, returning `None` if it isn't authentic.
+pub fn open(key: &MasterKey, nonce: &Nonce, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < TAG_LEN {
+        return None;
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+    let mut plaintext = vec![0; ciphertext.len()];
+    if AesGcm::new(KeySize::KeySize256, key, nonce, aad).decrypt(ciphertext, &mut plaintext, tag) {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: MasterKey = [7; 32];
+
+    #[test]
+    fn test_seal() {
+        let nonce = fresh_nonce();
+        let sealed = seal(&KEY, &nonce, &[2], b"root");
+        assert_eq!(sealed.len(), 4 + TAG_LEN);
+        assert_eq!(open(&KEY, &nonce, &[2], &sealed), Some(b"root".to_vec()));
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert_eq!(open(&KEY, &nonce, &[2], &tampered), None);
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(open(&KEY, &nonce, &[2], &tampered), None);
+        assert_eq!(open(&KEY, &nonce, &[2], &sealed[..TAG_LEN - 1]), None);
+        // a different message type
+        assert_eq!(open(&KEY, &nonce, &[3], &sealed), None);
+        assert_eq!(open(&[8; 32], &nonce, &[2], &sealed), None);
+        let mut other_nonce = nonce;
+        other_nonce[11] ^= 1;
+        assert_eq!(open(&KEY, &other_nonce, &[2], &sealed), None);
+    }
+
+    #[test]
+    fn test_replays() {
+        let nonce = fresh_nonce();
+        let sent = nonce_time(&nonce);
+        assert!(sent <= now() && now() - sent < 1000);
+
+        let mut replays = Replays::default();
+        assert_eq!(replays.check(&nonce, sent + 10), Ok(()));
+        assert_eq!(replays.check(&nonce, sent + 20), Err(Replay::Seen));
+        let mut other = nonce;
+        other[11] ^= 1;
+        assert_eq!(replays.check(&other, sent + 20), Ok(()));
+
+        // stale or from the future
+        let mut replays = Replays::default();
+        assert_eq!(replays.check(&nonce, sent + NONCE_LIFETIME + 1), Err(Replay::Skewed(NONCE_LIFETIME as i64 + 1)));
+        assert_eq!(replays.check(&nonce, sent - NONCE_LIFETIME - 1), Err(Replay::Skewed(-(NONCE_LIFETIME as i64) - 1)));
+        assert_eq!(replays.check(&nonce, sent + NONCE_LIFETIME), Ok(()));
+        assert_eq!(replays.check(&nonce, sent + NONCE_LIFETIME), Err(Replay::Seen));
+        // forgotten once stale
+        let mut later = [0; 12];
+        let time = sent + 2 * NONCE_LIFETIME;
+        for (i, byte) in later[..8].iter_mut().enumerate() {
+            *byte = (time >> (56 - 8 * i)) as u8;
+        }
+        assert_eq!(replays.check(&later, time), Ok(()));
+        assert_eq!(replays.seen.len(), 1);
+    }
+}